@@ -1,7 +1,13 @@
+#[cfg(windows)]
 use std::path::Path;
 
+#[cfg(windows)]
 use registry::{Hive, RegKey, Security};
 
+#[cfg(not(windows))]
+fn main() {}
+
+#[cfg(windows)]
 fn main() -> Result<(), std::io::Error> {
     let hive_key = Hive::load_file(
         Path::new(r"C:\Users\Default\NTUSER.DAT"),
@@ -13,6 +19,7 @@ fn main() -> Result<(), std::io::Error> {
     Ok(())
 }
 
+#[cfg(windows)]
 fn walk_keys(key: RegKey, tabstop: i32) {
     for _ in 0..tabstop {
         print!("\t");
@@ -1,6 +1,12 @@
+#[cfg(windows)]
 use registry::{Hive, Security};
+#[cfg(windows)]
 use windows::{core::PCWSTR, Win32::{Foundation::{HANDLE, LUID}, Security::{AdjustTokenPrivileges, LookupPrivilegeValueW, LUID_AND_ATTRIBUTES, SE_BACKUP_NAME, SE_PRIVILEGE_ENABLED, SE_RESTORE_NAME, TOKEN_ADJUST_PRIVILEGES, TOKEN_PRIVILEGES}, System::Threading::{GetCurrentProcess, OpenProcessToken}}};
 
+#[cfg(not(windows))]
+fn main() {}
+
+#[cfg(windows)]
 fn main() -> Result<(), windows::core::Error> {
     let mut token = HANDLE::default();
     unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_ADJUST_PRIVILEGES, &mut token)? };
@@ -19,6 +25,7 @@ fn main() -> Result<(), windows::core::Error> {
     Ok(())
 }
 
+#[cfg(windows)]
 fn set_privilege(handle: HANDLE, name: PCWSTR) -> Result<(), windows::core::Error> {
     let mut luid: LUID = LUID {
         LowPart: 0,
@@ -0,0 +1,418 @@
+//! Checks that a backend behaves as the Windows registry does, for implementers of
+//! [`Backend`](../trait.Backend.html).
+//!
+//! Each [`Check`](enum.Check.html) runs through `Hive` and `RegKey` on a fresh backend from
+//! a factory, in a new key below a root that the backend must allow writing to. A failing check
+//! panics, naming the check, so the suite runs as an ordinary test.
+//!
+//! ```
+//! use std::sync::Arc;
+//! use registry::backend::{conformance::Suite, Backend, Memory};
+//!
+//! Suite::new(|| Arc::new(Memory::new()) as Arc<dyn Backend>).run();
+//! ```
+
+use std::convert::TryInto;
+use std::fmt::{self, Display};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+use super::{scoped, Backend};
+use crate::{key, value, Data, Hive, RegKey, Security};
+
+/// A group of behaviours checked together.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Check {
+    /// Keys and values are found by names in any case, and keep the case they were created
+    /// with.
+    CaseInsensitivity,
+    /// The default value has an empty name and does not exist until it is set.
+    DefaultValues,
+    /// Keys with subkeys are only deleted recursively, and handles to deleted keys fail.
+    Deletion,
+    /// Subkeys are enumerated sorted by their upper cased names, and enumerations reflect
+    /// keys and values added and removed since.
+    Enumeration,
+    /// Missing keys and values, and handles without the access needed, give the matching
+    /// error kinds.
+    ErrorKinds,
+    /// Every kind of `Data` reads back as it was written.
+    DataTypes,
+}
+
+impl Check {
+    pub const ALL: [Check; 6] = [
+        Check::CaseInsensitivity,
+        Check::DefaultValues,
+        Check::Deletion,
+        Check::Enumeration,
+        Check::ErrorKinds,
+        Check::DataTypes,
+    ];
+
+    fn run(self, key: &RegKey) {
+        match self {
+            Check::CaseInsensitivity => case_insensitivity(key),
+            Check::DefaultValues => default_values(key),
+            Check::Deletion => deletion(key),
+            Check::Enumeration => enumeration(key),
+            Check::ErrorKinds => error_kinds(key),
+            Check::DataTypes => data_types(key),
+        }
+    }
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Check::CaseInsensitivity => "case insensitivity",
+            Check::DefaultValues => "default values",
+            Check::Deletion => "deletion",
+            Check::Enumeration => "enumeration",
+            Check::ErrorKinds => "error kinds",
+            Check::DataTypes => "data types",
+        })
+    }
+}
+
+/// The name of the key each check runs in, below the suite's root.
+const KEY: &str = "registry-rs conformance";
+
+/// The checks to run against backends made by a factory.
+pub struct Suite<F> {
+    factory: F,
+    hive: Hive,
+    path: String,
+}
+
+impl<F> Suite<F>
+where
+    F: Fn() -> Arc<dyn Backend>,
+{
+    /// Checks backends from `factory`, running below `HKEY_CURRENT_USER\Software`.
+    pub fn new(factory: F) -> Suite<F> {
+        Suite {
+            factory,
+            hive: Hive::CurrentUser,
+            path: "Software".to_string(),
+        }
+    }
+
+    /// Runs the checks below `path` in `hive` instead, creating it if missing.
+    pub fn root(mut self, hive: Hive, path: &str) -> Suite<F> {
+        self.hive = hive;
+        self.path = path.to_string();
+        self
+    }
+
+    /// Runs every check, each on a new backend.
+    pub fn run(&self) {
+        for check in Check::ALL.iter() {
+            self.check(*check);
+        }
+    }
+
+    /// Runs one check on a new backend, removing the key it ran in afterwards.
+    pub fn check(&self, check: Check) {
+        let _scope = scoped((self.factory)());
+        let root = match self.hive.create(&*self.path, Security::AllAccess) {
+            Ok(root) => root,
+            Err(e) => panic!("{}: could not create root {}: {}", check, self.path, e),
+        };
+        // Left behind by an earlier run that was interrupted.
+        let _ = root.delete(KEY, true);
+        let key = match root.create(KEY, Security::AllAccess) {
+            Ok(key) => key,
+            Err(e) => panic!("{}: could not create {}: {}", check, KEY, e),
+        };
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| check.run(&key)));
+        drop(key);
+        let _ = root.delete(KEY, true);
+
+        if let Err(payload) = result {
+            let message = payload
+                .downcast_ref::<String>()
+                .map(|x| x.as_str())
+                .or_else(|| payload.downcast_ref::<&str>().copied())
+                .unwrap_or("panicked");
+            panic!("conformance check `{}` failed: {}", check, message);
+        }
+    }
+}
+
+impl<F> fmt::Debug for Suite<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Suite")
+            .field("hive", &self.hive)
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+fn string(s: &str) -> Data {
+    Data::String(s.try_into().unwrap())
+}
+
+fn key_names(key: &RegKey) -> Vec<String> {
+    key.keys().map(|x| x.unwrap().to_string()).collect()
+}
+
+fn value_names(key: &RegKey) -> Vec<String> {
+    let mut names = key
+        .values()
+        .map(|x| x.unwrap().into_name().to_string_lossy())
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+fn case_insensitivity(key: &RegKey) {
+    key.create(r"Contoso\Alpha", Security::AllAccess).unwrap();
+    assert!(key.open(r"CONTOSO\alpha", Security::Read).is_ok());
+    key.create("contoso", Security::AllAccess).unwrap();
+    assert_eq!(key_names(key), ["Contoso"], "keys keep their first case");
+
+    key.set_value("Name", &Data::U32(1)).unwrap();
+    key.set_value("NAME", &Data::U32(2)).unwrap();
+    assert_eq!(key.value("name").unwrap(), Data::U32(2));
+    assert_eq!(value_names(key), ["Name"], "values keep their first case");
+
+    key.delete_value("nAmE").unwrap();
+    assert!(key.value("Name").is_err());
+    key.delete(r"contoso\ALPHA", false).unwrap();
+    assert!(key.open(r"Contoso\Alpha", Security::Read).is_err());
+}
+
+fn default_values(key: &RegKey) {
+    assert!(matches!(key.value(""), Err(value::Error::NotFound(..))));
+    assert!(value_names(key).is_empty());
+
+    key.set_value("", &string("default")).unwrap();
+    assert_eq!(key.value("").unwrap(), string("default"));
+    assert_eq!(value_names(key), [""]);
+
+    key.delete_value("").unwrap();
+    assert!(matches!(key.value(""), Err(value::Error::NotFound(..))));
+}
+
+fn deletion(key: &RegKey) {
+    key.create(r"Parent\Child\Grandchild", Security::AllAccess)
+        .unwrap();
+    assert!(matches!(
+        key.delete("Parent", false),
+        Err(key::Error::PermissionDenied(..))
+    ));
+    assert!(key.open(r"Parent\Child\Grandchild", Security::Read).is_ok());
+
+    key.delete(r"Parent\Child\Grandchild", false).unwrap();
+    assert!(key.open(r"Parent\Child", Security::Read).is_ok());
+    key.delete("Parent", true).unwrap();
+    assert!(matches!(
+        key.open("Parent", Security::Read),
+        Err(key::Error::NotFound(..))
+    ));
+    assert!(matches!(
+        key.delete("Parent", false),
+        Err(key::Error::NotFound(..))
+    ));
+
+    // Deleting the empty path recursively empties the key but keeps it.
+    let tree = key.create("Tree", Security::AllAccess).unwrap();
+    tree.create(r"A\B", Security::AllAccess).unwrap();
+    tree.set_value("Value", &Data::U32(1)).unwrap();
+    tree.delete("", true).unwrap();
+    assert!(key_names(&tree).is_empty());
+    assert!(value_names(&tree).is_empty());
+    assert!(key.open("Tree", Security::Read).is_ok());
+
+    let doomed = key.create("Doomed", Security::AllAccess).unwrap();
+    key.delete("Doomed", false).unwrap();
+    assert!(doomed.set_value("Value", &Data::U32(1)).is_err());
+    assert!(doomed.create("Child", Security::AllAccess).is_err());
+}
+
+fn enumeration(key: &RegKey) {
+    for name in &["beta", "Alpha", "_under", "gamma"] {
+        key.create(*name, Security::AllAccess).unwrap();
+    }
+    assert_eq!(key_names(key), ["Alpha", "beta", "gamma", "_under"]);
+
+    key.delete("beta", false).unwrap();
+    key.create("Delta", Security::AllAccess).unwrap();
+    assert_eq!(key_names(key), ["Alpha", "Delta", "gamma", "_under"]);
+
+    for name in &["One", "Two", "Three"] {
+        key.set_value(*name, &string(name)).unwrap();
+    }
+    key.delete_value("Two").unwrap();
+    key.set_value("Four", &Data::U32(4)).unwrap();
+    key.set_value("One", &Data::U32(1)).unwrap();
+    assert_eq!(value_names(key), ["Four", "One", "Three"]);
+
+    let values = key
+        .values()
+        .map(|x| {
+            let x = x.unwrap();
+            (x.name().to_string_lossy(), x.data().clone())
+        })
+        .collect::<Vec<_>>();
+    assert!(values.contains(&("One".to_string(), Data::U32(1))));
+}
+
+fn error_kinds(key: &RegKey) {
+    assert!(matches!(
+        key.open("Missing", Security::Read),
+        Err(key::Error::NotFound(..))
+    ));
+    assert!(matches!(
+        key.open(r"Missing\Child", Security::Read),
+        Err(key::Error::NotFound(..))
+    ));
+    assert!(matches!(
+        key.value("Missing"),
+        Err(value::Error::NotFound(..))
+    ));
+    assert!(matches!(
+        key.delete_value("Missing"),
+        Err(value::Error::NotFound(..))
+    ));
+
+    key.create("Locked", Security::AllAccess)
+        .unwrap()
+        .set_value("Value", &Data::U32(1))
+        .unwrap();
+    let locked = key.open("Locked", Security::Read).unwrap();
+    assert_eq!(locked.value("Value").unwrap(), Data::U32(1));
+    assert!(matches!(
+        locked.set_value("Value", &Data::U32(2)),
+        Err(value::Error::PermissionDenied(..))
+    ));
+    assert!(matches!(
+        locked.delete_value("Value"),
+        Err(value::Error::PermissionDenied(..))
+    ));
+    assert!(matches!(
+        locked.create("Child", Security::AllAccess),
+        Err(key::Error::PermissionDenied(..))
+    ));
+
+    let write_only = key.open("Locked", Security::SetValue).unwrap();
+    assert!(matches!(
+        write_only.value("Value"),
+        Err(value::Error::PermissionDenied(..))
+    ));
+}
+
+fn data_types(key: &RegKey) {
+    let data = vec![
+        ("None", Data::None),
+        ("String", string("Größe ✓")),
+        ("Empty string", string("")),
+        (
+            "Expand string",
+            Data::ExpandString(r"%SystemRoot%\System32".try_into().unwrap()),
+        ),
+        ("Binary", Data::Binary(vec![0, 1, 2, 254, 255])),
+        ("Empty binary", Data::Binary(vec![])),
+        // Large enough to be stored in segments in a hive file.
+        (
+            "Large binary",
+            Data::Binary((0..40_000).map(|x| x as u8).collect()),
+        ),
+        ("U32", Data::U32(0x1234_FEFE)),
+        ("U32BE", Data::U32BE(0x1234_FEFE)),
+        ("U64", Data::U64(u64::MAX)),
+        (
+            "Multi string",
+            Data::MultiString(vec!["a".try_into().unwrap(), "Größe".try_into().unwrap()]),
+        ),
+        // The variants without data only stand for empty data, so the bytes these types
+        // hold are checked through `Data::Raw`.
+        ("Link", Data::Link),
+        ("Resource list", Data::ResourceList),
+        ("None with data", Data::Raw(0, vec![0, 1, 2])),
+        ("Link with data", Data::Raw(6, string("Target").to_bytes())),
+        ("Resource list with data", Data::Raw(8, vec![1, 0, 0, 0])),
+        ("Full resource descriptor", Data::Raw(9, vec![0xff; 16])),
+        (
+            "Resource requirements list",
+            Data::Raw(0xa, vec![0x20, 0, 0, 0]),
+        ),
+    ];
+
+    for (name, data) in &data {
+        key.set_value(*name, data).unwrap();
+    }
+    for (name, data) in &data {
+        assert_eq!(&key.value(*name).unwrap(), data, "{} reads back", name);
+    }
+    for value in key.values() {
+        let value = value.unwrap();
+        let name = value.name().to_string_lossy();
+        let expected = data.iter().find(|(x, _)| *x == name).unwrap();
+        assert_eq!(value.data(), &expected.1, "{} enumerates", name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{ErrorCode, Fault, Faults, Memory, Offline, Operation, Overlay, Redirect};
+    use crate::offline::Editor;
+
+    #[test]
+    fn memory_conforms() {
+        Suite::new(|| Arc::new(Memory::new()) as Arc<dyn Backend>).run();
+    }
+
+    #[test]
+    fn offline_conforms() {
+        Suite::new(|| {
+            Arc::new(Offline::new(Editor::new(), Hive::LocalMachine, "SOFTWARE").unwrap()) as _
+        })
+        .root(Hive::LocalMachine, "SOFTWARE")
+        .run();
+    }
+
+    #[test]
+    fn wrappers_conform() {
+        Suite::new(|| Arc::new(Overlay::new(Arc::new(Memory::new()))) as _).run();
+        Suite::new(|| {
+            let redirect = Redirect::new(Arc::new(Memory::new()))
+                .redirect(Hive::CurrentUser, Hive::LocalMachine, "User")
+                .unwrap();
+            Arc::new(redirect) as _
+        })
+        .run();
+    }
+
+    /// Checks the suite itself against the registry it describes.
+    #[cfg(windows)]
+    #[test]
+    fn live_registry_conforms() {
+        let _sandbox = crate::backend::Sandbox::new().unwrap();
+        Suite::new(crate::backend::current).run();
+    }
+
+    #[test]
+    fn failures_name_the_check() {
+        let suite = Suite::new(|| {
+            let faults = Faults::new(Arc::new(Memory::new()));
+            faults.inject(
+                Fault::new(ErrorCode::INVALID_PARAMETER)
+                    .on(Operation::SetValue)
+                    .named("U64"),
+            );
+            Arc::new(faults) as _
+        });
+        suite.check(Check::Enumeration);
+
+        let message = panic::catch_unwind(|| suite.check(Check::DataTypes))
+            .unwrap_err()
+            .downcast::<String>()
+            .unwrap();
+        assert!(message.starts_with("conformance check `data types` failed: "));
+    }
+}
@@ -0,0 +1,574 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use utfx::{U16CStr, U16CString};
+
+use super::{fold, join, Backend, ErrorCode, Handle, KeyEntry, RawValue};
+use crate::info::KeyInfo;
+use crate::sec::Security;
+use crate::{value, Data, Hive};
+
+/// The operations of a [`Backend`](trait.Backend.html) a [`Fault`](struct.Fault.html) can
+/// apply to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Operation {
+    Predefined,
+    OpenKey,
+    CreateKey,
+    DeleteKey,
+    EnumKey,
+    EnumValue,
+    QueryValue,
+    SetValue,
+    DeleteValue,
+    OpenCurrentUser,
+    LoadAppKey,
+    SaveKey,
+    QueryInfo,
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Trigger {
+    Always,
+    Nth(u64),
+    Probability(f64),
+}
+
+/// A failure for [`Faults`](struct.Faults.html) to inject, and the calls it applies to.
+///
+/// By default a fault applies to every call of every operation. Paths are matched against
+/// the full path of the key an operation works on, such as
+/// `HKEY_LOCAL_MACHINE\SOFTWARE\Contoso`, ignoring case. In patterns, `?` matches any one
+/// character, `*` any run of characters within a key name, and `**` any run of characters
+/// including backslashes. Application hives are matched as `<App>`.
+#[derive(Debug, Clone)]
+pub struct Fault {
+    code: ErrorCode,
+    operations: Vec<Operation>,
+    path: Option<Vec<u16>>,
+    name: Option<Vec<u16>>,
+    trigger: Trigger,
+    calls: u64,
+}
+
+impl Fault {
+    /// Fails with `code`.
+    pub fn new(code: ErrorCode) -> Fault {
+        Fault {
+            code,
+            operations: vec![],
+            path: None,
+            name: None,
+            trigger: Trigger::Always,
+            calls: 0,
+        }
+    }
+
+    /// Applies only to `operation`, and to any other operations given this way.
+    pub fn on(mut self, operation: Operation) -> Fault {
+        self.operations.push(operation);
+        self
+    }
+
+    /// Applies only to keys whose path matches `pattern`.
+    pub fn at(mut self, pattern: &str) -> Fault {
+        self.path = Some(fold(&pattern.encode_utf16().collect::<Vec<_>>()));
+        self
+    }
+
+    /// Applies only to values whose name matches `pattern`. Operations on keys, and the
+    /// enumeration of values, never match.
+    pub fn named(mut self, pattern: &str) -> Fault {
+        self.name = Some(fold(&pattern.encode_utf16().collect::<Vec<_>>()));
+        self
+    }
+
+    /// Fails only the `n`th matching call, counting from 1.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is 0.
+    pub fn nth(mut self, n: u64) -> Fault {
+        assert!(n > 0, "calls are counted from 1");
+        self.trigger = Trigger::Nth(n);
+        self
+    }
+
+    /// Fails each matching call with the given probability, from 0 to 1. The calls that
+    /// fail are decided by the seed of the `Faults` the fault is injected into.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `p` is not from 0 to 1.
+    pub fn probability(mut self, p: f64) -> Fault {
+        assert!((0.0..=1.0).contains(&p), "probability must be from 0 to 1");
+        self.trigger = Trigger::Probability(p);
+        self
+    }
+
+    fn matches(&self, operation: Operation, path: &[u16], name: Option<&U16CStr>) -> bool {
+        if !self.operations.is_empty() && !self.operations.contains(&operation) {
+            return false;
+        }
+        if let Some(pattern) = &self.path {
+            if !glob(pattern, &fold(path)) {
+                return false;
+            }
+        }
+        match (&self.name, name) {
+            (None, _) => true,
+            (Some(pattern), Some(name)) => glob(pattern, &fold(name.as_slice())),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+fn glob(pattern: &[u16], text: &[u16]) -> bool {
+    const STAR: u16 = b'*' as u16;
+    const QUESTION: u16 = b'?' as u16;
+    const SEPARATOR: u16 = b'\\' as u16;
+
+    match pattern {
+        [] => text.is_empty(),
+        [STAR, STAR, rest @ ..] => (0..=text.len()).any(|i| glob(rest, &text[i..])),
+        [STAR, rest @ ..] => {
+            let end = text
+                .iter()
+                .position(|c| *c == SEPARATOR)
+                .unwrap_or(text.len());
+            (0..=end).any(|i| glob(rest, &text[i..]))
+        }
+        [QUESTION, rest @ ..] => !text.is_empty() && glob(rest, &text[1..]),
+        [c, rest @ ..] => text.first() == Some(c) && glob(rest, &text[1..]),
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    faults: Vec<Fault>,
+    /// The full paths of the open handles.
+    paths: HashMap<usize, Vec<u16>>,
+    rng: u64,
+    injected: usize,
+}
+
+impl State {
+    /// A random number from 0 to 1, by xorshift.
+    fn random(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn check(
+        &mut self,
+        operation: Operation,
+        path: &[u16],
+        name: Option<&U16CStr>,
+    ) -> Result<(), ErrorCode> {
+        let mut code = None;
+        for i in 0..self.faults.len() {
+            if !self.faults[i].matches(operation, path, name) {
+                continue;
+            }
+            self.faults[i].calls += 1;
+            let is_hit = match self.faults[i].trigger {
+                Trigger::Always => true,
+                Trigger::Nth(n) => self.faults[i].calls == n,
+                Trigger::Probability(p) => self.random() < p,
+            };
+            if is_hit && code.is_none() {
+                code = Some(self.faults[i].code);
+            }
+        }
+
+        match code {
+            Some(code) => {
+                self.injected += 1;
+                log::debug!(
+                    "Injecting {} into {:?} of {}",
+                    code,
+                    operation,
+                    String::from_utf16_lossy(path)
+                );
+                Err(code)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn path(&self, handle: Handle) -> Vec<u16> {
+        self.paths.get(&handle.raw()).cloned().unwrap_or_default()
+    }
+}
+
+/// Wraps a backend, failing the calls that match the injected [`Fault`](struct.Fault.html)s
+/// with their error codes, so error handling can be tested deterministically.
+///
+/// A failing call is not passed on to the wrapped backend. Every fault counts the calls it
+/// matches, so faults that fail only some calls see the same calls whether or not another
+/// fault failed them. When several faults fail a call, the one injected first gives the
+/// error code.
+///
+/// ```
+/// use std::sync::Arc;
+/// use registry::backend::{self, ErrorCode, Fault, Faults, Memory, Operation};
+/// use registry::{key, Hive, Security};
+///
+/// let faults = Arc::new(Faults::new(Arc::new(Memory::new())));
+/// faults.inject(
+///     Fault::new(ErrorCode::ACCESS_DENIED)
+///         .on(Operation::CreateKey)
+///         .at(r"HKEY_CURRENT_USER\Software\*"),
+/// );
+///
+/// let _scope = backend::scoped(faults.clone());
+/// let result = Hive::CurrentUser.create(r"Software\Contoso", Security::AllAccess);
+/// assert!(matches!(result, Err(key::Error::PermissionDenied(..))));
+/// assert_eq!(faults.injected(), 1);
+/// ```
+#[derive(Debug)]
+pub struct Faults {
+    inner: Arc<dyn Backend>,
+    state: Mutex<State>,
+}
+
+impl Faults {
+    /// Wraps `inner`, with no faults injected yet.
+    pub fn new(inner: Arc<dyn Backend>) -> Faults {
+        Faults {
+            inner,
+            state: Mutex::new(State {
+                faults: vec![],
+                paths: HashMap::new(),
+                rng: 0x2545_f491_4f6c_dd1d,
+                injected: 0,
+            }),
+        }
+    }
+
+    /// Seeds the choice of calls failed by faults with a probability.
+    pub fn with_seed(self, seed: u64) -> Faults {
+        // Xorshift never leaves zero.
+        self.lock().rng = seed.max(1);
+        self
+    }
+
+    /// Adds a fault, applying to calls from now on.
+    pub fn inject(&self, fault: Fault) {
+        self.lock().faults.push(fault);
+    }
+
+    /// Removes every fault.
+    pub fn clear(&self) {
+        self.lock().faults.clear();
+    }
+
+    /// The number of calls failed so far.
+    pub fn injected(&self) -> usize {
+        self.lock().injected
+    }
+
+    /// The wrapped backend.
+    pub fn inner(&self) -> &Arc<dyn Backend> {
+        &self.inner
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Checks the faults for an operation on a key.
+    fn check(&self, operation: Operation, handle: Handle) -> Result<(), ErrorCode> {
+        let mut state = self.lock();
+        let path = state.path(handle);
+        state.check(operation, &path, None)
+    }
+
+    /// Checks the faults for an operation on a named value.
+    fn check_value(
+        &self,
+        operation: Operation,
+        handle: Handle,
+        name: &U16CStr,
+    ) -> Result<(), ErrorCode> {
+        let mut state = self.lock();
+        let path = state.path(handle);
+        state.check(operation, &path, Some(name))
+    }
+
+    /// Opens or creates a key, remembering its path.
+    fn open(
+        &self,
+        operation: Operation,
+        base: Handle,
+        path: &U16CStr,
+        open: impl FnOnce() -> Result<Handle, ErrorCode>,
+    ) -> Result<Handle, ErrorCode> {
+        let path = join(&self.lock().path(base), path);
+        self.lock().check(operation, &path, None)?;
+        let handle = open()?;
+        self.lock().paths.insert(handle.raw(), path);
+        Ok(handle)
+    }
+}
+
+impl Backend for Faults {
+    fn predefined(&self, hive: Hive) -> Result<Handle, ErrorCode> {
+        let path = hive.to_string().encode_utf16().collect::<Vec<_>>();
+        self.lock().check(Operation::Predefined, &path, None)?;
+        let handle = self.inner.predefined(hive)?;
+        self.lock().paths.insert(handle.raw(), path);
+        Ok(handle)
+    }
+
+    fn open_key(&self, base: Handle, path: &U16CStr, sec: Security) -> Result<Handle, ErrorCode> {
+        self.open(Operation::OpenKey, base, path, || {
+            self.inner.open_key(base, path, sec)
+        })
+    }
+
+    fn create_key(&self, base: Handle, path: &U16CStr, sec: Security) -> Result<Handle, ErrorCode> {
+        self.open(Operation::CreateKey, base, path, || {
+            self.inner.create_key(base, path, sec)
+        })
+    }
+
+    fn delete_key(
+        &self,
+        base: Handle,
+        path: &U16CStr,
+        is_recursive: bool,
+    ) -> Result<(), ErrorCode> {
+        {
+            let mut state = self.lock();
+            let path = join(&state.path(base), path);
+            state.check(Operation::DeleteKey, &path, None)?;
+        }
+        self.inner.delete_key(base, path, is_recursive)
+    }
+
+    fn close_key(&self, handle: Handle) {
+        self.lock().paths.remove(&handle.raw());
+        self.inner.close_key(handle)
+    }
+
+    fn enum_key(&self, handle: Handle, index: u32) -> Result<Option<KeyEntry>, ErrorCode> {
+        self.check(Operation::EnumKey, handle)?;
+        self.inner.enum_key(handle, index)
+    }
+
+    fn enum_value(
+        &self,
+        handle: Handle,
+        index: u32,
+    ) -> Result<Option<(U16CString, RawValue)>, ErrorCode> {
+        self.check(Operation::EnumValue, handle)?;
+        self.inner.enum_value(handle, index)
+    }
+
+    fn query_value(&self, handle: Handle, name: &U16CStr) -> Result<RawValue, ErrorCode> {
+        self.check_value(Operation::QueryValue, handle, name)?;
+        self.inner.query_value(handle, name)
+    }
+
+    fn set_value(&self, handle: Handle, name: &U16CStr, value: &RawValue) -> Result<(), ErrorCode> {
+        self.check_value(Operation::SetValue, handle, name)?;
+        self.inner.set_value(handle, name, value)
+    }
+
+    fn delete_value(&self, handle: Handle, name: &U16CStr) -> Result<(), ErrorCode> {
+        self.check_value(Operation::DeleteValue, handle, name)?;
+        self.inner.delete_value(handle, name)
+    }
+
+    fn open_current_user(&self, sec: Security) -> Result<Handle, ErrorCode> {
+        let path = Hive::CurrentUser
+            .to_string()
+            .encode_utf16()
+            .collect::<Vec<_>>();
+        self.lock().check(Operation::OpenCurrentUser, &path, None)?;
+        let handle = self.inner.open_current_user(sec)?;
+        self.lock().paths.insert(handle.raw(), path);
+        Ok(handle)
+    }
+
+    fn load_app_key(&self, file_path: &Path, sec: Security) -> Result<Handle, ErrorCode> {
+        let path = Hive::Application
+            .to_string()
+            .encode_utf16()
+            .collect::<Vec<_>>();
+        self.lock().check(Operation::LoadAppKey, &path, None)?;
+        let handle = self.inner.load_app_key(file_path, sec)?;
+        self.lock().paths.insert(handle.raw(), path);
+        Ok(handle)
+    }
+
+    fn save_key(&self, handle: Handle, file_path: &U16CStr) -> Result<(), ErrorCode> {
+        self.check(Operation::SaveKey, handle)?;
+        self.inner.save_key(handle, file_path)
+    }
+
+    fn query_info(&self, handle: Handle) -> Result<KeyInfo, ErrorCode> {
+        self.check(Operation::QueryInfo, handle)?;
+        self.inner.query_info(handle)
+    }
+
+    fn decode(&self, value: &RawValue) -> Result<Data, value::Error> {
+        self.inner.decode(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{scoped, Memory};
+    use crate::iter::keys;
+    use crate::{key, value, Data};
+
+    fn faults() -> Arc<Faults> {
+        let memory = Memory::new();
+        let _scope = scoped(Arc::new(memory));
+        let key = Hive::LocalMachine
+            .create(r"SOFTWARE\Contoso", Security::AllAccess)
+            .unwrap();
+        key.set_value("Level", &Data::U32(3)).unwrap();
+        for name in &["A", "B", "C"] {
+            key.create(*name, Security::AllAccess).unwrap();
+        }
+        Arc::new(Faults::new(key.backend().clone()))
+    }
+
+    #[test]
+    fn matches_paths() {
+        let matches = |pattern: &str, path: &str| {
+            let utf16 = |x: &str| fold(&x.encode_utf16().collect::<Vec<_>>());
+            glob(&utf16(pattern), &utf16(path))
+        };
+
+        let pattern = r"HKEY_*\software\**\Update";
+        assert!(matches(
+            pattern,
+            r"HKEY_LOCAL_MACHINE\SOFTWARE\Contoso\Update"
+        ));
+        assert!(matches(
+            pattern,
+            r"HKEY_CURRENT_USER\Software\Contoso\App\update"
+        ));
+        assert!(!matches(pattern, r"HKEY_LOCAL_MACHINE\SOFTWARE\Update"));
+        assert!(!matches(
+            pattern,
+            r"HKEY_LOCAL_MACHINE\SOFTWARE\Contoso\Updates"
+        ));
+
+        let pattern = r"HKEY_LOCAL_MACHINE\*\C?nt*";
+        assert!(matches(pattern, r"HKEY_LOCAL_MACHINE\SOFTWARE\Contoso"));
+        assert!(!matches(
+            pattern,
+            r"HKEY_LOCAL_MACHINE\SOFTWARE\Contoso\Update"
+        ));
+    }
+
+    #[test]
+    fn maps_codes_to_errors() {
+        let faults = faults();
+        faults.inject(
+            Fault::new(ErrorCode::ACCESS_DENIED)
+                .on(Operation::OpenKey)
+                .at(r"HKEY_LOCAL_MACHINE\SOFTWARE\Contoso"),
+        );
+        faults.inject(Fault::new(ErrorCode::FILE_NOT_FOUND).named("level"));
+        let _scope = scoped(faults.clone());
+
+        assert!(matches!(
+            Hive::LocalMachine.open(r"software\contoso\", Security::Read),
+            Err(key::Error::PermissionDenied(..))
+        ));
+
+        let key = Hive::LocalMachine
+            .create(r"SOFTWARE\Contoso", Security::AllAccess)
+            .unwrap();
+        assert!(matches!(
+            key.value("Level"),
+            Err(value::Error::NotFound(..))
+        ));
+        key.set_value("Other", &Data::U32(1)).unwrap();
+        assert_eq!(key.value("Other").unwrap(), Data::U32(1));
+        assert!(key.open("A", Security::Read).is_ok());
+        assert_eq!(faults.injected(), 2);
+
+        faults.clear();
+        assert_eq!(key.value("Level").unwrap(), Data::U32(3));
+    }
+
+    #[test]
+    fn fails_mid_enumeration() {
+        let faults = faults();
+        // An entry that cannot be read is skipped, while other failures end enumeration.
+        faults.inject(
+            Fault::new(ErrorCode::MORE_DATA)
+                .on(Operation::EnumKey)
+                .nth(2),
+        );
+        let _scope = scoped(faults.clone());
+
+        let key = Hive::LocalMachine
+            .open(r"SOFTWARE\Contoso", Security::Read)
+            .unwrap();
+        let results = key.keys().collect::<Vec<_>>();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap().to_string(), "A");
+        match &results[1] {
+            Err(keys::Error::Unknown(1, e)) => assert_eq!(e.raw_os_error(), Some(234)),
+            x => panic!("{:?}", x),
+        }
+        assert_eq!(results[2].as_ref().unwrap().to_string(), "C");
+
+        faults.inject(
+            Fault::new(ErrorCode::KEY_DELETED)
+                .on(Operation::EnumKey)
+                .nth(2),
+        );
+        let results = key.keys().collect::<Vec<_>>();
+        assert_eq!(results.len(), 2);
+        match &results[1] {
+            Err(keys::Error::Unknown(1, e)) => assert_eq!(e.raw_os_error(), Some(1018)),
+            x => panic!("{:?}", x),
+        }
+
+        let names = key
+            .keys()
+            .map(|x| x.unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["A", "B", "C"]);
+    }
+
+    #[test]
+    fn seeds_decide_probabilities() {
+        let run = |seed| {
+            let faults = faults();
+            let faults = Arc::new(Arc::try_unwrap(faults).unwrap().with_seed(seed));
+            faults.inject(
+                Fault::new(ErrorCode::REGISTRY_IO_FAILED)
+                    .on(Operation::QueryValue)
+                    .probability(0.5),
+            );
+            let _scope = scoped(faults.clone());
+
+            let key = Hive::LocalMachine
+                .open(r"SOFTWARE\Contoso", Security::Read)
+                .unwrap();
+            (0..64)
+                .map(|_| key.value("Level").is_ok())
+                .collect::<Vec<_>>()
+        };
+
+        let results = run(7);
+        assert_eq!(results, run(7));
+        assert_ne!(results, run(8));
+        assert!(results.iter().any(|x| *x) && results.iter().any(|x| !*x));
+    }
+}
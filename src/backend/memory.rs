@@ -0,0 +1,532 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+use utfx::{U16CStr, U16CString};
+
+use super::{
+    components, fold, has, Backend, ErrorCode, Handle, KeyEntry, RawValue, MAX_VALUE_NAME_LEN,
+    ROOTS,
+};
+use crate::info::{FileTime, KeyInfo};
+use crate::sec::Security;
+use crate::Hive;
+
+/// A registry held entirely in memory, behaving as the Windows registry does.
+///
+/// * Names are compared ignoring case, and keep the case they were created with.
+/// * Subkeys are enumerated sorted by their upper cased names, and values in the order they
+///   were first set.
+/// * The default value has an empty name, and does not exist until it is set.
+/// * Handles keep their access rights: querying or enumerating values needs
+///   `Security::QueryValue`, enumerating subkeys `Security::EnumerateSubKeys`, changing
+///   values `Security::SetValue` and creating subkeys `Security::CreateSubKey`.
+///   Lacking them gives `ERROR_ACCESS_DENIED`.
+/// * Handles to deleted keys give `ERROR_KEY_DELETED`, and closed handles
+///   `ERROR_INVALID_HANDLE`.
+/// * Deleting a key that has subkeys fails with `ERROR_ACCESS_DENIED` unless deleting
+///   recursively. Deleting recursively with an empty path empties the key but keeps it, as
+///   `RegDeleteTreeW` does. Hive roots cannot be deleted.
+///
+/// Every `Hive` except application hives has its own, initially empty root. Unlike on
+/// Windows, the roots are independent: `HKEY_CLASSES_ROOT` is not a view of the classes in
+/// `HKEY_LOCAL_MACHINE` and `HKEY_CURRENT_USER`.
+///
+/// ```
+/// use std::sync::Arc;
+/// use registry::{backend::{self, Memory}, Data, Hive, Security};
+///
+/// let _scope = backend::scoped(Arc::new(Memory::new()));
+/// let key = Hive::CurrentUser.create(r"Software\Contoso", Security::AllAccess).unwrap();
+/// key.set_value("Level", &Data::U32(3)).unwrap();
+///
+/// let key = Hive::CurrentUser.open(r"SOFTWARE\contoso", Security::Read).unwrap();
+/// assert_eq!(key.value("level").unwrap(), Data::U32(3));
+/// ```
+#[derive(Debug)]
+pub struct Memory {
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct Node {
+    name: U16CString,
+    parent: Option<usize>,
+    /// Sorted by upper cased name.
+    keys: Vec<usize>,
+    values: Vec<(U16CString, RawValue)>,
+    last_write_time: FileTime,
+    is_deleted: bool,
+}
+
+#[derive(Debug)]
+struct State {
+    /// Deleted keys stay in place, so handles to them can tell they were deleted.
+    nodes: Vec<Node>,
+    handles: HashMap<usize, (usize, Security)>,
+    next_handle: usize,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Memory::new()
+    }
+}
+
+impl Memory {
+    /// An empty registry, holding only the hive roots.
+    pub fn new() -> Memory {
+        let mut state = State {
+            nodes: vec![],
+            handles: HashMap::new(),
+            next_handle: 4,
+        };
+        for (hive, handle) in ROOTS.iter() {
+            let node = state.nodes.len();
+            state.nodes.push(Node {
+                name: U16CString::from_str(hive.to_string()).unwrap(),
+                parent: None,
+                keys: vec![],
+                values: vec![],
+                last_write_time: FileTime::now(),
+                is_deleted: false,
+            });
+            state.handles.insert(*handle, (node, Security::AllAccess));
+        }
+
+        Memory {
+            state: Mutex::new(state),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl State {
+    /// The key behind a handle, and the access it was opened with.
+    fn node(&self, handle: Handle) -> Result<(usize, Security), ErrorCode> {
+        let (node, sec) = *self
+            .handles
+            .get(&handle.raw())
+            .ok_or(ErrorCode::INVALID_HANDLE)?;
+        if self.nodes[node].is_deleted {
+            return Err(ErrorCode::KEY_DELETED);
+        }
+        Ok((node, sec))
+    }
+
+    /// Finds a subkey by name, or where it would be inserted to keep them sorted.
+    fn find(&self, node: usize, name: &[u16]) -> Result<usize, usize> {
+        let name = fold(name);
+        self.nodes[node]
+            .keys
+            .binary_search_by(|x| fold(self.nodes[*x].name.as_slice()).cmp(&name))
+    }
+
+    fn walk(&self, node: usize, path: &U16CStr) -> Result<usize, ErrorCode> {
+        components(path)?.into_iter().try_fold(node, |node, name| {
+            self.find(node, name)
+                .map(|i| self.nodes[node].keys[i])
+                .map_err(|_| ErrorCode::FILE_NOT_FOUND)
+        })
+    }
+
+    fn open(&mut self, node: usize, sec: Security) -> Handle {
+        let handle = self.next_handle;
+        // Handles are kept aligned, as Win32 handles are.
+        self.next_handle += 4;
+        self.handles.insert(handle, (node, sec));
+        Handle::new(handle)
+    }
+
+    fn touch(&mut self, node: usize) {
+        self.nodes[node].last_write_time = FileTime::now();
+    }
+
+    fn value(&self, node: usize, name: &U16CStr) -> Option<usize> {
+        let name = fold(name.as_slice());
+        self.nodes[node]
+            .values
+            .iter()
+            .position(|(x, _)| fold(x.as_slice()) == name)
+    }
+
+    /// Marks a key and everything below it as deleted.
+    fn mark_deleted(&mut self, node: usize) {
+        self.nodes[node].is_deleted = true;
+        for key in std::mem::take(&mut self.nodes[node].keys) {
+            self.mark_deleted(key);
+        }
+    }
+}
+
+impl Backend for Memory {
+    fn predefined(&self, hive: Hive) -> Result<Handle, ErrorCode> {
+        ROOTS
+            .iter()
+            .find(|(x, _)| *x == hive)
+            .map(|(_, handle)| Handle::new(*handle))
+            .ok_or(ErrorCode::INVALID_HANDLE)
+    }
+
+    fn open_key(&self, base: Handle, path: &U16CStr, sec: Security) -> Result<Handle, ErrorCode> {
+        let mut state = self.lock();
+        let (node, _) = state.node(base)?;
+        let node = state.walk(node, path)?;
+        Ok(state.open(node, sec))
+    }
+
+    fn create_key(&self, base: Handle, path: &U16CStr, sec: Security) -> Result<Handle, ErrorCode> {
+        let mut state = self.lock();
+        let (mut node, base_sec) = state.node(base)?;
+
+        for name in components(path)? {
+            node = match state.find(node, name) {
+                Ok(i) => state.nodes[node].keys[i],
+                Err(i) => {
+                    has(base_sec, Security::CreateSubKey)?;
+                    let key = state.nodes.len();
+                    state.nodes.push(Node {
+                        // SAFETY: the name was split from a nul-free path.
+                        name: unsafe { U16CString::from_vec_unchecked(name) },
+                        parent: Some(node),
+                        keys: vec![],
+                        values: vec![],
+                        last_write_time: FileTime::now(),
+                        is_deleted: false,
+                    });
+                    state.nodes[node].keys.insert(i, key);
+                    state.touch(node);
+                    key
+                }
+            };
+        }
+
+        Ok(state.open(node, sec))
+    }
+
+    fn delete_key(
+        &self,
+        base: Handle,
+        path: &U16CStr,
+        is_recursive: bool,
+    ) -> Result<(), ErrorCode> {
+        let mut state = self.lock();
+        let (node, _) = state.node(base)?;
+        let node = state.walk(node, path)?;
+
+        if is_recursive && components(path)?.is_empty() {
+            for key in std::mem::take(&mut state.nodes[node].keys) {
+                state.mark_deleted(key);
+            }
+            state.nodes[node].values.clear();
+            state.touch(node);
+            return Ok(());
+        }
+
+        let parent = match state.nodes[node].parent {
+            Some(parent) => parent,
+            None => return Err(ErrorCode::ACCESS_DENIED),
+        };
+        if !is_recursive && !state.nodes[node].keys.is_empty() {
+            return Err(ErrorCode::ACCESS_DENIED);
+        }
+
+        state.nodes[parent].keys.retain(|x| *x != node);
+        state.mark_deleted(node);
+        state.touch(parent);
+        Ok(())
+    }
+
+    fn close_key(&self, handle: Handle) {
+        if ROOTS.iter().any(|(_, x)| *x == handle.raw()) {
+            return;
+        }
+        self.lock().handles.remove(&handle.raw());
+    }
+
+    fn enum_key(&self, handle: Handle, index: u32) -> Result<Option<KeyEntry>, ErrorCode> {
+        let state = self.lock();
+        let (node, sec) = state.node(handle)?;
+        has(sec, Security::EnumerateSubKeys)?;
+
+        Ok(state.nodes[node].keys.get(index as usize).map(|key| {
+            let key = &state.nodes[*key];
+            KeyEntry {
+                name: key.name.clone(),
+                class: None,
+                last_write_time: key.last_write_time,
+            }
+        }))
+    }
+
+    fn enum_value(
+        &self,
+        handle: Handle,
+        index: u32,
+    ) -> Result<Option<(U16CString, RawValue)>, ErrorCode> {
+        let state = self.lock();
+        let (node, sec) = state.node(handle)?;
+        has(sec, Security::QueryValue)?;
+        Ok(state.nodes[node].values.get(index as usize).cloned())
+    }
+
+    fn query_value(&self, handle: Handle, name: &U16CStr) -> Result<RawValue, ErrorCode> {
+        let state = self.lock();
+        let (node, sec) = state.node(handle)?;
+        has(sec, Security::QueryValue)?;
+
+        let i = state.value(node, name).ok_or(ErrorCode::FILE_NOT_FOUND)?;
+        Ok(state.nodes[node].values[i].1.clone())
+    }
+
+    fn set_value(&self, handle: Handle, name: &U16CStr, value: &RawValue) -> Result<(), ErrorCode> {
+        let mut state = self.lock();
+        let (node, sec) = state.node(handle)?;
+        has(sec, Security::SetValue)?;
+        if name.len() > MAX_VALUE_NAME_LEN {
+            return Err(ErrorCode::INVALID_PARAMETER);
+        }
+
+        // A value that is replaced keeps its name and position.
+        match state.value(node, name) {
+            Some(i) => state.nodes[node].values[i].1 = value.clone(),
+            None => state.nodes[node]
+                .values
+                .push((name.to_ucstring(), value.clone())),
+        }
+        state.touch(node);
+        Ok(())
+    }
+
+    fn delete_value(&self, handle: Handle, name: &U16CStr) -> Result<(), ErrorCode> {
+        let mut state = self.lock();
+        let (node, sec) = state.node(handle)?;
+        has(sec, Security::SetValue)?;
+
+        let i = state.value(node, name).ok_or(ErrorCode::FILE_NOT_FOUND)?;
+        state.nodes[node].values.remove(i);
+        state.touch(node);
+        Ok(())
+    }
+
+    fn query_info(&self, handle: Handle) -> Result<KeyInfo, ErrorCode> {
+        let state = self.lock();
+        let (node, sec) = state.node(handle)?;
+        has(sec, Security::QueryValue)?;
+
+        let node = &state.nodes[node];
+        let max = |lens: &mut dyn Iterator<Item = usize>| lens.max().unwrap_or(0) as u32;
+        Ok(KeyInfo {
+            class: None,
+            last_write_time: node.last_write_time,
+            subkey_count: node.keys.len() as u32,
+            max_subkey_name_len: max(&mut node.keys.iter().map(|x| state.nodes[*x].name.len())),
+            max_class_len: 0,
+            value_count: node.values.len() as u32,
+            max_value_name_len: max(&mut node.values.iter().map(|(x, _)| x.len())),
+            max_value_data_len: max(&mut node.values.iter().map(|(_, x)| x.data.len())),
+            security_descriptor_len: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::backend::scoped;
+    use crate::snapshot::Snapshot;
+    use crate::tree::{KeyTarget, Tree};
+    use crate::{key, value, Data, RegKey};
+
+    fn names(key: &RegKey) -> Vec<String> {
+        key.keys().map(|x| x.unwrap().to_string()).collect()
+    }
+
+    fn value_names(key: &RegKey) -> Vec<String> {
+        key.values()
+            .map(|x| x.unwrap().name().to_string_lossy())
+            .collect()
+    }
+
+    #[test]
+    fn ignores_and_preserves_case() {
+        let _scope = scoped(Arc::new(Memory::new()));
+        let key = Hive::CurrentUser
+            .create(r"Software\Contoso", Security::AllAccess)
+            .unwrap();
+        key.set_value("Level", &Data::U32(1)).unwrap();
+        key.set_value("LEVEL", &Data::U32(2)).unwrap();
+
+        let again = Hive::CurrentUser
+            .create(r"SOFTWARE\contoso\", Security::Read)
+            .unwrap();
+        assert_eq!(again.value("level").unwrap(), Data::U32(2));
+        assert_eq!(value_names(&again), vec!["Level"]);
+
+        let software = Hive::CurrentUser.open("software", Security::Read).unwrap();
+        assert_eq!(names(&software), vec!["Contoso"]);
+    }
+
+    #[test]
+    fn default_value() {
+        let _scope = scoped(Arc::new(Memory::new()));
+        let key = Hive::LocalMachine
+            .create("Contoso", Security::AllAccess)
+            .unwrap();
+        assert!(matches!(key.value(""), Err(value::Error::NotFound(..))));
+
+        key.set_value("", &Data::String("Default".try_into().unwrap()))
+            .unwrap();
+        assert_eq!(
+            key.value("").unwrap(),
+            Data::String("Default".try_into().unwrap())
+        );
+        assert_eq!(value_names(&key), vec![""]);
+        key.delete_value("").unwrap();
+        assert!(key.values().next().is_none());
+    }
+
+    #[test]
+    fn handles_to_deleted_keys() {
+        let _scope = scoped(Arc::new(Memory::new()));
+        let key = Hive::CurrentUser
+            .create(r"Contoso\Update", Security::AllAccess)
+            .unwrap();
+        Hive::CurrentUser.delete("Contoso", true).unwrap();
+
+        let deleted = |e: &std::io::Error| e.raw_os_error() == Some(1018);
+        match key.value("Level") {
+            Err(value::Error::Unknown(_, e)) => assert!(deleted(&e)),
+            x => panic!("{:?}", x),
+        }
+        match key.create("Sub", Security::AllAccess) {
+            Err(key::Error::Unknown(_, e)) => assert!(deleted(&e)),
+            x => panic!("{:?}", x),
+        }
+        match key.keys().next() {
+            Some(Err(crate::iter::keys::Error::Unknown(0, e))) => assert!(deleted(&e)),
+            x => panic!("{:?}", x),
+        }
+
+        // Recreating the key does not revive the old handle.
+        Hive::CurrentUser
+            .create(r"Contoso\Update", Security::AllAccess)
+            .unwrap();
+        assert!(key.info().is_err());
+    }
+
+    #[test]
+    fn deletes_like_windows() {
+        let _scope = scoped(Arc::new(Memory::new()));
+        let key = Hive::CurrentUser
+            .create(r"Contoso\Update\Channel", Security::AllAccess)
+            .unwrap();
+        drop(key);
+
+        assert!(matches!(
+            Hive::CurrentUser.delete("Contoso", false),
+            Err(key::Error::PermissionDenied(..))
+        ));
+        Hive::CurrentUser
+            .delete(r"Contoso\Update\Channel", false)
+            .unwrap();
+        assert!(matches!(
+            Hive::CurrentUser.delete(r"Contoso\Update\Channel", false),
+            Err(key::Error::NotFound(..))
+        ));
+
+        // A recursive delete of the key itself empties it.
+        let contoso = Hive::CurrentUser
+            .open("Contoso", Security::AllAccess)
+            .unwrap();
+        contoso.set_value("Level", &Data::U32(1)).unwrap();
+        contoso.delete("", true).unwrap();
+        assert!(names(&contoso).is_empty());
+        assert!(contoso.values().next().is_none());
+
+        contoso.delete_self(false).unwrap();
+        assert!(Hive::CurrentUser.open("Contoso", Security::Read).is_err());
+        assert!(matches!(
+            Hive::CurrentUser.delete("", false),
+            Err(key::Error::PermissionDenied(..))
+        ));
+    }
+
+    #[test]
+    fn enumeration_order() {
+        let _scope = scoped(Arc::new(Memory::new()));
+        let key = Hive::Users.create("Contoso", Security::AllAccess).unwrap();
+        for name in &["b", "C", "a", "_x"] {
+            key.create(*name, Security::Read).unwrap();
+            key.set_value(*name, &Data::U32(0)).unwrap();
+        }
+        key.set_value("b", &Data::U32(1)).unwrap();
+        key.delete_value("C").unwrap();
+        key.set_value("C", &Data::U32(2)).unwrap();
+
+        // Subkeys sort upper cased, so the underscore comes after the letters.
+        assert_eq!(names(&key), vec!["a", "b", "C", "_x"]);
+        assert_eq!(value_names(&key), vec!["b", "a", "_x", "C"]);
+
+        let info = key.info().unwrap();
+        assert_eq!(info.subkey_count(), 4);
+        assert_eq!(info.max_subkey_name_len(), 2);
+        assert_eq!(info.value_count(), 4);
+        assert_eq!(info.max_value_data_len(), 4);
+    }
+
+    #[test]
+    fn hive_roots_and_access() {
+        let _scope = scoped(Arc::new(Memory::new()));
+        for (hive, _) in ROOTS.iter() {
+            hive.create("Contoso", Security::AllAccess).unwrap();
+            assert_eq!(
+                names(&hive.open("", Security::Read).unwrap()),
+                vec!["Contoso"]
+            );
+        }
+        assert!(Hive::Application.open("", Security::Read).is_err());
+
+        let key = Hive::CurrentUser.open("Contoso", Security::Read).unwrap();
+        assert!(matches!(
+            key.set_value("Level", &Data::U32(1)),
+            Err(value::Error::PermissionDenied(..))
+        ));
+        assert!(matches!(
+            key.create("Sub", Security::AllAccess),
+            Err(key::Error::PermissionDenied(..))
+        ));
+        let key = Hive::CurrentUser
+            .open("Contoso", Security::SetValue)
+            .unwrap();
+        key.set_value("Level", &Data::U32(1)).unwrap();
+        assert!(matches!(
+            key.value("Level"),
+            Err(value::Error::PermissionDenied(..))
+        ));
+    }
+
+    #[test]
+    fn serves_key_sources_and_targets() {
+        let _scope = scoped(Arc::new(Memory::new()));
+        let mut tree = Tree::new();
+        tree.set_value("", Data::String("Contoso".try_into().unwrap()));
+        tree.create_key(r"Update\Channel")
+            .set_value("Name", Data::String("stable".try_into().unwrap()));
+
+        let mut hive = Hive::LocalMachine;
+        for operation in crate::operation::recreate(r"Software\Contoso", &tree) {
+            crate::operation::execute(&mut hive, &operation).unwrap();
+        }
+        let captured = hive.read_key(r"Software\Contoso").unwrap().unwrap();
+        assert_eq!(
+            Snapshot::new("", captured).without_times(),
+            Snapshot::new("", tree)
+        );
+    }
+}
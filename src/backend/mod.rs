@@ -0,0 +1,552 @@
+//! Pluggable implementations of the registry behind [`Hive`](../enum.Hive.html) and
+//! [`RegKey`](../struct.RegKey.html).
+//!
+//! Every operation on a `Hive` or `RegKey` goes through a [`Backend`](trait.Backend.html),
+//! which works on opaque [`Handle`](struct.Handle.html)s the way the Win32 API works on
+//! `HKEY`s, and reports failures as Win32 error codes. On Windows the default backend is the
+//! live registry. Other backends let code written against `RegKey` run elsewhere, such as
+//! [`Memory`](struct.Memory.html), a registry held in memory for unit tests on any platform.
+//! Others wrap a backend: [`Faults`](struct.Faults.html) fails chosen calls,
+//! [`trace::Recorder`](trace/struct.Recorder.html) records every call for replaying later,
+//! [`Overlay`](struct.Overlay.html) holds changes back from the backend for a dry run, and
+//! [`Redirect`](struct.Redirect.html) serves hives from other keys, which
+//! [`Sandbox`](struct.Sandbox.html) uses to give tests an isolated registry.
+//!
+//! `Hive` uses the backend installed for the current thread with [`scoped`](fn.scoped.html),
+//! or else the process default set with [`set_default`](fn.set_default.html). Keys opened
+//! from a `RegKey` use the same backend as that key.
+//!
+//! Implementations of `Backend` can check that they behave as the registry does with the
+//! [`conformance`](conformance/index.html) suite.
+//!
+//! With the `rpc` feature, [`rpc`](rpc/index.html) serves a backend to other processes and
+//! provides a backend that is a client of it.
+
+use std::cell::{Cell, RefCell};
+use std::fmt::{self, Debug, Display};
+use std::io;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use utfx::{U16CStr, U16CString};
+
+use crate::info::{FileTime, KeyInfo};
+use crate::offline::upcase;
+use crate::sec::Security;
+use crate::{Data, Hive};
+
+pub mod conformance;
+mod faults;
+mod memory;
+mod offline;
+mod overlay;
+mod redirect;
+#[cfg(feature = "rpc")]
+pub mod rpc;
+pub mod trace;
+#[cfg(windows)]
+mod win32;
+
+pub use faults::{Fault, Faults, Operation};
+pub use memory::Memory;
+pub use offline::Offline;
+pub use overlay::Overlay;
+pub use redirect::{Redirect, Sandbox};
+#[cfg(windows)]
+pub use win32::Win32;
+
+/// An open key, as understood by the backend that opened it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Handle(usize);
+
+impl Handle {
+    #[inline]
+    pub const fn new(raw: usize) -> Handle {
+        Handle(raw)
+    }
+
+    #[inline]
+    pub const fn raw(&self) -> usize {
+        self.0
+    }
+}
+
+/// A Win32 error code, as returned by the registry functions.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ErrorCode(u32);
+
+impl ErrorCode {
+    pub const FILE_NOT_FOUND: ErrorCode = ErrorCode(2);
+    pub const PATH_NOT_FOUND: ErrorCode = ErrorCode(3);
+    pub const ACCESS_DENIED: ErrorCode = ErrorCode(5);
+    pub const INVALID_HANDLE: ErrorCode = ErrorCode(6);
+    pub const NOT_SUPPORTED: ErrorCode = ErrorCode(50);
+    pub const BROKEN_PIPE: ErrorCode = ErrorCode(109);
+    pub const INVALID_PARAMETER: ErrorCode = ErrorCode(87);
+    pub const MORE_DATA: ErrorCode = ErrorCode(234);
+    pub const REGISTRY_CORRUPT: ErrorCode = ErrorCode(1015);
+    pub const REGISTRY_IO_FAILED: ErrorCode = ErrorCode(1016);
+    pub const KEY_DELETED: ErrorCode = ErrorCode(1018);
+    pub const NO_SYSTEM_RESOURCES: ErrorCode = ErrorCode(1450);
+
+    #[inline]
+    pub const fn new(code: u32) -> ErrorCode {
+        ErrorCode(code)
+    }
+
+    #[inline]
+    pub const fn code(&self) -> u32 {
+        self.0
+    }
+
+    /// The kind of I/O error the code is reported as on Windows.
+    #[cfg(windows)]
+    pub(crate) fn kind(&self) -> io::ErrorKind {
+        io::Error::from(*self).kind()
+    }
+
+    /// The kind of I/O error the code is reported as on Windows. Raw OS errors mean other
+    /// things elsewhere, so the codes the registry gives for missing keys and denied access
+    /// are mapped here.
+    #[cfg(not(windows))]
+    pub(crate) fn kind(&self) -> io::ErrorKind {
+        match *self {
+            ErrorCode::FILE_NOT_FOUND | ErrorCode::PATH_NOT_FOUND => io::ErrorKind::NotFound,
+            ErrorCode::ACCESS_DENIED => io::ErrorKind::PermissionDenied,
+            _ => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match *self {
+            ErrorCode::FILE_NOT_FOUND => "ERROR_FILE_NOT_FOUND",
+            ErrorCode::PATH_NOT_FOUND => "ERROR_PATH_NOT_FOUND",
+            ErrorCode::ACCESS_DENIED => "ERROR_ACCESS_DENIED",
+            ErrorCode::INVALID_HANDLE => "ERROR_INVALID_HANDLE",
+            ErrorCode::NOT_SUPPORTED => "ERROR_NOT_SUPPORTED",
+            ErrorCode::BROKEN_PIPE => "ERROR_BROKEN_PIPE",
+            ErrorCode::INVALID_PARAMETER => "ERROR_INVALID_PARAMETER",
+            ErrorCode::MORE_DATA => "ERROR_MORE_DATA",
+            ErrorCode::REGISTRY_CORRUPT => "ERROR_REGISTRY_CORRUPT",
+            ErrorCode::REGISTRY_IO_FAILED => "ERROR_REGISTRY_IO_FAILED",
+            ErrorCode::KEY_DELETED => "ERROR_KEY_DELETED",
+            ErrorCode::NO_SYSTEM_RESOURCES => "ERROR_NO_SYSTEM_RESOURCES",
+            _ => return write!(f, "Win32 error {}", self.0),
+        };
+        f.write_str(name)
+    }
+}
+
+/// Keeps the code as the raw OS error, so it can be matched on any platform.
+impl From<ErrorCode> for io::Error {
+    fn from(code: ErrorCode) -> Self {
+        io::Error::from_raw_os_error(code.0 as i32)
+    }
+}
+
+/// A value's type code and data, as stored in the registry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawValue {
+    pub ty: u32,
+    pub data: Vec<u8>,
+}
+
+impl RawValue {
+    pub fn from_data(data: &Data) -> RawValue {
+        RawValue {
+            ty: data.ty(),
+            data: data.to_bytes(),
+        }
+    }
+
+    #[inline]
+    pub fn to_data(&self) -> Result<Data, crate::value::Error> {
+        Data::from_bytes(self.ty, &self.data)
+    }
+}
+
+/// A subkey, as reported by enumeration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyEntry {
+    pub name: U16CString,
+    pub class: Option<U16CString>,
+    pub last_write_time: FileTime,
+}
+
+/// The registry operations behind `Hive` and `RegKey`.
+///
+/// Paths are relative to the handle they are given with, and may be empty to refer to that
+/// key itself. Names are compared ignoring case. Subkeys and values are enumerated by index
+/// from zero, with `None` past the last one. An entry that cannot be read, such as a value
+/// that keeps growing while it is read, fails with `ERROR_MORE_DATA` and enumeration goes on
+/// with the next index, while any other error is taken to fail every later index too.
+pub trait Backend: Debug + Send + Sync {
+    /// The handle of a predefined key. It is never closed.
+    fn predefined(&self, hive: Hive) -> Result<Handle, ErrorCode>;
+
+    fn open_key(&self, base: Handle, path: &U16CStr, sec: Security) -> Result<Handle, ErrorCode>;
+
+    /// Opens a key, creating it and any missing parents.
+    fn create_key(&self, base: Handle, path: &U16CStr, sec: Security) -> Result<Handle, ErrorCode>;
+
+    /// Deletes a key. Without `is_recursive`, fails if the key has subkeys.
+    fn delete_key(&self, base: Handle, path: &U16CStr, is_recursive: bool)
+        -> Result<(), ErrorCode>;
+
+    fn close_key(&self, handle: Handle);
+
+    /// The subkey at `index`, failing with `ERROR_MORE_DATA` if only that subkey cannot be
+    /// read.
+    fn enum_key(&self, handle: Handle, index: u32) -> Result<Option<KeyEntry>, ErrorCode>;
+
+    /// The value at `index`, failing with `ERROR_MORE_DATA` if only that value cannot be read.
+    fn enum_value(
+        &self,
+        handle: Handle,
+        index: u32,
+    ) -> Result<Option<(U16CString, RawValue)>, ErrorCode>;
+
+    fn query_value(&self, handle: Handle, name: &U16CStr) -> Result<RawValue, ErrorCode>;
+
+    fn set_value(&self, handle: Handle, name: &U16CStr, value: &RawValue) -> Result<(), ErrorCode>;
+
+    fn delete_value(&self, handle: Handle, name: &U16CStr) -> Result<(), ErrorCode>;
+
+    /// Opens the current user's key, which differs from `HKEY_CURRENT_USER` when
+    /// impersonating another user.
+    fn open_current_user(&self, sec: Security) -> Result<Handle, ErrorCode> {
+        let root = self.predefined(Hive::CurrentUser)?;
+        self.open_key(root, Default::default(), sec)
+    }
+
+    /// Loads a hive file as an application hive.
+    fn load_app_key(&self, _file_path: &Path, _sec: Security) -> Result<Handle, ErrorCode> {
+        Err(ErrorCode::NOT_SUPPORTED)
+    }
+
+    /// Saves a key and everything below it to a hive file.
+    fn save_key(&self, _handle: Handle, _file_path: &U16CStr) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOT_SUPPORTED)
+    }
+
+    /// Queries the size information of a key. By default this enumerates the key, leaving
+    /// its class and last write time unset.
+    fn query_info(&self, handle: Handle) -> Result<KeyInfo, ErrorCode> {
+        let mut info = KeyInfo::default();
+
+        while let Some(key) = self.enum_key(handle, info.subkey_count)? {
+            info.subkey_count += 1;
+            info.max_subkey_name_len = info.max_subkey_name_len.max(key.name.len() as u32);
+            let class_len = key.class.map(|x| x.len()).unwrap_or(0) as u32;
+            info.max_class_len = info.max_class_len.max(class_len);
+        }
+
+        while let Some((name, value)) = self.enum_value(handle, info.value_count)? {
+            info.value_count += 1;
+            info.max_value_name_len = info.max_value_name_len.max(name.len() as u32);
+            info.max_value_data_len = info.max_value_data_len.max(value.data.len() as u32);
+        }
+
+        Ok(info)
+    }
+
+    /// Parses the data of a value read through this backend. By default this is
+    /// `Data::from_bytes`, which accepts strings without terminators as hive files may hold.
+    fn decode(&self, value: &RawValue) -> Result<Data, crate::value::Error> {
+        value.to_data()
+    }
+}
+
+/// The longest key name the registry accepts, in UTF-16 code units.
+const MAX_KEY_NAME_LEN: usize = 255;
+/// The longest value name the registry accepts, in UTF-16 code units.
+const MAX_VALUE_NAME_LEN: usize = 16383;
+
+/// The hives with a root, and the values of their predefined handles in the Win32 API, for
+/// backends that hand out the same handles.
+const ROOTS: [(Hive, usize); 7] = [
+    (Hive::ClassesRoot, 0x8000_0000),
+    (Hive::CurrentUser, 0x8000_0001),
+    (Hive::LocalMachine, 0x8000_0002),
+    (Hive::Users, 0x8000_0003),
+    (Hive::PerformanceData, 0x8000_0004),
+    (Hive::CurrentConfig, 0x8000_0005),
+    (Hive::CurrentUserLocalSettings, 0x8000_0007),
+];
+
+/// Checks that a handle was opened with the access an operation needs.
+fn has(sec: Security, access: Security) -> Result<(), ErrorCode> {
+    if sec.contains(access) {
+        Ok(())
+    } else {
+        Err(ErrorCode::ACCESS_DENIED)
+    }
+}
+
+fn fold(name: &[u16]) -> Vec<u16> {
+    name.iter().map(|c| upcase(*c)).collect()
+}
+
+/// The names in a path. Empty components, such as from a trailing backslash, are skipped.
+fn components(path: &U16CStr) -> Result<Vec<&[u16]>, ErrorCode> {
+    let names = path
+        .as_slice()
+        .split(|c| *c == u16::from(b'\\'))
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<_>>();
+    if names.iter().any(|x| x.len() > MAX_KEY_NAME_LEN) {
+        return Err(ErrorCode::INVALID_PARAMETER);
+    }
+    Ok(names)
+}
+
+/// Joins a path below a key, skipping empty names, as the registry does.
+fn join(base: &[u16], path: &U16CStr) -> Vec<u16> {
+    let mut out = base.to_vec();
+    for name in path
+        .as_slice()
+        .split(|c| *c == u16::from(b'\\'))
+        .filter(|x| !x.is_empty())
+    {
+        out.push(u16::from(b'\\'));
+        out.extend_from_slice(name);
+    }
+    out
+}
+
+/// The backend used off Windows when none has been installed, where every operation fails.
+#[cfg(not(windows))]
+#[derive(Debug)]
+struct Unsupported;
+
+#[cfg(not(windows))]
+impl Backend for Unsupported {
+    fn predefined(&self, _hive: Hive) -> Result<Handle, ErrorCode> {
+        Err(ErrorCode::NOT_SUPPORTED)
+    }
+
+    fn open_key(&self, _: Handle, _: &U16CStr, _: Security) -> Result<Handle, ErrorCode> {
+        Err(ErrorCode::NOT_SUPPORTED)
+    }
+
+    fn create_key(&self, _: Handle, _: &U16CStr, _: Security) -> Result<Handle, ErrorCode> {
+        Err(ErrorCode::NOT_SUPPORTED)
+    }
+
+    fn delete_key(&self, _: Handle, _: &U16CStr, _: bool) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOT_SUPPORTED)
+    }
+
+    fn close_key(&self, _: Handle) {}
+
+    fn enum_key(&self, _: Handle, _: u32) -> Result<Option<KeyEntry>, ErrorCode> {
+        Err(ErrorCode::NOT_SUPPORTED)
+    }
+
+    fn enum_value(&self, _: Handle, _: u32) -> Result<Option<(U16CString, RawValue)>, ErrorCode> {
+        Err(ErrorCode::NOT_SUPPORTED)
+    }
+
+    fn query_value(&self, _: Handle, _: &U16CStr) -> Result<RawValue, ErrorCode> {
+        Err(ErrorCode::NOT_SUPPORTED)
+    }
+
+    fn set_value(&self, _: Handle, _: &U16CStr, _: &RawValue) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOT_SUPPORTED)
+    }
+
+    fn delete_value(&self, _: Handle, _: &U16CStr) -> Result<(), ErrorCode> {
+        Err(ErrorCode::NOT_SUPPORTED)
+    }
+}
+
+static DEFAULT: RwLock<Option<Arc<dyn Backend>>> = RwLock::new(None);
+
+thread_local! {
+    /// The backends installed on this thread with `scoped`, by the number of their guard. The
+    /// last one is in use.
+    static CURRENT: RefCell<Vec<(usize, Arc<dyn Backend>)>> = const { RefCell::new(Vec::new()) };
+    static NEXT_SCOPE: Cell<usize> = const { Cell::new(0) };
+}
+
+/// The backend `Hive` uses on this thread.
+pub fn current() -> Arc<dyn Backend> {
+    if let Some((_, backend)) = CURRENT.with(|x| x.borrow().last().cloned()) {
+        return backend;
+    }
+    if let Some(backend) = DEFAULT.read().unwrap_or_else(|e| e.into_inner()).clone() {
+        return backend;
+    }
+
+    #[cfg(windows)]
+    return Arc::new(Win32);
+    #[cfg(not(windows))]
+    return Arc::new(Unsupported);
+}
+
+/// Sets the backend `Hive` uses on threads without one of their own.
+pub fn set_default(backend: Arc<dyn Backend>) {
+    *DEFAULT.write().unwrap_or_else(|e| e.into_inner()) = Some(backend);
+}
+
+/// Makes `Hive` use `backend` on this thread until the returned guard is dropped.
+pub fn scoped(backend: Arc<dyn Backend>) -> Scope {
+    let id = NEXT_SCOPE.with(|x| x.replace(x.get() + 1));
+    CURRENT.with(|x| x.borrow_mut().push((id, backend)));
+    Scope {
+        id,
+        _thread: PhantomData,
+    }
+}
+
+/// Uninstalls the thread's backend when dropped, restoring the one installed before it.
+///
+/// Guards may be dropped in any order. Dropping one while a backend installed after it is
+/// still in place leaves that backend in use, and the earlier one is restored once it is gone.
+///
+/// The guard cannot be sent to another thread, as it restores the backend of the thread that
+/// made it.
+#[must_use = "the backend is uninstalled when the guard is dropped"]
+#[derive(Debug)]
+pub struct Scope {
+    id: usize,
+    _thread: PhantomData<*const ()>,
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        CURRENT.with(|x| x.borrow_mut().retain(|(id, _)| *id != self.id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+    use crate::key;
+
+    /// A registry holding only `Software` below each hive, with a single value.
+    #[derive(Debug)]
+    struct Fixed;
+
+    const ROOT: Handle = Handle::new(1);
+    const SOFTWARE: Handle = Handle::new(2);
+
+    impl Backend for Fixed {
+        fn predefined(&self, _hive: Hive) -> Result<Handle, ErrorCode> {
+            Ok(ROOT)
+        }
+
+        fn open_key(&self, base: Handle, path: &U16CStr, _: Security) -> Result<Handle, ErrorCode> {
+            match (base, path.to_string_lossy().to_uppercase().as_str()) {
+                (ROOT, "") => Ok(ROOT),
+                (ROOT, "SOFTWARE") | (SOFTWARE, "") => Ok(SOFTWARE),
+                _ => Err(ErrorCode::FILE_NOT_FOUND),
+            }
+        }
+
+        fn create_key(&self, _: Handle, _: &U16CStr, _: Security) -> Result<Handle, ErrorCode> {
+            Err(ErrorCode::ACCESS_DENIED)
+        }
+
+        fn delete_key(&self, _: Handle, _: &U16CStr, _: bool) -> Result<(), ErrorCode> {
+            Err(ErrorCode::ACCESS_DENIED)
+        }
+
+        fn close_key(&self, _: Handle) {}
+
+        fn enum_key(&self, handle: Handle, index: u32) -> Result<Option<KeyEntry>, ErrorCode> {
+            Ok(match (handle, index) {
+                (ROOT, 0) => Some(KeyEntry {
+                    name: "Software".try_into().unwrap(),
+                    class: None,
+                    last_write_time: FileTime::default(),
+                }),
+                _ => None,
+            })
+        }
+
+        fn enum_value(
+            &self,
+            handle: Handle,
+            index: u32,
+        ) -> Result<Option<(U16CString, RawValue)>, ErrorCode> {
+            Ok(match (handle, index) {
+                (SOFTWARE, 0) => Some((
+                    "Level".try_into().unwrap(),
+                    RawValue::from_data(&Data::U32(3)),
+                )),
+                _ => None,
+            })
+        }
+
+        fn query_value(&self, handle: Handle, name: &U16CStr) -> Result<RawValue, ErrorCode> {
+            match self.enum_value(handle, 0)? {
+                Some((x, value)) if *x == *name => Ok(value),
+                _ => Err(ErrorCode::FILE_NOT_FOUND),
+            }
+        }
+
+        fn set_value(&self, _: Handle, _: &U16CStr, _: &RawValue) -> Result<(), ErrorCode> {
+            Err(ErrorCode::ACCESS_DENIED)
+        }
+
+        fn delete_value(&self, _: Handle, _: &U16CStr) -> Result<(), ErrorCode> {
+            Err(ErrorCode::ACCESS_DENIED)
+        }
+    }
+
+    #[test]
+    fn hives_use_the_scoped_backend() {
+        let _scope = scoped(Arc::new(Fixed));
+
+        let root = Hive::LocalMachine.open("", Security::Read).unwrap();
+        let names = root
+            .keys()
+            .map(|x| x.map(|x| x.to_string()))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(names, vec!["Software"]);
+
+        let key = root.open("software", Security::Read).unwrap();
+        assert_eq!(key.to_string(), r"HKEY_LOCAL_MACHINE\software");
+        assert_eq!(key.value("Level").unwrap(), Data::U32(3));
+        assert_eq!(key.info().unwrap().value_count(), 1);
+        assert_eq!(key.info().unwrap().max_value_data_len(), 4);
+
+        assert!(matches!(
+            Hive::LocalMachine.open("Missing", Security::Read),
+            Err(key::Error::NotFound(..))
+        ));
+        assert!(matches!(
+            key.set_value("Level", &Data::U32(4)),
+            Err(crate::value::Error::PermissionDenied(..))
+        ));
+    }
+
+    #[test]
+    fn scopes_restore_the_previous_backend() {
+        let _outer = scoped(Arc::new(Fixed));
+        {
+            let _inner = scoped(Arc::new(Fixed));
+        }
+        assert!(Hive::CurrentUser.open("Software", Security::Read).is_ok());
+    }
+
+    #[test]
+    fn scopes_may_be_dropped_in_any_order() {
+        let (a, b): (Arc<dyn Backend>, Arc<dyn Backend>) = (Arc::new(Fixed), Arc::new(Fixed));
+        let outer = scoped(a.clone());
+        let inner = scoped(b.clone());
+        drop(outer);
+        assert!(Arc::ptr_eq(&current(), &b));
+
+        let _other = scoped(a.clone());
+        drop(inner);
+        assert!(Arc::ptr_eq(&current(), &a));
+    }
+}
@@ -0,0 +1,698 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use utfx::{U16CStr, U16CString};
+
+use super::{components, fold, has, Backend, ErrorCode, Handle, KeyEntry, RawValue, ROOTS};
+use crate::info::KeyInfo;
+use crate::offline::{self, Editor};
+use crate::sec::Security;
+use crate::Hive;
+
+/// A hive file served through `Hive` and `RegKey`, so code written for the live registry
+/// can read collected hives on any platform.
+///
+/// The hive is mounted at a path below one of the hives, such as `SOFTWARE` below
+/// `Hive::LocalMachine` for a collected `SOFTWARE` hive. The keys leading to the mount point
+/// exist but cannot be changed, and every other hive is empty.
+///
+/// Keys and values below the mount point behave as in [`Memory`](struct.Memory.html).
+/// Changes are saved to the hive's file by [`flush`](#method.flush) and when the backend is
+/// dropped, unless the backend is [`read_only`](#method.read_only), in which case writes fail
+/// with `ERROR_ACCESS_DENIED`.
+///
+/// ```no_run
+/// use std::sync::Arc;
+/// use registry::{backend::{self, Offline}, Hive, Security};
+///
+/// let software = Offline::load("SOFTWARE", Hive::LocalMachine, "SOFTWARE")?.read_only();
+/// let _scope = backend::scoped(Arc::new(software));
+///
+/// let key = Hive::LocalMachine
+///     .open(r"SOFTWARE\Microsoft\Windows NT\CurrentVersion", Security::Read)
+///     .unwrap();
+/// println!("{}", key.value("ProductName").unwrap());
+/// # Ok::<(), registry::offline::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct Offline {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Position {
+    /// The root of a hive, or a key leading to the mount point, at the given depth.
+    Virtual(Hive, usize),
+    /// A key in the hive file, by the offset of its cell.
+    File(u32),
+}
+
+#[derive(Debug)]
+struct Open {
+    position: Position,
+    sec: Security,
+    is_deleted: bool,
+}
+
+#[derive(Debug)]
+struct State {
+    editor: Editor,
+    /// The offset of the root key of the hive file.
+    root: u32,
+    hive: Hive,
+    mount: Vec<U16CString>,
+    is_read_only: bool,
+    /// Whether the hive has changed since it was last saved.
+    is_changed: bool,
+    handles: HashMap<usize, Open>,
+    next_handle: usize,
+}
+
+fn code(e: offline::Error) -> ErrorCode {
+    match e {
+        offline::Error::NotFound(_) => ErrorCode::FILE_NOT_FOUND,
+        offline::Error::HasSubkeys(_) | offline::Error::InvalidOperation(_) => {
+            ErrorCode::ACCESS_DENIED
+        }
+        offline::Error::InvalidName(_) | offline::Error::InvalidNul(_) => {
+            ErrorCode::INVALID_PARAMETER
+        }
+        offline::Error::Io(_) => ErrorCode::REGISTRY_IO_FAILED,
+        _ => ErrorCode::REGISTRY_CORRUPT,
+    }
+}
+
+impl Offline {
+    /// Serves the hive being edited at `path` below `hive`. Changes are saved to the
+    /// editor's file, if it was loaded from one. Fails if `path` contains a nul.
+    pub fn new(mut editor: Editor, hive: Hive, path: &str) -> Result<Offline, offline::Error> {
+        let mount = crate::tree::components(path)
+            .map(U16CString::from_str)
+            .collect::<Result<_, _>>()?;
+        let mut state = State {
+            root: editor.root().offset(),
+            editor,
+            hive,
+            mount,
+            is_read_only: false,
+            is_changed: false,
+            handles: HashMap::new(),
+            next_handle: 4,
+        };
+        for (hive, handle) in ROOTS.iter() {
+            let open = Open {
+                position: state.position(*hive, 0),
+                sec: Security::AllAccess,
+                is_deleted: false,
+            };
+            state.handles.insert(*handle, open);
+        }
+
+        Ok(Offline {
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Loads the hive file at `file_path` and serves it at `path` below `hive`.
+    pub fn load<P: AsRef<Path>>(
+        file_path: P,
+        hive: Hive,
+        path: &str,
+    ) -> Result<Offline, offline::Error> {
+        Offline::new(Editor::load(file_path)?, hive, path)
+    }
+
+    /// Rejects all changes with `ERROR_ACCESS_DENIED`.
+    pub fn read_only(self) -> Offline {
+        self.lock().is_read_only = true;
+        self
+    }
+
+    /// Saves the changes made through this backend to the editor's file, if it was loaded
+    /// from one.
+    pub fn flush(&self) -> Result<(), offline::Error> {
+        self.lock().flush()
+    }
+
+    /// The editor holding the hive, with any changes made through this backend. Changes not
+    /// yet flushed are left unsaved.
+    pub fn into_editor(self) -> Editor {
+        let mut state = self.lock();
+        state.is_changed = false;
+        std::mem::take(&mut state.editor)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl State {
+    /// The key at `depth` on the way to the mount point in `hive`, which is the root of the
+    /// hive file once the whole mount path is walked.
+    fn position(&self, hive: Hive, depth: usize) -> Position {
+        if hive == self.hive && depth == self.mount.len() {
+            Position::File(self.root)
+        } else {
+            Position::Virtual(hive, depth)
+        }
+    }
+
+    fn node(&self, handle: Handle) -> Result<(Position, Security), ErrorCode> {
+        let open = self
+            .handles
+            .get(&handle.raw())
+            .ok_or(ErrorCode::INVALID_HANDLE)?;
+        if open.is_deleted {
+            return Err(ErrorCode::KEY_DELETED);
+        }
+        Ok((open.position, open.sec))
+    }
+
+    fn open(&mut self, position: Position, sec: Security) -> Handle {
+        let handle = self.next_handle;
+        self.next_handle += 4;
+        self.handles.insert(
+            handle,
+            Open {
+                position,
+                sec,
+                is_deleted: false,
+            },
+        );
+        Handle::new(handle)
+    }
+
+    /// The name of the key below a virtual key, if it has one.
+    fn virtual_child(&self, hive: Hive, depth: usize) -> Option<&U16CString> {
+        if hive == self.hive {
+            self.mount.get(depth)
+        } else {
+            None
+        }
+    }
+
+    fn child(&self, position: Position, name: &[u16]) -> Result<Option<Position>, ErrorCode> {
+        match position {
+            Position::Virtual(hive, depth) => Ok(self
+                .virtual_child(hive, depth)
+                .filter(|x| fold(x.as_slice()) == fold(name))
+                .map(|_| self.position(hive, depth + 1))),
+            Position::File(offset) => {
+                let key = self.editor.key(offset).map_err(code)?;
+                let subkey = key.find_subkey(name).map_err(code)?;
+                Ok(subkey.map(|x| Position::File(x.offset())))
+            }
+        }
+    }
+
+    fn walk(&self, position: Position, path: &U16CStr) -> Result<Position, ErrorCode> {
+        components(path)?
+            .into_iter()
+            .try_fold(position, |position, name| {
+                self.child(position, name)?.ok_or(ErrorCode::FILE_NOT_FOUND)
+            })
+    }
+
+    /// The key in the hive file at a position, as changes can only be made there.
+    fn writable(&self, position: Position) -> Result<u32, ErrorCode> {
+        match position {
+            Position::File(offset) if !self.is_read_only => Ok(offset),
+            _ => Err(ErrorCode::ACCESS_DENIED),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), offline::Error> {
+        if self.is_changed && self.editor.path().is_some() {
+            self.editor.save()?;
+        }
+        self.is_changed = false;
+        Ok(())
+    }
+
+    /// The offsets of a key and everything below it.
+    fn subtree(&self, offset: u32) -> Result<Vec<u32>, ErrorCode> {
+        let mut offsets = vec![offset];
+        let mut i = 0;
+        while i < offsets.len() {
+            let key = self.editor.key(offsets[i]).map_err(code)?;
+            for subkey in key.keys().map_err(code)? {
+                offsets.push(subkey.map_err(code)?.offset());
+            }
+            i += 1;
+        }
+        Ok(offsets)
+    }
+
+    /// Marks open handles to the given keys as deleted.
+    fn mark_deleted(&mut self, offsets: &[u32]) {
+        for open in self.handles.values_mut() {
+            if let Position::File(x) = open.position {
+                if offsets.contains(&x) {
+                    open.is_deleted = true;
+                }
+            }
+        }
+    }
+
+    fn delete(&mut self, offset: u32, is_recursive: bool) -> Result<(), ErrorCode> {
+        let key = self.editor.key(offset).map_err(code)?;
+        if key.is_root().map_err(code)? {
+            return Err(ErrorCode::ACCESS_DENIED);
+        }
+        if !is_recursive && key.subkey_count().map_err(code)? > 0 {
+            return Err(ErrorCode::ACCESS_DENIED);
+        }
+
+        // Handles are only marked once the key is gone, so a failed delete leaves them usable.
+        let offsets = self.subtree(offset)?;
+        self.editor
+            .key_mut(offset)
+            .delete_self(is_recursive)
+            .map_err(code)?;
+        self.mark_deleted(&offsets);
+        Ok(())
+    }
+
+    /// Deletes everything below a key, keeping the key itself.
+    fn empty(&mut self, offset: u32) -> Result<(), ErrorCode> {
+        let (subkeys, values) = {
+            let key = self.editor.key(offset).map_err(code)?;
+            let subkeys = key
+                .keys()
+                .map_err(code)?
+                .map(|x| x.map(|x| x.offset()))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(code)?;
+            let values = key
+                .values()
+                .map_err(code)?
+                .map(|x| x.and_then(|x| x.name()))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(code)?;
+            (subkeys, values)
+        };
+
+        for subkey in subkeys {
+            self.delete(subkey, true)?;
+        }
+        let mut key = self.editor.key_mut(offset);
+        for name in values {
+            key.delete_value(name).map_err(code)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Offline {
+    fn drop(&mut self) {
+        if let Err(e) = self.lock().flush() {
+            log::warn!("Could not save offline hive: {}", e);
+        }
+    }
+}
+
+impl Backend for Offline {
+    fn predefined(&self, hive: Hive) -> Result<Handle, ErrorCode> {
+        ROOTS
+            .iter()
+            .find(|(x, _)| *x == hive)
+            .map(|(_, handle)| Handle::new(*handle))
+            .ok_or(ErrorCode::INVALID_HANDLE)
+    }
+
+    fn open_key(&self, base: Handle, path: &U16CStr, sec: Security) -> Result<Handle, ErrorCode> {
+        let mut state = self.lock();
+        let (position, _) = state.node(base)?;
+        let position = state.walk(position, path)?;
+        Ok(state.open(position, sec))
+    }
+
+    fn create_key(&self, base: Handle, path: &U16CStr, sec: Security) -> Result<Handle, ErrorCode> {
+        let mut state = self.lock();
+        let (mut position, base_sec) = state.node(base)?;
+        for name in components(path)? {
+            position = match state.child(position, name)? {
+                Some(position) => position,
+                None => {
+                    has(base_sec, Security::CreateSubKey)?;
+                    let offset = state.writable(position)?;
+                    // SAFETY: the name was split from a nul-free path.
+                    let name = unsafe { U16CString::from_vec_unchecked(name) };
+                    state.is_changed = true;
+                    let mut parent = state.editor.key_mut(offset);
+                    let key = parent.create(name).map_err(code)?.offset();
+                    Position::File(key)
+                }
+            };
+        }
+
+        Ok(state.open(position, sec))
+    }
+
+    fn delete_key(
+        &self,
+        base: Handle,
+        path: &U16CStr,
+        is_recursive: bool,
+    ) -> Result<(), ErrorCode> {
+        let mut state = self.lock();
+        let (position, _) = state.node(base)?;
+        let position = state.walk(position, path)?;
+        let offset = state.writable(position)?;
+
+        state.is_changed = true;
+        if is_recursive && components(path)?.is_empty() {
+            state.empty(offset)
+        } else {
+            state.delete(offset, is_recursive)
+        }
+    }
+
+    fn close_key(&self, handle: Handle) {
+        if ROOTS.iter().any(|(_, x)| *x == handle.raw()) {
+            return;
+        }
+        self.lock().handles.remove(&handle.raw());
+    }
+
+    fn enum_key(&self, handle: Handle, index: u32) -> Result<Option<KeyEntry>, ErrorCode> {
+        let state = self.lock();
+        let (position, sec) = state.node(handle)?;
+        has(sec, Security::EnumerateSubKeys)?;
+
+        match position {
+            Position::Virtual(hive, depth) => {
+                let name = match state.virtual_child(hive, depth) {
+                    Some(name) if index == 0 => name.clone(),
+                    _ => return Ok(None),
+                };
+                let root = state.editor.key(state.root).map_err(code)?;
+                Ok(Some(KeyEntry {
+                    name,
+                    class: None,
+                    last_write_time: root.last_write_time().map_err(code)?,
+                }))
+            }
+            Position::File(offset) => {
+                let key = state.editor.key(offset).map_err(code)?;
+                let subkey = match key.subkey_at(index as usize).map_err(code)? {
+                    Some(subkey) => subkey,
+                    None => return Ok(None),
+                };
+                Ok(Some(KeyEntry {
+                    name: subkey.name().map_err(code)?,
+                    class: subkey.class().map_err(code)?,
+                    last_write_time: subkey.last_write_time().map_err(code)?,
+                }))
+            }
+        }
+    }
+
+    fn enum_value(
+        &self,
+        handle: Handle,
+        index: u32,
+    ) -> Result<Option<(U16CString, RawValue)>, ErrorCode> {
+        let state = self.lock();
+        let (position, sec) = state.node(handle)?;
+        has(sec, Security::QueryValue)?;
+
+        let offset = match position {
+            Position::Virtual(..) => return Ok(None),
+            Position::File(offset) => offset,
+        };
+        let key = state.editor.key(offset).map_err(code)?;
+        let value = match key.value_at(index as usize).map_err(code)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let raw = RawValue {
+            ty: value.data_type().map_err(code)?,
+            data: value.raw_data().map_err(code)?.into_owned(),
+        };
+        Ok(Some((value.name().map_err(code)?, raw)))
+    }
+
+    fn query_value(&self, handle: Handle, name: &U16CStr) -> Result<RawValue, ErrorCode> {
+        let state = self.lock();
+        let (position, sec) = state.node(handle)?;
+        has(sec, Security::QueryValue)?;
+
+        let offset = match position {
+            Position::Virtual(..) => return Err(ErrorCode::FILE_NOT_FOUND),
+            Position::File(offset) => offset,
+        };
+        let key = state.editor.key(offset).map_err(code)?;
+        let value = key
+            .find_value(name.as_slice())
+            .map_err(code)?
+            .ok_or(ErrorCode::FILE_NOT_FOUND)?;
+        Ok(RawValue {
+            ty: value.data_type().map_err(code)?,
+            data: value.raw_data().map_err(code)?.into_owned(),
+        })
+    }
+
+    fn set_value(&self, handle: Handle, name: &U16CStr, value: &RawValue) -> Result<(), ErrorCode> {
+        let mut state = self.lock();
+        let (position, sec) = state.node(handle)?;
+        has(sec, Security::SetValue)?;
+        let offset = state.writable(position)?;
+
+        state.is_changed = true;
+        state
+            .editor
+            .key_mut(offset)
+            .set_raw_value(name.to_ucstring(), value.ty, &value.data)
+            .map_err(code)
+    }
+
+    fn delete_value(&self, handle: Handle, name: &U16CStr) -> Result<(), ErrorCode> {
+        let mut state = self.lock();
+        let (position, sec) = state.node(handle)?;
+        has(sec, Security::SetValue)?;
+        let offset = state.writable(position)?;
+
+        state.is_changed = true;
+        state
+            .editor
+            .key_mut(offset)
+            .delete_value(name.to_ucstring())
+            .map_err(code)
+    }
+
+    fn query_info(&self, handle: Handle) -> Result<KeyInfo, ErrorCode> {
+        let state = self.lock();
+        let (position, sec) = state.node(handle)?;
+        has(sec, Security::QueryValue)?;
+
+        match position {
+            Position::Virtual(hive, depth) => {
+                let mut info = KeyInfo::default();
+                if let Some(name) = state.virtual_child(hive, depth) {
+                    info.subkey_count = 1;
+                    info.max_subkey_name_len = name.len() as u32;
+                }
+                Ok(info)
+            }
+            Position::File(offset) => state
+                .editor
+                .key(offset)
+                .and_then(|x| x.info())
+                .map_err(code),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::backend::scoped;
+    use crate::{key, Data};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("registry-rs-{}-{}", std::process::id(), name))
+    }
+
+    fn software(name: &str) -> PathBuf {
+        let path = temp_path(name);
+        let mut editor = Editor::new();
+        editor
+            .create(r"Contoso\Update")
+            .unwrap()
+            .set_value("Channel", &Data::String("stable".try_into().unwrap()))
+            .unwrap();
+        editor.create("Fabrikam").unwrap();
+        editor.save_as(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn serves_the_hive_at_its_mount_point() {
+        let path = software("backend-mount.hiv");
+        let backend = Offline::load(&path, Hive::LocalMachine, "SOFTWARE").unwrap();
+        let _scope = scoped(Arc::new(backend.read_only()));
+
+        let root = Hive::LocalMachine.open("", Security::Read).unwrap();
+        let names = root
+            .keys()
+            .map(|x| x.unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["SOFTWARE"]);
+
+        let software = Hive::LocalMachine.open("software", Security::Read).unwrap();
+        let names = software
+            .keys()
+            .map(|x| x.unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["Contoso", "Fabrikam"]);
+        assert_eq!(software.info().unwrap().subkey_count(), 2);
+
+        let key = software.open(r"contoso\UPDATE", Security::Read).unwrap();
+        assert_eq!(
+            key.to_string(),
+            r"HKEY_LOCAL_MACHINE\software\contoso\UPDATE"
+        );
+        assert_eq!(
+            key.value("channel").unwrap(),
+            Data::String("stable".try_into().unwrap())
+        );
+
+        assert!(matches!(
+            Hive::CurrentUser.open("SOFTWARE", Security::Read),
+            Err(key::Error::NotFound(..))
+        ));
+        let key = Hive::LocalMachine
+            .open(r"SOFTWARE\Contoso", Security::AllAccess)
+            .unwrap();
+        assert!(matches!(
+            key.set_value("Level", &Data::U32(1)),
+            Err(crate::value::Error::PermissionDenied(..))
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn saves_writes_to_the_file() {
+        let path = software("backend-write.hiv");
+        let backend = Arc::new(Offline::load(&path, Hive::LocalMachine, "SOFTWARE").unwrap());
+        let _scope = scoped(backend.clone());
+
+        let key = Hive::LocalMachine
+            .create(r"SOFTWARE\Contoso\Telemetry", Security::AllAccess)
+            .unwrap();
+        key.set_value("Level", &Data::U32(3)).unwrap();
+        Hive::LocalMachine
+            .delete(r"SOFTWARE\Fabrikam", false)
+            .unwrap();
+        assert!(matches!(
+            Hive::LocalMachine.delete("SOFTWARE", true),
+            Err(key::Error::PermissionDenied(..))
+        ));
+        assert!(matches!(
+            Hive::LocalMachine.create(r"SYSTEM\Contoso", Security::AllAccess),
+            Err(key::Error::PermissionDenied(..))
+        ));
+
+        let contoso = Hive::LocalMachine
+            .open(r"SOFTWARE\Contoso", Security::AllAccess)
+            .unwrap();
+        assert!(matches!(
+            contoso.delete("", false),
+            Err(key::Error::PermissionDenied(..))
+        ));
+        Hive::LocalMachine
+            .delete(r"SOFTWARE\Contoso\Telemetry", true)
+            .unwrap();
+        match key.value("Level") {
+            Err(crate::value::Error::Unknown(_, e)) => assert_eq!(e.raw_os_error(), Some(1018)),
+            x => panic!("{:?}", x),
+        }
+        contoso.open("Update", Security::Read).unwrap();
+
+        // Nothing is saved until the backend is flushed.
+        assert!(Editor::load(&path).unwrap().hive().open("Fabrikam").is_ok());
+        backend.flush().unwrap();
+
+        let hive = Editor::load(&path).unwrap();
+        let hive = hive.hive();
+        assert!(hive.open("Fabrikam").is_err());
+        assert!(hive.open(r"Contoso\Telemetry").is_err());
+        assert!(hive.open(r"Contoso\Update").is_ok());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn keeps_handles_when_a_delete_fails() {
+        let mut editor = Editor::new();
+        let mut contoso = editor.create("Contoso").unwrap();
+        contoso.set_value("Level", &Data::U32(1)).unwrap();
+        let offset = contoso.offset() as usize;
+
+        // Point the value list of Contoso past the end of the hive, after the 4096 byte base
+        // block and the cell's size, so freeing the key fails.
+        let mut bytes = editor.into_bytes();
+        let at = 4096 + offset + 4 + 0x28;
+        bytes[at..at + 4].copy_from_slice(&0x7fff_0000u32.to_le_bytes());
+        let editor = Editor::from_bytes(bytes).unwrap();
+        let _scope = scoped(Arc::new(
+            Offline::new(editor, Hive::LocalMachine, "SOFTWARE").unwrap(),
+        ));
+
+        let contoso = Hive::LocalMachine
+            .open(r"SOFTWARE\Contoso", Security::Read)
+            .unwrap();
+        assert!(Hive::LocalMachine
+            .delete(r"SOFTWARE\Contoso", false)
+            .is_err());
+        assert_eq!(contoso.info().unwrap().value_count(), 1);
+    }
+
+    #[test]
+    fn rejects_long_key_names() {
+        let backend = Offline::new(Editor::new(), Hive::LocalMachine, "SOFTWARE").unwrap();
+        let root = backend.predefined(Hive::LocalMachine).unwrap();
+        let path = U16CString::from_str(format!(r"SOFTWARE\{}", "a".repeat(256))).unwrap();
+        assert_eq!(
+            backend.create_key(root, &path, Security::AllAccess),
+            Err(ErrorCode::INVALID_PARAMETER)
+        );
+        assert_eq!(
+            backend.open_key(root, &path, Security::Read),
+            Err(ErrorCode::INVALID_PARAMETER)
+        );
+    }
+
+    #[test]
+    fn rejects_mount_paths_with_nuls() {
+        assert!(matches!(
+            Offline::new(Editor::new(), Hive::LocalMachine, "SOFT\0WARE"),
+            Err(offline::Error::InvalidNul(_))
+        ));
+    }
+
+    #[test]
+    fn saves_when_dropped() {
+        let path = software("backend-drop.hiv");
+        {
+            let backend = Arc::new(Offline::load(&path, Hive::LocalMachine, "SOFTWARE").unwrap());
+            let _scope = scoped(backend);
+            Hive::LocalMachine
+                .create(r"SOFTWARE\Contoso\Telemetry", Security::AllAccess)
+                .unwrap();
+        }
+
+        let hive = Editor::load(&path).unwrap();
+        assert!(hive.hive().open(r"Contoso\Telemetry").is_ok());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,1068 @@
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use utfx::{U16CStr, U16CString};
+
+use super::{
+    components, fold, has, scoped, Backend, ErrorCode, Handle, KeyEntry, RawValue,
+    MAX_VALUE_NAME_LEN, ROOTS,
+};
+use crate::info::{FileTime, KeyInfo};
+use crate::operation::{self, Operation};
+use crate::sec::Security;
+use crate::{value, Data, Hive};
+
+/// Changes the overlay holds for a key, or for keys below it.
+#[derive(Debug)]
+struct Node {
+    name: U16CString,
+    /// The key was deleted, hiding whatever the base has here and below.
+    is_tombstone: bool,
+    /// The key was created by the overlay, so exists whatever the base has.
+    is_created: bool,
+    /// Values set, or deleted when `None`, in the order they were first changed.
+    values: Vec<(U16CString, Option<Data>)>,
+    /// Subkeys with changes, by upper cased name.
+    keys: BTreeMap<Vec<u16>, Node>,
+    last_write_time: FileTime,
+}
+
+impl Node {
+    fn new(name: U16CString) -> Node {
+        Node {
+            name,
+            is_tombstone: false,
+            is_created: false,
+            values: vec![],
+            keys: BTreeMap::new(),
+            last_write_time: FileTime::now(),
+        }
+    }
+
+    fn is_unchanged(&self) -> bool {
+        !self.is_tombstone && self.values.is_empty() && self.keys.is_empty()
+    }
+
+    fn value(&self, name: &[u16]) -> Option<usize> {
+        let name = fold(name);
+        self.values
+            .iter()
+            .position(|(x, _)| fold(x.as_slice()) == name)
+    }
+}
+
+#[derive(Debug)]
+struct Open {
+    hive: Hive,
+    path: Vec<U16CString>,
+    sec: Security,
+    /// The same key in the base, opened for reading, if it exists there.
+    base: Option<Handle>,
+    /// The key was deleted through the overlay, so the handle fails even if a key is created
+    /// again at the same path.
+    is_deleted: bool,
+    /// The subkeys and values last listed for enumeration, with the generation of the
+    /// changes they were listed at.
+    keys: Option<(u64, Vec<KeyEntry>)>,
+    values: Option<(u64, Vec<(U16CString, RawValue)>)>,
+}
+
+#[derive(Debug)]
+struct State {
+    roots: Vec<(Hive, Node)>,
+    handles: HashMap<usize, Open>,
+    next_handle: usize,
+    /// Counts the changes made, so listings made before one are not used after it.
+    generation: u64,
+}
+
+fn roots() -> Vec<(Hive, Node)> {
+    ROOTS
+        .iter()
+        .map(|(hive, _)| {
+            (
+                *hive,
+                Node::new(U16CString::from_str(hive.to_string()).unwrap()),
+            )
+        })
+        .collect()
+}
+
+fn joined(path: &[U16CString]) -> U16CString {
+    let mut out = vec![];
+    for name in path {
+        if !out.is_empty() {
+            out.push(u16::from(b'\\'));
+        }
+        out.extend_from_slice(name.as_slice());
+    }
+    // SAFETY: the names are nul-free.
+    unsafe { U16CString::from_vec_unchecked(out) }
+}
+
+impl State {
+    fn open(&self, handle: Handle) -> Result<&Open, ErrorCode> {
+        self.handles
+            .get(&handle.raw())
+            .ok_or(ErrorCode::INVALID_HANDLE)
+    }
+
+    fn insert(&mut self, open: Open) -> Handle {
+        let handle = self.next_handle;
+        // Handles are kept aligned, as Win32 handles are.
+        self.next_handle += 4;
+        self.handles.insert(handle, open);
+        Handle::new(handle)
+    }
+
+    fn root(&self, hive: Hive) -> &Node {
+        &self.roots.iter().find(|(x, _)| *x == hive).unwrap().1
+    }
+
+    /// The node for a path, if the overlay has one, and whether the base is hidden there.
+    fn lookup(&self, hive: Hive, path: &[U16CString]) -> (Option<&Node>, bool) {
+        let mut node = self.root(hive);
+        let mut is_hidden = node.is_tombstone;
+        for name in path {
+            node = match node.keys.get(&fold(name.as_slice())) {
+                Some(node) => node,
+                None => return (None, is_hidden),
+            };
+            is_hidden |= node.is_tombstone;
+        }
+        (Some(node), is_hidden)
+    }
+
+    /// Whether the base is hidden for a path by the deletion of one of its parents.
+    fn is_parent_hidden(&self, hive: Hive, path: &[U16CString]) -> bool {
+        match path.split_last() {
+            Some((_, parent)) => self.lookup(hive, parent).1,
+            None => false,
+        }
+    }
+
+    /// The node for a path, adding any that are missing.
+    fn node_mut(&mut self, hive: Hive, path: &[U16CString]) -> &mut Node {
+        let mut node = &mut self.roots.iter_mut().find(|(x, _)| *x == hive).unwrap().1;
+        for name in path {
+            node = node
+                .keys
+                .entry(fold(name.as_slice()))
+                .or_insert_with(|| Node::new(name.clone()));
+        }
+        node
+    }
+
+    /// Whether the key behind a handle still exists.
+    fn exists(&self, open: &Open) -> bool {
+        let (node, is_hidden) = self.lookup(open.hive, &open.path);
+        !open.is_deleted
+            && (open.path.is_empty()
+                || node.map(|x| x.is_created).unwrap_or(false)
+                || (!is_hidden && open.base.is_some()))
+    }
+
+    /// Marks handles to a key and the keys below it as deleted, or only those below it when
+    /// the key itself is kept.
+    fn mark_deleted(&mut self, hive: Hive, path: &[U16CString], is_kept: bool) {
+        let min_len = path.len() + is_kept as usize;
+        for open in self.handles.values_mut() {
+            if open.hive == hive
+                && open.path.len() >= min_len
+                && open
+                    .path
+                    .iter()
+                    .zip(path)
+                    .all(|(a, b)| fold(a.as_slice()) == fold(b.as_slice()))
+            {
+                open.is_deleted = true;
+            }
+        }
+    }
+
+    /// The key behind a handle, if it still exists.
+    fn key(&self, handle: Handle) -> Result<&Open, ErrorCode> {
+        let open = self.open(handle)?;
+        if !self.exists(open) {
+            return Err(ErrorCode::KEY_DELETED);
+        }
+        Ok(open)
+    }
+
+    /// The changes below a node, with paths relative to the hive.
+    fn operations(path: &str, node: &Node, out: &mut Vec<Operation>) {
+        if node.is_tombstone {
+            out.push(Operation::DeleteKey {
+                path: path.to_string(),
+            });
+        }
+        if node.is_created {
+            out.push(Operation::CreateKey {
+                path: path.to_string(),
+            });
+        }
+        for (name, data) in &node.values {
+            let name = name.to_string_lossy();
+            out.push(match data {
+                Some(data) => Operation::SetValue {
+                    path: path.to_string(),
+                    name,
+                    data: data.clone(),
+                },
+                None => Operation::DeleteValue {
+                    path: path.to_string(),
+                    name,
+                },
+            });
+        }
+        for key in node.keys.values() {
+            let path = operation::join(path, &key.name.to_string_lossy());
+            State::operations(&path, key, out);
+        }
+    }
+}
+
+/// A copy-on-write layer over another backend, for trying out changes without making them.
+///
+/// Reads fall through to the base, while keys and values that are created, set or deleted
+/// through the overlay are held in memory, along with tombstones for deleted keys and values
+/// that hide them in the base. Nothing is written to the base until
+/// [`commit`](#method.commit) is called.
+///
+/// The overlay follows the rules of [`Memory`](struct.Memory.html) for case, enumeration
+/// order, access rights and deleting keys. Values of types that [`Data`](../enum.Data.html)
+/// cannot hold are rejected with `ERROR_NOT_SUPPORTED`, as the changes are kept as
+/// [`Operation`](../operation/enum.Operation.html)s. Application hives are not supported.
+///
+/// ```
+/// use std::sync::Arc;
+/// use registry::backend::{self, Memory, Overlay};
+/// use registry::{Data, Hive, Security};
+///
+/// let base = Arc::new(Memory::new());
+/// let overlay = Arc::new(Overlay::new(base.clone()));
+/// {
+///     let _scope = backend::scoped(overlay.clone());
+///     let key = Hive::CurrentUser.create(r"Software\Contoso", Security::AllAccess).unwrap();
+///     key.set_value("Level", &Data::U32(3)).unwrap();
+/// }
+/// assert_eq!(overlay.changes().len(), 3);
+///
+/// let _scope = backend::scoped(base);
+/// assert!(Hive::CurrentUser.open(r"Software\Contoso", Security::Read).is_err());
+/// overlay.commit().unwrap();
+/// let key = Hive::CurrentUser.open(r"Software\Contoso", Security::Read).unwrap();
+/// assert_eq!(key.value("Level").unwrap(), Data::U32(3));
+/// ```
+#[derive(Debug)]
+pub struct Overlay {
+    base: Arc<dyn Backend>,
+    state: Mutex<State>,
+}
+
+impl Overlay {
+    /// An overlay over `base`, with no changes yet.
+    pub fn new(base: Arc<dyn Backend>) -> Overlay {
+        Overlay {
+            base,
+            state: Mutex::new(State {
+                roots: roots(),
+                handles: HashMap::new(),
+                next_handle: 4,
+                generation: 0,
+            }),
+        }
+    }
+
+    /// The backend below the overlay.
+    pub fn base(&self) -> &Arc<dyn Backend> {
+        &self.base
+    }
+
+    /// The changes held by the overlay, as the operations that make them, with paths starting
+    /// with the hive. Deleted keys come before anything created in their place.
+    pub fn changes(&self) -> Vec<Operation> {
+        let state = self.lock();
+        let mut out = vec![];
+        for (hive, node) in &state.roots {
+            let mut operations = vec![];
+            State::operations("", node, &mut operations);
+            out.extend(operations.into_iter().map(|x| prefix(*hive, x)));
+        }
+        out
+    }
+
+    /// Makes the changes in the base, leaving the overlay without changes. Open keys go on
+    /// to read the committed keys, except those deleted through the overlay.
+    ///
+    /// Should the base fail part way, the changes made so far stay in the base, and the
+    /// overlay keeps all of its changes.
+    pub fn commit(&self) -> Result<(), crate::Error> {
+        let mut state = self.lock();
+        {
+            let _scope = scoped(self.base.clone());
+            for (hive, node) in &state.roots {
+                let mut operations = vec![];
+                State::operations("", node, &mut operations);
+                let mut target = *hive;
+                for operation in &operations {
+                    operation::execute(&mut target, operation)?;
+                }
+            }
+        }
+
+        state.roots = roots();
+        state.generation += 1;
+        // Keys created by the overlay are now found in the base, and keys it deleted and
+        // created again are new keys there, so every open key is opened in the base afresh.
+        for open in state.handles.values_mut() {
+            if let Some(base) = open.base.take() {
+                self.base.close_key(base);
+            }
+            if !open.is_deleted {
+                open.base = self.base_key(open.hive, &open.path).unwrap_or(None);
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the changes held by the overlay.
+    pub fn discard(&self) {
+        let mut state = self.lock();
+        state.roots = roots();
+        state.generation += 1;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Opens a key in the base for reading, if it exists there.
+    fn base_key(&self, hive: Hive, path: &[U16CString]) -> Result<Option<Handle>, ErrorCode> {
+        let root = self.base.predefined(hive)?;
+        match self.base.open_key(root, &joined(path), Security::Read) {
+            Ok(handle) => Ok(Some(handle)),
+            Err(ErrorCode::FILE_NOT_FOUND) | Err(ErrorCode::PATH_NOT_FOUND) => Ok(None),
+            Err(code) => Err(code),
+        }
+    }
+
+    /// Whether a key exists in the base, unless hidden by the overlay.
+    fn is_in_base(
+        &self,
+        state: &State,
+        hive: Hive,
+        path: &[U16CString],
+    ) -> Result<bool, ErrorCode> {
+        if state.lookup(hive, path).1 {
+            return Ok(false);
+        }
+        match self.base_key(hive, path)? {
+            Some(handle) => {
+                self.base.close_key(handle);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn key_exists(
+        &self,
+        state: &State,
+        hive: Hive,
+        path: &[U16CString],
+    ) -> Result<bool, ErrorCode> {
+        match state.lookup(hive, path).0 {
+            _ if path.is_empty() => Ok(true),
+            Some(node) if node.is_created => Ok(true),
+            _ => self.is_in_base(state, hive, path),
+        }
+    }
+
+    /// The subkeys of a key, as the base has them with the changes made.
+    fn keys(&self, state: &State, open: &Open) -> Result<Vec<KeyEntry>, ErrorCode> {
+        let (node, is_hidden) = state.lookup(open.hive, &open.path);
+
+        let mut entries = vec![];
+        if let (false, Some(base)) = (is_hidden, open.base) {
+            while let Some(entry) = self.base.enum_key(base, entries.len() as u32)? {
+                entries.push(entry);
+            }
+        }
+
+        if let Some(node) = node {
+            entries.retain(|x| match node.keys.get(&fold(x.name.as_slice())) {
+                Some(key) => !key.is_tombstone || key.is_created,
+                None => true,
+            });
+            for (name, key) in &node.keys {
+                if key.is_created && !entries.iter().any(|x| fold(x.name.as_slice()) == *name) {
+                    entries.push(KeyEntry {
+                        name: key.name.clone(),
+                        class: None,
+                        last_write_time: key.last_write_time,
+                    });
+                }
+            }
+            entries.sort_by_key(|x| fold(x.name.as_slice()));
+        }
+
+        Ok(entries)
+    }
+
+    /// The values of a key, as the base has them with the changes made.
+    fn values(&self, state: &State, open: &Open) -> Result<Vec<(U16CString, RawValue)>, ErrorCode> {
+        let (node, is_hidden) = state.lookup(open.hive, &open.path);
+
+        let mut values = vec![];
+        if let (false, Some(base)) = (is_hidden, open.base) {
+            while let Some(value) = self.base.enum_value(base, values.len() as u32)? {
+                values.push(value);
+            }
+        }
+
+        for (name, data) in node.map(|x| &x.values[..]).unwrap_or_default() {
+            let i = values
+                .iter()
+                .position(|(x, _)| fold(x.as_slice()) == fold(name.as_slice()));
+            match (i, data) {
+                (Some(i), Some(data)) => values[i].1 = RawValue::from_data(data),
+                (None, Some(data)) => values.push((name.clone(), RawValue::from_data(data))),
+                (Some(i), None) => {
+                    values.remove(i);
+                }
+                (None, None) => {}
+            }
+        }
+
+        Ok(values)
+    }
+
+    /// The base key to read a key from directly, when the overlay holds no changes to it.
+    fn unchanged(state: &State, open: &Open) -> Option<Handle> {
+        match state.lookup(open.hive, &open.path) {
+            (_, true) => None,
+            (Some(node), false) if !node.is_unchanged() => None,
+            _ => open.base,
+        }
+    }
+}
+
+fn prefix(hive: Hive, operation: Operation) -> Operation {
+    let full = |path: &str| operation::join(&hive.to_string(), path);
+    match operation {
+        Operation::CreateKey { path } => Operation::CreateKey { path: full(&path) },
+        Operation::DeleteKey { path } => Operation::DeleteKey { path: full(&path) },
+        Operation::SetValue { path, name, data } => Operation::SetValue {
+            path: full(&path),
+            name,
+            data,
+        },
+        Operation::DeleteValue { path, name } => Operation::DeleteValue {
+            path: full(&path),
+            name,
+        },
+    }
+}
+
+impl Backend for Overlay {
+    fn predefined(&self, hive: Hive) -> Result<Handle, ErrorCode> {
+        let (_, handle) = *ROOTS
+            .iter()
+            .find(|(x, _)| *x == hive)
+            .ok_or(ErrorCode::INVALID_HANDLE)?;
+
+        let mut state = self.lock();
+        if let Entry::Vacant(entry) = state.handles.entry(handle) {
+            entry.insert(Open {
+                hive,
+                path: vec![],
+                sec: Security::AllAccess,
+                base: self.base_key(hive, &[])?,
+                is_deleted: false,
+                keys: None,
+                values: None,
+            });
+        }
+        Ok(Handle::new(handle))
+    }
+
+    fn open_key(&self, base: Handle, path: &U16CStr, sec: Security) -> Result<Handle, ErrorCode> {
+        let mut state = self.lock();
+        let open = state.key(base)?;
+        let hive = open.hive;
+        let mut full = open.path.clone();
+        for name in components(path)? {
+            // SAFETY: the name was split from a nul-free path.
+            full.push(unsafe { U16CString::from_vec_unchecked(name) });
+        }
+
+        let (node, is_hidden) = state.lookup(hive, &full);
+        let is_created = node.map(|x| x.is_created).unwrap_or(false);
+        let base = match is_hidden {
+            true => None,
+            false => self.base_key(hive, &full)?,
+        };
+        if !is_created && base.is_none() && !full.is_empty() {
+            return Err(ErrorCode::FILE_NOT_FOUND);
+        }
+
+        Ok(state.insert(Open {
+            hive,
+            path: full,
+            sec,
+            base,
+            is_deleted: false,
+            keys: None,
+            values: None,
+        }))
+    }
+
+    fn create_key(&self, base: Handle, path: &U16CStr, sec: Security) -> Result<Handle, ErrorCode> {
+        let mut state = self.lock();
+        let open = state.key(base)?;
+        let (hive, base_sec) = (open.hive, open.sec);
+        let mut full = open.path.clone();
+        let start = full.len();
+        for name in components(path)? {
+            // SAFETY: the name was split from a nul-free path.
+            full.push(unsafe { U16CString::from_vec_unchecked(name) });
+        }
+
+        for i in start + 1..=full.len() {
+            if self.key_exists(&state, hive, &full[..i])? {
+                continue;
+            }
+            has(base_sec, Security::CreateSubKey)?;
+            state.generation += 1;
+            for j in i..=full.len() {
+                let node = state.node_mut(hive, &full[..j]);
+                node.is_created = true;
+                node.last_write_time = FileTime::now();
+            }
+            break;
+        }
+
+        let base = match state.lookup(hive, &full).1 {
+            true => None,
+            false => self.base_key(hive, &full)?,
+        };
+        Ok(state.insert(Open {
+            hive,
+            path: full,
+            sec,
+            base,
+            is_deleted: false,
+            keys: None,
+            values: None,
+        }))
+    }
+
+    fn delete_key(
+        &self,
+        base: Handle,
+        path: &U16CStr,
+        is_recursive: bool,
+    ) -> Result<(), ErrorCode> {
+        let mut state = self.lock();
+        let open = state.key(base)?;
+        let hive = open.hive;
+        let mut full = open.path.clone();
+        let names = components(path)?;
+        for name in &names {
+            // SAFETY: the name was split from a nul-free path.
+            full.push(unsafe { U16CString::from_vec_unchecked(name.to_vec()) });
+        }
+        if !self.key_exists(&state, hive, &full)? {
+            return Err(ErrorCode::FILE_NOT_FOUND);
+        }
+
+        let is_in_base = !state.is_parent_hidden(hive, &full) && {
+            let node = state.lookup(hive, &full).0;
+            match node {
+                Some(node) if node.is_tombstone => true,
+                _ => self.is_in_base(&state, hive, &full)?,
+            }
+        };
+
+        if is_recursive && names.is_empty() {
+            state.generation += 1;
+            state.mark_deleted(hive, &full, true);
+            let node = state.node_mut(hive, &full);
+            node.is_tombstone |= is_in_base;
+            node.is_created = !full.is_empty();
+            node.values.clear();
+            node.keys.clear();
+            node.last_write_time = FileTime::now();
+            return Ok(());
+        }
+
+        if full.is_empty() {
+            return Err(ErrorCode::ACCESS_DENIED);
+        }
+        if !is_recursive {
+            let open = Open {
+                hive,
+                path: full.clone(),
+                sec: Security::Read,
+                base: match is_in_base {
+                    true => self.base_key(hive, &full)?,
+                    false => None,
+                },
+                is_deleted: false,
+                keys: None,
+                values: None,
+            };
+            let keys = self.keys(&state, &open);
+            if let Some(handle) = open.base {
+                self.base.close_key(handle);
+            }
+            if !keys?.is_empty() {
+                return Err(ErrorCode::ACCESS_DENIED);
+            }
+        }
+
+        state.generation += 1;
+        state.mark_deleted(hive, &full, false);
+        if is_in_base {
+            let node = state.node_mut(hive, &full);
+            node.is_tombstone = true;
+            node.is_created = false;
+            node.values.clear();
+            node.keys.clear();
+        } else {
+            let (name, parent) = full.split_last().unwrap();
+            state
+                .node_mut(hive, parent)
+                .keys
+                .remove(&fold(name.as_slice()));
+        }
+        state
+            .node_mut(hive, &full[..full.len() - 1])
+            .last_write_time = FileTime::now();
+        Ok(())
+    }
+
+    fn close_key(&self, handle: Handle) {
+        if ROOTS.iter().any(|(_, x)| *x == handle.raw()) {
+            return;
+        }
+        if let Some(open) = self.lock().handles.remove(&handle.raw()) {
+            if let Some(base) = open.base {
+                self.base.close_key(base);
+            }
+        }
+    }
+
+    fn enum_key(&self, handle: Handle, index: u32) -> Result<Option<KeyEntry>, ErrorCode> {
+        let mut state = self.lock();
+        let open = state.key(handle)?;
+        has(open.sec, Security::EnumerateSubKeys)?;
+
+        if let Some(base) = Overlay::unchanged(&state, open) {
+            return self.base.enum_key(base, index);
+        }
+
+        // Enumeration starts again from the base at index 0, and reuses the listing after.
+        let generation = state.generation;
+        let keys = match &open.keys {
+            Some((x, _)) if *x == generation && index > 0 => None,
+            _ => Some(self.keys(&state, open)?),
+        };
+        let open = state.handles.get_mut(&handle.raw()).unwrap();
+        if let Some(keys) = keys {
+            open.keys = Some((generation, keys));
+        }
+        Ok(open
+            .keys
+            .as_ref()
+            .and_then(|(_, x)| x.get(index as usize).cloned()))
+    }
+
+    fn enum_value(
+        &self,
+        handle: Handle,
+        index: u32,
+    ) -> Result<Option<(U16CString, RawValue)>, ErrorCode> {
+        let mut state = self.lock();
+        let open = state.key(handle)?;
+        has(open.sec, Security::QueryValue)?;
+
+        if let Some(base) = Overlay::unchanged(&state, open) {
+            return self.base.enum_value(base, index);
+        }
+
+        let generation = state.generation;
+        let values = match &open.values {
+            Some((x, _)) if *x == generation && index > 0 => None,
+            _ => Some(self.values(&state, open)?),
+        };
+        let open = state.handles.get_mut(&handle.raw()).unwrap();
+        if let Some(values) = values {
+            open.values = Some((generation, values));
+        }
+        Ok(open
+            .values
+            .as_ref()
+            .and_then(|(_, x)| x.get(index as usize).cloned()))
+    }
+
+    fn query_value(&self, handle: Handle, name: &U16CStr) -> Result<RawValue, ErrorCode> {
+        let state = self.lock();
+        let open = state.key(handle)?;
+        has(open.sec, Security::QueryValue)?;
+
+        let (node, is_hidden) = state.lookup(open.hive, &open.path);
+        if let Some(node) = node {
+            if let Some(i) = node.value(name.as_slice()) {
+                return match &node.values[i].1 {
+                    Some(data) => Ok(RawValue::from_data(data)),
+                    None => Err(ErrorCode::FILE_NOT_FOUND),
+                };
+            }
+        }
+        match (is_hidden, open.base) {
+            (false, Some(base)) => self.base.query_value(base, name),
+            _ => Err(ErrorCode::FILE_NOT_FOUND),
+        }
+    }
+
+    fn set_value(&self, handle: Handle, name: &U16CStr, value: &RawValue) -> Result<(), ErrorCode> {
+        let mut state = self.lock();
+        let open = state.key(handle)?;
+        has(open.sec, Security::SetValue)?;
+        if name.len() > MAX_VALUE_NAME_LEN {
+            return Err(ErrorCode::INVALID_PARAMETER);
+        }
+        let data = value.to_data().map_err(|_| ErrorCode::NOT_SUPPORTED)?;
+
+        let (hive, path) = (open.hive, open.path.clone());
+        state.generation += 1;
+        let node = state.node_mut(hive, &path);
+        match node.value(name.as_slice()) {
+            Some(i) => node.values[i].1 = Some(data),
+            None => node.values.push((name.to_ucstring(), Some(data))),
+        }
+        node.last_write_time = FileTime::now();
+        Ok(())
+    }
+
+    fn delete_value(&self, handle: Handle, name: &U16CStr) -> Result<(), ErrorCode> {
+        let mut state = self.lock();
+        let open = state.key(handle)?;
+        has(open.sec, Security::SetValue)?;
+
+        let (hive, path) = (open.hive, open.path.clone());
+        let (node, is_hidden) = state.lookup(hive, &path);
+        let change = node.and_then(|x| x.value(name.as_slice()).map(|i| &x.values[i].1));
+        let is_in_base = match (is_hidden, open.base) {
+            (false, Some(base)) => match self.base.query_value(base, name) {
+                Ok(_) => true,
+                Err(ErrorCode::FILE_NOT_FOUND) => false,
+                Err(code) => return Err(code),
+            },
+            _ => false,
+        };
+        match change {
+            Some(None) => return Err(ErrorCode::FILE_NOT_FOUND),
+            None if !is_in_base => return Err(ErrorCode::FILE_NOT_FOUND),
+            _ => {}
+        }
+
+        state.generation += 1;
+        let node = state.node_mut(hive, &path);
+        let i = node.value(name.as_slice());
+        match (i, is_in_base) {
+            (Some(i), true) => node.values[i].1 = None,
+            (None, true) => node.values.push((name.to_ucstring(), None)),
+            (Some(i), false) => {
+                node.values.remove(i);
+            }
+            (None, false) => {}
+        }
+        node.last_write_time = FileTime::now();
+        Ok(())
+    }
+
+    fn query_info(&self, handle: Handle) -> Result<KeyInfo, ErrorCode> {
+        let state = self.lock();
+        let open = state.key(handle)?;
+        has(open.sec, Security::QueryValue)?;
+
+        if let Some(base) = Overlay::unchanged(&state, open) {
+            return self.base.query_info(base);
+        }
+
+        let keys = self.keys(&state, open)?;
+        let values = self.values(&state, open)?;
+        let max = |lens: &mut dyn Iterator<Item = usize>| lens.max().unwrap_or(0) as u32;
+        let node = state.lookup(open.hive, &open.path).0;
+        Ok(KeyInfo {
+            class: None,
+            last_write_time: node.map(|x| x.last_write_time).unwrap_or_default(),
+            subkey_count: keys.len() as u32,
+            max_subkey_name_len: max(&mut keys.iter().map(|x| x.name.len())),
+            max_class_len: max(&mut keys.iter().map(|x| x.class.as_ref().map_or(0, |x| x.len()))),
+            value_count: values.len() as u32,
+            max_value_name_len: max(&mut values.iter().map(|(x, _)| x.len())),
+            max_value_data_len: max(&mut values.iter().map(|(_, x)| x.data.len())),
+            security_descriptor_len: 0,
+        })
+    }
+
+    fn decode(&self, value: &RawValue) -> Result<Data, value::Error> {
+        self.base.decode(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+    use crate::backend::Memory;
+    use crate::{key, value, RegKey};
+
+    fn names(key: &RegKey) -> Vec<String> {
+        key.keys().map(|x| x.unwrap().to_string()).collect()
+    }
+
+    fn value_names(key: &RegKey) -> Vec<String> {
+        key.values()
+            .map(|x| x.unwrap().name().to_string_lossy())
+            .collect()
+    }
+
+    /// A base holding `HKLM\SOFTWARE\Contoso` with two values and two subkeys.
+    fn base() -> Arc<dyn Backend> {
+        let base: Arc<dyn Backend> = Arc::new(Memory::new());
+        let _scope = scoped(base.clone());
+        let key = Hive::LocalMachine
+            .create(r"SOFTWARE\Contoso", Security::AllAccess)
+            .unwrap();
+        key.set_value("Level", &Data::U32(3)).unwrap();
+        key.set_value("Name", &Data::String("Contoso".try_into().unwrap()))
+            .unwrap();
+        key.create(r"Update\Channels", Security::AllAccess).unwrap();
+        key.create("Telemetry", Security::AllAccess).unwrap();
+        base
+    }
+
+    #[test]
+    fn reads_fall_through() {
+        let overlay = Arc::new(Overlay::new(base()));
+        let _scope = scoped(overlay.clone());
+
+        let key = Hive::LocalMachine
+            .open(r"software\contoso", Security::Read)
+            .unwrap();
+        assert_eq!(key.value("level").unwrap(), Data::U32(3));
+        assert_eq!(names(&key), vec!["Telemetry", "Update"]);
+        assert_eq!(value_names(&key), vec!["Level", "Name"]);
+        assert_eq!(key.info().unwrap().subkey_count(), 2);
+        assert!(matches!(
+            Hive::LocalMachine.open(r"SOFTWARE\Missing", Security::Read),
+            Err(key::Error::NotFound(..))
+        ));
+        assert!(overlay.changes().is_empty());
+    }
+
+    #[test]
+    fn writes_stay_in_the_overlay() {
+        let base = base();
+        let overlay = Arc::new(Overlay::new(base.clone()));
+        {
+            let _scope = scoped(overlay.clone());
+            let key = Hive::LocalMachine
+                .open(r"SOFTWARE\Contoso", Security::AllAccess)
+                .unwrap();
+            key.set_value("Level", &Data::U32(4)).unwrap();
+            key.set_value("Added", &Data::U32(1)).unwrap();
+            key.delete_value("name").unwrap();
+            key.create(r"Beta\Ring", Security::AllAccess).unwrap();
+            key.delete("Update", true).unwrap();
+
+            assert_eq!(key.value("Level").unwrap(), Data::U32(4));
+            assert!(matches!(key.value("Name"), Err(value::Error::NotFound(..))));
+            assert_eq!(value_names(&key), vec!["Level", "Added"]);
+            assert_eq!(names(&key), vec!["Beta", "Telemetry"]);
+            assert_eq!(key.info().unwrap().value_count(), 2);
+            assert!(key.open(r"Update\Channels", Security::Read).is_err());
+
+            // A value only the overlay has leaves no tombstone.
+            key.delete_value("Added").unwrap();
+            assert!(matches!(
+                key.delete_value("Added"),
+                Err(value::Error::NotFound(..))
+            ));
+        }
+
+        let _scope = scoped(base);
+        let key = Hive::LocalMachine
+            .open(r"SOFTWARE\Contoso", Security::Read)
+            .unwrap();
+        assert_eq!(key.value("Level").unwrap(), Data::U32(3));
+        assert_eq!(value_names(&key), vec!["Level", "Name"]);
+        assert_eq!(names(&key), vec!["Telemetry", "Update"]);
+    }
+
+    #[test]
+    fn listings_follow_changes() {
+        let overlay = Arc::new(Overlay::new(base()));
+        let _scope = scoped(overlay);
+        let key = Hive::LocalMachine
+            .open(r"SOFTWARE\Contoso", Security::AllAccess)
+            .unwrap();
+        key.create("Beta", Security::AllAccess).unwrap();
+
+        // A change part way through enumerating is seen by the next index.
+        let mut keys = key.keys();
+        assert_eq!(keys.next().unwrap().unwrap().to_string(), "Beta");
+        key.create("Alpha", Security::AllAccess).unwrap();
+        assert_eq!(keys.next().unwrap().unwrap().to_string(), "Beta");
+        assert_eq!(names(&key), vec!["Alpha", "Beta", "Telemetry", "Update"]);
+
+        let mut values = key.values();
+        assert_eq!(
+            values.next().unwrap().unwrap().name().to_string_lossy(),
+            "Level"
+        );
+        key.delete_value("Level").unwrap();
+        assert!(values.next().is_none());
+        assert_eq!(value_names(&key), vec!["Name"]);
+    }
+
+    #[test]
+    fn tombstones_hide_recreated_keys() {
+        let overlay = Arc::new(Overlay::new(base()));
+        let _scope = scoped(overlay.clone());
+
+        let old = Hive::LocalMachine
+            .open(r"SOFTWARE\Contoso\Update", Security::Read)
+            .unwrap();
+        Hive::LocalMachine
+            .delete(r"SOFTWARE\Contoso\Update", true)
+            .unwrap();
+        match old.value("x") {
+            Err(value::Error::Unknown(_, e)) => assert_eq!(e.raw_os_error(), Some(1018)),
+            x => panic!("{:?}", x),
+        }
+
+        let key = Hive::LocalMachine
+            .create(r"SOFTWARE\Contoso\update\Stable", Security::AllAccess)
+            .unwrap();
+        let update = Hive::LocalMachine
+            .open(r"SOFTWARE\Contoso\Update", Security::Read)
+            .unwrap();
+        assert_eq!(names(&update), vec!["Stable"]);
+        drop(key);
+        // The key created in its place is a different key.
+        match old.value("x") {
+            Err(value::Error::Unknown(_, e)) => assert_eq!(e.raw_os_error(), Some(1018)),
+            x => panic!("{:?}", x),
+        }
+
+        let contoso = Hive::LocalMachine
+            .open(r"SOFTWARE\Contoso", Security::AllAccess)
+            .unwrap();
+        assert!(matches!(
+            contoso.delete("Update", false),
+            Err(key::Error::PermissionDenied(..))
+        ));
+        contoso.delete("", true).unwrap();
+        assert!(names(&contoso).is_empty());
+        assert!(value_names(&contoso).is_empty());
+        assert!(matches!(
+            Hive::LocalMachine.delete("", false),
+            Err(key::Error::PermissionDenied(..))
+        ));
+
+        assert_eq!(
+            overlay.changes(),
+            vec![
+                Operation::DeleteKey {
+                    path: r"HKEY_LOCAL_MACHINE\SOFTWARE\Contoso".to_string()
+                },
+                Operation::CreateKey {
+                    path: r"HKEY_LOCAL_MACHINE\SOFTWARE\Contoso".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn commits_changes_to_the_base() {
+        let base = base();
+        let overlay = Arc::new(Overlay::new(base.clone()));
+        {
+            let _scope = scoped(overlay.clone());
+            let key = Hive::LocalMachine
+                .open(r"SOFTWARE\Contoso", Security::AllAccess)
+                .unwrap();
+            key.delete("Update", true).unwrap();
+            let update = key.create(r"Update\Stable", Security::AllAccess).unwrap();
+            update.set_value("Version", &Data::U32(2)).unwrap();
+            key.delete_value("Level").unwrap();
+            Hive::CurrentUser
+                .create(r"Software\Contoso", Security::AllAccess)
+                .unwrap();
+
+            let changes = overlay.changes();
+            assert_eq!(
+                changes.iter().map(|x| x.path()).collect::<Vec<_>>(),
+                vec![
+                    r"HKEY_CURRENT_USER\Software",
+                    r"HKEY_CURRENT_USER\Software\Contoso",
+                    r"HKEY_LOCAL_MACHINE\SOFTWARE\Contoso",
+                    r"HKEY_LOCAL_MACHINE\SOFTWARE\Contoso\Update",
+                    r"HKEY_LOCAL_MACHINE\SOFTWARE\Contoso\Update",
+                    r"HKEY_LOCAL_MACHINE\SOFTWARE\Contoso\Update\Stable",
+                    r"HKEY_LOCAL_MACHINE\SOFTWARE\Contoso\Update\Stable",
+                ]
+            );
+
+            overlay.commit().unwrap();
+            assert!(overlay.changes().is_empty());
+            assert_eq!(update.value("Version").unwrap(), Data::U32(2));
+            assert_eq!(names(&key), vec!["Telemetry", "Update"]);
+        }
+
+        let _scope = scoped(base);
+        let key = Hive::LocalMachine
+            .open(r"SOFTWARE\Contoso", Security::Read)
+            .unwrap();
+        assert_eq!(value_names(&key), vec!["Name"]);
+        assert_eq!(names(&key), vec!["Telemetry", "Update"]);
+        let update = key.open("Update", Security::Read).unwrap();
+        assert_eq!(names(&update), vec!["Stable"]);
+        assert!(Hive::CurrentUser
+            .open(r"Software\Contoso", Security::Read)
+            .is_ok());
+    }
+
+    #[test]
+    fn handles_outlive_commits() {
+        let overlay = Arc::new(Overlay::new(base()));
+        let _scope = scoped(overlay.clone());
+        let contoso = Hive::LocalMachine
+            .open(r"SOFTWARE\Contoso", Security::AllAccess)
+            .unwrap();
+        let update = Hive::LocalMachine
+            .open(r"SOFTWARE\Contoso\Update", Security::Read)
+            .unwrap();
+
+        // Emptying the key deletes and creates it again in the base.
+        contoso.delete("", true).unwrap();
+        contoso.set_value("Level", &Data::U32(5)).unwrap();
+        overlay.commit().unwrap();
+
+        assert_eq!(contoso.value("Level").unwrap(), Data::U32(5));
+        assert_eq!(value_names(&contoso), vec!["Level"]);
+        assert!(names(&contoso).is_empty());
+        match update.value("x") {
+            Err(value::Error::Unknown(_, e)) => assert_eq!(e.raw_os_error(), Some(1018)),
+            x => panic!("{:?}", x),
+        }
+    }
+}
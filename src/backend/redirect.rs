@@ -0,0 +1,409 @@
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use utfx::{U16CStr, U16CString};
+
+use super::{current, scoped, Backend, ErrorCode, Handle, KeyEntry, RawValue, Scope, ROOTS};
+use crate::info::KeyInfo;
+use crate::key::Error;
+use crate::sec::Security;
+use crate::{value, Data, Hive};
+
+/// Wraps a backend, serving chosen hives from keys elsewhere in it, as
+/// `RegOverridePredefKey` does for a process on Windows.
+///
+/// Only the handles of the hives change, so keys opened below a redirected hive are still
+/// shown with the name of the hive. Hives that are not redirected are served by the wrapped
+/// backend as they are.
+///
+/// ```
+/// use std::sync::Arc;
+/// use registry::backend::{self, Memory, Redirect};
+/// use registry::{Hive, Security};
+///
+/// let memory = Arc::new(Memory::new());
+/// let redirect = Redirect::new(memory.clone())
+///     .redirect(Hive::LocalMachine, Hive::CurrentUser, r"Software\Machine")
+///     .unwrap();
+///
+/// let _scope = backend::scoped(Arc::new(redirect));
+/// let key = Hive::LocalMachine.create(r"SOFTWARE\Contoso", Security::AllAccess).unwrap();
+/// assert_eq!(key.to_string(), r"HKEY_LOCAL_MACHINE\SOFTWARE\Contoso");
+///
+/// let _scope = backend::scoped(memory);
+/// assert!(Hive::CurrentUser.open(r"Software\Machine\SOFTWARE\Contoso", Security::Read).is_ok());
+/// assert!(Hive::LocalMachine.open("SOFTWARE", Security::Read).is_err());
+/// ```
+#[derive(Debug)]
+pub struct Redirect {
+    inner: Arc<dyn Backend>,
+    /// The keys in the wrapped backend serving each redirected hive.
+    roots: Vec<(Hive, Handle)>,
+}
+
+impl Redirect {
+    /// Wraps `inner`, with no hives redirected yet.
+    pub fn new(inner: Arc<dyn Backend>) -> Redirect {
+        Redirect {
+            inner,
+            roots: vec![],
+        }
+    }
+
+    /// Serves `hive` from the key at `path` below `target` in the wrapped backend, creating
+    /// the key if it is missing.
+    pub fn redirect<P>(mut self, hive: Hive, target: Hive, path: P) -> Result<Redirect, Error>
+    where
+        P: TryInto<U16CString>,
+        P::Error: Into<Error>,
+    {
+        let path = path.try_into().map_err(Into::into)?;
+        let error = |code| Error::from_code(code, path.to_string_lossy());
+        let root = self.inner.predefined(target).map_err(error)?;
+        let handle = self
+            .inner
+            .create_key(root, &path, Security::AllAccess)
+            .map_err(error)?;
+
+        if let Some(i) = self.roots.iter().position(|(x, _)| *x == hive) {
+            let (_, old) = self.roots.remove(i);
+            self.inner.close_key(old);
+        }
+        self.roots.push((hive, handle));
+        Ok(self)
+    }
+
+    /// The wrapped backend.
+    pub fn inner(&self) -> &Arc<dyn Backend> {
+        &self.inner
+    }
+
+    fn root(&self, hive: Hive) -> Option<Handle> {
+        self.roots
+            .iter()
+            .find(|(x, _)| *x == hive)
+            .map(|(_, handle)| *handle)
+    }
+
+    fn is_root(&self, handle: Handle) -> bool {
+        self.roots.iter().any(|(_, x)| *x == handle)
+    }
+}
+
+impl Drop for Redirect {
+    fn drop(&mut self) {
+        for (_, handle) in self.roots.drain(..) {
+            self.inner.close_key(handle);
+        }
+    }
+}
+
+impl Backend for Redirect {
+    fn predefined(&self, hive: Hive) -> Result<Handle, ErrorCode> {
+        match self.root(hive) {
+            Some(handle) => Ok(handle),
+            None => self.inner.predefined(hive),
+        }
+    }
+
+    fn open_key(&self, base: Handle, path: &U16CStr, sec: Security) -> Result<Handle, ErrorCode> {
+        self.inner.open_key(base, path, sec)
+    }
+
+    fn create_key(&self, base: Handle, path: &U16CStr, sec: Security) -> Result<Handle, ErrorCode> {
+        self.inner.create_key(base, path, sec)
+    }
+
+    fn delete_key(
+        &self,
+        base: Handle,
+        path: &U16CStr,
+        is_recursive: bool,
+    ) -> Result<(), ErrorCode> {
+        // The key serving a hive cannot be deleted through it, as a hive cannot.
+        if self.is_root(base) && path.as_slice().iter().all(|c| *c == u16::from(b'\\')) {
+            return Err(ErrorCode::ACCESS_DENIED);
+        }
+        self.inner.delete_key(base, path, is_recursive)
+    }
+
+    fn close_key(&self, handle: Handle) {
+        // Predefined handles are never closed.
+        if !self.is_root(handle) {
+            self.inner.close_key(handle)
+        }
+    }
+
+    fn enum_key(&self, handle: Handle, index: u32) -> Result<Option<KeyEntry>, ErrorCode> {
+        self.inner.enum_key(handle, index)
+    }
+
+    fn enum_value(
+        &self,
+        handle: Handle,
+        index: u32,
+    ) -> Result<Option<(U16CString, RawValue)>, ErrorCode> {
+        self.inner.enum_value(handle, index)
+    }
+
+    fn query_value(&self, handle: Handle, name: &U16CStr) -> Result<RawValue, ErrorCode> {
+        self.inner.query_value(handle, name)
+    }
+
+    fn set_value(&self, handle: Handle, name: &U16CStr, value: &RawValue) -> Result<(), ErrorCode> {
+        self.inner.set_value(handle, name, value)
+    }
+
+    fn delete_value(&self, handle: Handle, name: &U16CStr) -> Result<(), ErrorCode> {
+        self.inner.delete_value(handle, name)
+    }
+
+    fn open_current_user(&self, sec: Security) -> Result<Handle, ErrorCode> {
+        match self.root(Hive::CurrentUser) {
+            Some(root) => self.inner.open_key(root, Default::default(), sec),
+            None => self.inner.open_current_user(sec),
+        }
+    }
+
+    fn load_app_key(&self, file_path: &Path, sec: Security) -> Result<Handle, ErrorCode> {
+        self.inner.load_app_key(file_path, sec)
+    }
+
+    fn save_key(&self, handle: Handle, file_path: &U16CStr) -> Result<(), ErrorCode> {
+        self.inner.save_key(handle, file_path)
+    }
+
+    fn query_info(&self, handle: Handle) -> Result<KeyInfo, ErrorCode> {
+        self.inner.query_info(handle)
+    }
+
+    fn decode(&self, value: &RawValue) -> Result<Data, value::Error> {
+        self.inner.decode(value)
+    }
+}
+
+/// Where sandboxes are made, below `HKEY_CURRENT_USER`.
+const SANDBOXES: &str = r"Software\registry-rs";
+
+static NEXT_SANDBOX: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The redirects installed by the sandboxes in use on this thread, by address, with the
+    /// backends the sandboxes were made in.
+    static IN_USE: RefCell<Vec<(*const (), Arc<dyn Backend>)>> = const { RefCell::new(Vec::new()) };
+}
+
+fn address(backend: &Arc<dyn Backend>) -> *const () {
+    Arc::as_ptr(backend) as *const ()
+}
+
+/// An isolated registry for tests, with every hive served from its own, initially empty key.
+///
+/// The sandbox is made below `HKEY_CURRENT_USER\Software\registry-rs` in the backend
+/// `Hive` uses on the current thread, with a name unique to the process and sandbox. It is
+/// installed with [`scoped`](fn.scoped.html) until dropped, when its keys are deleted, so tests
+/// writing to any hive neither see nor leave behind anything outside of it.
+///
+/// Like the guard returned by `scoped`, a sandbox belongs to the thread that made it. A
+/// sandbox made while another is in use is made beside it, in the backend the other was made
+/// in, so sandboxes can be dropped in any order without removing each other's keys.
+///
+/// ```
+/// use std::sync::Arc;
+/// use registry::backend::{self, Memory, Sandbox};
+/// use registry::{Hive, Security};
+///
+/// # let _scope = backend::scoped(Arc::new(Memory::new()));
+/// let sandbox = Sandbox::new().unwrap();
+/// Hive::LocalMachine.create(r"SOFTWARE\Contoso", Security::AllAccess).unwrap();
+/// drop(sandbox);
+///
+/// assert!(Hive::LocalMachine.open(r"SOFTWARE\Contoso", Security::Read).is_err());
+/// ```
+#[must_use = "the sandbox is removed when dropped"]
+#[derive(Debug)]
+pub struct Sandbox {
+    scope: Option<Scope>,
+    /// The redirect installed for the thread.
+    redirect: Option<Arc<dyn Backend>>,
+    inner: Arc<dyn Backend>,
+    path: U16CString,
+}
+
+impl Sandbox {
+    /// Makes a sandbox in the current thread's backend and installs it for the thread.
+    pub fn new() -> Result<Sandbox, Error> {
+        let current = current();
+        let inner = IN_USE
+            .with(|x| {
+                x.borrow()
+                    .iter()
+                    .find(|(redirect, _)| *redirect == address(&current))
+                    .map(|(_, inner)| inner.clone())
+            })
+            .unwrap_or(current);
+        let path = format!(
+            r"{}\sandbox-{}-{}",
+            SANDBOXES,
+            std::process::id(),
+            NEXT_SANDBOX.fetch_add(1, Ordering::Relaxed)
+        );
+
+        let mut sandbox = Sandbox {
+            scope: None,
+            redirect: None,
+            inner: inner.clone(),
+            path: U16CString::from_str(&path).unwrap(),
+        };
+        let mut redirect = Redirect::new(inner);
+        for (hive, _) in ROOTS.iter() {
+            // Dropping the sandbox removes any keys made before a failure.
+            redirect =
+                redirect.redirect(*hive, Hive::CurrentUser, format!(r"{}\{}", path, hive))?;
+        }
+        let redirect: Arc<dyn Backend> = Arc::new(redirect);
+        sandbox.scope = Some(scoped(redirect.clone()));
+        IN_USE.with(|x| {
+            x.borrow_mut()
+                .push((address(&redirect), sandbox.inner.clone()))
+        });
+        sandbox.redirect = Some(redirect);
+        Ok(sandbox)
+    }
+
+    /// The path of the sandbox below `HKEY_CURRENT_USER`.
+    pub fn path(&self) -> &U16CStr {
+        &self.path
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        if let Some(redirect) = self.redirect.take() {
+            IN_USE.with(|x| x.borrow_mut().retain(|(x, _)| *x != address(&redirect)));
+        }
+        self.scope.take();
+
+        let root = match self.inner.predefined(Hive::CurrentUser) {
+            Ok(root) => root,
+            Err(_) => return,
+        };
+        if let Err(code) = self.inner.delete_key(root, &self.path, true) {
+            log::warn!(
+                "Could not remove sandbox {}: {}",
+                self.path.to_string_lossy(),
+                code
+            );
+        }
+        // Fails while other sandboxes remain.
+        let _ = self
+            .inner
+            .delete_key(root, &U16CString::from_str(SANDBOXES).unwrap(), false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Memory;
+    use crate::{key, Data, RegKey};
+
+    #[test]
+    fn redirects_hives() {
+        let memory: Arc<dyn Backend> = Arc::new(Memory::new());
+        let redirect = Redirect::new(memory.clone())
+            .redirect(Hive::LocalMachine, Hive::CurrentUser, r"Sandbox\HKLM")
+            .unwrap()
+            .redirect(Hive::CurrentUser, Hive::CurrentUser, r"Sandbox\HKCU")
+            .unwrap();
+        let redirect = Arc::new(redirect);
+
+        {
+            let _scope = scoped(redirect.clone());
+            let key = Hive::LocalMachine
+                .create(r"SOFTWARE\Contoso", Security::AllAccess)
+                .unwrap();
+            key.set_value("Level", &Data::U32(3)).unwrap();
+            assert_eq!(key.to_string(), r"HKEY_LOCAL_MACHINE\SOFTWARE\Contoso");
+
+            let root = Hive::CurrentUser.open("", Security::Read).unwrap();
+            assert!(root.keys().next().is_none());
+            let user = RegKey::open_current_user(Security::Read).unwrap();
+            assert!(user.keys().next().is_none());
+            assert!(matches!(
+                Hive::LocalMachine.delete("", false),
+                Err(key::Error::PermissionDenied(..))
+            ));
+        }
+
+        let _scope = scoped(memory);
+        let key = Hive::CurrentUser
+            .open(r"Sandbox\HKLM\SOFTWARE\Contoso", Security::Read)
+            .unwrap();
+        assert_eq!(key.value("Level").unwrap(), Data::U32(3));
+        assert!(Hive::CurrentUser
+            .open(r"Sandbox\HKCU", Security::Read)
+            .is_ok());
+        assert!(Hive::LocalMachine.open("SOFTWARE", Security::Read).is_err());
+    }
+
+    #[test]
+    fn sandboxes_are_isolated_and_removed() {
+        let _scope = scoped(Arc::new(Memory::new()));
+        Hive::CurrentUser
+            .create(r"Software\Outside", Security::AllAccess)
+            .unwrap();
+
+        let first = Sandbox::new().unwrap();
+        Hive::CurrentUser
+            .create(r"Software\Inside", Security::AllAccess)
+            .unwrap();
+        assert!(Hive::CurrentUser
+            .open(r"Software\Outside", Security::Read)
+            .is_err());
+
+        let second = Sandbox::new().unwrap();
+        assert_ne!(first.path(), second.path());
+        assert!(Hive::CurrentUser
+            .open(r"Software\Inside", Security::Read)
+            .is_err());
+        drop(second);
+
+        assert!(Hive::CurrentUser
+            .open(r"Software\Inside", Security::Read)
+            .is_ok());
+        drop(first);
+
+        let software = Hive::CurrentUser.open("Software", Security::Read).unwrap();
+        let names = software
+            .keys()
+            .map(|x| x.unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["Outside"]);
+    }
+
+    #[test]
+    fn sandboxes_may_be_dropped_in_any_order() {
+        let _scope = scoped(Arc::new(Memory::new()));
+        let first = Sandbox::new().unwrap();
+        Hive::CurrentUser
+            .create("First", Security::AllAccess)
+            .unwrap();
+        let second = Sandbox::new().unwrap();
+        Hive::CurrentUser
+            .create("Second", Security::AllAccess)
+            .unwrap();
+
+        // The later sandbox stays in use, and keeps its keys.
+        drop(first);
+        assert!(Hive::CurrentUser.open("Second", Security::Read).is_ok());
+        assert!(Hive::CurrentUser.open("First", Security::Read).is_err());
+
+        drop(second);
+        assert!(Hive::CurrentUser.open("Second", Security::Read).is_err());
+        assert!(Hive::CurrentUser.open(SANDBOXES, Security::Read).is_err());
+    }
+}
@@ -0,0 +1,205 @@
+use std::fmt::{self, Debug};
+use std::io::{self, Read, Write};
+use std::sync::{Mutex, MutexGuard};
+
+use utfx::{U16CStr, U16CString};
+
+use super::{decode_reply, hive_code, read_frame, write_frame, Request, Response};
+use crate::backend::{Backend, ErrorCode, Handle, KeyEntry, RawValue};
+use crate::info::KeyInfo;
+use crate::sec::Security;
+use crate::Hive;
+
+trait Stream: Read + Write + Send {}
+
+impl<S: Read + Write + Send> Stream for S {}
+
+/// A backend making every call on a [`Server`](struct.Server.html), usually in another
+/// process.
+///
+/// Calls are made one at a time, each waiting for its reply. Requests the server denies fail
+/// with `ERROR_ACCESS_DENIED`. If the connection fails, that call and every later one fail
+/// with `ERROR_BROKEN_PIPE`. Loading and saving hive files are not supported.
+pub struct Client {
+    /// The connection, or `None` once it has failed.
+    stream: Mutex<Option<Box<dyn Stream>>>,
+}
+
+impl Client {
+    /// A client of the server at the other end of `stream`.
+    pub fn new<S: Read + Write + Send + 'static>(stream: S) -> Client {
+        Client {
+            stream: Mutex::new(Some(Box::new(stream))),
+        }
+    }
+
+    /// Connects to a server listening on the Unix socket at `path`.
+    #[cfg(unix)]
+    pub fn connect<P: AsRef<std::path::Path>>(path: P) -> io::Result<Client> {
+        std::os::unix::net::UnixStream::connect(path).map(Client::new)
+    }
+
+    /// Connects to a server listening on the named pipe `name`, such as
+    /// `\\.\pipe\contoso-settings`.
+    #[cfg(windows)]
+    pub fn connect(name: &str) -> io::Result<Client> {
+        std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(name)
+            .map(Client::new)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<Box<dyn Stream>>> {
+        self.stream.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn call(&self, request: Request) -> Result<Response, ErrorCode> {
+        let mut stream = self.lock();
+        let result = match stream.as_mut() {
+            Some(stream) => exchange(stream, &request),
+            None => return Err(ErrorCode::BROKEN_PIPE),
+        };
+        match result {
+            Ok(reply) => reply,
+            Err(e) => {
+                log::error!("Registry server connection failed: {}", e);
+                *stream = None;
+                Err(ErrorCode::BROKEN_PIPE)
+            }
+        }
+    }
+
+    fn key(&self, request: Request) -> Result<Handle, ErrorCode> {
+        match self.call(request)? {
+            Response::Key(id) => Ok(Handle::new(id as usize)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn done(&self, request: Request) -> Result<(), ErrorCode> {
+        match self.call(request)? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+fn exchange(
+    stream: &mut Box<dyn Stream>,
+    request: &Request,
+) -> io::Result<Result<Response, ErrorCode>> {
+    write_frame(stream, &request.encode())?;
+    match read_frame(stream)? {
+        Some(frame) => decode_reply(&frame),
+        None => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+/// A reply of the wrong kind, which only a broken server sends.
+fn unexpected(response: Response) -> ErrorCode {
+    log::error!("Unexpected reply from registry server: {:?}", response);
+    ErrorCode::BROKEN_PIPE
+}
+
+fn id(handle: Handle) -> u64 {
+    handle.raw() as u64
+}
+
+impl Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("is_connected", &self.lock().is_some())
+            .finish()
+    }
+}
+
+impl Backend for Client {
+    fn predefined(&self, hive: Hive) -> Result<Handle, ErrorCode> {
+        if hive_code(hive).is_none() {
+            return Err(ErrorCode::INVALID_HANDLE);
+        }
+        self.key(Request::Predefined(hive))
+    }
+
+    fn open_key(&self, base: Handle, path: &U16CStr, sec: Security) -> Result<Handle, ErrorCode> {
+        self.key(Request::OpenKey {
+            id: id(base),
+            path: path.to_ucstring(),
+            sec,
+        })
+    }
+
+    fn create_key(&self, base: Handle, path: &U16CStr, sec: Security) -> Result<Handle, ErrorCode> {
+        self.key(Request::CreateKey {
+            id: id(base),
+            path: path.to_ucstring(),
+            sec,
+        })
+    }
+
+    fn delete_key(
+        &self,
+        base: Handle,
+        path: &U16CStr,
+        is_recursive: bool,
+    ) -> Result<(), ErrorCode> {
+        self.done(Request::DeleteKey {
+            id: id(base),
+            path: path.to_ucstring(),
+            is_recursive,
+        })
+    }
+
+    fn close_key(&self, handle: Handle) {
+        let _ = self.done(Request::CloseKey(id(handle)));
+    }
+
+    fn enum_key(&self, handle: Handle, index: u32) -> Result<Option<KeyEntry>, ErrorCode> {
+        match self.call(Request::EnumKey(id(handle), index))? {
+            Response::Entry(entry) => Ok(entry),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn enum_value(
+        &self,
+        handle: Handle,
+        index: u32,
+    ) -> Result<Option<(U16CString, RawValue)>, ErrorCode> {
+        match self.call(Request::EnumValue(id(handle), index))? {
+            Response::Value(value) => Ok(value),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn query_value(&self, handle: Handle, name: &U16CStr) -> Result<RawValue, ErrorCode> {
+        match self.call(Request::QueryValue(id(handle), name.to_ucstring()))? {
+            Response::Data(value) => Ok(value),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn set_value(&self, handle: Handle, name: &U16CStr, value: &RawValue) -> Result<(), ErrorCode> {
+        self.done(Request::SetValue(
+            id(handle),
+            name.to_ucstring(),
+            value.clone(),
+        ))
+    }
+
+    fn delete_value(&self, handle: Handle, name: &U16CStr) -> Result<(), ErrorCode> {
+        self.done(Request::DeleteValue(id(handle), name.to_ucstring()))
+    }
+
+    fn open_current_user(&self, sec: Security) -> Result<Handle, ErrorCode> {
+        self.key(Request::OpenCurrentUser(sec))
+    }
+
+    fn query_info(&self, handle: Handle) -> Result<KeyInfo, ErrorCode> {
+        match self.call(Request::QueryInfo(id(handle)))? {
+            Response::Info(info) => Ok(info),
+            response => Err(unexpected(response)),
+        }
+    }
+}
@@ -0,0 +1,688 @@
+//! Serving a backend to other processes, such as a privileged helper reading and writing
+//! settings for an unprivileged process, and a backend that is a client of such a server.
+//!
+//! A [`Server`](struct.Server.html) answers requests from one connection at a time on any
+//! stream, or accepts connections on a Unix socket or a Windows named pipe with
+//! [`listen`](struct.Server.html#method.listen). It only allows what its rules allow, by hive
+//! and path. A [`Client`](struct.Client.html) is a `Backend`, so code written against `Hive`
+//! and `RegKey` runs unchanged once it is installed.
+//!
+//! ```
+//! # #[cfg(unix)] fn main() {
+//! use std::os::unix::net::UnixStream;
+//! use std::sync::Arc;
+//! use registry::backend::{self, rpc::{Access, Client, Server}, Memory};
+//! use registry::{Data, Hive, Security};
+//!
+//! let (client, stream) = UnixStream::pair().unwrap();
+//! let server = Server::new(Arc::new(Memory::new()))
+//!     .allow(Hive::CurrentUser, r"Software\Contoso", Access::ReadWrite);
+//! std::thread::spawn(move || server.serve(stream));
+//!
+//! let _scope = backend::scoped(Arc::new(Client::new(client)));
+//! let key = Hive::CurrentUser.create(r"Software\Contoso", Security::AllAccess).unwrap();
+//! key.set_value("Level", &Data::U32(3)).unwrap();
+//! assert!(Hive::CurrentUser.open("Software", Security::Read).is_err());
+//! # }
+//! # #[cfg(not(unix))] fn main() {}
+//! ```
+//!
+//! Each request and reply is a frame: its length as a little-endian `u32`, then that many
+//! bytes. A request starts with a byte naming the operation, followed by its arguments, and a
+//! reply starts with a `u32` error code, zero on success, followed by a byte naming what was
+//! returned and the result. Integers are little-endian, strings are a `u32` count of UTF-16
+//! units followed by the units, and data is a `u32` length followed by the bytes. Keys are
+//! referred to by ids the server gives out, which are only valid on that connection.
+
+use std::io::{self, Read, Write};
+
+use utfx::{U16CStr, U16CString};
+
+use super::{ErrorCode, KeyEntry, RawValue, ROOTS};
+use crate::info::{FileTime, KeyInfo};
+use crate::sec::Security;
+use crate::Hive;
+
+mod client;
+mod server;
+
+pub use client::Client;
+pub use server::{Access, Server};
+
+/// The largest frame accepted, well above the largest value the registry can hold.
+const MAX_FRAME_LEN: usize = 64 << 20;
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME_LEN {
+        return Err(invalid("frame too long"));
+    }
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(payload)?;
+    writer.flush()
+}
+
+/// Reads a frame, or `None` if the stream ended before one started.
+fn read_frame<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    let mut read = 0;
+    while read < len.len() {
+        match reader.read(&mut len[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(invalid("frame too long"));
+    }
+    // Grow the payload as it arrives rather than trusting the length with an allocation.
+    let mut payload = Vec::new();
+    reader.by_ref().take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some(payload))
+}
+
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, x: u8) -> &mut Self {
+        self.0.push(x);
+        self
+    }
+
+    fn u32(&mut self, x: u32) -> &mut Self {
+        self.0.extend_from_slice(&x.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, x: u64) -> &mut Self {
+        self.0.extend_from_slice(&x.to_le_bytes());
+        self
+    }
+
+    fn string(&mut self, x: &U16CStr) -> &mut Self {
+        self.u32(x.len() as u32);
+        for unit in x.as_slice() {
+            self.0.extend_from_slice(&unit.to_le_bytes());
+        }
+        self
+    }
+
+    fn bytes(&mut self, x: &[u8]) -> &mut Self {
+        self.u32(x.len() as u32);
+        self.0.extend_from_slice(x);
+        self
+    }
+
+    fn raw_value(&mut self, x: &RawValue) -> &mut Self {
+        self.u32(x.ty).bytes(&x.data)
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(invalid("truncated frame"));
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut x = [0; 4];
+        x.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(x))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let mut x = [0; 8];
+        x.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(x))
+    }
+
+    fn string(&mut self) -> io::Result<U16CString> {
+        let len = self.u32()? as usize;
+        let units = self
+            .take(
+                len.checked_mul(2)
+                    .ok_or_else(|| invalid("string too long"))?,
+            )?
+            .chunks_exact(2)
+            .map(|x| u16::from_le_bytes([x[0], x[1]]))
+            .collect::<Vec<_>>();
+        U16CString::new(units).map_err(|_| invalid("nul in string"))
+    }
+
+    fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn raw_value(&mut self) -> io::Result<RawValue> {
+        Ok(RawValue {
+            ty: self.u32()?,
+            data: self.bytes()?,
+        })
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(invalid("trailing bytes in frame"))
+        }
+    }
+}
+
+/// A hive as its predefined `HKEY` value.
+fn hive_code(hive: Hive) -> Option<u32> {
+    ROOTS
+        .iter()
+        .find(|(x, _)| *x == hive)
+        .map(|(_, raw)| *raw as u32)
+}
+
+fn hive(code: u32) -> io::Result<Hive> {
+    ROOTS
+        .iter()
+        .find(|(_, raw)| *raw as u32 == code)
+        .map(|(hive, _)| *hive)
+        .ok_or_else(|| invalid("unknown hive"))
+}
+
+/// A call made by a client, with keys referred to by the ids the server gave out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Request {
+    Predefined(Hive),
+    OpenKey {
+        id: u64,
+        path: U16CString,
+        sec: Security,
+    },
+    CreateKey {
+        id: u64,
+        path: U16CString,
+        sec: Security,
+    },
+    DeleteKey {
+        id: u64,
+        path: U16CString,
+        is_recursive: bool,
+    },
+    CloseKey(u64),
+    EnumKey(u64, u32),
+    EnumValue(u64, u32),
+    QueryValue(u64, U16CString),
+    SetValue(u64, U16CString, RawValue),
+    DeleteValue(u64, U16CString),
+    OpenCurrentUser(Security),
+    QueryInfo(u64),
+}
+
+impl Request {
+    fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::default();
+        match self {
+            Request::Predefined(hive) => {
+                // Only hives with a predefined handle are sent.
+                w.u8(0).u32(hive_code(*hive).unwrap_or_default());
+            }
+            Request::OpenKey { id, path, sec } => {
+                w.u8(1).u64(*id).string(path).u32(sec.bits());
+            }
+            Request::CreateKey { id, path, sec } => {
+                w.u8(2).u64(*id).string(path).u32(sec.bits());
+            }
+            Request::DeleteKey {
+                id,
+                path,
+                is_recursive,
+            } => {
+                w.u8(3).u64(*id).string(path).u8(*is_recursive as u8);
+            }
+            Request::CloseKey(id) => {
+                w.u8(4).u64(*id);
+            }
+            Request::EnumKey(id, index) => {
+                w.u8(5).u64(*id).u32(*index);
+            }
+            Request::EnumValue(id, index) => {
+                w.u8(6).u64(*id).u32(*index);
+            }
+            Request::QueryValue(id, name) => {
+                w.u8(7).u64(*id).string(name);
+            }
+            Request::SetValue(id, name, value) => {
+                w.u8(8).u64(*id).string(name).raw_value(value);
+            }
+            Request::DeleteValue(id, name) => {
+                w.u8(9).u64(*id).string(name);
+            }
+            Request::OpenCurrentUser(sec) => {
+                w.u8(10).u32(sec.bits());
+            }
+            Request::QueryInfo(id) => {
+                w.u8(11).u64(*id);
+            }
+        }
+        w.0
+    }
+
+    fn decode(frame: &[u8]) -> io::Result<Request> {
+        let mut r = Reader(frame);
+        let sec = |r: &mut Reader<'_>| r.u32().map(Security::from_bits_truncate);
+        let request = match r.u8()? {
+            0 => Request::Predefined(hive(r.u32()?)?),
+            1 => Request::OpenKey {
+                id: r.u64()?,
+                path: r.string()?,
+                sec: sec(&mut r)?,
+            },
+            2 => Request::CreateKey {
+                id: r.u64()?,
+                path: r.string()?,
+                sec: sec(&mut r)?,
+            },
+            3 => Request::DeleteKey {
+                id: r.u64()?,
+                path: r.string()?,
+                is_recursive: r.u8()? != 0,
+            },
+            4 => Request::CloseKey(r.u64()?),
+            5 => Request::EnumKey(r.u64()?, r.u32()?),
+            6 => Request::EnumValue(r.u64()?, r.u32()?),
+            7 => Request::QueryValue(r.u64()?, r.string()?),
+            8 => Request::SetValue(r.u64()?, r.string()?, r.raw_value()?),
+            9 => Request::DeleteValue(r.u64()?, r.string()?),
+            10 => Request::OpenCurrentUser(sec(&mut r)?),
+            11 => Request::QueryInfo(r.u64()?),
+            _ => return Err(invalid("unknown request")),
+        };
+        r.finish()?;
+        Ok(request)
+    }
+}
+
+/// What a request returned when it succeeded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Response {
+    Done,
+    Key(u64),
+    Entry(Option<KeyEntry>),
+    Value(Option<(U16CString, RawValue)>),
+    Data(RawValue),
+    Info(KeyInfo),
+}
+
+fn encode_reply(reply: &Result<Response, ErrorCode>) -> Vec<u8> {
+    let mut w = Writer::default();
+    let response = match reply {
+        Ok(response) => response,
+        Err(code) => {
+            w.u32(code.code());
+            return w.0;
+        }
+    };
+
+    w.u32(0);
+    match response {
+        Response::Done => {
+            w.u8(0);
+        }
+        Response::Key(id) => {
+            w.u8(1).u64(*id);
+        }
+        Response::Entry(None) => {
+            w.u8(2).u8(0);
+        }
+        Response::Entry(Some(entry)) => {
+            w.u8(2).u8(1).string(&entry.name);
+            match &entry.class {
+                Some(class) => w.u8(1).string(class),
+                None => w.u8(0),
+            };
+            w.u64(entry.last_write_time.ticks());
+        }
+        Response::Value(None) => {
+            w.u8(3).u8(0);
+        }
+        Response::Value(Some((name, value))) => {
+            w.u8(3).u8(1).string(name).raw_value(value);
+        }
+        Response::Data(value) => {
+            w.u8(4).raw_value(value);
+        }
+        Response::Info(info) => {
+            w.u8(5);
+            match &info.class {
+                Some(class) => w.u8(1).string(class),
+                None => w.u8(0),
+            };
+            w.u64(info.last_write_time.ticks())
+                .u32(info.subkey_count)
+                .u32(info.max_subkey_name_len)
+                .u32(info.max_class_len)
+                .u32(info.value_count)
+                .u32(info.max_value_name_len)
+                .u32(info.max_value_data_len)
+                .u32(info.security_descriptor_len);
+        }
+    }
+    w.0
+}
+
+fn decode_reply(frame: &[u8]) -> io::Result<Result<Response, ErrorCode>> {
+    let mut r = Reader(frame);
+    let code = r.u32()?;
+    if code != 0 {
+        r.finish()?;
+        return Ok(Err(ErrorCode::new(code)));
+    }
+
+    let string = |r: &mut Reader<'_>| -> io::Result<Option<U16CString>> {
+        match r.u8()? {
+            0 => Ok(None),
+            _ => r.string().map(Some),
+        }
+    };
+    let response = match r.u8()? {
+        0 => Response::Done,
+        1 => Response::Key(r.u64()?),
+        2 => Response::Entry(match r.u8()? {
+            0 => None,
+            _ => Some(KeyEntry {
+                name: r.string()?,
+                class: string(&mut r)?,
+                last_write_time: FileTime::new(r.u64()?),
+            }),
+        }),
+        3 => Response::Value(match r.u8()? {
+            0 => None,
+            _ => Some((r.string()?, r.raw_value()?)),
+        }),
+        4 => Response::Data(r.raw_value()?),
+        5 => Response::Info(KeyInfo {
+            class: string(&mut r)?,
+            last_write_time: FileTime::new(r.u64()?),
+            subkey_count: r.u32()?,
+            max_subkey_name_len: r.u32()?,
+            max_class_len: r.u32()?,
+            value_count: r.u32()?,
+            max_value_name_len: r.u32()?,
+            max_value_data_len: r.u32()?,
+            security_descriptor_len: r.u32()?,
+        }),
+        _ => return Err(invalid("unknown reply")),
+    };
+    r.finish()?;
+    Ok(Ok(response))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::convert::TryInto;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+    use crate::backend::conformance::Suite;
+    use crate::backend::{scoped, Backend, Memory};
+    use crate::{key, value, Data};
+
+    fn connect(server: Server) -> Client {
+        let (client, stream) = UnixStream::pair().unwrap();
+        thread::spawn(move || server.serve(stream));
+        Client::new(client)
+    }
+
+    #[test]
+    fn requests_and_replies_round_trip() {
+        let requests = vec![
+            Request::Predefined(Hive::Users),
+            Request::OpenKey {
+                id: 7,
+                path: r"Software\Contoso".try_into().unwrap(),
+                sec: Security::Read,
+            },
+            Request::DeleteKey {
+                id: 7,
+                path: "".try_into().unwrap(),
+                is_recursive: true,
+            },
+            Request::SetValue(
+                7,
+                "Level".try_into().unwrap(),
+                RawValue::from_data(&Data::U32(3)),
+            ),
+            Request::OpenCurrentUser(Security::AllAccess),
+        ];
+        for request in requests {
+            assert_eq!(Request::decode(&request.encode()).unwrap(), request);
+        }
+
+        let replies = vec![
+            Err(ErrorCode::KEY_DELETED),
+            Ok(Response::Entry(Some(KeyEntry {
+                name: "Contoso".try_into().unwrap(),
+                class: None,
+                last_write_time: FileTime::new(42),
+            }))),
+            Ok(Response::Value(None)),
+            Ok(Response::Info(KeyInfo {
+                class: Some("Class".try_into().unwrap()),
+                value_count: 2,
+                ..Default::default()
+            })),
+        ];
+        for reply in replies {
+            assert_eq!(decode_reply(&encode_reply(&reply)).unwrap(), reply);
+        }
+
+        assert!(Request::decode(&[1, 0]).is_err());
+        assert!(Request::decode(&[4, 0, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+        assert!(read_frame(&mut &[0, 0, 0, 4, 1][..]).is_err());
+    }
+
+    #[test]
+    fn clients_use_the_served_backend() {
+        let memory: Arc<dyn Backend> = Arc::new(Memory::new());
+        let server = Server::new(memory.clone()).allow(
+            Hive::CurrentUser,
+            r"Software\Contoso",
+            Access::ReadWrite,
+        );
+
+        {
+            let _scope = scoped(Arc::new(connect(server)));
+            let key = Hive::CurrentUser
+                .create(r"Software\Contoso\Settings", Security::AllAccess)
+                .unwrap();
+            key.set_value("Level", &Data::U32(3)).unwrap();
+            assert_eq!(key.value("Level").unwrap(), Data::U32(3));
+            assert_eq!(
+                key.to_string(),
+                r"HKEY_CURRENT_USER\Software\Contoso\Settings"
+            );
+        }
+
+        let _scope = scoped(memory);
+        let key = Hive::CurrentUser
+            .open(r"Software\Contoso\Settings", Security::Read)
+            .unwrap();
+        assert_eq!(key.value("Level").unwrap(), Data::U32(3));
+    }
+
+    #[test]
+    fn rules_limit_what_clients_may_do() {
+        let memory: Arc<dyn Backend> = Arc::new(Memory::new());
+        {
+            let _scope = scoped(memory.clone());
+            Hive::LocalMachine
+                .create(r"SOFTWARE\Contoso", Security::AllAccess)
+                .unwrap()
+                .set_value("Version", &Data::U32(2))
+                .unwrap();
+            Hive::LocalMachine
+                .create(r"SOFTWARE\Fabrikam", Security::AllAccess)
+                .unwrap();
+        }
+
+        let server = Server::new(memory)
+            .allow(Hive::LocalMachine, r"SOFTWARE\Contoso", Access::Read)
+            .allow(Hive::CurrentUser, "", Access::ReadWrite);
+        let _scope = scoped(Arc::new(connect(server)));
+
+        let key = Hive::LocalMachine
+            .open(r"software\CONTOSO", Security::AllAccess)
+            .unwrap();
+        assert_eq!(key.value("Version").unwrap(), Data::U32(2));
+        assert!(matches!(
+            key.set_value("Version", &Data::U32(3)),
+            Err(value::Error::PermissionDenied(..))
+        ));
+        assert!(matches!(
+            key.create("Child", Security::AllAccess),
+            Err(key::Error::PermissionDenied(..))
+        ));
+        assert!(matches!(
+            Hive::LocalMachine.open(r"SOFTWARE\Fabrikam", Security::Read),
+            Err(key::Error::PermissionDenied(..))
+        ));
+        assert!(matches!(
+            Hive::LocalMachine.open("SOFTWARE", Security::Read),
+            Err(key::Error::PermissionDenied(..))
+        ));
+        assert!(Hive::CurrentUser
+            .create("Anything", Security::AllAccess)
+            .is_ok());
+    }
+
+    #[test]
+    fn clients_may_only_open_so_many_keys() {
+        let server =
+            Server::new(Arc::new(Memory::new())).allow(Hive::CurrentUser, "", Access::ReadWrite);
+        let client = connect(server);
+        let root = client.predefined(Hive::CurrentUser).unwrap();
+        let path: U16CString = "Software".try_into().unwrap();
+
+        let handles = (1..server::MAX_OPEN_KEYS)
+            .map(|_| client.create_key(root, &path, Security::AllAccess).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            client.open_key(root, &path, Security::Read),
+            Err(ErrorCode::NO_SYSTEM_RESOURCES)
+        );
+        client.close_key(handles[0]);
+        assert!(client.open_key(root, &path, Security::Read).is_ok());
+    }
+
+    #[test]
+    fn clients_pass_the_conformance_suite() {
+        Suite::new(|| {
+            let server = Server::new(Arc::new(Memory::new())).allow(
+                Hive::CurrentUser,
+                "Software",
+                Access::ReadWrite,
+            );
+            Arc::new(connect(server)) as _
+        })
+        .run();
+    }
+
+    #[test]
+    fn listens_on_unix_sockets() {
+        let path = std::env::temp_dir().join(format!("registry-rs-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let server =
+            Server::new(Arc::new(Memory::new())).allow(Hive::CurrentUser, "", Access::ReadWrite);
+        thread::spawn(move || server.listen(listener));
+
+        for _ in 0..2 {
+            let _scope = scoped(Arc::new(Client::connect(&path).unwrap()));
+            Hive::CurrentUser
+                .create("Shared", Security::AllAccess)
+                .unwrap()
+                .set_value("Count", &Data::U32(1))
+                .unwrap();
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn closed_servers_fail_calls() {
+        let (client, stream) = UnixStream::pair().unwrap();
+        drop(stream);
+        let _scope = scoped(Arc::new(Client::new(client)));
+        assert!(matches!(
+            Hive::CurrentUser.open("Software", Security::Read),
+            Err(key::Error::Unknown(..))
+        ));
+    }
+}
+
+#[cfg(all(test, windows))]
+mod pipe_tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::backend::{scoped, Memory};
+    use crate::Data;
+
+    #[test]
+    fn listens_on_named_pipes() {
+        let name = format!(r"\\.\pipe\registry-rs-{}", std::process::id());
+        let server =
+            Server::new(Arc::new(Memory::new())).allow(Hive::CurrentUser, "", Access::ReadWrite);
+        let listening = name.clone();
+        thread::spawn(move || server.listen(&listening));
+
+        // The pipe exists once the server has started listening.
+        let client = (0..100)
+            .find_map(|_| {
+                Client::connect(&name)
+                    .map_err(|_| thread::sleep(Duration::from_millis(10)))
+                    .ok()
+            })
+            .unwrap();
+        let _scope = scoped(Arc::new(client));
+        let key = Hive::CurrentUser
+            .create("Shared", Security::AllAccess)
+            .unwrap();
+        key.set_value("Count", &Data::U32(1)).unwrap();
+        assert_eq!(key.value("Count").unwrap(), Data::U32(1));
+
+        // Another server cannot take the pipe over while it is being served.
+        assert!(Server::new(Arc::new(Memory::new())).listen(&name).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_security_descriptors() {
+        let name = format!(r"\\.\pipe\registry-rs-sddl-{}", std::process::id());
+        let error = Server::new(Arc::new(Memory::new()))
+            .listen_with_sddl(&name, "D:(nonsense)")
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}
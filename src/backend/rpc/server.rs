@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use utfx::U16CStr;
+
+use super::{encode_reply, read_frame, write_frame, Request, Response};
+use crate::backend::{fold, join, Backend, ErrorCode, Handle};
+use crate::sec::Security;
+use crate::Hive;
+
+/// What a client may do with the keys a rule covers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[non_exhaustive]
+pub enum Access {
+    /// Opening keys, enumerating them and querying values.
+    Read,
+    /// Reading, and also creating and deleting keys and setting and deleting values.
+    ReadWrite,
+}
+
+#[derive(Debug)]
+struct Rule {
+    hive: Hive,
+    /// The upper cased path, as made by `join`.
+    path: Vec<u16>,
+    access: Access,
+}
+
+/// Answers requests from [`Client`](struct.Client.html)s with a backend.
+///
+/// Nothing is allowed until a rule allows it. A rule covers a key and everything below it,
+/// and a request is allowed if a rule covering the key it acts on gives the access it needs:
+/// opening a key and anything that only reads needs `Access::Read`, while creating or
+/// deleting a key, or setting or deleting a value, needs `Access::ReadWrite`. Other requests
+/// fail with `ERROR_ACCESS_DENIED`. Keys are matched by the path they were opened by,
+/// ignoring case, so a key must be opened by a path within a rule rather than one above it.
+/// Loading and saving hive files are not offered, and a client may have at most 4096 keys
+/// open at once, after which opening another fails with `ERROR_NO_SYSTEM_RESOURCES`.
+///
+/// Anyone able to connect can make the requests the rules allow, so limit who can open the
+/// socket or pipe the server listens on. Named pipes only let the user running the server and
+/// administrators connect unless they are given a security descriptor of their own.
+#[derive(Debug)]
+pub struct Server {
+    backend: Arc<dyn Backend>,
+    rules: Vec<Rule>,
+}
+
+/// A key opened by a client.
+#[derive(Debug)]
+struct Open {
+    handle: Handle,
+    hive: Hive,
+    /// The path the key was opened by, as made by `join`.
+    path: Vec<u16>,
+    /// Handles of predefined keys are shared and never closed.
+    is_predefined: bool,
+}
+
+/// The most keys a client may have open at once on one connection.
+pub(super) const MAX_OPEN_KEYS: usize = 4096;
+
+/// Lets SYSTEM, administrators and the owner of a named pipe connect to it.
+#[cfg(windows)]
+const PIPE_SDDL: &str = "D:P(A;;GA;;;SY)(A;;GA;;;BA)(A;;GA;;;OW)";
+
+/// The keys opened on one connection, by the ids given to the client.
+struct Connection<'a> {
+    server: &'a Server,
+    opened: HashMap<u64, Open>,
+    next_id: u64,
+}
+
+impl Server {
+    /// Serves `backend`, allowing nothing until rules are added.
+    pub fn new(backend: Arc<dyn Backend>) -> Server {
+        Server {
+            backend,
+            rules: vec![],
+        }
+    }
+
+    /// Allows `access` to the key at `path` in `hive` and everything below it. An empty path
+    /// covers the whole hive.
+    pub fn allow(mut self, hive: Hive, path: &str, access: Access) -> Server {
+        let path = crate::tree::components(path)
+            .flat_map(|name| {
+                Some(u16::from(b'\\'))
+                    .into_iter()
+                    .chain(name.encode_utf16())
+            })
+            .collect::<Vec<_>>();
+        let path = fold(&path);
+        self.rules.push(Rule { hive, path, access });
+        self
+    }
+
+    /// The most access any rule gives to a key.
+    fn access(&self, hive: Hive, path: &[u16]) -> Option<Access> {
+        let path = fold(path);
+        self.rules
+            .iter()
+            .filter(|rule| rule.hive == hive && is_within(&path, &rule.path))
+            .map(|rule| rule.access)
+            .max()
+    }
+
+    /// Answers requests read from `stream` until it ends, then closes the keys the client
+    /// left open.
+    pub fn serve<S: Read + Write>(&self, mut stream: S) -> io::Result<()> {
+        let mut connection = Connection {
+            server: self,
+            opened: HashMap::new(),
+            next_id: 1,
+        };
+        while let Some(frame) = read_frame(&mut stream)? {
+            let request = Request::decode(&frame)?;
+            let reply = connection.handle(request);
+            write_frame(&mut stream, &encode_reply(&reply))?;
+        }
+        Ok(())
+    }
+
+    /// Serves each client connecting to `listener` on a thread of its own. Returns only if
+    /// accepting a connection fails.
+    #[cfg(unix)]
+    pub fn listen(self, listener: std::os::unix::net::UnixListener) -> io::Result<()> {
+        let server = Arc::new(self);
+        loop {
+            let (stream, _) = listener.accept()?;
+            let server = server.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = server.serve(stream) {
+                    log::warn!("Registry client failed: {}", e);
+                }
+            });
+        }
+    }
+
+    /// Serves each client connecting to the named pipe `name`, such as
+    /// `\\.\pipe\contoso-settings`, on a thread of its own. Clients on other machines are
+    /// rejected, and only SYSTEM, administrators and the owner of the pipe, normally the user
+    /// running the server, may connect; use
+    /// [`listen_with_sddl`](#method.listen_with_sddl) to let others connect. Fails if the pipe
+    /// already exists, so another process cannot serve clients in its place, and otherwise
+    /// returns only if creating or connecting an instance of the pipe fails.
+    #[cfg(windows)]
+    pub fn listen(self, name: &str) -> io::Result<()> {
+        self.listen_with_sddl(name, PIPE_SDDL)
+    }
+
+    /// Like [`listen`](#method.listen), but securing the pipe with a security descriptor in
+    /// the Security Descriptor Definition Language, such as `D:P(A;;GA;;;SY)(A;;GRGW;;;IU)`
+    /// to let SYSTEM and interactively logged on users connect.
+    #[cfg(windows)]
+    pub fn listen_with_sddl(self, name: &str, sddl: &str) -> io::Result<()> {
+        use std::fs::File;
+        use std::os::windows::io::{FromRawHandle, RawHandle};
+
+        use windows::core::PCWSTR;
+        use windows::Win32::Foundation::{
+            LocalFree, ERROR_PIPE_CONNECTED, HLOCAL, INVALID_HANDLE_VALUE,
+        };
+        use windows::Win32::Security::Authorization::{
+            ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1,
+        };
+        use windows::Win32::Security::{PSECURITY_DESCRIPTOR, SECURITY_ATTRIBUTES};
+        use windows::Win32::Storage::FileSystem::{
+            FILE_FLAG_FIRST_PIPE_INSTANCE, PIPE_ACCESS_DUPLEX,
+        };
+        use windows::Win32::System::Pipes::{
+            ConnectNamedPipe, CreateNamedPipeW, PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS,
+            PIPE_TYPE_BYTE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+        };
+
+        /// A security descriptor allocated by Windows, freed when dropped.
+        struct Descriptor(PSECURITY_DESCRIPTOR);
+
+        impl Drop for Descriptor {
+            fn drop(&mut self) {
+                let _ = unsafe { LocalFree(HLOCAL(self.0 .0)) };
+            }
+        }
+
+        let sddl = sddl.encode_utf16().chain(Some(0)).collect::<Vec<_>>();
+        let mut descriptor = Descriptor(PSECURITY_DESCRIPTOR::default());
+        unsafe {
+            ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                PCWSTR(sddl.as_ptr()),
+                SDDL_REVISION_1,
+                &mut descriptor.0,
+                None,
+            )
+        }
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let attributes = SECURITY_ATTRIBUTES {
+            nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+            lpSecurityDescriptor: descriptor.0 .0,
+            bInheritHandle: false.into(),
+        };
+
+        let server = Arc::new(self);
+        let name = name.encode_utf16().chain(Some(0)).collect::<Vec<_>>();
+        let mut is_first = true;
+        loop {
+            // Only the first instance creates the pipe; it fails if another process has it.
+            let mut mode = PIPE_ACCESS_DUPLEX;
+            if is_first {
+                mode |= FILE_FLAG_FIRST_PIPE_INSTANCE;
+                is_first = false;
+            }
+            let handle = unsafe {
+                CreateNamedPipeW(
+                    PCWSTR(name.as_ptr()),
+                    mode,
+                    PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                    PIPE_UNLIMITED_INSTANCES,
+                    64 * 1024,
+                    64 * 1024,
+                    0,
+                    Some(&attributes),
+                )
+            };
+            if handle == INVALID_HANDLE_VALUE {
+                return Err(io::Error::last_os_error());
+            }
+            // The file closes the pipe when dropped.
+            let pipe = unsafe { File::from_raw_handle(handle.0 as RawHandle) };
+            if let Err(e) = unsafe { ConnectNamedPipe(handle, None) } {
+                // A client may connect between creating the pipe and waiting for one.
+                if e.code() != ERROR_PIPE_CONNECTED.to_hresult() {
+                    return Err(e.into());
+                }
+            }
+
+            let server = server.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = server.serve(pipe) {
+                    log::warn!("Registry client failed: {}", e);
+                }
+            });
+        }
+    }
+}
+
+/// Whether an upper cased path is, or is below, another.
+fn is_within(path: &[u16], ancestor: &[u16]) -> bool {
+    path.starts_with(ancestor)
+        && (path.len() == ancestor.len() || path[ancestor.len()] == u16::from(b'\\'))
+}
+
+impl<'a> Connection<'a> {
+    fn open(&self, id: u64) -> Result<&Open, ErrorCode> {
+        self.opened.get(&id).ok_or(ErrorCode::INVALID_HANDLE)
+    }
+
+    /// Checks that a rule gives `access` to a key, logging requests that are denied.
+    fn check(&self, hive: Hive, path: &[u16], access: Access) -> Result<(), ErrorCode> {
+        match self.server.access(hive, path) {
+            Some(x) if x >= access => Ok(()),
+            _ => {
+                log::warn!(
+                    "Denied {:?} access to {}{}",
+                    access,
+                    hive,
+                    String::from_utf16_lossy(path)
+                );
+                Err(ErrorCode::ACCESS_DENIED)
+            }
+        }
+    }
+
+    /// Gives an id to an opened key, or closes it if the client has too many open.
+    fn insert(&mut self, open: Open) -> Result<Response, ErrorCode> {
+        if self.opened.len() >= MAX_OPEN_KEYS {
+            if !open.is_predefined {
+                self.server.backend.close_key(open.handle);
+            }
+            return Err(ErrorCode::NO_SYSTEM_RESOURCES);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.opened.insert(id, open);
+        Ok(Response::Key(id))
+    }
+
+    fn open_key(
+        &mut self,
+        id: u64,
+        path: &U16CStr,
+        sec: Security,
+        is_create: bool,
+    ) -> Result<Response, ErrorCode> {
+        let backend = &self.server.backend;
+        let open = self.open(id)?;
+        let (hive, target) = (open.hive, join(&open.path, path));
+        let handle = if is_create {
+            self.check(hive, &target, Access::ReadWrite)?;
+            backend.create_key(open.handle, path, sec)?
+        } else {
+            self.check(hive, &target, Access::Read)?;
+            backend.open_key(open.handle, path, sec)?
+        };
+        self.insert(Open {
+            handle,
+            hive,
+            path: target,
+            is_predefined: false,
+        })
+    }
+
+    fn handle(&mut self, request: Request) -> Result<Response, ErrorCode> {
+        let backend = &self.server.backend;
+        match request {
+            Request::Predefined(hive) => {
+                if let Some((id, _)) = self
+                    .opened
+                    .iter()
+                    .find(|(_, x)| x.is_predefined && x.hive == hive && x.path.is_empty())
+                {
+                    return Ok(Response::Key(*id));
+                }
+                let handle = backend.predefined(hive)?;
+                self.insert(Open {
+                    handle,
+                    hive,
+                    path: vec![],
+                    is_predefined: true,
+                })
+            }
+            Request::OpenKey { id, path, sec } => self.open_key(id, &path, sec, false),
+            Request::CreateKey { id, path, sec } => self.open_key(id, &path, sec, true),
+            Request::DeleteKey {
+                id,
+                path,
+                is_recursive,
+            } => {
+                let open = self.open(id)?;
+                self.check(open.hive, &join(&open.path, &path), Access::ReadWrite)?;
+                backend.delete_key(open.handle, &path, is_recursive)?;
+                Ok(Response::Done)
+            }
+            Request::CloseKey(id) => {
+                match self.opened.remove(&id) {
+                    Some(open) if open.is_predefined => {
+                        self.opened.insert(id, open);
+                    }
+                    Some(open) => backend.close_key(open.handle),
+                    None => return Err(ErrorCode::INVALID_HANDLE),
+                }
+                Ok(Response::Done)
+            }
+            Request::EnumKey(id, index) => {
+                let open = self.open(id)?;
+                self.check(open.hive, &open.path, Access::Read)?;
+                Ok(Response::Entry(backend.enum_key(open.handle, index)?))
+            }
+            Request::EnumValue(id, index) => {
+                let open = self.open(id)?;
+                self.check(open.hive, &open.path, Access::Read)?;
+                Ok(Response::Value(backend.enum_value(open.handle, index)?))
+            }
+            Request::QueryValue(id, name) => {
+                let open = self.open(id)?;
+                self.check(open.hive, &open.path, Access::Read)?;
+                Ok(Response::Data(backend.query_value(open.handle, &name)?))
+            }
+            Request::SetValue(id, name, value) => {
+                let open = self.open(id)?;
+                self.check(open.hive, &open.path, Access::ReadWrite)?;
+                backend.set_value(open.handle, &name, &value)?;
+                Ok(Response::Done)
+            }
+            Request::DeleteValue(id, name) => {
+                let open = self.open(id)?;
+                self.check(open.hive, &open.path, Access::ReadWrite)?;
+                backend.delete_value(open.handle, &name)?;
+                Ok(Response::Done)
+            }
+            Request::OpenCurrentUser(sec) => {
+                self.check(Hive::CurrentUser, &[], Access::Read)?;
+                let handle = backend.open_current_user(sec)?;
+                self.insert(Open {
+                    handle,
+                    hive: Hive::CurrentUser,
+                    path: vec![],
+                    is_predefined: false,
+                })
+            }
+            Request::QueryInfo(id) => {
+                let open = self.open(id)?;
+                self.check(open.hive, &open.path, Access::Read)?;
+                Ok(Response::Info(backend.query_info(open.handle)?))
+            }
+        }
+    }
+}
+
+impl<'a> Drop for Connection<'a> {
+    fn drop(&mut self) {
+        for (_, open) in self.opened.drain() {
+            if !open.is_predefined {
+                self.server.backend.close_key(open.handle);
+            }
+        }
+    }
+}
@@ -0,0 +1,1175 @@
+//! Recording the calls made to a backend, and replaying them against another backend to
+//! check that it answers the same way.
+//!
+//! A [`Recorder`](struct.Recorder.html) wraps a backend and keeps a
+//! [`Trace`](struct.Trace.html) of every call made through it and its result. A trace is
+//! written as text, one call per line, and read back with
+//! [`Trace::parse`](struct.Trace.html#method.parse):
+//!
+//! ```text
+//! predefined HKEY_LOCAL_MACHINE -> #1
+//! open_key #1 "SOFTWARE\\Contoso" 0x20019 -> #2
+//! query_value #2 "Level" -> data 4:03000000
+//! query_value #2 "Missing" -> error 2
+//! enum_key #2 0 -> key "Update"
+//! enum_key #2 1 -> end
+//! close_key #2 -> ok
+//! ```
+//!
+//! Handles are numbered in the order they were first seen. Strings are quoted, with `\\`,
+//! `\"` and `\u{...}` escapes, the last for UTF-16 code units that are control characters
+//! or unpaired surrogates. Data is written as its type code and bytes in hex. Last write
+//! times and the sizes of security descriptors are not recorded, as they differ between
+//! backends holding the same keys. Lines starting with `;` are comments.
+//!
+//! ```
+//! use std::sync::Arc;
+//! use registry::backend::{self, trace::{Recorder, Trace}, Memory};
+//! use registry::{Data, Hive, Security};
+//!
+//! let recorder = Arc::new(Recorder::new(Arc::new(Memory::new())));
+//! {
+//!     let _scope = backend::scoped(recorder.clone());
+//!     let key = Hive::CurrentUser.create(r"Software\Contoso", Security::AllAccess).unwrap();
+//!     key.set_value("Level", &Data::U32(3)).unwrap();
+//!     assert!(key.value("Missing").is_err());
+//! }
+//!
+//! let text = recorder.trace().to_string();
+//! let trace = Trace::parse(&text).unwrap();
+//! trace.replay(&Memory::new()).unwrap();
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::{self, Display, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use utfx::{U16CStr, U16CString};
+
+use super::{join, Backend, ErrorCode, Handle, KeyEntry, RawValue, ROOTS};
+use crate::info::KeyInfo;
+use crate::sec::Security;
+use crate::{value, Data, Hive};
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("Syntax error on line {0}: {1}")]
+    Syntax(usize, &'static str),
+
+    #[error("Call {index} gave `{actual}`, but `{expected}` was recorded")]
+    Mismatch {
+        index: usize,
+        expected: String,
+        actual: String,
+    },
+
+    #[error("Call {0} uses handle #{1}, which was not opened")]
+    UnknownHandle(usize, u32),
+}
+
+/// A call to a backend. Keys are referred to by the numbers of their handles in the trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Call {
+    Predefined {
+        hive: Hive,
+    },
+    OpenKey {
+        base: u32,
+        path: U16CString,
+        sec: Security,
+    },
+    CreateKey {
+        base: u32,
+        path: U16CString,
+        sec: Security,
+    },
+    DeleteKey {
+        base: u32,
+        path: U16CString,
+        is_recursive: bool,
+    },
+    CloseKey {
+        handle: u32,
+    },
+    EnumKey {
+        handle: u32,
+        index: u32,
+    },
+    EnumValue {
+        handle: u32,
+        index: u32,
+    },
+    QueryValue {
+        handle: u32,
+        name: U16CString,
+    },
+    SetValue {
+        handle: u32,
+        name: U16CString,
+        value: RawValue,
+    },
+    DeleteValue {
+        handle: u32,
+        name: U16CString,
+    },
+    OpenCurrentUser {
+        sec: Security,
+    },
+    LoadAppKey {
+        file_path: PathBuf,
+        sec: Security,
+    },
+    SaveKey {
+        handle: u32,
+        file_path: U16CString,
+    },
+    QueryInfo {
+        handle: u32,
+    },
+}
+
+/// What a call returned when it succeeded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Reply {
+    /// The call returns nothing.
+    Done,
+    /// A handle, by its number in the trace.
+    Handle(u32),
+    /// Enumeration is past the last subkey or value.
+    End,
+    Data(RawValue),
+    Key {
+        name: U16CString,
+        class: Option<U16CString>,
+    },
+    Value {
+        name: U16CString,
+        value: RawValue,
+    },
+    /// The size information of a key, without its last write time or the size of its
+    /// security descriptor.
+    Info(KeyInfo),
+}
+
+/// A call and its result.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// The full path of the key the call works on, such as `HKEY_LOCAL_MACHINE\SOFTWARE`.
+    pub path: String,
+    pub call: Call,
+    pub result: Result<Reply, ErrorCode>,
+}
+
+/// The calls made through a [`Recorder`](struct.Recorder.html), in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace {
+    events: Vec<Event>,
+}
+
+impl Trace {
+    #[inline]
+    pub fn new() -> Trace {
+        Trace::default()
+    }
+
+    #[inline]
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// Reads a trace from its text form.
+    pub fn parse(text: &str) -> Result<Trace, Error> {
+        let mut paths = Paths::default();
+        let mut trace = Trace::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            let (call, result) = parse_line(line).map_err(|e| Error::Syntax(i + 1, e))?;
+            trace.events.push(paths.event(call, result));
+        }
+
+        Ok(trace)
+    }
+
+    /// Makes each call in the trace on `backend`, checking that it gives the recorded
+    /// result. Handles that the trace leaves open are closed afterwards.
+    pub fn replay(&self, backend: &dyn Backend) -> Result<(), Error> {
+        let mut replay = Replay {
+            backend,
+            handles: HashMap::new(),
+            open: HashMap::new(),
+        };
+        let result = self
+            .events
+            .iter()
+            .enumerate()
+            .try_for_each(|(i, event)| replay.event(i + 1, event));
+
+        for handle in replay.open.values() {
+            backend.close_key(*handle);
+        }
+        result
+    }
+}
+
+impl Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for event in &self.events {
+            writeln!(f, "{} -> {}", event.call, Outcome(&event.result))?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut out = String::new();
+        match self {
+            Call::Predefined { hive } => {
+                let _ = write!(out, "predefined {}", hive);
+            }
+            Call::OpenKey { base, path, sec } | Call::CreateKey { base, path, sec } => {
+                let op = match self {
+                    Call::OpenKey { .. } => "open_key",
+                    _ => "create_key",
+                };
+                let _ = write!(out, "{} #{} ", op, base);
+                quote(&mut out, path.as_slice());
+                let _ = write!(out, " {:#x}", sec.bits());
+            }
+            Call::DeleteKey {
+                base,
+                path,
+                is_recursive,
+            } => {
+                let _ = write!(out, "delete_key #{} ", base);
+                quote(&mut out, path.as_slice());
+                if *is_recursive {
+                    out.push_str(" recursive");
+                }
+            }
+            Call::CloseKey { handle } => {
+                let _ = write!(out, "close_key #{}", handle);
+            }
+            Call::EnumKey { handle, index } => {
+                let _ = write!(out, "enum_key #{} {}", handle, index);
+            }
+            Call::EnumValue { handle, index } => {
+                let _ = write!(out, "enum_value #{} {}", handle, index);
+            }
+            Call::QueryValue { handle, name } => {
+                let _ = write!(out, "query_value #{} ", handle);
+                quote(&mut out, name.as_slice());
+            }
+            Call::SetValue {
+                handle,
+                name,
+                value,
+            } => {
+                let _ = write!(out, "set_value #{} ", handle);
+                quote(&mut out, name.as_slice());
+                out.push(' ');
+                write_raw(&mut out, value);
+            }
+            Call::DeleteValue { handle, name } => {
+                let _ = write!(out, "delete_value #{} ", handle);
+                quote(&mut out, name.as_slice());
+            }
+            Call::OpenCurrentUser { sec } => {
+                let _ = write!(out, "open_current_user {:#x}", sec.bits());
+            }
+            Call::LoadAppKey { file_path, sec } => {
+                out.push_str("load_app_key ");
+                let file_path = file_path
+                    .to_string_lossy()
+                    .encode_utf16()
+                    .collect::<Vec<_>>();
+                quote(&mut out, &file_path);
+                let _ = write!(out, " {:#x}", sec.bits());
+            }
+            Call::SaveKey { handle, file_path } => {
+                let _ = write!(out, "save_key #{} ", handle);
+                quote(&mut out, file_path.as_slice());
+            }
+            Call::QueryInfo { handle } => {
+                let _ = write!(out, "query_info #{}", handle);
+            }
+        }
+        f.write_str(&out)
+    }
+}
+
+impl Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut out = String::new();
+        match self {
+            Reply::Done => out.push_str("ok"),
+            Reply::Handle(id) => {
+                let _ = write!(out, "#{}", id);
+            }
+            Reply::End => out.push_str("end"),
+            Reply::Data(value) => {
+                out.push_str("data ");
+                write_raw(&mut out, value);
+            }
+            Reply::Key { name, class } => {
+                out.push_str("key ");
+                quote(&mut out, name.as_slice());
+                if let Some(class) = class {
+                    out.push(' ');
+                    quote(&mut out, class.as_slice());
+                }
+            }
+            Reply::Value { name, value } => {
+                out.push_str("value ");
+                quote(&mut out, name.as_slice());
+                out.push(' ');
+                write_raw(&mut out, value);
+            }
+            Reply::Info(info) => {
+                let _ = write!(
+                    out,
+                    "info {} {} {} {} {} {}",
+                    info.subkey_count,
+                    info.max_subkey_name_len,
+                    info.max_class_len,
+                    info.value_count,
+                    info.max_value_name_len,
+                    info.max_value_data_len
+                );
+                if let Some(class) = &info.class {
+                    out.push(' ');
+                    quote(&mut out, class.as_slice());
+                }
+            }
+        }
+        f.write_str(&out)
+    }
+}
+
+/// Displays the result of a call as it is written in a trace.
+struct Outcome<'a>(&'a Result<Reply, ErrorCode>);
+
+impl Display for Outcome<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Ok(reply) => Display::fmt(reply, f),
+            Err(code) => write!(f, "error {}", code.code()),
+        }
+    }
+}
+
+fn quote(out: &mut String, s: &[u16]) {
+    out.push('"');
+    for c in std::char::decode_utf16(s.iter().copied()) {
+        match c {
+            Ok('"') => out.push_str("\\\""),
+            Ok('\\') => out.push_str("\\\\"),
+            Ok(c) if !c.is_control() => out.push(c),
+            // Control characters are all single code units.
+            Ok(c) => {
+                let _ = write!(out, "\\u{{{:x}}}", c as u32);
+            }
+            Err(e) => {
+                let _ = write!(out, "\\u{{{:x}}}", e.unpaired_surrogate());
+            }
+        }
+    }
+    out.push('"');
+}
+
+fn write_raw(out: &mut String, value: &RawValue) {
+    let _ = write!(out, "{}:", value.ty);
+    for byte in &value.data {
+        let _ = write!(out, "{:02x}", byte);
+    }
+}
+
+enum Token<'a> {
+    Word(&'a str),
+    Text(Vec<u16>),
+}
+
+fn tokens(line: &str) -> Result<Vec<Token<'_>>, &'static str> {
+    let mut tokens = vec![];
+    let mut rest = line.trim_start();
+
+    while !rest.is_empty() {
+        if let Some(quoted) = rest.strip_prefix('"') {
+            let (text, after) = unquote(quoted)?;
+            tokens.push(Token::Text(text));
+            rest = after;
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            tokens.push(Token::Word(&rest[..end]));
+            rest = &rest[end..];
+        }
+        rest = rest.trim_start();
+    }
+
+    Ok(tokens)
+}
+
+/// Reads a quoted string up to its closing quote, returning the text after it.
+fn unquote(s: &str) -> Result<(Vec<u16>, &str), &'static str> {
+    let mut out = vec![];
+    let mut chars = s.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((out, &s[i + 1..])),
+            '\\' => match chars.next() {
+                Some((_, '\\')) => out.push(u16::from(b'\\')),
+                Some((_, '"')) => out.push(u16::from(b'"')),
+                Some((i, 'u')) => {
+                    let rest = s[i + 1..]
+                        .strip_prefix('{')
+                        .ok_or("invalid unicode escape")?;
+                    let end = rest.find('}').ok_or("invalid unicode escape")?;
+                    let unit = u16::from_str_radix(&rest[..end], 16)
+                        .map_err(|_| "invalid unicode escape")?;
+                    out.push(unit);
+                    // Skip the braces and digits.
+                    for _ in 0..end + 2 {
+                        chars.next();
+                    }
+                }
+                _ => return Err("invalid escape"),
+            },
+            c => {
+                let mut buf = [0u16; 2];
+                out.extend_from_slice(c.encode_utf16(&mut buf));
+            }
+        }
+    }
+
+    Err("unterminated string")
+}
+
+/// The arguments of a call or reply, read in order.
+struct Args<'a>(std::vec::IntoIter<Token<'a>>);
+
+impl<'a> Args<'a> {
+    fn word(&mut self) -> Result<&'a str, &'static str> {
+        match self.0.next() {
+            Some(Token::Word(word)) => Ok(word),
+            _ => Err("expected a word"),
+        }
+    }
+
+    fn string(&mut self) -> Result<U16CString, &'static str> {
+        match self.0.next() {
+            Some(Token::Text(text)) => U16CString::new(text).map_err(|_| "null in string"),
+            _ => Err("expected a quoted string"),
+        }
+    }
+
+    fn optional_string(&mut self) -> Result<Option<U16CString>, &'static str> {
+        match self.0.as_slice().first() {
+            Some(Token::Text(_)) => self.string().map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    fn number(&mut self) -> Result<u32, &'static str> {
+        self.word()?.parse().map_err(|_| "invalid number")
+    }
+
+    fn handle(&mut self) -> Result<u32, &'static str> {
+        self.word()?
+            .strip_prefix('#')
+            .and_then(|x| x.parse().ok())
+            .ok_or("invalid handle")
+    }
+
+    fn sec(&mut self) -> Result<Security, &'static str> {
+        let bits = self.word()?.strip_prefix("0x").ok_or("invalid access")?;
+        u32::from_str_radix(bits, 16)
+            .ok()
+            .and_then(Security::from_bits)
+            .ok_or("invalid access")
+    }
+
+    fn raw(&mut self) -> Result<RawValue, &'static str> {
+        let word = self.word()?;
+        let mut parts = word.splitn(2, ':');
+        let ty = parts.next().and_then(|x| x.parse().ok());
+        let hex = parts.next().unwrap_or("");
+        let data = (0..hex.len())
+            .step_by(2)
+            .map(|i| {
+                hex.get(i..i + 2)
+                    .and_then(|x| u8::from_str_radix(x, 16).ok())
+            })
+            .collect::<Option<Vec<_>>>();
+        match (ty, data) {
+            (Some(ty), Some(data)) if word.contains(':') => Ok(RawValue { ty, data }),
+            _ => Err("invalid data"),
+        }
+    }
+
+    fn end(mut self) -> Result<(), &'static str> {
+        match self.0.next() {
+            None => Ok(()),
+            Some(_) => Err("unexpected text at end of line"),
+        }
+    }
+}
+
+fn hive(name: &str) -> Option<Hive> {
+    ROOTS
+        .iter()
+        .map(|(hive, _)| *hive)
+        .chain(std::iter::once(Hive::Application))
+        .find(|x| x.to_string() == name)
+}
+
+fn parse_line(line: &str) -> Result<(Call, Result<Reply, ErrorCode>), &'static str> {
+    let mut tokens = tokens(line)?;
+    let arrow = tokens
+        .iter()
+        .position(|x| matches!(x, Token::Word("->")))
+        .ok_or("missing result")?;
+    let reply = tokens.split_off(arrow + 1);
+    tokens.pop();
+
+    let mut args = Args(tokens.into_iter());
+    let call = match args.word()? {
+        "predefined" => Call::Predefined {
+            hive: hive(args.word()?).ok_or("unknown hive")?,
+        },
+        "open_key" => Call::OpenKey {
+            base: args.handle()?,
+            path: args.string()?,
+            sec: args.sec()?,
+        },
+        "create_key" => Call::CreateKey {
+            base: args.handle()?,
+            path: args.string()?,
+            sec: args.sec()?,
+        },
+        "delete_key" => Call::DeleteKey {
+            base: args.handle()?,
+            path: args.string()?,
+            is_recursive: match args.0.as_slice().first() {
+                Some(Token::Word("recursive")) => {
+                    args.0.next();
+                    true
+                }
+                _ => false,
+            },
+        },
+        "close_key" => Call::CloseKey {
+            handle: args.handle()?,
+        },
+        "enum_key" => Call::EnumKey {
+            handle: args.handle()?,
+            index: args.number()?,
+        },
+        "enum_value" => Call::EnumValue {
+            handle: args.handle()?,
+            index: args.number()?,
+        },
+        "query_value" => Call::QueryValue {
+            handle: args.handle()?,
+            name: args.string()?,
+        },
+        "set_value" => Call::SetValue {
+            handle: args.handle()?,
+            name: args.string()?,
+            value: args.raw()?,
+        },
+        "delete_value" => Call::DeleteValue {
+            handle: args.handle()?,
+            name: args.string()?,
+        },
+        "open_current_user" => Call::OpenCurrentUser { sec: args.sec()? },
+        "load_app_key" => Call::LoadAppKey {
+            file_path: PathBuf::from(args.string()?.to_string_lossy()),
+            sec: args.sec()?,
+        },
+        "save_key" => Call::SaveKey {
+            handle: args.handle()?,
+            file_path: args.string()?,
+        },
+        "query_info" => Call::QueryInfo {
+            handle: args.handle()?,
+        },
+        _ => return Err("unknown call"),
+    };
+    args.end()?;
+
+    let mut args = Args(reply.into_iter());
+    let word = args.word()?;
+    let result = match word {
+        "ok" => Ok(Reply::Done),
+        "end" => Ok(Reply::End),
+        "error" => Err(ErrorCode::new(args.number()?)),
+        "data" => Ok(Reply::Data(args.raw()?)),
+        "key" => Ok(Reply::Key {
+            name: args.string()?,
+            class: args.optional_string()?,
+        }),
+        "value" => Ok(Reply::Value {
+            name: args.string()?,
+            value: args.raw()?,
+        }),
+        "info" => {
+            let mut info = KeyInfo {
+                subkey_count: args.number()?,
+                max_subkey_name_len: args.number()?,
+                max_class_len: args.number()?,
+                value_count: args.number()?,
+                max_value_name_len: args.number()?,
+                max_value_data_len: args.number()?,
+                ..KeyInfo::default()
+            };
+            info.class = args.optional_string()?;
+            Ok(Reply::Info(info))
+        }
+        _ => {
+            let mut handle = Args(vec![Token::Word(word)].into_iter());
+            Ok(Reply::Handle(
+                handle.handle().map_err(|_| "unknown result")?,
+            ))
+        }
+    };
+    args.end()?;
+
+    Ok((call, result))
+}
+
+/// The paths of the keys behind the handles in a trace.
+#[derive(Debug, Default)]
+struct Paths(HashMap<u32, Vec<u16>>);
+
+impl Paths {
+    fn of(&self, call: &Call) -> Vec<u16> {
+        let hive = |hive: Hive| hive.to_string().encode_utf16().collect();
+        let get = |id: &u32| self.0.get(id).cloned().unwrap_or_default();
+
+        match call {
+            Call::Predefined { hive: x } => hive(*x),
+            Call::OpenKey { base, path, .. }
+            | Call::CreateKey { base, path, .. }
+            | Call::DeleteKey { base, path, .. } => join(&get(base), path),
+            Call::OpenCurrentUser { .. } => hive(Hive::CurrentUser),
+            Call::LoadAppKey { .. } => hive(Hive::Application),
+            Call::CloseKey { handle }
+            | Call::EnumKey { handle, .. }
+            | Call::EnumValue { handle, .. }
+            | Call::QueryValue { handle, .. }
+            | Call::SetValue { handle, .. }
+            | Call::DeleteValue { handle, .. }
+            | Call::SaveKey { handle, .. }
+            | Call::QueryInfo { handle } => get(handle),
+        }
+    }
+
+    fn event(&mut self, call: Call, result: Result<Reply, ErrorCode>) -> Event {
+        let path = self.of(&call);
+        if let Ok(Reply::Handle(id)) = &result {
+            self.0.insert(*id, path.clone());
+        }
+        Event {
+            path: String::from_utf16_lossy(&path),
+            call,
+            result,
+        }
+    }
+}
+
+fn key_reply(entry: Option<KeyEntry>) -> Reply {
+    match entry {
+        Some(entry) => Reply::Key {
+            name: entry.name,
+            class: entry.class,
+        },
+        None => Reply::End,
+    }
+}
+
+fn value_reply(entry: Option<(U16CString, RawValue)>) -> Reply {
+    match entry {
+        Some((name, value)) => Reply::Value { name, value },
+        None => Reply::End,
+    }
+}
+
+fn info_reply(info: KeyInfo) -> Reply {
+    Reply::Info(KeyInfo {
+        last_write_time: Default::default(),
+        security_descriptor_len: 0,
+        ..info
+    })
+}
+
+#[derive(Debug, Default)]
+struct State {
+    trace: Trace,
+    paths: Paths,
+    /// The numbers of the open handles, by their raw values.
+    ids: HashMap<usize, u32>,
+    next_id: u32,
+}
+
+impl State {
+    fn id(&mut self, handle: Handle) -> u32 {
+        if let Some(id) = self.ids.get(&handle.raw()) {
+            return *id;
+        }
+        self.next_id += 1;
+        self.ids.insert(handle.raw(), self.next_id);
+        self.next_id
+    }
+
+    fn record<T>(
+        &mut self,
+        call: Call,
+        result: Result<T, ErrorCode>,
+        reply: impl FnOnce(&mut State, &T) -> Reply,
+    ) -> Result<T, ErrorCode> {
+        let recorded = match &result {
+            Ok(x) => Ok(reply(self, x)),
+            Err(code) => Err(*code),
+        };
+        let event = self.paths.event(call, recorded);
+        self.trace.events.push(event);
+        result
+    }
+}
+
+/// Wraps a backend, recording every call made through it into a
+/// [`Trace`](struct.Trace.html).
+///
+/// Calls are passed on to the wrapped backend one at a time, so the trace has them in the
+/// order the wrapped backend saw them.
+#[derive(Debug)]
+pub struct Recorder {
+    inner: Arc<dyn Backend>,
+    state: Mutex<State>,
+}
+
+impl Recorder {
+    pub fn new(inner: Arc<dyn Backend>) -> Recorder {
+        Recorder {
+            inner,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// The calls recorded so far.
+    pub fn trace(&self) -> Trace {
+        self.lock().trace.clone()
+    }
+
+    /// The wrapped backend.
+    pub fn inner(&self) -> &Arc<dyn Backend> {
+        &self.inner
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Backend for Recorder {
+    fn predefined(&self, hive: Hive) -> Result<Handle, ErrorCode> {
+        let mut state = self.lock();
+        let result = self.inner.predefined(hive);
+        state.record(Call::Predefined { hive }, result, |s, x| {
+            Reply::Handle(s.id(*x))
+        })
+    }
+
+    fn open_key(&self, base: Handle, path: &U16CStr, sec: Security) -> Result<Handle, ErrorCode> {
+        let mut state = self.lock();
+        let call = Call::OpenKey {
+            base: state.id(base),
+            path: path.to_ucstring(),
+            sec,
+        };
+        let result = self.inner.open_key(base, path, sec);
+        state.record(call, result, |s, x| Reply::Handle(s.id(*x)))
+    }
+
+    fn create_key(&self, base: Handle, path: &U16CStr, sec: Security) -> Result<Handle, ErrorCode> {
+        let mut state = self.lock();
+        let call = Call::CreateKey {
+            base: state.id(base),
+            path: path.to_ucstring(),
+            sec,
+        };
+        let result = self.inner.create_key(base, path, sec);
+        state.record(call, result, |s, x| Reply::Handle(s.id(*x)))
+    }
+
+    fn delete_key(
+        &self,
+        base: Handle,
+        path: &U16CStr,
+        is_recursive: bool,
+    ) -> Result<(), ErrorCode> {
+        let mut state = self.lock();
+        let call = Call::DeleteKey {
+            base: state.id(base),
+            path: path.to_ucstring(),
+            is_recursive,
+        };
+        let result = self.inner.delete_key(base, path, is_recursive);
+        state.record(call, result, |_, _| Reply::Done)
+    }
+
+    fn close_key(&self, handle: Handle) {
+        let mut state = self.lock();
+        let call = Call::CloseKey {
+            handle: state.id(handle),
+        };
+        self.inner.close_key(handle);
+        let _ = state.record(call, Ok(()), |_, _| Reply::Done);
+        // The raw value may be handed out again for another key.
+        state.ids.remove(&handle.raw());
+    }
+
+    fn enum_key(&self, handle: Handle, index: u32) -> Result<Option<KeyEntry>, ErrorCode> {
+        let mut state = self.lock();
+        let call = Call::EnumKey {
+            handle: state.id(handle),
+            index,
+        };
+        let result = self.inner.enum_key(handle, index);
+        state.record(call, result, |_, x| key_reply(x.clone()))
+    }
+
+    fn enum_value(
+        &self,
+        handle: Handle,
+        index: u32,
+    ) -> Result<Option<(U16CString, RawValue)>, ErrorCode> {
+        let mut state = self.lock();
+        let call = Call::EnumValue {
+            handle: state.id(handle),
+            index,
+        };
+        let result = self.inner.enum_value(handle, index);
+        state.record(call, result, |_, x| value_reply(x.clone()))
+    }
+
+    fn query_value(&self, handle: Handle, name: &U16CStr) -> Result<RawValue, ErrorCode> {
+        let mut state = self.lock();
+        let call = Call::QueryValue {
+            handle: state.id(handle),
+            name: name.to_ucstring(),
+        };
+        let result = self.inner.query_value(handle, name);
+        state.record(call, result, |_, x| Reply::Data(x.clone()))
+    }
+
+    fn set_value(&self, handle: Handle, name: &U16CStr, value: &RawValue) -> Result<(), ErrorCode> {
+        let mut state = self.lock();
+        let call = Call::SetValue {
+            handle: state.id(handle),
+            name: name.to_ucstring(),
+            value: value.clone(),
+        };
+        let result = self.inner.set_value(handle, name, value);
+        state.record(call, result, |_, _| Reply::Done)
+    }
+
+    fn delete_value(&self, handle: Handle, name: &U16CStr) -> Result<(), ErrorCode> {
+        let mut state = self.lock();
+        let call = Call::DeleteValue {
+            handle: state.id(handle),
+            name: name.to_ucstring(),
+        };
+        let result = self.inner.delete_value(handle, name);
+        state.record(call, result, |_, _| Reply::Done)
+    }
+
+    fn open_current_user(&self, sec: Security) -> Result<Handle, ErrorCode> {
+        let mut state = self.lock();
+        let result = self.inner.open_current_user(sec);
+        state.record(Call::OpenCurrentUser { sec }, result, |s, x| {
+            Reply::Handle(s.id(*x))
+        })
+    }
+
+    fn load_app_key(&self, file_path: &Path, sec: Security) -> Result<Handle, ErrorCode> {
+        let mut state = self.lock();
+        let call = Call::LoadAppKey {
+            file_path: file_path.to_path_buf(),
+            sec,
+        };
+        let result = self.inner.load_app_key(file_path, sec);
+        state.record(call, result, |s, x| Reply::Handle(s.id(*x)))
+    }
+
+    fn save_key(&self, handle: Handle, file_path: &U16CStr) -> Result<(), ErrorCode> {
+        let mut state = self.lock();
+        let call = Call::SaveKey {
+            handle: state.id(handle),
+            file_path: file_path.to_ucstring(),
+        };
+        let result = self.inner.save_key(handle, file_path);
+        state.record(call, result, |_, _| Reply::Done)
+    }
+
+    fn query_info(&self, handle: Handle) -> Result<KeyInfo, ErrorCode> {
+        let mut state = self.lock();
+        let call = Call::QueryInfo {
+            handle: state.id(handle),
+        };
+        let result = self.inner.query_info(handle);
+        state.record(call, result, |_, x| info_reply(x.clone()))
+    }
+
+    fn decode(&self, value: &RawValue) -> Result<Data, value::Error> {
+        self.inner.decode(value)
+    }
+}
+
+/// A trace being replayed, with the handles it has opened on the backend.
+struct Replay<'a> {
+    backend: &'a dyn Backend,
+    handles: HashMap<u32, Handle>,
+    /// The handles that need closing, leaving out predefined handles.
+    open: HashMap<u32, Handle>,
+}
+
+impl Replay<'_> {
+    fn handle(&self, index: usize, id: u32) -> Result<Handle, Error> {
+        self.handles
+            .get(&id)
+            .copied()
+            .ok_or(Error::UnknownHandle(index, id))
+    }
+
+    /// Takes the handle a call opened under the number it was recorded with.
+    fn opened(
+        &mut self,
+        event: &Event,
+        result: Result<Handle, ErrorCode>,
+    ) -> Result<Reply, ErrorCode> {
+        let handle = result?;
+        let is_predefined = matches!(event.call, Call::Predefined { .. });
+        match event.result {
+            Ok(Reply::Handle(id)) => {
+                self.handles.insert(id, handle);
+                if !is_predefined {
+                    self.open.insert(id, handle);
+                }
+                Ok(Reply::Handle(id))
+            }
+            _ => {
+                if !is_predefined {
+                    self.backend.close_key(handle);
+                }
+                // Handles are numbered from 1, so this never matches.
+                Ok(Reply::Handle(0))
+            }
+        }
+    }
+
+    fn event(&mut self, index: usize, event: &Event) -> Result<(), Error> {
+        let backend = self.backend;
+        let result = match &event.call {
+            Call::Predefined { hive } => {
+                let result = backend.predefined(*hive);
+                self.opened(event, result)
+            }
+            Call::OpenKey { base, path, sec } => {
+                let result = backend.open_key(self.handle(index, *base)?, path, *sec);
+                self.opened(event, result)
+            }
+            Call::CreateKey { base, path, sec } => {
+                let result = backend.create_key(self.handle(index, *base)?, path, *sec);
+                self.opened(event, result)
+            }
+            Call::DeleteKey {
+                base,
+                path,
+                is_recursive,
+            } => backend
+                .delete_key(self.handle(index, *base)?, path, *is_recursive)
+                .map(|_| Reply::Done),
+            Call::CloseKey { handle } => {
+                backend.close_key(self.handle(index, *handle)?);
+                self.handles.remove(handle);
+                self.open.remove(handle);
+                Ok(Reply::Done)
+            }
+            Call::EnumKey { handle, index: i } => backend
+                .enum_key(self.handle(index, *handle)?, *i)
+                .map(key_reply),
+            Call::EnumValue { handle, index: i } => backend
+                .enum_value(self.handle(index, *handle)?, *i)
+                .map(value_reply),
+            Call::QueryValue { handle, name } => backend
+                .query_value(self.handle(index, *handle)?, name)
+                .map(Reply::Data),
+            Call::SetValue {
+                handle,
+                name,
+                value,
+            } => backend
+                .set_value(self.handle(index, *handle)?, name, value)
+                .map(|_| Reply::Done),
+            Call::DeleteValue { handle, name } => backend
+                .delete_value(self.handle(index, *handle)?, name)
+                .map(|_| Reply::Done),
+            Call::OpenCurrentUser { sec } => {
+                let result = backend.open_current_user(*sec);
+                self.opened(event, result)
+            }
+            Call::LoadAppKey { file_path, sec } => {
+                let result = backend.load_app_key(file_path, *sec);
+                self.opened(event, result)
+            }
+            Call::SaveKey { handle, file_path } => backend
+                .save_key(self.handle(index, *handle)?, file_path)
+                .map(|_| Reply::Done),
+            Call::QueryInfo { handle } => backend
+                .query_info(self.handle(index, *handle)?)
+                .map(info_reply),
+        };
+
+        if result == event.result {
+            Ok(())
+        } else {
+            Err(Error::Mismatch {
+                index,
+                expected: format!("{} -> {}", event.call, Outcome(&event.result)),
+                actual: format!("{} -> {}", event.call, Outcome(&result)),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{scoped, Fault, Faults, Memory, Operation};
+    use crate::Data;
+
+    fn record() -> Trace {
+        let recorder = Arc::new(Recorder::new(Arc::new(Memory::new())));
+        let _scope = scoped(recorder.clone());
+
+        let key = Hive::LocalMachine
+            .create(r"SOFTWARE\Contoso", Security::AllAccess)
+            .unwrap();
+        key.set_value("Level", &Data::U32(3)).unwrap();
+        key.set_value("Path\t\"x\"", &Data::Binary(vec![])).unwrap();
+        key.create("Update", Security::AllAccess).unwrap();
+        assert!(key.value("Missing").is_err());
+        assert_eq!(key.keys().count(), 1);
+        assert_eq!(key.values().count(), 2);
+        drop(key);
+        Hive::LocalMachine.delete("SOFTWARE", true).unwrap();
+
+        recorder.trace()
+    }
+
+    #[test]
+    fn records_calls() {
+        let trace = record();
+        let text = trace.to_string();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(
+            &lines[..6],
+            &[
+                "predefined HKEY_LOCAL_MACHINE -> #1",
+                r#"create_key #1 "SOFTWARE\\Contoso" 0xf003f -> #2"#,
+                r#"set_value #2 "Level" 4:03000000 -> ok"#,
+                r#"set_value #2 "Path\u{9}\"x\"" 3: -> ok"#,
+                r#"create_key #2 "Update" 0xf003f -> #3"#,
+                "close_key #3 -> ok",
+            ]
+        );
+        assert!(lines.contains(&r#"query_value #2 "Missing" -> error 2"#));
+        assert!(lines.contains(&r#"enum_key #2 0 -> key "Update""#));
+        assert!(lines.contains(&"enum_key #2 1 -> end"));
+        assert!(lines.contains(&r#"enum_value #2 0 -> value "Level" 4:03000000"#));
+        assert_eq!(
+            lines.last(),
+            Some(&r#"delete_key #1 "SOFTWARE" recursive -> ok"#)
+        );
+
+        let event = &trace.events()[2];
+        assert_eq!(event.path, r"HKEY_LOCAL_MACHINE\SOFTWARE\Contoso");
+        match &event.call {
+            Call::SetValue { value, .. } => assert_eq!(value.to_data().unwrap(), Data::U32(3)),
+            x => panic!("{:?}", x),
+        }
+
+        assert_eq!(Trace::parse(&text).unwrap(), trace);
+    }
+
+    #[test]
+    fn replays_against_other_backends() {
+        let trace = record();
+        trace.replay(&Memory::new()).unwrap();
+
+        let faults = Faults::new(Arc::new(Memory::new()));
+        faults.inject(
+            Fault::new(ErrorCode::ACCESS_DENIED)
+                .on(Operation::SetValue)
+                .named("level"),
+        );
+        match trace.replay(&faults) {
+            Err(Error::Mismatch {
+                index,
+                expected,
+                actual,
+            }) => {
+                assert_eq!(index, 3);
+                assert_eq!(expected, r#"set_value #2 "Level" 4:03000000 -> ok"#);
+                assert_eq!(actual, r#"set_value #2 "Level" 4:03000000 -> error 5"#);
+            }
+            x => panic!("{:?}", x),
+        }
+
+        let memory = Arc::new(Memory::new());
+        {
+            let _scope = scoped(memory.clone());
+            Hive::LocalMachine
+                .create(r"SOFTWARE\Contoso\Other", Security::AllAccess)
+                .unwrap();
+        }
+        match trace.replay(&*memory) {
+            Err(Error::Mismatch { actual, .. }) => {
+                assert_eq!(actual, r#"enum_key #2 0 -> key "Other""#)
+            }
+            x => panic!("{:?}", x),
+        }
+    }
+
+    #[test]
+    fn reports_errors() {
+        assert!(matches!(
+            Trace::parse("predefined HKEY_LOCAL_MACHINE -> #1\n\nopen_key #1 \"x -> #2"),
+            Err(Error::Syntax(3, "unterminated string"))
+        ));
+        assert!(matches!(
+            Trace::parse("; comment\nquery_value #1 \"x\""),
+            Err(Error::Syntax(2, "missing result"))
+        ));
+        assert!(matches!(
+            Trace::parse("set_value #1 \"x\" 4:0 -> ok"),
+            Err(Error::Syntax(1, "invalid data"))
+        ));
+
+        let trace = Trace::parse("query_info #7 -> info 0 0 0 0 0 0").unwrap();
+        assert!(matches!(
+            trace.replay(&Memory::new()),
+            Err(Error::UnknownHandle(1, 7))
+        ));
+    }
+}
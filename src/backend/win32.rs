@@ -0,0 +1,356 @@
+use std::path::Path;
+
+use utfx::{U16CStr, U16CString};
+use windows::{
+    core::{PCWSTR, PWSTR},
+    Win32::{
+        Foundation::{ERROR_MORE_DATA, ERROR_NO_MORE_ITEMS, FILETIME, WIN32_ERROR},
+        System::Registry::{
+            RegCloseKey, RegCreateKeyExW, RegDeleteKeyW, RegDeleteTreeW, RegDeleteValueW,
+            RegEnumKeyExW, RegEnumValueW, RegLoadAppKeyW, RegOpenCurrentUser, RegOpenKeyExW,
+            RegQueryInfoKeyW, RegQueryValueExW, RegSaveKeyExW, RegSetValueExW, HKEY,
+            HKEY_CLASSES_ROOT, HKEY_CURRENT_CONFIG, HKEY_CURRENT_USER,
+            HKEY_CURRENT_USER_LOCAL_SETTINGS, HKEY_LOCAL_MACHINE, HKEY_PERFORMANCE_DATA,
+            HKEY_USERS, REG_NO_COMPRESSION, REG_OPEN_CREATE_OPTIONS, REG_VALUE_TYPE,
+        },
+    },
+};
+
+use super::{Backend, ErrorCode, Handle, KeyEntry, RawValue, MAX_KEY_NAME_LEN, MAX_VALUE_NAME_LEN};
+use crate::info::{self, FileTime, KeyInfo};
+use crate::sec::Security;
+use crate::{value, Data, Hive};
+
+/// The live Windows registry.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Win32;
+
+#[inline]
+fn hkey(handle: Handle) -> HKEY {
+    HKEY(handle.raw() as *mut _)
+}
+
+#[inline]
+fn handle(hkey: HKEY) -> Handle {
+    Handle::new(hkey.0 as usize)
+}
+
+#[inline]
+fn check(result: WIN32_ERROR) -> Result<(), ErrorCode> {
+    if result.is_ok() {
+        Ok(())
+    } else {
+        Err(ErrorCode::new(result.0))
+    }
+}
+
+/// How many times a call is made with bigger buffers before giving up with `ERROR_MORE_DATA`,
+/// in case what it reads keeps growing.
+const MAX_ATTEMPTS: usize = 8;
+
+/// The length of the longest class name of a key's subkeys, without its terminator.
+fn max_subkey_class_len(handle: Handle) -> Result<u32, ErrorCode> {
+    let mut len = 0u32;
+    check(unsafe {
+        RegQueryInfoKeyW(
+            hkey(handle),
+            PWSTR::null(),
+            None,
+            None,
+            None,
+            None,
+            Some(&mut len),
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+    })?;
+    Ok(len)
+}
+
+impl Backend for Win32 {
+    fn predefined(&self, hive: Hive) -> Result<Handle, ErrorCode> {
+        Ok(handle(match hive {
+            Hive::ClassesRoot => HKEY_CLASSES_ROOT,
+            Hive::CurrentConfig => HKEY_CURRENT_CONFIG,
+            Hive::CurrentUser => HKEY_CURRENT_USER,
+            Hive::CurrentUserLocalSettings => HKEY_CURRENT_USER_LOCAL_SETTINGS,
+            Hive::LocalMachine => HKEY_LOCAL_MACHINE,
+            Hive::PerformanceData => HKEY_PERFORMANCE_DATA,
+            Hive::Users => HKEY_USERS,
+            Hive::Application => return Err(ErrorCode::INVALID_HANDLE),
+        }))
+    }
+
+    fn open_key(&self, base: Handle, path: &U16CStr, sec: Security) -> Result<Handle, ErrorCode> {
+        let mut out = HKEY::default();
+        check(unsafe {
+            RegOpenKeyExW(hkey(base), PCWSTR(path.as_ptr()), 0, sec.into(), &mut out)
+        })?;
+        Ok(handle(out))
+    }
+
+    fn create_key(&self, base: Handle, path: &U16CStr, sec: Security) -> Result<Handle, ErrorCode> {
+        let mut out = HKEY::default();
+        check(unsafe {
+            RegCreateKeyExW(
+                hkey(base),
+                PCWSTR(path.as_ptr()),
+                0,
+                None,
+                REG_OPEN_CREATE_OPTIONS::default(),
+                sec.into(),
+                None,
+                &mut out,
+                None,
+            )
+        })?;
+        Ok(handle(out))
+    }
+
+    fn delete_key(
+        &self,
+        base: Handle,
+        path: &U16CStr,
+        is_recursive: bool,
+    ) -> Result<(), ErrorCode> {
+        check(if is_recursive {
+            unsafe { RegDeleteTreeW(hkey(base), PCWSTR(path.as_ptr())) }
+        } else {
+            unsafe { RegDeleteKeyW(hkey(base), PCWSTR(path.as_ptr())) }
+        })
+    }
+
+    fn close_key(&self, handle: Handle) {
+        // No point checking the return value here.
+        let _ = unsafe { RegCloseKey(hkey(handle)) };
+    }
+
+    fn enum_key(&self, handle: Handle, index: u32) -> Result<Option<KeyEntry>, ErrorCode> {
+        // Key names have a fixed maximum length, but class names have none, so a class too
+        // long for the buffer is sized from the longest the key's subkeys have.
+        let mut name = vec![0u16; MAX_KEY_NAME_LEN + 1];
+        let mut class = vec![0u16; 64];
+
+        for _ in 0..MAX_ATTEMPTS {
+            let mut name_len = name.len() as u32;
+            let mut class_len = class.len() as u32;
+            let mut last_write_time = FILETIME::default();
+
+            let result = unsafe {
+                RegEnumKeyExW(
+                    hkey(handle),
+                    index,
+                    PWSTR(name.as_mut_ptr()),
+                    &mut name_len,
+                    None,
+                    PWSTR(class.as_mut_ptr()),
+                    Some(&mut class_len),
+                    Some(&mut last_write_time),
+                )
+            };
+            if result == ERROR_NO_MORE_ITEMS {
+                return Ok(None);
+            }
+            if result == ERROR_MORE_DATA {
+                let len = max_subkey_class_len(handle)? as usize + 1;
+                class.resize(len.max(class.len()), 0);
+                continue;
+            }
+            check(result)?;
+
+            name.truncate(name_len as usize);
+            return Ok(Some(KeyEntry {
+                name: U16CString::new(name).map_err(|_| ErrorCode::INVALID_PARAMETER)?,
+                class: info::decode_class(&class[..class_len as usize]),
+                last_write_time: FileTime::from_parts(
+                    last_write_time.dwLowDateTime,
+                    last_write_time.dwHighDateTime,
+                ),
+            }));
+        }
+        Err(ErrorCode::MORE_DATA)
+    }
+
+    fn enum_value(
+        &self,
+        handle: Handle,
+        index: u32,
+    ) -> Result<Option<(U16CString, RawValue)>, ErrorCode> {
+        // Most names and data are short, so start small and grow the buffers as needed
+        // rather than querying the key for their largest sizes.
+        let mut name = vec![0u16; 256];
+        let mut data = vec![0u8; 256];
+
+        for _ in 0..MAX_ATTEMPTS {
+            let mut name_len = name.len() as u32;
+            let mut data_len = data.len() as u32;
+            let mut ty = 0u32;
+
+            let result = unsafe {
+                RegEnumValueW(
+                    hkey(handle),
+                    index,
+                    PWSTR(name.as_mut_ptr()),
+                    &mut name_len,
+                    None,
+                    Some(&mut ty),
+                    Some(data.as_mut_ptr()),
+                    Some(&mut data_len),
+                )
+            };
+            if result == ERROR_NO_MORE_ITEMS {
+                return Ok(None);
+            }
+            if result == ERROR_MORE_DATA {
+                // Either buffer may be too small, but only the size of the data is reported,
+                // so otherwise it is the name, which has a fixed maximum length.
+                if data_len as usize > data.len() {
+                    data.resize(data_len as usize, 0);
+                } else {
+                    name.resize(MAX_VALUE_NAME_LEN + 1, 0);
+                }
+                continue;
+            }
+            check(result)?;
+
+            name.truncate(name_len as usize);
+            data.truncate(data_len as usize);
+            let name = U16CString::new(name).map_err(|_| ErrorCode::INVALID_PARAMETER)?;
+            return Ok(Some((name, RawValue { ty, data })));
+        }
+        Err(ErrorCode::MORE_DATA)
+    }
+
+    fn query_value(&self, handle: Handle, name: &U16CStr) -> Result<RawValue, ErrorCode> {
+        for _ in 0..MAX_ATTEMPTS {
+            // Get the required buffer size first
+            let mut len = 0u32;
+            check(unsafe {
+                RegQueryValueExW(
+                    hkey(handle),
+                    PCWSTR(name.as_ptr()),
+                    None,
+                    None,
+                    None,
+                    Some(&mut len),
+                )
+            })?;
+
+            let mut data = vec![0u8; len as usize];
+            let mut ty = REG_VALUE_TYPE::default();
+            let result = unsafe {
+                RegQueryValueExW(
+                    hkey(handle),
+                    PCWSTR(name.as_ptr()),
+                    None,
+                    Some(&mut ty),
+                    Some(data.as_mut_ptr()),
+                    Some(&mut len),
+                )
+            };
+
+            // The value grew in between; try again.
+            if result == ERROR_MORE_DATA {
+                continue;
+            }
+            check(result)?;
+
+            data.truncate(len as usize);
+            return Ok(RawValue { ty: ty.0, data });
+        }
+        Err(ErrorCode::MORE_DATA)
+    }
+
+    fn set_value(&self, handle: Handle, name: &U16CStr, value: &RawValue) -> Result<(), ErrorCode> {
+        check(unsafe {
+            RegSetValueExW(
+                hkey(handle),
+                PCWSTR(name.as_ptr()),
+                0,
+                REG_VALUE_TYPE(value.ty),
+                Some(&value.data),
+            )
+        })
+    }
+
+    fn delete_value(&self, handle: Handle, name: &U16CStr) -> Result<(), ErrorCode> {
+        check(unsafe { RegDeleteValueW(hkey(handle), PCWSTR(name.as_ptr())) })
+    }
+
+    fn open_current_user(&self, sec: Security) -> Result<Handle, ErrorCode> {
+        let mut out = HKEY::default();
+        check(unsafe { RegOpenCurrentUser(sec.bits(), &mut out) })?;
+        Ok(handle(out))
+    }
+
+    fn load_app_key(&self, file_path: &Path, sec: Security) -> Result<Handle, ErrorCode> {
+        let path = U16CString::from_os_str(file_path.as_os_str())
+            .map_err(|_| ErrorCode::INVALID_PARAMETER)?;
+        let mut out = HKEY::default();
+        check(unsafe { RegLoadAppKeyW(PCWSTR(path.as_ptr()), &mut out, sec.bits(), 0, 0) })?;
+        Ok(handle(out))
+    }
+
+    fn save_key(&self, handle: Handle, file_path: &U16CStr) -> Result<(), ErrorCode> {
+        check(unsafe {
+            RegSaveKeyExW(
+                hkey(handle),
+                PCWSTR(file_path.as_ptr()),
+                None,
+                REG_NO_COMPRESSION,
+            )
+        })
+    }
+
+    fn query_info(&self, handle: Handle) -> Result<KeyInfo, ErrorCode> {
+        // Class names have no documented maximum length, so size the buffer from the length
+        // reported when it is too small.
+        let mut class = vec![0u16; 64];
+
+        for _ in 0..MAX_ATTEMPTS {
+            let mut class_len = class.len() as u32;
+            let mut info = KeyInfo::default();
+            let mut last_write_time = FILETIME::default();
+
+            let result = unsafe {
+                RegQueryInfoKeyW(
+                    hkey(handle),
+                    PWSTR(class.as_mut_ptr()),
+                    Some(&mut class_len),
+                    None,
+                    Some(&mut info.subkey_count),
+                    Some(&mut info.max_subkey_name_len),
+                    Some(&mut info.max_class_len),
+                    Some(&mut info.value_count),
+                    Some(&mut info.max_value_name_len),
+                    Some(&mut info.max_value_data_len),
+                    Some(&mut info.security_descriptor_len),
+                    Some(&mut last_write_time),
+                )
+            };
+
+            if result == ERROR_MORE_DATA {
+                let len = class_len as usize + 1;
+                class.resize(len.max(class.len()), 0);
+                continue;
+            }
+            check(result)?;
+
+            info.class = info::decode_class(&class[..class_len as usize]);
+            info.last_write_time = FileTime::from_parts(
+                last_write_time.dwLowDateTime,
+                last_write_time.dwHighDateTime,
+            );
+            return Ok(info);
+        }
+        Err(ErrorCode::MORE_DATA)
+    }
+
+    fn decode(&self, value: &RawValue) -> Result<Data, value::Error> {
+        // Reading the live registry has always reported strings missing their terminators.
+        Data::from_bytes_strict(value.ty, &value.data)
+    }
+}
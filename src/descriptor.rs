@@ -0,0 +1,464 @@
+//! Parsing of self-relative security descriptors, as attached to registry keys.
+//!
+//! These are the bytes returned by `RegGetKeySecurity` and stored in the `sk` cells of hive
+//! files. Parsing is done without the Windows API, so it works on any platform.
+#![allow(non_upper_case_globals)]
+
+use std::fmt::Display;
+
+use crate::sec::Security;
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("Invalid security descriptor: {0}")]
+    Invalid(&'static str),
+}
+
+bitflags::bitflags! {
+    /// The control flags of a security descriptor (`SECURITY_DESCRIPTOR_CONTROL`).
+    pub struct Control: u16 {
+        const OwnerDefaulted = 0x0001;
+        const GroupDefaulted = 0x0002;
+        const DaclPresent = 0x0004;
+        const DaclDefaulted = 0x0008;
+        const SaclPresent = 0x0010;
+        const SaclDefaulted = 0x0020;
+        const DaclAutoInheritReq = 0x0100;
+        const SaclAutoInheritReq = 0x0200;
+        const DaclAutoInherited = 0x0400;
+        const SaclAutoInherited = 0x0800;
+        const DaclProtected = 0x1000;
+        const SaclProtected = 0x2000;
+        const RmControlValid = 0x4000;
+        const SelfRelative = 0x8000;
+    }
+}
+
+bitflags::bitflags! {
+    /// The inheritance and audit flags of an access control entry.
+    pub struct AceFlags: u8 {
+        const ObjectInherit = 0x01;
+        const ContainerInherit = 0x02;
+        const NoPropagateInherit = 0x04;
+        const InheritOnly = 0x08;
+        const Inherited = 0x10;
+        const SuccessfulAccess = 0x40;
+        const FailedAccess = 0x80;
+    }
+}
+
+const GENERIC_READ: u32 = 0x8000_0000;
+const GENERIC_WRITE: u32 = 0x4000_0000;
+const GENERIC_EXECUTE: u32 = 0x2000_0000;
+const GENERIC_ALL: u32 = 0x1000_0000;
+
+/// A security identifier, such as `S-1-5-32-544`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Sid {
+    revision: u8,
+    authority: u64,
+    sub_authorities: Vec<u32>,
+}
+
+impl Sid {
+    pub fn new(authority: u64, sub_authorities: Vec<u32>) -> Sid {
+        Sid {
+            revision: 1,
+            authority,
+            sub_authorities,
+        }
+    }
+
+    /// Parses a SID from the start of `bytes`, returning it and its length in bytes.
+    fn parse(bytes: &[u8]) -> Result<(Sid, usize), Error> {
+        if bytes.len() < 8 {
+            return Err(Error::Invalid("SID is truncated"));
+        }
+
+        let count = bytes[1] as usize;
+        let len = 8 + count * 4;
+        if bytes.len() < len {
+            return Err(Error::Invalid("SID is truncated"));
+        }
+
+        let authority = bytes[2..8]
+            .iter()
+            .fold(0u64, |acc, x| (acc << 8) | u64::from(*x));
+        let sub_authorities = bytes[8..len]
+            .chunks_exact(4)
+            .map(|x| u32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+            .collect();
+
+        Ok((
+            Sid {
+                revision: bytes[0],
+                authority,
+                sub_authorities,
+            },
+            len,
+        ))
+    }
+
+    #[inline]
+    pub fn revision(&self) -> u8 {
+        self.revision
+    }
+
+    /// The 48-bit identifier authority, such as 5 for `SECURITY_NT_AUTHORITY`.
+    #[inline]
+    pub fn authority(&self) -> u64 {
+        self.authority
+    }
+
+    #[inline]
+    pub fn sub_authorities(&self) -> &[u32] {
+        &self.sub_authorities
+    }
+}
+
+impl Display for Sid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "S-{}-", self.revision)?;
+        if self.authority >= 1 << 32 {
+            write!(f, "{:#014x}", self.authority)?;
+        } else {
+            write!(f, "{}", self.authority)?;
+        }
+        for x in &self.sub_authorities {
+            write!(f, "-{}", x)?;
+        }
+        Ok(())
+    }
+}
+
+/// The type of an access control entry.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AceType {
+    AccessAllowed,
+    AccessDenied,
+    SystemAudit,
+    SystemAlarm,
+    AccessAllowedObject,
+    AccessDeniedObject,
+    SystemAuditObject,
+    SystemAlarmObject,
+    SystemMandatoryLabel,
+    Other(u8),
+}
+
+impl AceType {
+    fn from_raw(ty: u8) -> AceType {
+        match ty {
+            0x00 => AceType::AccessAllowed,
+            0x01 => AceType::AccessDenied,
+            0x02 => AceType::SystemAudit,
+            0x03 => AceType::SystemAlarm,
+            0x05 => AceType::AccessAllowedObject,
+            0x06 => AceType::AccessDeniedObject,
+            0x07 => AceType::SystemAuditObject,
+            0x08 => AceType::SystemAlarmObject,
+            0x11 => AceType::SystemMandatoryLabel,
+            ty => AceType::Other(ty),
+        }
+    }
+
+    fn is_object(&self) -> bool {
+        matches!(
+            self,
+            AceType::AccessAllowedObject
+                | AceType::AccessDeniedObject
+                | AceType::SystemAuditObject
+                | AceType::SystemAlarmObject
+        )
+    }
+}
+
+/// An access control entry: an access mask granted, denied or audited for a SID.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ace {
+    ace_type: AceType,
+    flags: AceFlags,
+    mask: u32,
+    sid: Sid,
+}
+
+impl Ace {
+    fn parse(bytes: &[u8]) -> Result<(Ace, usize), Error> {
+        if bytes.len() < 8 {
+            return Err(Error::Invalid("ACE is truncated"));
+        }
+
+        let ace_type = AceType::from_raw(bytes[0]);
+        let flags = AceFlags::from_bits_truncate(bytes[1]);
+        let len = u16::from_le_bytes([bytes[2], bytes[3]]) as usize;
+        if len < 8 || len > bytes.len() {
+            return Err(Error::Invalid("ACE size out of bounds"));
+        }
+
+        let body = &bytes[..len];
+        let mask = u32::from_le_bytes([body[4], body[5], body[6], body[7]]);
+
+        // Object ACEs carry up to two GUIDs, flagged as present, between the mask and the SID.
+        let mut sid_start = 8;
+        if ace_type.is_object() {
+            let object_flags = body
+                .get(8..12)
+                .map(|x| u32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+                .ok_or(Error::Invalid("object ACE is truncated"))?;
+            sid_start = 12 + 16 * (object_flags & 0x3).count_ones() as usize;
+        }
+
+        let sid = body
+            .get(sid_start..)
+            .ok_or(Error::Invalid("ACE is truncated"))
+            .and_then(Sid::parse)?
+            .0;
+
+        Ok((
+            Ace {
+                ace_type,
+                flags,
+                mask,
+                sid,
+            },
+            len,
+        ))
+    }
+
+    #[inline]
+    pub fn ace_type(&self) -> AceType {
+        self.ace_type
+    }
+
+    #[inline]
+    pub fn flags(&self) -> AceFlags {
+        self.flags
+    }
+
+    /// The raw access mask.
+    #[inline]
+    pub fn mask(&self) -> u32 {
+        self.mask
+    }
+
+    #[inline]
+    pub fn sid(&self) -> &Sid {
+        &self.sid
+    }
+
+    /// The access mask as registry key rights. Generic rights are mapped to the key rights
+    /// they imply, and bits with no registry meaning are dropped.
+    pub fn security(&self) -> Security {
+        let mut security = Security::from_bits_truncate(self.mask);
+        if self.mask & GENERIC_READ != 0 {
+            security |= Security::Read;
+        }
+        if self.mask & GENERIC_WRITE != 0 {
+            security |= Security::Write;
+        }
+        if self.mask & GENERIC_EXECUTE != 0 {
+            security |= Security::Execute;
+        }
+        if self.mask & GENERIC_ALL != 0 {
+            security |= Security::AllAccess;
+        }
+        security
+    }
+}
+
+/// An access control list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Acl {
+    revision: u8,
+    aces: Vec<Ace>,
+}
+
+impl Acl {
+    fn parse(bytes: &[u8]) -> Result<Acl, Error> {
+        if bytes.len() < 8 {
+            return Err(Error::Invalid("ACL is truncated"));
+        }
+
+        let size = u16::from_le_bytes([bytes[2], bytes[3]]) as usize;
+        let count = u16::from_le_bytes([bytes[4], bytes[5]]) as usize;
+        if size < 8 || size > bytes.len() {
+            return Err(Error::Invalid("ACL size out of bounds"));
+        }
+
+        let mut aces = Vec::with_capacity(count);
+        let mut at = 8;
+        for _ in 0..count {
+            let (ace, len) = Ace::parse(&bytes[at..size])?;
+            aces.push(ace);
+            at += len;
+        }
+
+        Ok(Acl {
+            revision: bytes[0],
+            aces,
+        })
+    }
+
+    #[inline]
+    pub fn revision(&self) -> u8 {
+        self.revision
+    }
+
+    #[inline]
+    pub fn aces(&self) -> &[Ace] {
+        &self.aces
+    }
+
+    /// The rights explicitly allowed to the given SID, less those explicitly denied to it.
+    ///
+    /// This only considers entries naming the SID itself, not any groups it belongs to.
+    pub fn allowed(&self, sid: &Sid) -> Security {
+        let (allowed, denied) = self
+            .aces
+            .iter()
+            .filter(|x| &x.sid == sid && !x.flags.contains(AceFlags::InheritOnly))
+            .fold(
+                (Security::empty(), Security::empty()),
+                |(allowed, denied), ace| match ace.ace_type {
+                    AceType::AccessAllowed => (allowed | ace.security(), denied),
+                    AceType::AccessDenied => (allowed, denied | ace.security()),
+                    _ => (allowed, denied),
+                },
+            );
+        allowed - denied
+    }
+}
+
+/// A parsed self-relative security descriptor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecurityDescriptor {
+    revision: u8,
+    control: Control,
+    owner: Option<Sid>,
+    group: Option<Sid>,
+    sacl: Option<Acl>,
+    dacl: Option<Acl>,
+}
+
+impl SecurityDescriptor {
+    /// Parses a security descriptor in self-relative format.
+    pub fn from_bytes(bytes: &[u8]) -> Result<SecurityDescriptor, Error> {
+        if bytes.len() < 20 {
+            return Err(Error::Invalid("descriptor is truncated"));
+        }
+
+        let control = Control::from_bits_truncate(u16::from_le_bytes([bytes[2], bytes[3]]));
+        if !control.contains(Control::SelfRelative) {
+            return Err(Error::Invalid("descriptor is not self-relative"));
+        }
+
+        let offset = |at: usize| {
+            u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]) as usize
+        };
+        let section = |at: usize| match offset(at) {
+            0 => Ok(None),
+            x if x < bytes.len() => Ok(Some(&bytes[x..])),
+            _ => Err(Error::Invalid("offset out of bounds")),
+        };
+
+        let owner = section(4)?.map(Sid::parse).transpose()?.map(|x| x.0);
+        let group = section(8)?.map(Sid::parse).transpose()?.map(|x| x.0);
+        let sacl = match control.contains(Control::SaclPresent) {
+            true => section(12)?.map(Acl::parse).transpose()?,
+            false => None,
+        };
+        let dacl = match control.contains(Control::DaclPresent) {
+            true => section(16)?.map(Acl::parse).transpose()?,
+            false => None,
+        };
+
+        Ok(SecurityDescriptor {
+            revision: bytes[0],
+            control,
+            owner,
+            group,
+            sacl,
+            dacl,
+        })
+    }
+
+    #[inline]
+    pub fn revision(&self) -> u8 {
+        self.revision
+    }
+
+    #[inline]
+    pub fn control(&self) -> Control {
+        self.control
+    }
+
+    #[inline]
+    pub fn owner(&self) -> Option<&Sid> {
+        self.owner.as_ref()
+    }
+
+    #[inline]
+    pub fn group(&self) -> Option<&Sid> {
+        self.group.as_ref()
+    }
+
+    /// The system ACL, used for auditing and integrity labels.
+    #[inline]
+    pub fn sacl(&self) -> Option<&Acl> {
+        self.sacl.as_ref()
+    }
+
+    /// The discretionary ACL. When the descriptor has [`Control::DaclPresent`] but no DACL,
+    /// the DACL is null and everyone has full access.
+    #[inline]
+    pub fn dacl(&self) -> Option<&Acl> {
+        self.dacl.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::offline::fixture::SECURITY_DESCRIPTOR;
+
+    #[test]
+    fn parses_descriptor() {
+        let sd = SecurityDescriptor::from_bytes(SECURITY_DESCRIPTOR).unwrap();
+        assert_eq!(sd.control(), Control::SelfRelative | Control::DaclPresent);
+        assert_eq!(sd.owner().unwrap().to_string(), "S-1-5-32-544");
+        assert_eq!(sd.group().unwrap().to_string(), "S-1-5-18");
+        assert!(sd.sacl().is_none());
+
+        let dacl = sd.dacl().unwrap();
+        assert_eq!(dacl.aces().len(), 2);
+        assert_eq!(dacl.aces()[0].ace_type(), AceType::AccessAllowed);
+        assert_eq!(dacl.aces()[0].flags(), AceFlags::ContainerInherit);
+        assert_eq!(dacl.aces()[0].security(), Security::AllAccess);
+        assert_eq!(dacl.aces()[1].sid().to_string(), "S-1-5-32-545");
+        assert_eq!(dacl.aces()[1].security(), Security::Read);
+
+        let users = Sid::new(5, vec![32, 545]);
+        assert_eq!(dacl.allowed(&users), Security::Read);
+        assert!(!dacl.allowed(&users).intersects(Security::SetValue));
+    }
+
+    #[test]
+    fn maps_generic_rights() {
+        let ace = Ace {
+            ace_type: AceType::AccessAllowed,
+            flags: AceFlags::empty(),
+            mask: GENERIC_WRITE,
+            sid: Sid::new(1, vec![0]),
+        };
+        assert_eq!(ace.security(), Security::Write);
+        assert_eq!(ace.sid().to_string(), "S-1-1-0");
+    }
+
+    #[test]
+    fn rejects_truncated() {
+        assert!(SecurityDescriptor::from_bytes(&SECURITY_DESCRIPTOR[..30]).is_err());
+        assert!(SecurityDescriptor::from_bytes(&[0u8; 4]).is_err());
+    }
+}
@@ -0,0 +1,447 @@
+//! Key trees as JSON, YAML or TOML documents, for keeping registry content in version
+//! control and reviewing changes to it.
+//!
+//! [`Tree`](../tree/struct.Tree.html) implements `Serialize` and `Deserialize` with the
+//! `serde` feature, and the `json`, `yaml` and `toml` features add functions for each
+//! format. Every value records its type, so a tree reads back with identical data:
+//!
+//! ```yaml
+//! values:
+//! - name: ''
+//!   type: REG_SZ
+//!   data: Contoso Tools
+//! - name: Count
+//!   type: REG_DWORD
+//!   data: 42
+//! keys:
+//!   Settings:
+//!     values:
+//!     - name: Servers
+//!       type: REG_MULTI_SZ
+//!       data:
+//!       - one.example.com
+//!       - two.example.com
+//! ```
+//!
+//! Binary data is written as a hex string, and quad words above `i64::MAX` as a `0x` prefixed
+//! hex string, as TOML has no larger integers. Strings that are not valid UTF-16 are written
+//! as `raw` hex bytes in place of `data`, as is `Data::Raw`, with a `0x` prefixed type code
+//! for types without a name. Last write times are not kept.
+//!
+//! A tree is exported from the registry with
+//! [`Tree::capture`](../tree/struct.Tree.html#method.capture) and imported by applying the
+//! operations from [`operation::recreate`](../operation/fn.recreate.html).
+
+use std::convert::TryInto;
+use std::fmt;
+
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use serde::ser::{SerializeMap, Serializer};
+use serde::{Deserialize, Serialize};
+use utfx::U16CString;
+
+use crate::tree::Tree;
+use crate::Data;
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    #[cfg(feature = "json")]
+    #[error("Invalid JSON document")]
+    Json(#[from] serde_json::Error),
+
+    #[cfg(feature = "yaml")]
+    #[error("Invalid YAML document")]
+    Yaml(#[from] serde_yaml::Error),
+
+    #[cfg(feature = "toml")]
+    #[error("Invalid TOML document")]
+    TomlDe(#[from] toml::de::Error),
+
+    #[cfg(feature = "toml")]
+    #[error("Error writing TOML document")]
+    TomlSer(#[from] toml::ser::Error),
+}
+
+#[cfg(feature = "json")]
+pub fn to_json(tree: &Tree) -> Result<String, Error> {
+    Ok(serde_json::to_string_pretty(tree)?)
+}
+
+#[cfg(feature = "json")]
+pub fn from_json(text: &str) -> Result<Tree, Error> {
+    Ok(serde_json::from_str(text)?)
+}
+
+#[cfg(feature = "yaml")]
+pub fn to_yaml(tree: &Tree) -> Result<String, Error> {
+    Ok(serde_yaml::to_string(tree)?)
+}
+
+#[cfg(feature = "yaml")]
+pub fn from_yaml(text: &str) -> Result<Tree, Error> {
+    Ok(serde_yaml::from_str(text)?)
+}
+
+#[cfg(feature = "toml")]
+pub fn to_toml(tree: &Tree) -> Result<String, Error> {
+    Ok(toml::to_string_pretty(tree)?)
+}
+
+#[cfg(feature = "toml")]
+pub fn from_toml(text: &str) -> Result<Tree, Error> {
+    Ok(toml::from_str(text)?)
+}
+
+/// The types as they are named in documents, by type code.
+const TYPES: [&str; 12] = [
+    "REG_NONE",
+    "REG_SZ",
+    "REG_EXPAND_SZ",
+    "REG_BINARY",
+    "REG_DWORD",
+    "REG_DWORD_BIG_ENDIAN",
+    "REG_LINK",
+    "REG_MULTI_SZ",
+    "REG_RESOURCE_LIST",
+    "REG_FULL_RESOURCE_DESCRIPTOR",
+    "REG_RESOURCE_REQUIREMENTS_LIST",
+    "REG_QWORD",
+];
+
+/// A key as it is written in a document.
+#[derive(Serialize, Deserialize)]
+struct Key {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    values: Vec<Value>,
+    #[serde(default, skip_serializing_if = "Keys::is_empty")]
+    keys: Keys,
+}
+
+/// Subkeys by name, kept in order.
+#[derive(Default)]
+struct Keys(Vec<(String, Key)>);
+
+impl Keys {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Serialize for Keys {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (name, key) in &self.0 {
+            map.serialize_entry(name, key)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Keys {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Keys, D::Error> {
+        struct KeysVisitor;
+
+        impl<'de> Visitor<'de> for KeysVisitor {
+            type Value = Keys;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a map of key names to keys")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Keys, A::Error> {
+                let mut keys = vec![];
+                while let Some(entry) = map.next_entry()? {
+                    keys.push(entry);
+                }
+                Ok(Keys(keys))
+            }
+        }
+
+        deserializer.deserialize_map(KeysVisitor)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Value {
+    name: String,
+    #[serde(rename = "type")]
+    ty: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data: Option<Payload>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    raw: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Payload {
+    Number(u64),
+    Text(String),
+    List(Vec<String>),
+}
+
+impl From<&Tree> for Key {
+    fn from(tree: &Tree) -> Key {
+        Key {
+            values: tree
+                .values()
+                .iter()
+                .map(|(name, data)| value(name, data))
+                .collect(),
+            keys: Keys(
+                tree.keys()
+                    .map(|(name, key)| (name.to_string(), Key::from(key)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+fn value(name: &str, data: &Data) -> Value {
+    let ty = match TYPES.get(data.ty() as usize) {
+        Some(x) => x.to_string(),
+        None => format!("{:#x}", data.ty()),
+    };
+    let text = |s: &U16CString| s.to_string().ok();
+
+    let payload = match data {
+        Data::String(s) | Data::ExpandString(s) => text(s).map(Payload::Text),
+        Data::MultiString(x) => x
+            .iter()
+            .map(text)
+            .collect::<Option<Vec<_>>>()
+            .map(Payload::List),
+        Data::Binary(x) => Some(Payload::Text(hex(x))),
+        Data::U32(x) | Data::U32BE(x) => Some(Payload::Number(u64::from(*x))),
+        Data::U64(x) if *x > i64::MAX as u64 => Some(Payload::Text(format!("{:#x}", x))),
+        Data::U64(x) => Some(Payload::Number(*x)),
+        Data::None
+        | Data::Link
+        | Data::ResourceList
+        | Data::FullResourceDescriptor
+        | Data::ResourceRequirementsList
+        | Data::Raw(..) => None,
+    };
+
+    // Only strings that are not valid UTF-16 are without a payload while having bytes.
+    let raw = match (&payload, data.to_bytes()) {
+        (None, bytes) if !bytes.is_empty() => Some(hex(&bytes)),
+        _ => None,
+    };
+
+    Value {
+        name: name.to_string(),
+        ty,
+        data: payload,
+        raw,
+    }
+}
+
+impl Key {
+    fn into_tree(self, path: &str) -> Result<Tree, String> {
+        let mut tree = Tree::new();
+        for value in self.values {
+            let data = value
+                .data()
+                .map_err(|e| format!("value {:?} of key {:?}: {}", value.name, path, e))?;
+            tree.set_value(&value.name, data);
+        }
+        for (name, key) in self.keys.0 {
+            let subtree = key.into_tree(&crate::operation::join(path, &name))?;
+            *tree.create_key(&name) = subtree;
+        }
+        Ok(tree)
+    }
+}
+
+impl Value {
+    fn data(&self) -> Result<Data, String> {
+        let ty = match TYPES.iter().position(|x| *x == self.ty) {
+            Some(x) => x as u32,
+            None => self
+                .ty
+                .strip_prefix("0x")
+                .and_then(|x| u32::from_str_radix(x, 16).ok())
+                .ok_or_else(|| format!("unknown type {:?}", self.ty))?,
+        };
+        let is_named = (ty as usize) < TYPES.len();
+
+        if let Some(raw) = &self.raw {
+            let bytes = unhex(raw).ok_or("invalid raw data")?;
+            if !is_named {
+                return Ok(Data::Raw(ty, bytes));
+            }
+            return Data::from_bytes(ty, &bytes).map_err(|e| e.to_string());
+        }
+
+        let string = |s: &str| -> Result<U16CString, String> {
+            s.try_into()
+                .map_err(|_| "string contains a null".to_string())
+        };
+        let number = |max: u64| match &self.data {
+            Some(Payload::Number(x)) if *x <= max => Ok(*x),
+            Some(Payload::Text(s)) => s
+                .strip_prefix("0x")
+                .and_then(|x| u64::from_str_radix(x, 16).ok())
+                .filter(|x| *x <= max)
+                .ok_or_else(|| "invalid number".to_string()),
+            _ => Err("expected a number".to_string()),
+        };
+
+        Ok(match (ty, &self.data) {
+            (1, Some(Payload::Text(s))) => Data::String(string(s)?),
+            (2, Some(Payload::Text(s))) => Data::ExpandString(string(s)?),
+            (7, Some(Payload::List(x))) => {
+                Data::MultiString(x.iter().map(|x| string(x)).collect::<Result<Vec<_>, _>>()?)
+            }
+            (3, Some(Payload::Text(s))) => Data::Binary(unhex(s).ok_or("invalid hex data")?),
+            (4, _) => Data::U32(number(u64::from(u32::MAX))? as u32),
+            (5, _) => Data::U32BE(number(u64::from(u32::MAX))? as u32),
+            (11, _) => Data::U64(number(u64::MAX)?),
+            (0, None) | (6, None) | (8, None) | (9, None) | (10, None) => {
+                Data::from_bytes(ty, &[]).map_err(|e| e.to_string())?
+            }
+            (_, None) if !is_named => Data::Raw(ty, vec![]),
+            _ => return Err(format!("unexpected data for {}", self.ty)),
+        })
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if s.len() & 1 != 0 || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Writes the tree in the document schema, leaving out last write times.
+impl Serialize for Tree {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Key::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Tree {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Tree, D::Error> {
+        Key::deserialize(deserializer)?
+            .into_tree("")
+            .map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Data {
+        Data::String(s.try_into().unwrap())
+    }
+
+    fn tree() -> Tree {
+        let mut tree = Tree::new();
+        tree.set_value("", string("Contoso Tools"));
+        tree.set_value("Count", Data::U32(42));
+        let key = tree.create_key("Settings");
+        key.set_value(
+            "Servers",
+            Data::MultiString(vec![
+                "one.example.com".try_into().unwrap(),
+                "two.example.com".try_into().unwrap(),
+            ]),
+        );
+        key.set_value("Path", Data::ExpandString("%TEMP%".try_into().unwrap()));
+        key.set_value("Blob", Data::Binary(vec![0xde, 0xad, 0xbe, 0xef]));
+        key.set_value("Empty", Data::MultiString(vec![]));
+        key.set_value("Big", Data::U32BE(7));
+        key.set_value("Small", Data::U64(1));
+        key.set_value("Huge", Data::U64(u64::MAX));
+        key.set_value("Nothing", Data::None);
+        key.set_value(
+            "Broken",
+            Data::String(U16CString::from_vec_with_nul(vec![0xd800, 0x41, 0]).unwrap()),
+        );
+        key.set_value("Link", Data::Raw(6, vec![0x41, 0]));
+        key.set_value("Custom", Data::Raw(0x100, vec![1]));
+        tree.create_key(r"Settings\Empty Key");
+        tree
+    }
+
+    #[test]
+    fn round_trips() {
+        let tree = tree();
+
+        #[cfg(feature = "json")]
+        assert_eq!(from_json(&to_json(&tree).unwrap()).unwrap(), tree);
+        #[cfg(feature = "yaml")]
+        assert_eq!(from_yaml(&to_yaml(&tree).unwrap()).unwrap(), tree);
+        #[cfg(feature = "toml")]
+        assert_eq!(from_toml(&to_toml(&tree).unwrap()).unwrap(), tree);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn writes_typed_values() {
+        let json = to_json(&tree()).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let settings = &value["keys"]["Settings"];
+
+        assert_eq!(
+            value["values"][1],
+            serde_json::json!({ "name": "Count", "type": "REG_DWORD", "data": 42 })
+        );
+        assert_eq!(settings["values"][2]["data"], "deadbeef");
+        assert_eq!(settings["values"][6]["data"], "0xffffffffffffffff");
+        assert_eq!(
+            settings["values"][7],
+            serde_json::json!({ "name": "Nothing", "type": "REG_NONE" })
+        );
+        assert_eq!(
+            settings["values"][8],
+            serde_json::json!({ "name": "Broken", "type": "REG_SZ", "raw": "00d841000000" })
+        );
+        assert_eq!(
+            settings["values"][10],
+            serde_json::json!({ "name": "Custom", "type": "0x100", "raw": "01" })
+        );
+        assert_eq!(settings["keys"], serde_json::json!({ "Empty Key": {} }));
+
+        let error =
+            from_json(r#"{ "values": [{ "name": "x", "type": "REG_DWORD", "data": "1" }] }"#)
+                .unwrap_err()
+                .to_string();
+        assert_eq!(error, "Invalid JSON document");
+        assert!(from_json(r#"{ "values": [{ "name": "x", "type": "REG_FOO" }] }"#).is_err());
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn writes_module_example() {
+        let mut tree = tree();
+        let settings = tree.key_mut("Settings").unwrap();
+        *settings = Tree::new();
+        settings.set_value(
+            "Servers",
+            Data::MultiString(vec![
+                "one.example.com".try_into().unwrap(),
+                "two.example.com".try_into().unwrap(),
+            ]),
+        );
+
+        let expected = include_str!("document.rs")
+            .lines()
+            .skip_while(|x| *x != "//! ```yaml")
+            .skip(1)
+            .take_while(|x| *x != "//! ```")
+            .map(|x| format!("{}\n", x.strip_prefix("//! ").unwrap_or("")))
+            .collect::<String>();
+        assert_eq!(to_yaml(&tree).unwrap(), expected);
+    }
+}
@@ -0,0 +1,312 @@
+//! Content hashes of key trees, for finding out cheaply whether a subtree has drifted from an
+//! approved state and, if it has, which branches changed.
+//!
+//! Every key has a [`Fingerprint`](struct.Fingerprint.html) covering its values and, through
+//! the fingerprints of its subkeys, everything below it, like a Merkle tree. Two keys with
+//! the same fingerprint hold the same content, so only the fingerprints of branches that
+//! differ need comparing to find what changed.
+//!
+//! Fingerprints do not depend on the order keys and values are stored in, or on the case of
+//! their names, which are compared upper cased as the registry compares them. Value types and
+//! data are included exactly, with the data of `REG_NONE`, link and resource values read as
+//! `Data::Raw` so that it is hashed byte for byte. Last write times are not included.
+//!
+//! The fingerprints are SHA-256 hashes, so they can be computed elsewhere:
+//!
+//! * A value is encoded as its upper cased name as UTF-16LE preceded by its length in code
+//!   units as a little endian `u32`, then its type as a little endian `u32`, then its data
+//!   preceded by its length in bytes as a little endian `u64`.
+//! * The values fingerprint of a key is the hash of `values\0` followed by its encoded
+//!   values, sorted by their upper cased names.
+//! * The fingerprint of a key is the hash of `key\0`, its values fingerprint, and then for
+//!   each subkey sorted by upper cased name, that name encoded as for values followed by the
+//!   subkey's fingerprint.
+//!
+//! ```
+//! use registry::{fingerprint::KeyFingerprint, tree::Tree, Data};
+//!
+//! let mut approved = Tree::new();
+//! approved.create_key(r"Contoso\Update").set_value("Enabled", Data::U32(1));
+//! approved.create_key(r"Contoso\Telemetry").set_value("Level", Data::U32(0));
+//!
+//! let mut current = approved.clone();
+//! current.create_key(r"contoso\telemetry").set_value("LEVEL", Data::U32(3));
+//!
+//! let approved = KeyFingerprint::of(&approved);
+//! let current = KeyFingerprint::of(&current);
+//! assert_ne!(approved.fingerprint(), current.fingerprint());
+//! assert_eq!(approved.drift(&current), vec![r"Contoso\Telemetry".to_string()]);
+//! ```
+
+use std::cmp::Ordering;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use sha2::{Digest, Sha256};
+
+use crate::operation::join;
+use crate::tree::{fold, KeySource, Tree};
+use crate::Data;
+
+/// A SHA-256 hash of registry content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Fingerprint([u8; 32]);
+
+impl Fingerprint {
+    #[inline]
+    pub fn from_bytes(bytes: [u8; 32]) -> Fingerprint {
+        Fingerprint(bytes)
+    }
+
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+/// Writes the fingerprint as lower case hex.
+impl Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid fingerprint; expected 64 hex digits")]
+pub struct ParseFingerprintError;
+
+impl FromStr for Fingerprint {
+    type Err = ParseFingerprintError;
+
+    fn from_str(s: &str) -> Result<Fingerprint, ParseFingerprintError> {
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(ParseFingerprintError);
+        }
+        let mut bytes = [0u8; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte =
+                u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).map_err(|_| ParseFingerprintError)?;
+        }
+        Ok(Fingerprint(bytes))
+    }
+}
+
+/// The fingerprints of a key and of every key below it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyFingerprint {
+    fingerprint: Fingerprint,
+    values: Fingerprint,
+    /// Subkeys sorted by upper cased name.
+    keys: Vec<(String, KeyFingerprint)>,
+}
+
+impl KeyFingerprint {
+    pub fn of(tree: &Tree) -> KeyFingerprint {
+        let mut values = tree
+            .values()
+            .iter()
+            .map(|(name, data)| (fold(name), data))
+            .collect::<Vec<_>>();
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut hasher = Sha256::new();
+        hasher.update(b"values\0");
+        for (name, data) in values {
+            hash_value(&mut hasher, &name, data);
+        }
+        let values = Fingerprint(hasher.finalize().into());
+
+        // Trees keep their keys sorted by upper cased name already.
+        let keys = tree
+            .keys()
+            .map(|(name, key)| (name.to_string(), KeyFingerprint::of(key)))
+            .collect::<Vec<_>>();
+
+        let mut hasher = Sha256::new();
+        hasher.update(b"key\0");
+        hasher.update(values.0);
+        for (name, key) in &keys {
+            hash_name(&mut hasher, &fold(name));
+            hasher.update(key.fingerprint.0);
+        }
+
+        KeyFingerprint {
+            fingerprint: Fingerprint(hasher.finalize().into()),
+            values,
+            keys,
+        }
+    }
+
+    /// Reads a key and everything below it from a source and fingerprints it.
+    pub fn capture<S: KeySource>(source: &S) -> Result<KeyFingerprint, S::Error> {
+        Ok(KeyFingerprint::of(&Tree::capture(source)?))
+    }
+
+    /// The fingerprint of the key and everything below it.
+    #[inline]
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.fingerprint
+    }
+
+    /// The fingerprint of the key's values alone.
+    #[inline]
+    pub fn values_fingerprint(&self) -> Fingerprint {
+        self.values
+    }
+
+    /// The immediate subkeys with their fingerprints, sorted the way the registry sorts them.
+    pub fn keys(&self) -> impl Iterator<Item = (&str, &KeyFingerprint)> {
+        self.keys.iter().map(|(name, key)| (name.as_str(), key))
+    }
+
+    /// Finds the fingerprints of a descendant by its path, ignoring case.
+    pub fn key(&self, path: &str) -> Option<&KeyFingerprint> {
+        let mut key = self;
+        for name in crate::tree::components(path) {
+            let name = fold(name);
+            key = key
+                .keys
+                .iter()
+                .find(|(x, _)| fold(x) == name)
+                .map(|(_, key)| key)?;
+        }
+        Some(key)
+    }
+
+    /// The paths of the branches that differ from `other`, relative to this key: keys whose
+    /// values differ, and keys present on only one side. Branches with matching fingerprints
+    /// are not descended into.
+    pub fn drift(&self, other: &KeyFingerprint) -> Vec<String> {
+        let mut paths = vec![];
+        drift(self, other, "", &mut paths);
+        paths
+    }
+}
+
+fn drift(a: &KeyFingerprint, b: &KeyFingerprint, path: &str, paths: &mut Vec<String>) {
+    if a.fingerprint == b.fingerprint {
+        return;
+    }
+    if a.values != b.values {
+        paths.push(path.to_string());
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < a.keys.len() || j < b.keys.len() {
+        let order = match (a.keys.get(i), b.keys.get(j)) {
+            (Some((x, _)), Some((y, _))) => fold(x).cmp(&fold(y)),
+            (Some(_), None) => Ordering::Less,
+            _ => Ordering::Greater,
+        };
+        match order {
+            Ordering::Less => {
+                paths.push(join(path, &a.keys[i].0));
+                i += 1;
+            }
+            Ordering::Greater => {
+                paths.push(join(path, &b.keys[j].0));
+                j += 1;
+            }
+            Ordering::Equal => {
+                let (name, key) = &a.keys[i];
+                drift(key, &b.keys[j].1, &join(path, name), paths);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+}
+
+fn hash_name(hasher: &mut Sha256, name: &[u16]) {
+    hasher.update((name.len() as u32).to_le_bytes());
+    for unit in name {
+        hasher.update(unit.to_le_bytes());
+    }
+}
+
+fn hash_value(hasher: &mut Sha256, name: &[u16], data: &Data) {
+    hash_name(hasher, name);
+    hasher.update(data.ty().to_le_bytes());
+    let bytes = data.to_bytes();
+    hasher.update((bytes.len() as u64).to_le_bytes());
+    hasher.update(&bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+
+    fn tree() -> Tree {
+        let mut tree = Tree::new();
+        tree.set_value("", Data::String("Contoso".try_into().unwrap()));
+        tree.set_value("Level", Data::U32(1));
+        tree.create_key(r"Update\Channel")
+            .set_value("Name", Data::String("stable".try_into().unwrap()));
+        tree.create_key("Telemetry");
+        tree
+    }
+
+    #[test]
+    fn ignores_order_and_case() {
+        let mut other = Tree::new();
+        other.create_key("TELEMETRY");
+        other
+            .create_key(r"update\channel")
+            .set_value("NAME", Data::String("stable".try_into().unwrap()));
+        other.set_value("level", Data::U32(1));
+        other.set_value("", Data::String("Contoso".try_into().unwrap()));
+
+        let a = KeyFingerprint::of(&tree());
+        let b = KeyFingerprint::of(&other);
+        assert_eq!(a.fingerprint(), b.fingerprint());
+        assert!(a.drift(&b).is_empty());
+
+        // A value's type is part of its content.
+        other.set_value("level", Data::U32BE(1));
+        assert_ne!(KeyFingerprint::of(&other).fingerprint(), a.fingerprint());
+
+        // So is the data of types without a variant holding it.
+        let raw = |x: u8| {
+            let mut tree = Tree::new();
+            tree.set_value("List", Data::Raw(8, vec![x]));
+            KeyFingerprint::of(&tree).fingerprint()
+        };
+        assert_ne!(raw(1), raw(2));
+
+        let text = a.fingerprint().to_string();
+        assert_eq!(text.len(), 64);
+        assert_eq!(text.parse::<Fingerprint>().unwrap(), a.fingerprint());
+        assert!("xyz".parse::<Fingerprint>().is_err());
+    }
+
+    #[test]
+    fn localises_drift() {
+        let approved = KeyFingerprint::of(&tree());
+
+        let mut current = tree();
+        current
+            .create_key(r"Update\Channel")
+            .set_value("Name", Data::String("beta".try_into().unwrap()));
+        current.remove_key("Telemetry");
+        current.create_key(r"Update\Proxy");
+        let current = KeyFingerprint::of(&current);
+
+        assert_eq!(
+            approved.drift(&current),
+            vec![r"Telemetry", r"Update\Channel", r"Update\Proxy"]
+        );
+        assert_eq!(
+            approved.key("update").unwrap().values_fingerprint(),
+            current.key("Update").unwrap().values_fingerprint()
+        );
+        assert_ne!(
+            approved.key("update").unwrap().fingerprint(),
+            current.key("Update").unwrap().fingerprint()
+        );
+    }
+}
@@ -0,0 +1,309 @@
+//! Declaring registry trees for tests, writing them into any
+//! [`KeyTarget`](../tree/trait.KeyTarget.html) and checking a subtree against an expected one.
+//!
+//! [`reg_tree!`](../macro.reg_tree.html) builds a [`Tree`](../tree/struct.Tree.html) from
+//! nested names, where a name followed by braces is a key and one followed by anything else
+//! is a value converted with [`IntoData`](trait.IntoData.html).
+//!
+//! ```
+//! use std::sync::Arc;
+//! use registry::{backend::{self, Memory}, fixture, reg_tree, Hive, Security};
+//!
+//! # let _scope = backend::scoped(Arc::new(Memory::new()));
+//! let tree = reg_tree! {
+//!     r"Software\Contoso" => {
+//!         "Version" => 3u32,
+//!         "Paths" => ["a", "b"],
+//!         "Sub" => { "" => "default" },
+//!     },
+//! };
+//!
+//! let mut root = Hive::CurrentUser.create("Fixture", Security::AllAccess).unwrap();
+//! fixture::write(&mut root, &tree).unwrap();
+//!
+//! let key = root.open(r"Software\Contoso", Security::Read).unwrap();
+//! fixture::assert_matches(&key, &reg_tree! {
+//!     "Version" => 3u32,
+//!     "Paths" => ["a", "b"],
+//!     "Sub" => { "" => "default" },
+//! });
+//! ```
+
+use std::convert::TryInto;
+use std::fmt::{Debug, Write};
+
+use crate::operation::{self, Operation};
+use crate::snapshot::{Change, Snapshot};
+use crate::tree::{KeySource, KeyTarget, Tree};
+use crate::Data;
+
+/// Builds a [`Tree`](tree/struct.Tree.html) from nested `name => ...` entries.
+///
+/// An entry whose right side is in braces is a key, and its name may be a backslash separated
+/// path. Any other entry is a value, converted with
+/// [`IntoData`](fixture/trait.IntoData.html). See the [`fixture`](fixture/index.html) module.
+#[macro_export]
+macro_rules! reg_tree {
+    (@key $tree:ident) => {};
+    (@key $tree:ident $name:expr => { $($key:tt)* } $(, $($rest:tt)*)?) => {
+        {
+            #[allow(unused_variables)]
+            let key = $tree.create_key($name);
+            $crate::reg_tree!(@key key $($key)*);
+        }
+        $crate::reg_tree!(@key $tree $($($rest)*)?);
+    };
+    (@key $tree:ident $name:expr => $value:expr $(, $($rest:tt)*)?) => {
+        $tree.set_value($name, $crate::fixture::IntoData::into_data($value));
+        $crate::reg_tree!(@key $tree $($($rest)*)?);
+    };
+    ($($entries:tt)*) => {{
+        #[allow(unused_mut)]
+        let mut tree = $crate::tree::Tree::new();
+        $crate::reg_tree!(@key tree $($entries)*);
+        tree
+    }};
+}
+
+/// Conversion of the values written in [`reg_tree!`](../macro.reg_tree.html) into `Data`.
+///
+/// Strings become `Data::String`, lists of strings `Data::MultiString`, bytes
+/// `Data::Binary`, `u32` `Data::U32` and `u64` `Data::U64`. Any other type can be given as
+/// `Data` directly.
+pub trait IntoData {
+    /// Converts the value, panicking if a string contains a nul.
+    fn into_data(self) -> Data;
+}
+
+impl IntoData for Data {
+    fn into_data(self) -> Data {
+        self
+    }
+}
+
+impl IntoData for u32 {
+    fn into_data(self) -> Data {
+        Data::U32(self)
+    }
+}
+
+impl IntoData for u64 {
+    fn into_data(self) -> Data {
+        Data::U64(self)
+    }
+}
+
+impl IntoData for &str {
+    fn into_data(self) -> Data {
+        Data::String(string(self))
+    }
+}
+
+impl IntoData for String {
+    fn into_data(self) -> Data {
+        self.as_str().into_data()
+    }
+}
+
+impl IntoData for &[&str] {
+    fn into_data(self) -> Data {
+        Data::MultiString(self.iter().map(|x| string(x)).collect())
+    }
+}
+
+impl<const N: usize> IntoData for [&str; N] {
+    fn into_data(self) -> Data {
+        self[..].into_data()
+    }
+}
+
+impl IntoData for Vec<&str> {
+    fn into_data(self) -> Data {
+        self[..].into_data()
+    }
+}
+
+impl IntoData for Vec<String> {
+    fn into_data(self) -> Data {
+        Data::MultiString(self.iter().map(|x| string(x)).collect())
+    }
+}
+
+impl IntoData for &[u8] {
+    fn into_data(self) -> Data {
+        Data::Binary(self.to_vec())
+    }
+}
+
+impl<const N: usize> IntoData for [u8; N] {
+    fn into_data(self) -> Data {
+        Data::Binary(self.to_vec())
+    }
+}
+
+impl IntoData for Vec<u8> {
+    fn into_data(self) -> Data {
+        Data::Binary(self)
+    }
+}
+
+fn string(s: &str) -> utfx::U16CString {
+    match s.try_into() {
+        Ok(s) => s,
+        Err(_) => panic!("registry string contains a nul: {:?}", s),
+    }
+}
+
+/// Writes a tree into a target, creating its keys and setting its values. Anything already in
+/// the target is left in place.
+pub fn write<T: KeyTarget>(target: &mut T, tree: &Tree) -> Result<(), T::Error> {
+    operation::recreate("", tree)
+        .iter()
+        .try_for_each(|x| operation::execute(target, x))
+}
+
+/// Panics with the differences if a key and everything below it does not hold exactly the
+/// keys and values of `expected`, ignoring case in names and last write times.
+///
+/// Each difference is shown on its own line, with `-` for what was expected but is missing or
+/// different, and `+` for what was found instead.
+#[track_caller]
+pub fn assert_matches<S>(actual: &S, expected: &Tree)
+where
+    S: KeySource,
+    S::Error: Debug,
+{
+    let actual = match Snapshot::capture(actual, "") {
+        Ok(x) => x.without_times(),
+        Err(e) => panic!("could not read registry tree: {:?}", e),
+    };
+    let diff = Snapshot::new("", expected.clone())
+        .without_times()
+        .diff(&actual);
+    if diff.is_empty() {
+        return;
+    }
+
+    let mut message = String::from("registry tree does not match (- expected, + actual):\n");
+    for change in diff.changes() {
+        render(change, &mut message);
+    }
+    panic!("{}", message);
+}
+
+fn render(change: &Change, out: &mut String) {
+    fn key(path: &str) -> &str {
+        if path.is_empty() {
+            "<root>"
+        } else {
+            path
+        }
+    }
+
+    fn value(sign: char, path: &str, name: &str, data: &Data, out: &mut String) {
+        let name = if name.is_empty() { "@" } else { name };
+        let _ = writeln!(out, "{} {}: {:?} = {:?}", sign, key(path), name, data);
+    }
+
+    match change {
+        Change::AddKey { path } => {
+            let _ = writeln!(out, "+ [{}]", key(path));
+        }
+        Change::RemoveKey { path, old } => {
+            let _ = writeln!(out, "- [{}]", key(path));
+            // What the missing key should have held follows it.
+            for operation in operation::recreate(path, old).iter().skip(1) {
+                match operation {
+                    Operation::CreateKey { path } => {
+                        let _ = writeln!(out, "- [{}]", path);
+                    }
+                    Operation::SetValue { path, name, data } => value('-', path, name, data, out),
+                    _ => {}
+                }
+            }
+        }
+        Change::ModifyKey { .. } => {}
+        Change::AddValue { path, name, data } => value('+', path, name, data, out),
+        Change::RemoveValue { path, name, data } => value('-', path, name, data, out),
+        Change::ModifyValue {
+            path,
+            name,
+            old,
+            new,
+        } => {
+            value('-', path, name, old, out);
+            value('+', path, name, new, out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::backend::{self, Memory};
+    use crate::{Hive, Security};
+
+    #[test]
+    fn builds_trees() {
+        let tree = reg_tree! {
+            r"Software\Contoso" => {
+                "Version" => 3u32,
+                "Paths" => ["a", "b"],
+                "Blob" => vec![1u8, 2],
+                "Sub" => {},
+            },
+            "Empty" => {},
+        };
+
+        let key = tree.key(r"software\contoso").unwrap();
+        assert_eq!(key.value("version"), Some(&Data::U32(3)));
+        assert_eq!(key.value("Paths"), Some(&["a", "b"].into_data()));
+        assert_eq!(key.value("Blob"), Some(&Data::Binary(vec![1, 2])));
+        assert!(key.key("Sub").is_some());
+        assert!(tree.key("Empty").is_some());
+        assert_eq!(reg_tree! {}, Tree::new());
+    }
+
+    #[test]
+    fn writes_into_keys() {
+        let _scope = backend::scoped(Arc::new(Memory::new()));
+        let tree = reg_tree! {
+            "Contoso" => { "" => "default", "Level" => 2u64 },
+        };
+
+        let mut root = Hive::CurrentUser
+            .create("Fixture", Security::AllAccess)
+            .unwrap();
+        write(&mut root, &tree).unwrap();
+        assert_matches(&root, &tree);
+
+        let key = root.open("Contoso", Security::Read).unwrap();
+        assert_eq!(key.value("Level").unwrap(), Data::U64(2));
+    }
+
+    #[test]
+    fn reports_differences() {
+        let expected = reg_tree! {
+            "Contoso" => { "Version" => 3u32, "Sub" => { "Name" => "x" } },
+        };
+        let actual = reg_tree! {
+            "Contoso" => { "Version" => 4u32, "Extra" => {} },
+        };
+
+        let message = std::panic::catch_unwind(|| assert_matches(&&actual, &expected))
+            .unwrap_err()
+            .downcast::<String>()
+            .unwrap();
+        assert_eq!(
+            *message,
+            "registry tree does not match (- expected, + actual):\n\
+             - Contoso: \"Version\" = U32(3)\n\
+             + Contoso: \"Version\" = U32(4)\n\
+             - [Contoso\\Sub]\n\
+             - Contoso\\Sub: \"Name\" = String(\"x\")\n\
+             + [Contoso\\Extra]\n"
+        );
+    }
+}
@@ -1,14 +1,13 @@
-use std::{convert::TryInto, fmt::Display};
+use std::{convert::TryInto, fmt::Display, sync::Arc};
 
-use utfx::{U16CStr, U16CString};
-use windows::core::PCWSTR;
-use windows::Win32::System::Registry::{RegLoadAppKeyW, HKEY, HKEY_CLASSES_ROOT, HKEY_CURRENT_CONFIG, HKEY_CURRENT_USER, HKEY_CURRENT_USER_LOCAL_SETTINGS, HKEY_LOCAL_MACHINE, HKEY_PERFORMANCE_DATA, HKEY_USERS};
+use utfx::U16CString;
 
-use crate::key::{self, Error};
+use crate::backend::{self, Backend, Handle};
+use crate::key::Error;
 use crate::{sec::Security, RegKey};
 
 /// All hives of the Windows Registry. Start here to get to a registry key.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 #[non_exhaustive]
 pub enum Hive {
     ClassesRoot,
@@ -24,17 +23,13 @@ pub enum Hive {
 }
 
 impl Hive {
+    /// The current backend, with its handle for this hive.
     #[inline]
-    fn as_hkey(&self) -> HKEY {
-        match self {
-            Hive::ClassesRoot => HKEY_CLASSES_ROOT,
-            Hive::CurrentConfig => HKEY_CURRENT_CONFIG,
-            Hive::CurrentUser => HKEY_CURRENT_USER,
-            Hive::CurrentUserLocalSettings => HKEY_CURRENT_USER_LOCAL_SETTINGS,
-            Hive::LocalMachine => HKEY_LOCAL_MACHINE,
-            Hive::PerformanceData => HKEY_PERFORMANCE_DATA,
-            Hive::Users => HKEY_USERS,
-            Hive::Application => panic!("as_hkey must not be called for Application hives"),
+    fn root(&self, path: &U16CString) -> Result<(Arc<dyn Backend>, Handle), Error> {
+        let backend = backend::current();
+        match backend.predefined(*self) {
+            Ok(handle) => Ok((backend, handle)),
+            Err(code) => Err(Error::from_code(code, path.to_string_lossy())),
         }
     }
 
@@ -45,11 +40,16 @@ impl Hive {
         P::Error: Into<Error>,
     {
         let path = path.try_into().map_err(Into::into)?;
-        key::open_hkey(self.as_hkey(), &path, sec).map(|handle| RegKey {
-            hive: *self,
-            handle,
-            path,
-        })
+        let (backend, root) = self.root(&path)?;
+        match backend.open_key(root, &path, sec) {
+            Ok(handle) => Ok(RegKey {
+                hive: *self,
+                handle,
+                path,
+                backend,
+            }),
+            Err(code) => Err(Error::from_code(code, path.to_string_lossy())),
+        }
     }
 
     #[inline]
@@ -59,7 +59,10 @@ impl Hive {
         P::Error: Into<Error>,
     {
         let path = file_path.try_into().map_err(Into::into)?;
-        key::save_hkey(self.as_hkey(), &path)
+        let (backend, root) = self.root(&path)?;
+        backend
+            .save_key(root, &path)
+            .map_err(|code| Error::from_code(code, path.to_string_lossy()))
     }
 
     #[inline]
@@ -69,11 +72,16 @@ impl Hive {
         P::Error: Into<Error>,
     {
         let path = path.try_into().map_err(Into::into)?;
-        key::create_hkey(self.as_hkey(), &path, sec).map(|handle| RegKey {
-            hive: *self,
-            handle,
-            path,
-        })
+        let (backend, root) = self.root(&path)?;
+        match backend.create_key(root, &path, sec) {
+            Ok(handle) => Ok(RegKey {
+                hive: *self,
+                handle,
+                path,
+                backend,
+            }),
+            Err(code) => Err(Error::from_code(code, path.to_string_lossy())),
+        }
     }
 
     #[inline]
@@ -83,7 +91,10 @@ impl Hive {
         P::Error: Into<Error>,
     {
         let path = path.try_into().map_err(Into::into)?;
-        key::delete_hkey(self.as_hkey(), path, is_recursive)
+        let (backend, root) = self.root(&path)?;
+        backend
+            .delete_key(root, &path, is_recursive)
+            .map_err(|code| Error::from_code(code, path.to_string_lossy()))
     }
 
     #[inline]
@@ -97,12 +108,13 @@ impl Hive {
                 format!("No hive found at path: {:?}", file_path.as_ref()),
             ));
         }
-        let path = U16CString::from_os_str(file_path.as_ref().as_os_str())
-            .expect("Path must always be UTF-16 on Windows");
-        load_appkey(&path, sec).map(|handle| RegKey {
+        let backend = backend::current();
+        let handle = backend.load_app_key(file_path.as_ref(), sec)?;
+        Ok(RegKey {
             hive: Hive::Application,
             handle,
             path: "".try_into().unwrap(),
+            backend,
         })
     }
 }
@@ -121,22 +133,3 @@ impl Display for Hive {
         })
     }
 }
-
-#[inline]
-pub(crate) fn load_appkey<P>(path: P, sec: Security) -> Result<HKEY, std::io::Error>
-where
-    P: AsRef<U16CStr>,
-{
-    let path = path.as_ref();
-    let mut hkey = HKEY::default();
-    let result = unsafe {
-        RegLoadAppKeyW(PCWSTR(path.as_ptr()), &mut hkey, sec.bits(), 0, 0)
-    };
-
-    if result.is_ok() {
-        return Ok(hkey);
-    }
-    else {
-        Err(std::io::Error::from_raw_os_error(result.0 as i32))
-    }
-}
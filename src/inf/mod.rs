@@ -0,0 +1,776 @@
+//! Reading the registry changes of INF files, as used by driver packages and legacy
+//! installers, from their `AddReg` and `DelReg` sections.
+//!
+//! Each line of such a section names a root such as `HKLM` or `HKR`, a subkey, and usually a
+//! value, with `FLG_ADDREG_*` flags giving the type of the data and how it is applied.
+//! `%token%`s are replaced from the `[Strings]` section. The lines are parsed into
+//! [`Entry`](enum.Entry.html)s, which [`to_operations`](fn.to_operations.html) turns into
+//! [`Operation`](../operation/enum.Operation.html)s.
+//!
+//! ```
+//! use registry::{inf::{Entry, InfFile, Mode, Root}, Data};
+//!
+//! let file = InfFile::parse(r#"
+//! [DefaultInstall]
+//! AddReg = Contoso.AddReg
+//!
+//! [Contoso.AddReg]
+//! HKLM, "Software\%Company%", Version, 0x00010001, 2
+//!
+//! [Strings]
+//! Company = "Contoso"
+//! "#)?;
+//!
+//! assert_eq!(
+//!     file.install("DefaultInstall")?,
+//!     vec![Entry::SetValue {
+//!         root: Root::LocalMachine,
+//!         key: r"Software\Contoso".into(),
+//!         name: "Version".into(),
+//!         data: Data::U32(2),
+//!         mode: Mode::Overwrite,
+//!     }]
+//! );
+//! # Ok::<(), registry::inf::Error>(())
+//! ```
+
+use utfx::U16CString;
+
+use crate::operation::{execute, join, DryRun, Operation};
+use crate::tree::{fold, KeyTarget};
+use crate::Data;
+
+mod parse;
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("Syntax error on line {0}: {1}")]
+    Syntax(usize, &'static str),
+
+    #[error("Section not found: {0}")]
+    SectionNotFound(String),
+
+    #[error("Unknown registry root {1:?} on line {0}")]
+    InvalidRoot(usize, String),
+
+    #[error("Unsupported value type {1:#x} on line {0}")]
+    UnsupportedType(usize, u32),
+
+    #[error("Invalid value data on line {0}")]
+    Data(usize, #[source] crate::value::Error),
+}
+
+const FLG_ADDREG_BINVALUETYPE: u32 = 0x0000_0001;
+const FLG_ADDREG_NOCLOBBER: u32 = 0x0000_0002;
+const FLG_ADDREG_DELVAL: u32 = 0x0000_0004;
+const FLG_ADDREG_APPEND: u32 = 0x0000_0008;
+const FLG_ADDREG_KEYONLY: u32 = 0x0000_0010;
+const FLG_ADDREG_OVERWRITEONLY: u32 = 0x0000_0020;
+const FLG_ADDREG_KEYONLY_COMMON: u32 = 0x0000_2000;
+const FLG_ADDREG_DELREG_BIT: u32 = 0x0000_8000;
+const FLG_ADDREG_TYPE_MASK: u32 = 0xffff_0000 | FLG_ADDREG_BINVALUETYPE;
+const FLG_ADDREG_TYPE_SZ: u32 = 0x0000_0000;
+const FLG_ADDREG_TYPE_MULTI_SZ: u32 = 0x0001_0000;
+const FLG_ADDREG_TYPE_EXPAND_SZ: u32 = 0x0002_0000;
+const FLG_ADDREG_TYPE_BINARY: u32 = FLG_ADDREG_BINVALUETYPE;
+const FLG_ADDREG_TYPE_DWORD: u32 = 0x0001_0001;
+const FLG_ADDREG_TYPE_NONE: u32 = 0x0002_0001;
+const FLG_DELREG_MULTI_SZ_DELSTRING: u32 = 0x0001_8002;
+
+/// The root a line's subkey is relative to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Root {
+    /// `HKCR`
+    ClassesRoot,
+    /// `HKCU`
+    CurrentUser,
+    /// `HKLM`
+    LocalMachine,
+    /// `HKU`
+    Users,
+    /// `HKR`: a key given by the context the INF is installed in, such as a device's
+    /// driver key.
+    Relative,
+}
+
+impl Root {
+    fn parse(s: &str) -> Option<Root> {
+        Some(match s.to_ascii_uppercase().as_str() {
+            "HKCR" => Root::ClassesRoot,
+            "HKCU" => Root::CurrentUser,
+            "HKLM" => Root::LocalMachine,
+            "HKU" => Root::Users,
+            "HKR" => Root::Relative,
+            _ => return None,
+        })
+    }
+
+    /// The abbreviation the root is written as, such as `HKLM`.
+    pub fn abbreviation(&self) -> &'static str {
+        match self {
+            Root::ClassesRoot => "HKCR",
+            Root::CurrentUser => "HKCU",
+            Root::LocalMachine => "HKLM",
+            Root::Users => "HKU",
+            Root::Relative => "HKR",
+        }
+    }
+
+    /// The name of the predefined hive the root stands for, or `None` for `HKR`.
+    pub fn hive_name(&self) -> Option<&'static str> {
+        Some(match self {
+            Root::ClassesRoot => "HKEY_CLASSES_ROOT",
+            Root::CurrentUser => "HKEY_CURRENT_USER",
+            Root::LocalMachine => "HKEY_LOCAL_MACHINE",
+            Root::Users => "HKEY_USERS",
+            Root::Relative => return None,
+        })
+    }
+}
+
+/// How a value is set, from the flags of its `AddReg` line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Mode {
+    /// Sets the value whether or not it exists.
+    Overwrite,
+    /// `FLG_ADDREG_NOCLOBBER`: sets the value only if it does not exist.
+    NoClobber,
+    /// `FLG_ADDREG_OVERWRITEONLY`: sets the value only if it already exists.
+    OverwriteOnly,
+    /// `FLG_ADDREG_APPEND`: adds the strings of a multi-string to an existing multi-string
+    /// value, skipping those it already holds.
+    Append,
+}
+
+/// A line of an `AddReg` or `DelReg` section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    /// Creates a key, from a line without a value or with `FLG_ADDREG_KEYONLY`.
+    CreateKey { root: Root, key: String },
+
+    SetValue {
+        root: Root,
+        key: String,
+        name: String,
+        data: Data,
+        mode: Mode,
+    },
+
+    DeleteValue {
+        root: Root,
+        key: String,
+        name: String,
+    },
+
+    /// `FLG_DELREG_MULTI_SZ_DELSTRING`: removes a string from a multi-string value.
+    DeleteString {
+        root: Root,
+        key: String,
+        name: String,
+        string: String,
+    },
+
+    /// Deletes a key and its subkeys, from a deletion without a value name.
+    DeleteKey { root: Root, key: String },
+}
+
+impl Entry {
+    /// The root this entry's key is relative to.
+    pub fn root(&self) -> Root {
+        match self {
+            Entry::CreateKey { root, .. }
+            | Entry::SetValue { root, .. }
+            | Entry::DeleteValue { root, .. }
+            | Entry::DeleteString { root, .. }
+            | Entry::DeleteKey { root, .. } => *root,
+        }
+    }
+
+    /// The path of the key this entry applies to, relative to its root.
+    pub fn key(&self) -> &str {
+        match self {
+            Entry::CreateKey { key, .. }
+            | Entry::SetValue { key, .. }
+            | Entry::DeleteValue { key, .. }
+            | Entry::DeleteString { key, .. }
+            | Entry::DeleteKey { key, .. } => key,
+        }
+    }
+}
+
+/// A line of a section, split into its fields.
+#[derive(Debug, Clone)]
+struct Line {
+    number: usize,
+    key: Option<String>,
+    fields: Vec<String>,
+}
+
+/// The sections of an INF file, with its `[Strings]` kept for substitution.
+#[derive(Debug, Clone)]
+pub struct InfFile {
+    sections: Vec<(String, Vec<Line>)>,
+    strings: Vec<(String, String)>,
+}
+
+impl InfFile {
+    /// Parses the text of an INF file.
+    pub fn parse(text: &str) -> Result<InfFile, Error> {
+        parse::parse(text)
+    }
+
+    /// Parses an INF file from its raw bytes, which may be UTF-16LE or UTF-8 with a byte
+    /// order mark, or otherwise UTF-8 or Windows-1252.
+    pub fn from_bytes(bytes: &[u8]) -> Result<InfFile, Error> {
+        InfFile::parse(&crate::regfile::decode(bytes))
+    }
+
+    /// The names of the sections, in the order they first appear.
+    pub fn sections(&self) -> impl Iterator<Item = &str> {
+        self.sections.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// The value of a `[Strings]` token, matched ignoring case.
+    pub fn string(&self, token: &str) -> Option<&str> {
+        self.strings
+            .iter()
+            .rev()
+            .find(|(x, _)| x.eq_ignore_ascii_case(token))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The entries of the `DelReg` and then the `AddReg` sections an install section names,
+    /// in the order SetupAPI processes them.
+    pub fn install(&self, section: &str) -> Result<Vec<Entry>, Error> {
+        let lines = self.section(section)?;
+        let names = |directive: &str| {
+            lines
+                .iter()
+                .filter(move |line| {
+                    line.key
+                        .as_deref()
+                        .is_some_and(|x| x.eq_ignore_ascii_case(directive))
+                })
+                .flat_map(|line| line.fields.iter())
+                .map(|x| self.substitute(x))
+                .filter(|x| !x.is_empty())
+                .collect::<Vec<_>>()
+        };
+
+        let mut entries = vec![];
+        for name in names("DelReg") {
+            entries.extend(self.del_reg(&name)?);
+        }
+        for name in names("AddReg") {
+            entries.extend(self.add_reg(&name)?);
+        }
+        Ok(entries)
+    }
+
+    /// The entries of an `AddReg` section. Lines with `FLG_ADDREG_DELVAL` are deletions.
+    pub fn add_reg(&self, section: &str) -> Result<Vec<Entry>, Error> {
+        self.entries(section, false)
+    }
+
+    /// The entries of a `DelReg` section.
+    pub fn del_reg(&self, section: &str) -> Result<Vec<Entry>, Error> {
+        self.entries(section, true)
+    }
+
+    fn section(&self, name: &str) -> Result<&[Line], Error> {
+        self.sections
+            .iter()
+            .find(|(x, _)| x.eq_ignore_ascii_case(name))
+            .map(|(_, lines)| lines.as_slice())
+            .ok_or_else(|| Error::SectionNotFound(name.to_string()))
+    }
+
+    fn entries(&self, section: &str, delete: bool) -> Result<Vec<Entry>, Error> {
+        let mut entries = vec![];
+        for line in self.section(section)? {
+            let fields = line
+                .fields
+                .iter()
+                .map(|x| self.substitute(x))
+                .collect::<Vec<_>>();
+            if let Some(entry) = self.entry(line.number, &fields, delete)? {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Interprets the fields of a line: `root, [subkey], [value name], [flags], [data...]`.
+    /// Returns `None` for lines SetupAPI skips.
+    fn entry(
+        &self,
+        number: usize,
+        fields: &[String],
+        delete: bool,
+    ) -> Result<Option<Entry>, Error> {
+        let root =
+            Root::parse(&fields[0]).ok_or_else(|| Error::InvalidRoot(number, fields[0].clone()))?;
+        let key = fields.get(1).cloned().unwrap_or_default();
+        let name = fields.get(2).cloned();
+        let mut flags = match fields.get(3).map(String::as_str) {
+            None | Some("") => 0,
+            Some(x) => parse_number(x).ok_or(Error::Syntax(number, "invalid flags"))?,
+        };
+        let data = fields.get(4..).unwrap_or_default();
+
+        // Lines of the other kind of section are skipped.
+        if delete {
+            if flags == 0 {
+                flags = FLG_ADDREG_DELREG_BIT;
+            } else if flags & FLG_ADDREG_DELREG_BIT == 0 {
+                return Ok(None);
+            }
+        } else if flags & FLG_ADDREG_DELREG_BIT != 0 {
+            return Ok(None);
+        }
+
+        if flags & (FLG_ADDREG_DELREG_BIT | FLG_ADDREG_DELVAL) != 0 {
+            return Ok(Some(match name {
+                Some(name) if flags & FLG_ADDREG_KEYONLY_COMMON == 0 => {
+                    if flags & FLG_DELREG_MULTI_SZ_DELSTRING == FLG_DELREG_MULTI_SZ_DELSTRING {
+                        Entry::DeleteString {
+                            root,
+                            key,
+                            name,
+                            string: data.first().cloned().unwrap_or_default(),
+                        }
+                    } else {
+                        Entry::DeleteValue { root, key, name }
+                    }
+                }
+                _ => Entry::DeleteKey { root, key },
+            }));
+        }
+
+        if name.is_none() || flags & (FLG_ADDREG_KEYONLY | FLG_ADDREG_KEYONLY_COMMON) != 0 {
+            return Ok(Some(Entry::CreateKey { root, key }));
+        }
+
+        let ty = match flags & FLG_ADDREG_TYPE_MASK {
+            FLG_ADDREG_TYPE_SZ => 1,
+            FLG_ADDREG_TYPE_MULTI_SZ => 7,
+            FLG_ADDREG_TYPE_EXPAND_SZ => 2,
+            FLG_ADDREG_TYPE_BINARY => 3,
+            FLG_ADDREG_TYPE_DWORD => 4,
+            FLG_ADDREG_TYPE_NONE => 0,
+            _ => flags >> 16,
+        };
+        let from_bytes = |bytes: &[u8]| match Data::from_bytes(ty, bytes) {
+            Ok(data) => Ok(data),
+            Err(crate::value::Error::UnhandledType(ty)) => Err(Error::UnsupportedType(number, ty)),
+            Err(e) => Err(Error::Data(number, e)),
+        };
+
+        // Data is text unless the flags say it is binary, though a dword given as a single
+        // field is a number either way.
+        let data = if flags & FLG_ADDREG_BINVALUETYPE == 0 || (ty == 4 && data.len() == 1) {
+            match ty {
+                7 => Data::MultiString(data.iter().map(|x| wide(x)).collect()),
+                4 => {
+                    let value = match data.first().map(String::as_str) {
+                        None | Some("") => 0,
+                        Some(x) => {
+                            parse_number(x).ok_or(Error::Syntax(number, "invalid number"))?
+                        }
+                    };
+                    Data::U32(value)
+                }
+                _ => {
+                    let s = data.first().map(String::as_str).unwrap_or_default();
+                    let bytes = wide(s)
+                        .into_vec_with_nul()
+                        .iter()
+                        .flat_map(|x| x.to_le_bytes().to_vec())
+                        .collect::<Vec<_>>();
+                    from_bytes(&bytes)?
+                }
+            }
+        } else {
+            let bytes = data
+                .iter()
+                .filter(|x| !x.is_empty())
+                .map(|x| u8::from_str_radix(x, 16))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| Error::Syntax(number, "invalid hex byte"))?;
+            from_bytes(&bytes)?
+        };
+
+        let mode = if flags & FLG_ADDREG_NOCLOBBER != 0 {
+            Mode::NoClobber
+        } else if flags & FLG_ADDREG_OVERWRITEONLY != 0 {
+            Mode::OverwriteOnly
+        } else if flags & FLG_ADDREG_APPEND != 0 && ty == 7 {
+            Mode::Append
+        } else {
+            Mode::Overwrite
+        };
+
+        Ok(Some(Entry::SetValue {
+            root,
+            key,
+            name: name.unwrap_or_default(),
+            data,
+            mode,
+        }))
+    }
+
+    /// Replaces `%token%`s from the `[Strings]` section, and `%%` with a percent sign. Tokens
+    /// that are not defined, such as directory ids, are left as they are.
+    fn substitute(&self, s: &str) -> String {
+        let mut out = String::new();
+        let mut rest = s;
+
+        while let Some(start) = rest.find('%') {
+            out.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let end = match after.find('%') {
+                Some(end) => end,
+                None => {
+                    rest = &rest[start..];
+                    break;
+                }
+            };
+
+            let token = &after[..end];
+            match token {
+                "" => out.push('%'),
+                token => match self.string(token) {
+                    Some(value) => out.push_str(value),
+                    None => {
+                        out.push('%');
+                        out.push_str(token);
+                        out.push('%');
+                    }
+                },
+            }
+            rest = &after[end + 1..];
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+/// Converts entries to operations, with paths under the predefined hives and `HKR` replaced
+/// by `relative`.
+///
+/// Appending to multi-strings, removing strings from them and conditional sets depend on the
+/// values already present, which are read from `current` as changed by the preceding
+/// entries. Paths given to `current` are full paths such as
+/// `HKEY_LOCAL_MACHINE\Software\Contoso`, so a [`Tree`](../tree/struct.Tree.html) holding
+/// the hives as its keys serves. Appending to a value that is not a multi-string leaves it
+/// as it is.
+pub fn to_operations<T: KeyTarget>(
+    entries: &[Entry],
+    relative: &str,
+    current: &T,
+) -> Result<Vec<Operation>, T::Error> {
+    let mut state = DryRun::new(current);
+    let mut operations = vec![];
+
+    for entry in entries {
+        let path = join(entry.root().hive_name().unwrap_or(relative), entry.key());
+        let change = match entry {
+            Entry::CreateKey { .. } => Some(Operation::CreateKey { path }),
+            Entry::SetValue {
+                name, data, mode, ..
+            } => {
+                let existing = state.read_value(&path, name)?;
+                let data = match (mode, existing) {
+                    (Mode::Overwrite, _) => Some(data.clone()),
+                    (Mode::NoClobber, existing) => existing.map_or(Some(data.clone()), |_| None),
+                    (Mode::OverwriteOnly, existing) => existing.map(|_| data.clone()),
+                    (Mode::Append, None) => Some(data.clone()),
+                    (Mode::Append, Some(Data::MultiString(mut strings))) => {
+                        let mut changed = false;
+                        if let Data::MultiString(new) = data {
+                            for string in new {
+                                if !strings.iter().any(|x| same(x, string)) {
+                                    strings.push(string.clone());
+                                    changed = true;
+                                }
+                            }
+                        }
+                        Some(Data::MultiString(strings)).filter(|_| changed)
+                    }
+                    (Mode::Append, Some(_)) => None,
+                };
+                data.map(|data| Operation::SetValue {
+                    path,
+                    name: name.clone(),
+                    data,
+                })
+            }
+            Entry::DeleteValue { name, .. } => Some(Operation::DeleteValue {
+                path,
+                name: name.clone(),
+            }),
+            Entry::DeleteString { name, string, .. } => match state.read_value(&path, name)? {
+                Some(Data::MultiString(strings)) => {
+                    let string = wide(string);
+                    let kept = strings
+                        .iter()
+                        .filter(|x| !same(x, &string))
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    if kept.len() == strings.len() {
+                        None
+                    } else {
+                        Some(Operation::SetValue {
+                            path,
+                            name: name.clone(),
+                            data: Data::MultiString(kept),
+                        })
+                    }
+                }
+                _ => None,
+            },
+            Entry::DeleteKey { .. } => Some(Operation::DeleteKey { path }),
+        };
+
+        if let Some(change) = change {
+            execute(&mut state, &change)?;
+            operations.push(change);
+        }
+    }
+
+    Ok(operations)
+}
+
+/// Compares strings of a multi-string ignoring case, as SetupAPI does.
+fn same(a: &U16CString, b: &U16CString) -> bool {
+    fold(&a.to_string_lossy()) == fold(&b.to_string_lossy())
+}
+
+/// A string as a null terminated wide string, cut short at any null it contains.
+fn wide(s: &str) -> U16CString {
+    let mut units = s.encode_utf16().collect::<Vec<_>>();
+    units.push(0);
+    U16CString::from_vec_with_nul(units).expect("terminated")
+}
+
+/// Parses a number the way `strtoul` does with a base of zero: hex after `0x`, octal after a
+/// leading zero, and decimal otherwise.
+fn parse_number(s: &str) -> Option<u32> {
+    let s = s.trim();
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16).ok()
+    } else if s.len() > 1 && s.starts_with('0') {
+        u32::from_str_radix(&s[1..], 8).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+    use crate::tree::Tree;
+
+    const INF: &str = r#"[Version]
+Signature = "$Windows NT$"
+
+[DefaultInstall]
+DelReg = Contoso.DelReg
+AddReg = Contoso.AddReg, Device.AddReg
+
+[Contoso.AddReg]
+HKLM, %Key%, , , "%Company% Tools"
+HKLM, %Key%, Path, 0x00020000, "%%ProgramFiles%%\%Company%"
+HKLM, %Key%, Count, 0x00010001, 0x2a
+HKLM, %Key%, Flags, 0x00010001, 01, 00, 00, 00
+HKLM, %Key%, Blob, 1, de, ad, \
+    be, ef                          ; continued
+HKLM, %Key%, Quad, 0x000b0001, 01, 00, 00, 00, 00, 00, 00, 00
+HKLM, %Key%, List, 0x00010008, "b", "c"
+HKLM, %Key%, Keep, 0x00000002, "new"
+HKLM, %Key%, Absent, 0x00000020, "new"
+HKLM, %Key%\Empty, , 0x00000010
+HKLM, %Key%, Old, 0x00000004
+HKLM, %Key%, Skipped, 0x00008000
+
+[Device.AddReg]
+HKR, , EnumPropPages32, , "contoso.dll,PropPages"
+hkcu, Software\Contoso
+
+[Contoso.DelReg]
+HKLM, %Key%\Cache
+HKLM, %Key%, Legacy
+HKLM, %Key%, Order, 0x00018002, "a"
+HKLM, %Key%, Ignored, 0x00000001
+
+[Strings]
+Company = "Contoso"
+KEY = "Software\Contoso"
+"#;
+
+    fn string(s: &str) -> Data {
+        Data::String(s.try_into().unwrap())
+    }
+
+    fn multi(strings: &[&str]) -> Data {
+        Data::MultiString(strings.iter().map(|x| (*x).try_into().unwrap()).collect())
+    }
+
+    #[test]
+    fn reads_install_sections() {
+        let file = InfFile::parse(INF).unwrap();
+        assert_eq!(
+            file.sections().collect::<Vec<_>>(),
+            vec![
+                "Version",
+                "DefaultInstall",
+                "Contoso.AddReg",
+                "Device.AddReg",
+                "Contoso.DelReg",
+                "Strings"
+            ]
+        );
+        assert_eq!(file.string("company"), Some("Contoso"));
+
+        let key = r"Software\Contoso";
+        let lm = Root::LocalMachine;
+        let set = |name: &str, data: Data, mode: Mode| Entry::SetValue {
+            root: lm,
+            key: key.into(),
+            name: name.into(),
+            data,
+            mode,
+        };
+        assert_eq!(
+            file.install("defaultinstall").unwrap(),
+            vec![
+                Entry::DeleteKey {
+                    root: lm,
+                    key: format!(r"{}\Cache", key)
+                },
+                Entry::DeleteValue {
+                    root: lm,
+                    key: key.into(),
+                    name: "Legacy".into()
+                },
+                Entry::DeleteString {
+                    root: lm,
+                    key: key.into(),
+                    name: "Order".into(),
+                    string: "a".into()
+                },
+                set("", string("Contoso Tools"), Mode::Overwrite),
+                set(
+                    "Path",
+                    Data::ExpandString(r"%ProgramFiles%\Contoso".try_into().unwrap()),
+                    Mode::Overwrite
+                ),
+                set("Count", Data::U32(42), Mode::Overwrite),
+                set("Flags", Data::U32(1), Mode::Overwrite),
+                set(
+                    "Blob",
+                    Data::Binary(vec![0xde, 0xad, 0xbe, 0xef]),
+                    Mode::Overwrite
+                ),
+                set("Quad", Data::U64(1), Mode::Overwrite),
+                set("List", multi(&["b", "c"]), Mode::Append),
+                set("Keep", string("new"), Mode::NoClobber),
+                set("Absent", string("new"), Mode::OverwriteOnly),
+                Entry::CreateKey {
+                    root: lm,
+                    key: format!(r"{}\Empty", key)
+                },
+                Entry::DeleteValue {
+                    root: lm,
+                    key: key.into(),
+                    name: "Old".into()
+                },
+                Entry::SetValue {
+                    root: Root::Relative,
+                    key: "".into(),
+                    name: "EnumPropPages32".into(),
+                    data: string("contoso.dll,PropPages"),
+                    mode: Mode::Overwrite
+                },
+                Entry::CreateKey {
+                    root: Root::CurrentUser,
+                    key: r"Software\Contoso".into()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn converts_to_operations() {
+        let file = InfFile::parse(INF).unwrap();
+        let entries = file.install("DefaultInstall").unwrap();
+
+        let path = r"HKEY_LOCAL_MACHINE\Software\Contoso";
+        let mut current = Tree::new();
+        let key = current.create_key(path);
+        key.set_value("Order", multi(&["A", "b"]));
+        key.set_value("List", multi(&["a", "B"]));
+        key.set_value("Keep", string("old"));
+        key.set_value("Legacy", Data::U32(0));
+
+        let relative = r"HKEY_LOCAL_MACHINE\SYSTEM\CurrentControlSet\Control\Class\0000";
+        let operations = to_operations(&entries, relative, &current).unwrap();
+        let set = |name: &str, data: Data| Operation::SetValue {
+            path: path.into(),
+            name: name.into(),
+            data,
+        };
+
+        // `Keep` exists and `Absent` does not, so neither is set, and only `c` is new to
+        // `List`.
+        assert_eq!(operations[2], set("Order", multi(&["b"])));
+        assert!(operations.contains(&set("List", multi(&["a", "B", "c"]))));
+        assert!(!operations.iter().any(|x| matches!(x,
+            Operation::SetValue { name, .. } if name == "Keep" || name == "Absent")));
+        assert!(operations.contains(&Operation::SetValue {
+            path: relative.into(),
+            name: "EnumPropPages32".into(),
+            data: string("contoso.dll,PropPages"),
+        }));
+
+        let mut tree = current.clone();
+        for operation in &operations {
+            execute(&mut tree, operation).unwrap();
+        }
+        let key = tree.key(path).unwrap();
+        assert_eq!(key.value("Keep"), Some(&string("old")));
+        assert_eq!(key.value("Legacy"), None);
+        assert_eq!(key.value("Count"), Some(&Data::U32(42)));
+        assert!(tree.key(r"HKEY_CURRENT_USER\Software\Contoso").is_some());
+    }
+
+    #[test]
+    fn reports_errors() {
+        let error = |text: &str| {
+            InfFile::parse(text)
+                .and_then(|file| file.add_reg("A"))
+                .unwrap_err()
+        };
+
+        assert!(matches!(error("[B]\n"), Error::SectionNotFound(name) if name == "A"));
+        assert!(matches!(
+            error("[A]\nHKXX, Software\n"),
+            Error::InvalidRoot(2, root) if root == "HKXX"
+        ));
+        assert!(matches!(
+            error("[A]\nHKLM, Software, X, 0x00010001, 1, zz\n"),
+            Error::Syntax(2, "invalid hex byte")
+        ));
+        assert!(matches!(
+            error("[A]\nHKLM, Software, X, 0x01000001, 00\n"),
+            Error::UnsupportedType(2, 0x100)
+        ));
+        assert!(matches!(
+            error("HKLM, Software\n"),
+            Error::Syntax(1, "entry outside of a section")
+        ));
+    }
+}
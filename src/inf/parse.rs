@@ -0,0 +1,171 @@
+use super::{Error, InfFile, Line};
+
+pub(super) fn parse(text: &str) -> Result<InfFile, Error> {
+    let mut file = InfFile {
+        sections: vec![],
+        strings: vec![],
+    };
+    let mut current: Option<usize> = None;
+    let mut lines = text.lines().enumerate().map(|(i, line)| (i + 1, line));
+
+    while let Some((number, line)) = lines.next() {
+        // A backslash ending a line continues it onto the next one.
+        let mut logical = String::new();
+        let mut code = strip_comment(line).trim_end();
+        while let Some(head) = code.strip_suffix('\\') {
+            logical.push_str(head);
+            code = match lines.next() {
+                Some((_, next)) => strip_comment(next).trim_end(),
+                None => "",
+            };
+        }
+        logical.push_str(code);
+
+        let trimmed = logical.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+
+        if let Some(rest) = trimmed.strip_prefix('[') {
+            let name = rest
+                .find(']')
+                .map(|end| rest[..end].trim())
+                .ok_or(Error::Syntax(number, "unterminated section name"))?;
+
+            // Sections of the same name are merged, as SetupAPI does.
+            current = Some(
+                match file
+                    .sections
+                    .iter()
+                    .position(|(x, _)| x.eq_ignore_ascii_case(name))
+                {
+                    Some(index) => index,
+                    None => {
+                        file.sections.push((name.to_string(), vec![]));
+                        file.sections.len() - 1
+                    }
+                },
+            );
+            continue;
+        }
+
+        let index = current.ok_or(Error::Syntax(number, "entry outside of a section"))?;
+        let (key, fields) = split(trimmed, number)?;
+
+        let (name, lines) = &mut file.sections[index];
+        if name.eq_ignore_ascii_case("Strings") {
+            if let Some(key) = key {
+                file.strings.push((key, fields.join(",")));
+            }
+            continue;
+        }
+        lines.push(Line {
+            number,
+            key,
+            fields,
+        });
+    }
+
+    Ok(file)
+}
+
+/// The text of a line before any comment, which starts with a semicolon outside of quotes.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Splits a line into its key, if it has one before an `=`, and its comma separated fields.
+///
+/// Fields are trimmed of surrounding whitespace, except within quotes, where a doubled quote
+/// stands for a quote character.
+fn split(line: &str, number: usize) -> Result<(Option<String>, Vec<String>), Error> {
+    let mut key = None;
+    let mut fields = vec![];
+    let mut field = String::new();
+    // Whitespace that is only kept if more of the field follows it.
+    let mut pending = String::new();
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                field.push_str(&pending);
+                pending.clear();
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            field.push('"');
+                        }
+                        Some('"') => break,
+                        Some(c) => field.push(c),
+                        None => return Err(Error::Syntax(number, "unterminated string")),
+                    }
+                }
+            }
+            ',' => {
+                fields.push(std::mem::take(&mut field));
+                pending.clear();
+            }
+            '=' if key.is_none() && fields.is_empty() => {
+                key = Some(std::mem::take(&mut field));
+                pending.clear();
+            }
+            c if c.is_whitespace() => {
+                if !field.is_empty() {
+                    pending.push(c);
+                }
+            }
+            c => {
+                field.push_str(&pending);
+                pending.clear();
+                field.push(c);
+            }
+        }
+    }
+    fields.push(field);
+
+    Ok((key, fields))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_fields() {
+        let split = |line: &str| split(line, 1).unwrap();
+
+        assert_eq!(
+            split(r#"HKLM, Software\Contoso , "Name",, "a, ""b""" , c d "#),
+            (
+                None,
+                vec![
+                    "HKLM".to_string(),
+                    r"Software\Contoso".into(),
+                    "Name".into(),
+                    "".into(),
+                    r#"a, "b""#.into(),
+                    "c d".into()
+                ]
+            )
+        );
+        assert_eq!(
+            split(r#"AddReg = One, Two"#),
+            (Some("AddReg".into()), vec!["One".into(), "Two".into()])
+        );
+        assert_eq!(strip_comment(r#"HKR,,"a;b" ; comment"#), r#"HKR,,"a;b" "#);
+        assert!(matches!(
+            super::split("HKR,,\"abc", 4),
+            Err(Error::Syntax(4, "unterminated string"))
+        ));
+    }
+}
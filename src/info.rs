@@ -0,0 +1,236 @@
+//! Metadata describing a registry key, such as its class name and last write time.
+
+use std::{
+    fmt::Display,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use utfx::{U16CStr, U16CString};
+
+/// A Windows `FILETIME`, counting 100-nanosecond intervals since January 1, 1601 (UTC).
+///
+/// This is the timestamp format used for key last write times, both by the Win32 API and
+/// inside hive files.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileTime(u64);
+
+impl FileTime {
+    /// The Unix epoch expressed as a `FILETIME`.
+    const UNIX_EPOCH: u64 = 116_444_736_000_000_000;
+    const TICKS_PER_SEC: u64 = 10_000_000;
+
+    #[inline]
+    pub const fn new(ticks: u64) -> FileTime {
+        FileTime(ticks)
+    }
+
+    /// Builds a `FileTime` from the `dwLowDateTime` and `dwHighDateTime` halves of a `FILETIME`.
+    #[inline]
+    pub const fn from_parts(low: u32, high: u32) -> FileTime {
+        FileTime(((high as u64) << 32) | low as u64)
+    }
+
+    #[inline]
+    pub const fn from_le_bytes(bytes: [u8; 8]) -> FileTime {
+        FileTime(u64::from_le_bytes(bytes))
+    }
+
+    /// The current system time.
+    #[inline]
+    pub fn now() -> FileTime {
+        SystemTime::now().into()
+    }
+
+    /// The raw number of 100-nanosecond intervals since 1601-01-01.
+    #[inline]
+    pub const fn ticks(&self) -> u64 {
+        self.0
+    }
+
+    #[inline]
+    pub const fn to_le_bytes(&self) -> [u8; 8] {
+        self.0.to_le_bytes()
+    }
+
+    pub fn to_system_time(&self) -> SystemTime {
+        let to_duration = |ticks: u64| {
+            Duration::new(
+                ticks / Self::TICKS_PER_SEC,
+                ((ticks % Self::TICKS_PER_SEC) * 100) as u32,
+            )
+        };
+
+        if self.0 >= Self::UNIX_EPOCH {
+            UNIX_EPOCH + to_duration(self.0 - Self::UNIX_EPOCH)
+        } else {
+            UNIX_EPOCH - to_duration(Self::UNIX_EPOCH - self.0)
+        }
+    }
+}
+
+impl From<FileTime> for SystemTime {
+    fn from(time: FileTime) -> Self {
+        time.to_system_time()
+    }
+}
+
+impl From<SystemTime> for FileTime {
+    /// Converts a `SystemTime`, saturating at the bounds of the `FILETIME` range.
+    fn from(time: SystemTime) -> Self {
+        let ticks = |d: Duration| {
+            d.as_secs()
+                .saturating_mul(Self::TICKS_PER_SEC)
+                .saturating_add(u64::from(d.subsec_nanos() / 100))
+        };
+
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(d) => FileTime(Self::UNIX_EPOCH.saturating_add(ticks(d))),
+            Err(e) => FileTime(Self::UNIX_EPOCH.saturating_sub(ticks(e.duration()))),
+        }
+    }
+}
+
+impl Display for FileTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#018x}", self.0)
+    }
+}
+
+/// Information about a registry key, as reported by `RegQueryInfoKeyW` or read from a
+/// key node in a hive file.
+///
+/// Lengths of names are counted in UTF-16 code units, excluding the terminating null.
+/// Lengths of data are counted in bytes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyInfo {
+    pub(crate) class: Option<U16CString>,
+    pub(crate) last_write_time: FileTime,
+    pub(crate) subkey_count: u32,
+    pub(crate) max_subkey_name_len: u32,
+    pub(crate) max_class_len: u32,
+    pub(crate) value_count: u32,
+    pub(crate) max_value_name_len: u32,
+    pub(crate) max_value_data_len: u32,
+    pub(crate) security_descriptor_len: u32,
+}
+
+impl KeyInfo {
+    /// The class name of the key, if it has one.
+    #[inline]
+    pub fn class(&self) -> Option<&U16CStr> {
+        self.class.as_deref()
+    }
+
+    #[inline]
+    pub fn last_write_time(&self) -> FileTime {
+        self.last_write_time
+    }
+
+    #[inline]
+    pub fn subkey_count(&self) -> u32 {
+        self.subkey_count
+    }
+
+    /// The length of the longest subkey name.
+    #[inline]
+    pub fn max_subkey_name_len(&self) -> u32 {
+        self.max_subkey_name_len
+    }
+
+    /// The length of the longest class name of any subkey.
+    #[inline]
+    pub fn max_class_len(&self) -> u32 {
+        self.max_class_len
+    }
+
+    #[inline]
+    pub fn value_count(&self) -> u32 {
+        self.value_count
+    }
+
+    /// The length of the longest value name.
+    #[inline]
+    pub fn max_value_name_len(&self) -> u32 {
+        self.max_value_name_len
+    }
+
+    /// The size in bytes of the largest value data.
+    #[inline]
+    pub fn max_value_data_len(&self) -> u32 {
+        self.max_value_data_len
+    }
+
+    /// The size in bytes of the key's security descriptor.
+    #[inline]
+    pub fn security_descriptor_len(&self) -> u32 {
+        self.security_descriptor_len
+    }
+}
+
+/// Decodes a class name from a UTF-16 buffer, stopping at the first null. An empty class
+/// is reported as no class at all, which is how Windows treats it.
+pub(crate) fn decode_class(buf: &[u16]) -> Option<U16CString> {
+    let len = buf.iter().position(|c| *c == 0).unwrap_or(buf.len());
+    if len == 0 {
+        return None;
+    }
+
+    // SAFETY: the slice was cut at the first null, so it contains none.
+    Some(unsafe { U16CString::from_vec_unchecked(&buf[..len]) })
+}
+
+/// Decodes a class name stored as little-endian UTF-16 bytes, as found in hive files.
+pub(crate) fn decode_class_bytes(bytes: &[u8]) -> Option<U16CString> {
+    let buf = bytes
+        .chunks_exact(2)
+        .map(|x| u16::from_le_bytes([x[0], x[1]]))
+        .collect::<Vec<_>>();
+    decode_class(&buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filetime_round_trip() {
+        // 2021-06-20T00:00:00Z
+        let time = FileTime::from_parts(0x366f_0000, 0x01d7_6567);
+        let system = time.to_system_time();
+        assert_eq!(
+            system.duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            1_624_147_200
+        );
+        assert_eq!(FileTime::from(system), time);
+        assert_eq!(FileTime::from(UNIX_EPOCH).ticks(), FileTime::UNIX_EPOCH);
+    }
+
+    #[test]
+    fn filetime_before_unix_epoch() {
+        let time = FileTime::new(0);
+        let system = time.to_system_time();
+        assert_eq!(
+            UNIX_EPOCH.duration_since(system).unwrap().as_secs(),
+            11_644_473_600
+        );
+        assert_eq!(FileTime::from(system), time);
+    }
+
+    #[test]
+    fn class_decoding() {
+        assert_eq!(decode_class(&[0, 0, 0]), None);
+        assert_eq!(decode_class(&[]), None);
+        assert_eq!(
+            decode_class(&[0x41, 0x42, 0, 0x43])
+                .unwrap()
+                .to_string_lossy(),
+            "AB"
+        );
+        assert_eq!(
+            decode_class_bytes(&[0x41, 0, 0x42, 0])
+                .unwrap()
+                .to_string_lossy(),
+            "AB"
+        );
+    }
+}
@@ -1,11 +1,9 @@
-use std::{
-    fmt::{Debug, Display},
-    ptr::null_mut,
-};
+use std::fmt::{Debug, Display};
 
-use utfx::{U16CString, U16String};
-use windows::{core::PWSTR, Win32::{Foundation::ERROR_NO_MORE_ITEMS, System::Registry::{RegEnumKeyExW, RegQueryInfoKeyW}}};
+use utfx::{U16CStr, U16CString};
 
+use crate::backend::ErrorCode;
+use crate::info::{FileTime, KeyInfo};
 use crate::key::RegKey;
 use crate::sec::Security;
 
@@ -20,18 +18,24 @@ pub enum Error {
 
     #[error("Invalid null found in string")]
     InvalidNul(#[from] utfx::NulError<u16>),
+
+    #[error("An unknown IO error occurred for index: {0:?}")]
+    Unknown(u32, #[source] std::io::Error),
 }
 
 #[derive(Debug)]
 pub struct Keys<'a> {
     regkey: &'a RegKey,
-    buf: Vec<u16>,
     index: u32,
+    /// Set once enumeration fails in a way that later indices would too.
+    is_done: bool,
 }
 
 pub struct KeyRef<'a> {
     regkey: &'a RegKey,
     name: U16CString,
+    class: Option<U16CString>,
+    last_write_time: FileTime,
 }
 
 impl Display for KeyRef<'_> {
@@ -51,23 +55,35 @@ impl<'a> Debug for KeyRef<'a> {
 impl<'a> KeyRef<'a> {
     #[inline]
     pub fn open(&self, sec: Security) -> Result<RegKey, crate::key::Error> {
-        let path = self.regkey.path.to_ustring();
-        let suffix = self.name.to_ustring();
-        let bs = U16String::from_str("\\");
-        let chars = path
-            .as_slice()
-            .iter()
-            .chain(bs.as_slice())
-            .chain(suffix.as_slice())
-            .copied()
-            .collect::<Vec<u16>>();
-
-        let path = U16CString::new(chars)?;
-        crate::key::open_hkey(self.regkey.handle, &self.name, sec).map(|handle| RegKey {
-            hive: self.regkey.hive,
-            handle,
-            path,
-        })
+        match self
+            .regkey
+            .backend
+            .open_key(self.regkey.handle, &self.name, sec)
+        {
+            Ok(handle) => Ok(self.regkey.child(&self.name, handle)),
+            Err(code) => Err(crate::key::Error::from_code(
+                code,
+                self.name.to_string_lossy(),
+            )),
+        }
+    }
+
+    /// The class name of the subkey, as reported during enumeration.
+    #[inline]
+    pub fn class(&self) -> Option<&U16CStr> {
+        self.class.as_deref()
+    }
+
+    /// The last write time of the subkey, as reported during enumeration.
+    #[inline]
+    pub fn last_write_time(&self) -> FileTime {
+        self.last_write_time
+    }
+
+    /// Opens the subkey to query its full information.
+    #[inline]
+    pub fn info(&self) -> Result<KeyInfo, crate::key::Error> {
+        self.open(Security::QueryValue)?.info()
     }
 }
 
@@ -75,77 +91,46 @@ impl<'a> Iterator for Keys<'a> {
     type Item = Result<KeyRef<'a>, Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        // Reset first byte, just in case.
-        self.buf[0] = 0;
-        let mut len = self.buf.len() as u32;
-
-        let result = unsafe {
-            RegEnumKeyExW(
-                self.regkey.handle,
-                self.index,
-                PWSTR(self.buf.as_mut_ptr()),
-                &mut len,
-                None,
-                PWSTR(null_mut()),
-                None,
-                None,
-            )
-        };
-
-        if result == ERROR_NO_MORE_ITEMS {
+        if self.is_done {
             return None;
         }
 
-        self.index += 1;
-
-        if result.is_err() {
-            // TODO: don't panic
-            panic!();
-        }
-
-        let name = match U16CString::new(&self.buf[0..len as usize]) {
-            Ok(v) => v,
-            Err(e) => return Some(Err(Error::InvalidNul(e))),
+        let index = self.index;
+        let entry = match self.regkey.backend.enum_key(self.regkey.handle, index) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => {
+                self.is_done = true;
+                return None;
+            }
+            Err(code) => {
+                // Backends fail only an entry that cannot be read with ERROR_MORE_DATA; anything
+                // else, such as the key being deleted, fails every later index too.
+                if code == ErrorCode::MORE_DATA {
+                    self.index += 1;
+                } else {
+                    self.is_done = true;
+                }
+                return Some(Err(Error::Unknown(index, code.into())));
+            }
         };
 
+        self.index += 1;
+
         Some(Ok(KeyRef {
             regkey: self.regkey,
-            name,
+            name: entry.name,
+            class: entry.class,
+            last_write_time: entry.last_write_time,
         }))
     }
 }
 
 impl<'a> Keys<'a> {
     pub fn new(regkey: &'a RegKey) -> Result<Keys<'a>, std::io::Error> {
-        let mut subkeys_max_str_len = 0u32;
-        // let mut subkeys_len = 0u32;
-
-        let result = unsafe {
-            RegQueryInfoKeyW(
-                regkey.handle,
-                PWSTR(null_mut()),
-                None,
-                None,
-                None, // &mut subkeys_len,
-                Some(&mut subkeys_max_str_len),
-                None,
-                None,
-                None,
-                None,
-                None,
-                None,
-            )
-        };
-
-        if result.is_ok() {
-            return Ok(Keys {
-                regkey,
-                buf: vec![0u16; subkeys_max_str_len as usize + 1],
-                index: 0,
-            });
-        }
-        else {
-            return Err(std::io::Error::from_raw_os_error(result.0 as i32));
-        }
+        Ok(Keys {
+            regkey,
+            index: 0,
+            is_done: false,
+        })
     }
 }
@@ -1,9 +1,8 @@
-use std::{convert::TryInto, fmt::Debug, ptr::null_mut};
+use std::{convert::TryInto, fmt::Debug};
 
 use utfx::{U16CStr, U16CString};
-use windows::{core::PWSTR, Win32::{Foundation::ERROR_NO_MORE_ITEMS, System::Registry::{RegEnumValueW, RegQueryInfoKeyW, REG_VALUE_TYPE}}};
 
-use crate::{key::RegKey, Data};
+use crate::{backend::ErrorCode, key::RegKey, Data};
 
 #[derive(Debug, thiserror::Error)]
 #[non_exhaustive]
@@ -27,9 +26,9 @@ pub enum Error {
 #[derive(Debug)]
 pub struct Values<'a> {
     regkey: &'a RegKey,
-    name_buf: Vec<u16>,
-    data_buf: Vec<u16>,
     index: u32,
+    /// Set once enumeration fails in a way that later indices would too.
+    is_done: bool,
 }
 
 pub struct ValueRef<'a> {
@@ -94,47 +93,33 @@ impl<'a> Iterator for Values<'a> {
     type Item = Result<ValueRef<'a>, Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.name_buf[0] = 0;
-        let mut name_len = self.name_buf.len() as u32;
-
-        for v in &mut self.data_buf {
-            *v = 0;
-        }
-        let mut data_type: u32 = 0u32;
-        let mut data_len = (self.data_buf.len() * 2) as u32;
-
-        let result = unsafe {
-            RegEnumValueW(
-                self.regkey.handle,
-                self.index,
-                PWSTR(self.name_buf.as_mut_ptr()),
-                &mut name_len,
-                None,
-                Some(&mut data_type),
-                Some(self.data_buf.as_mut_ptr() as *mut u8),
-                Some(&mut data_len),
-            )
-        };
-
-        if result == ERROR_NO_MORE_ITEMS {
+        if self.is_done {
             return None;
         }
 
-        if result.is_err() {
-            return Some(Err(Error::Unknown(
-                self.index,
-                std::io::Error::from_raw_os_error(result.0 as i32),
-            )));
-        }
+        let index = self.index;
+        let result = self.regkey.backend.enum_value(self.regkey.handle, index);
+        let (name, value) = match result {
+            Ok(Some(entry)) => entry,
+            Ok(None) => {
+                self.is_done = true;
+                return None;
+            }
+            Err(code) => {
+                // Backends fail only an entry that cannot be read with ERROR_MORE_DATA; anything
+                // else, such as the key being deleted, fails every later index too.
+                if code == ErrorCode::MORE_DATA {
+                    self.index += 1;
+                } else {
+                    self.is_done = true;
+                }
+                return Some(Err(Error::Unknown(index, code.into())));
+            }
+        };
 
         self.index += 1;
 
-        let name = match U16CString::new(&self.name_buf[0..name_len as usize]) {
-            Ok(v) => v,
-            Err(e) => return Some(Err(Error::InvalidNul(e))),
-        };
-
-        let data = match crate::value::parse_value_type_data(REG_VALUE_TYPE(data_type), self.data_buf.clone()) {
+        let data = match self.regkey.backend.decode(&value) {
             Ok(v) => v,
             Err(e) => return Some(Err(Error::Data(e))),
         };
@@ -149,37 +134,10 @@ impl<'a> Iterator for Values<'a> {
 
 impl<'a> Values<'a> {
     pub fn new(regkey: &'a RegKey) -> Result<Values<'a>, std::io::Error> {
-        let mut value_count = 0u32;
-        let mut max_value_name_len = 0u32;
-        let mut max_value_data_len = 0u32;
-
-        let result = unsafe {
-            RegQueryInfoKeyW(
-                regkey.handle,
-                PWSTR(null_mut()),
-                None,
-                None,
-                None,
-                None,
-                None,
-                Some(&mut value_count),
-                Some(&mut max_value_name_len),
-                Some(&mut max_value_data_len),
-                None,
-                None,
-            )
-        };
-
-        if result.is_ok() {
-            return Ok(Values {
-                regkey,
-                name_buf: vec![0u16; max_value_name_len as usize + 1],
-                data_buf: vec![0u16; (max_value_data_len / 2 + max_value_data_len % 2) as usize],
-                index: 0,
-            });
-        }
-        else {
-            Err(std::io::Error::from_raw_os_error(result.0 as i32))
-        }
+        Ok(Values {
+            regkey,
+            index: 0,
+            is_done: false,
+        })
     }
 }
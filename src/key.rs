@@ -2,11 +2,13 @@ use std::{
     convert::{Infallible, TryInto},
     fmt::Display,
     io,
+    sync::Arc,
 };
 
 use utfx::{U16CStr, U16CString};
-use windows::{core::PCWSTR, Win32::System::Registry::{RegCloseKey, RegCreateKeyExW, RegDeleteKeyW, RegDeleteTreeW, RegOpenCurrentUser, RegOpenKeyExW, RegSaveKeyExW, HKEY, REG_NO_COMPRESSION, REG_OPEN_CREATE_OPTIONS}};
 
+use crate::backend::{self, Backend, ErrorCode, Handle, RawValue};
+use crate::info::KeyInfo;
 use crate::iter;
 use crate::sec::Security;
 use crate::{value, Hive};
@@ -28,22 +30,19 @@ pub enum Error {
 }
 
 impl Error {
-    #[cfg(test)]
+    #[cfg(all(test, windows))]
     pub(crate) fn is_not_found(&self) -> bool {
-        match self {
-            Error::NotFound(_, _) => true,
-            _ => false,
-        }
+        matches!(self, Error::NotFound(_, _))
     }
 
-    fn from_code(code: i32, value_name: String) -> Self {
-        let err = io::Error::from_raw_os_error(code);
+    pub(crate) fn from_code(code: ErrorCode, path: String) -> Self {
+        let err = io::Error::from(code);
 
-        return match err.kind() {
-            io::ErrorKind::NotFound => Error::NotFound(value_name, err),
-            io::ErrorKind::PermissionDenied => Error::PermissionDenied(value_name, err),
-            _ => Error::Unknown(value_name, err),
-        };
+        match code.kind() {
+            io::ErrorKind::NotFound => Error::NotFound(path, err),
+            io::ErrorKind::PermissionDenied => Error::PermissionDenied(path, err),
+            _ => Error::Unknown(path, err),
+        }
     }
 }
 
@@ -57,8 +56,9 @@ impl From<Infallible> for Error {
 #[derive(Debug)]
 pub struct RegKey {
     pub(crate) hive: Hive,
-    pub(crate) handle: HKEY,
+    pub(crate) handle: Handle,
     pub(crate) path: U16CString,
+    pub(crate) backend: Arc<dyn Backend>,
 }
 
 impl Display for RegKey {
@@ -66,7 +66,7 @@ impl Display for RegKey {
         write!(f, "{}", &self.hive)?;
         let path = self.path.to_string_lossy();
 
-        if path != "" {
+        if !path.is_empty() {
             f.write_str(r"\")?;
             f.write_str(&path)?;
         }
@@ -77,12 +77,34 @@ impl Display for RegKey {
 
 impl Drop for RegKey {
     fn drop(&mut self) {
-        // No point checking the return value here.
-        let _ = unsafe { RegCloseKey(self.handle) };
+        self.backend.close_key(self.handle);
     }
 }
 
 impl RegKey {
+    /// The backend this key was opened with, which keys opened from it also use.
+    #[inline]
+    pub fn backend(&self) -> &Arc<dyn Backend> {
+        &self.backend
+    }
+
+    /// Wraps a handle to a key below this one, found at `path`.
+    pub(crate) fn child(&self, path: &U16CStr, handle: Handle) -> RegKey {
+        let mut joined = self.path.as_slice().to_vec();
+        if !joined.is_empty() {
+            joined.push(b'\\' as u16);
+        }
+        joined.extend_from_slice(path.as_slice());
+
+        RegKey {
+            hive: self.hive,
+            handle,
+            // SAFETY: both halves are nul-free, as is the separator.
+            path: unsafe { U16CString::from_vec_unchecked(joined) },
+            backend: self.backend.clone(),
+        }
+    }
+
     #[inline]
     pub fn open<P>(&self, path: P, sec: Security) -> Result<RegKey, Error>
     where
@@ -90,18 +112,10 @@ impl RegKey {
         P::Error: Into<Error>,
     {
         let path = path.try_into().map_err(Into::into)?;
-        open_hkey(self.handle, &path, sec).map(|handle| {
-            let joined_path = format!(
-                r"{}\{}",
-                self.path.to_string().unwrap(),
-                path.to_string().unwrap()
-            );
-            RegKey {
-                hive: self.hive,
-                handle,
-                path: joined_path.try_into().unwrap(),
-            }
-        })
+        self.backend
+            .open_key(self.handle, &path, sec)
+            .map(|handle| self.child(&path, handle))
+            .map_err(|code| Error::from_code(code, path.to_string_lossy()))
     }
 
     #[inline]
@@ -111,7 +125,9 @@ impl RegKey {
         P::Error: Into<Error>,
     {
         let path = file_path.try_into().map_err(Into::into)?;
-        save_hkey(self.handle, &path)
+        self.backend
+            .save_key(self.handle, &path)
+            .map_err(|code| Error::from_code(code, path.to_string_lossy()))
     }
 
     #[inline]
@@ -121,18 +137,10 @@ impl RegKey {
         P::Error: Into<Error>,
     {
         let path = path.try_into().map_err(Into::into)?;
-        create_hkey(self.handle, &path, sec).map(|handle| {
-            let joined_path = format!(
-                r"{}\{}",
-                self.path.to_string().unwrap(),
-                path.to_string().unwrap()
-            );
-            RegKey {
-                hive: self.hive,
-                handle,
-                path: joined_path.try_into().unwrap(),
-            }
-        })
+        self.backend
+            .create_key(self.handle, &path, sec)
+            .map(|handle| self.child(&path, handle))
+            .map_err(|code| Error::from_code(code, path.to_string_lossy()))
     }
 
     #[inline]
@@ -142,12 +150,16 @@ impl RegKey {
         P::Error: Into<Error>,
     {
         let path = path.try_into().map_err(Into::into)?;
-        delete_hkey(self.handle, path, is_recursive)
+        self.backend
+            .delete_key(self.handle, &path, is_recursive)
+            .map_err(|code| Error::from_code(code, path.to_string_lossy()))
     }
 
     #[inline]
     pub fn delete_self(self, is_recursive: bool) -> Result<(), Error> {
-        delete_hkey(self.handle, U16CString::default(), is_recursive)
+        self.backend
+            .delete_key(self.handle, &U16CString::default(), is_recursive)
+            .map_err(|code| Error::from_code(code, self.path.to_string_lossy()))
     }
 
     #[inline]
@@ -156,7 +168,11 @@ impl RegKey {
         S: TryInto<U16CString>,
         S::Error: Into<value::Error>,
     {
-        value::query_value(self.handle, value_name)
+        let name = value_name.try_into().map_err(Into::into)?;
+        self.backend
+            .query_value(self.handle, &name)
+            .map_err(|code| value::Error::from_code(code, name.to_string_lossy()))
+            .and_then(|x| self.backend.decode(&x))
     }
 
     #[inline]
@@ -165,7 +181,10 @@ impl RegKey {
         S: TryInto<U16CString>,
         S::Error: Into<value::Error>,
     {
-        value::delete_value(self.handle, value_name)
+        let name = value_name.try_into().map_err(Into::into)?;
+        self.backend
+            .delete_value(self.handle, &name)
+            .map_err(|code| value::Error::from_code(code, name.to_string_lossy()))
     }
 
     #[inline]
@@ -174,7 +193,18 @@ impl RegKey {
         S: TryInto<U16CString>,
         S::Error: Into<value::Error>,
     {
-        value::set_value(self.handle, value_name, data)
+        let name = value_name.try_into().map_err(Into::into)?;
+        self.backend
+            .set_value(self.handle, &name, &RawValue::from_data(data))
+            .map_err(|code| value::Error::from_code(code, name.to_string_lossy()))
+    }
+
+    /// Queries the class name, last write time and size information of this key.
+    #[inline]
+    pub fn info(&self) -> Result<KeyInfo, Error> {
+        self.backend
+            .query_info(self.handle)
+            .map_err(|code| Error::from_code(code, self.path.to_string_lossy()))
     }
 
     #[inline]
@@ -194,112 +224,21 @@ impl RegKey {
     }
 
     pub fn open_current_user(sec: Security) -> Result<RegKey, Error> {
-        let mut hkey = HKEY::default();
-
-        let result = unsafe { RegOpenCurrentUser(sec.bits(), &mut hkey) };
-
-        if result.is_ok() {
+        let backend = backend::current();
+        match backend.open_current_user(sec) {
             // TODO: use NT API to query path
-            return Ok(RegKey {
+            Ok(handle) => Ok(RegKey {
                 hive: Hive::CurrentUser,
-                handle: hkey,
+                handle,
                 path: "".try_into().unwrap(),
-            });
+                backend,
+            }),
+            Err(code) => Err(Error::from_code(code, "<current user>".to_string())),
         }
-
-        let path = "<current user>".to_string();
-        Err(Error::from_code(result.0 as i32, path))
-    }
-}
-
-#[inline]
-pub(crate) fn open_hkey<'a, P>(base: HKEY, path: P, sec: Security) -> Result<HKEY, Error>
-where
-    P: AsRef<U16CStr>,
-{
-    let path = path.as_ref();
-    let mut hkey = HKEY::default();
-    let result = unsafe { RegOpenKeyExW(base, PCWSTR(path.as_ptr()), 0, sec.into(), &mut hkey) };
-
-    if result.is_ok() {
-        return Ok(hkey);
-    }
-    else {
-        let path = path.to_string_lossy();
-        Err(Error::from_code(result.0 as i32, path))
-    }
-}
-
-#[inline]
-pub(crate) fn save_hkey<'a, P>(hkey: HKEY, path: P) -> Result<(), Error>
-where
-    P: AsRef<U16CStr>,
-{
-    let path = path.as_ref();
-    let result = unsafe { RegSaveKeyExW(hkey, PCWSTR(path.as_ptr()), None, REG_NO_COMPRESSION) };
-
-    if result.is_ok() {
-        return Ok(());
-    }
-    else {
-        let path = path.to_string_lossy();
-        Err(Error::from_code(result.0 as i32, path))
-    }
-}
-
-#[inline]
-pub(crate) fn delete_hkey<P>(base: HKEY, path: P, is_recursive: bool) -> Result<(), Error>
-where
-    P: AsRef<U16CStr>,
-{
-    let path = path.as_ref();
-
-    let result = if is_recursive {
-        unsafe { RegDeleteTreeW(base, PCWSTR(path.as_ptr())) }
-    } else {
-        unsafe { RegDeleteKeyW(base, PCWSTR(path.as_ptr()))}
-    };
-
-    if result.is_ok() {
-        return Ok(());
-    }
-    else {
-        let path = path.to_string_lossy();
-        Err(Error::from_code(result.0 as i32, path))
-    }
-}
-
-#[inline]
-pub(crate) fn create_hkey<P>(base: HKEY, path: P, sec: Security) -> Result<HKEY, Error>
-where
-    P: AsRef<U16CStr>,
-{
-    let path = path.as_ref();
-    let mut hkey = HKEY::default();
-    let result = unsafe {
-        RegCreateKeyExW(
-            base,
-            PCWSTR(path.as_ptr()),
-            0,
-            None,
-            REG_OPEN_CREATE_OPTIONS::default(),
-            sec.into(),
-            None,
-            &mut hkey,
-            None,
-        )
-    };
-
-    if result.is_ok() {
-        return Ok(hkey);
-    }
-    else{
-        let path = path.to_string_lossy();
-        Err(Error::from_code(result.0 as i32, path))
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, windows))]
 mod tests {
     use crate::Hive;
 
@@ -334,4 +273,18 @@ mod tests {
 
         assert!(value_err.is_not_found());
     }
+
+    #[test]
+    fn key_info() {
+        let key = Hive::CurrentUser
+            .open("SOFTWARE", crate::Security::Read)
+            .unwrap();
+        let info = key.info().unwrap();
+        assert!(info.subkey_count() > 0);
+        assert!(info.last_write_time().ticks() > 0);
+        assert_eq!(
+            info.subkey_count() as usize,
+            key.keys().collect::<Result<Vec<_>, _>>().unwrap().len()
+        );
+    }
 }
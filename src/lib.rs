@@ -1,4 +1,3 @@
-#![cfg(windows)]
 #![deny(rust_2018_idioms)]
 
 //! # Registry
@@ -11,32 +10,58 @@
 //! the conversion of `String` and `str` into a UTF-16 string suitable for FFI usage.
 //!
 //! ```no_run
+//! # #[cfg(windows)] fn main() -> Result<(), registry::Error> {
 //! # use registry::{Hive, Security};
 //! let regkey = Hive::CurrentUser.open(r"some\nested\path", Security::Read)?;
-//! # Ok::<(), registry::Error>(())
+//! # Ok(()) }
+//! # #[cfg(not(windows))] fn main() {}
 //! ```
 //!
 //! A [`RegKey`](struct.RegKey.html) has all necessary functionality for querying subkeys, values within a key,
 //! and accessing key value data.
 //!
 //! ```no_run
+//! # #[cfg(windows)] fn main() -> Result<(), registry::Error> {
 //! # use registry::{Data, Hive, Security};
 //! # let regkey = Hive::CurrentUser.open(r"some\nested\path", Security::Read)?;
 //! regkey.set_value("SomeValue", &Data::U32(42))?;
 //! assert!(matches!(regkey.value("SomeValue")?, Data::U32(42)));
-//! # Ok::<(), registry::Error>(())
+//! # Ok(()) }
+//! # #[cfg(not(windows))] fn main() {}
 //! ```
 //!
 //! [`RegKey`](struct.RegKey.html)s also support iteration of all subkeys with the `keys()` function, and all values with the `values()` function.
+//! Metadata such as the class name and last write time of a key is available with the `info()` function.
+//!
+//! Every operation goes through a [`backend::Backend`](backend/trait.Backend.html), which is the
+//! live registry on Windows and can be replaced, for example to test code using this crate on
+//! other platforms.
 //!
 
+pub mod backend;
+pub mod descriptor;
+#[cfg(feature = "serde")]
+pub mod document;
+#[cfg(feature = "fingerprint")]
+pub mod fingerprint;
+pub mod fixture;
 mod hive;
+pub mod inf;
+pub mod info;
 pub mod iter;
 pub mod key;
+pub mod offline;
+pub mod operation;
+pub mod policy;
+pub mod regfile;
 mod sec;
+pub mod snapshot;
+pub mod tree;
 pub mod value;
+pub mod wine;
 
 pub use hive::Hive;
+pub use info::{FileTime, KeyInfo};
 #[doc(inline)]
 pub use key::RegKey;
 pub use sec::Security;
@@ -58,7 +83,7 @@ pub enum Error {
     Values(#[from] iter::values::Error),
 }
 
-#[cfg(test)]
+#[cfg(all(test, windows))]
 mod tests {
     use super::*;
     use std::convert::TryInto;
@@ -104,6 +129,7 @@ mod tests {
 
     #[test]
     fn set_value_and_delete() {
+        let _sandbox = backend::Sandbox::new().unwrap();
         let regkey = Hive::CurrentUser
             .create(r"Test\registry-rust-crate", Security::AllAccess)
             .unwrap();
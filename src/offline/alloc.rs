@@ -0,0 +1,371 @@
+//! Allocation of cells within the hive bins of a hive being edited.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::cell::{self, Cells, BASE_BLOCK_SIZE, BIG_DATA_SEGMENT_SIZE, NONE};
+use super::{Error, Key};
+use crate::info::FileTime;
+
+/// The size of a hive bin header.
+const HBIN_HEADER_SIZE: u32 = 32;
+
+/// The unit that hive bins, and the pages tracked as dirty, are sized in.
+pub(crate) const PAGE_SIZE: u32 = 4096;
+
+/// An editable hive file, tracking free cells and the pages written since the last save.
+pub(crate) struct Store {
+    pub(crate) data: Vec<u8>,
+    minor_version: u32,
+    /// Free cells by offset, with their sizes.
+    free: BTreeMap<u32, u32>,
+    /// The same free cells by size, then offset, for finding the best fit.
+    free_sizes: BTreeSet<(u32, u32)>,
+    /// Hive bins by offset, with their sizes.
+    bins: BTreeMap<u32, u32>,
+    /// Pages of the hive bins written since the last save.
+    dirty: BTreeSet<u32>,
+}
+
+impl Store {
+    /// Takes ownership of a whole hive file, indexing the free cells of every hive bin.
+    pub(crate) fn new(data: Vec<u8>, bins_len: u32, minor_version: u32) -> Result<Store, Error> {
+        let mut store = Store {
+            data,
+            minor_version,
+            free: BTreeMap::new(),
+            free_sizes: BTreeSet::new(),
+            bins: BTreeMap::new(),
+            dirty: BTreeSet::new(),
+        };
+
+        let bins = &store.data[BASE_BLOCK_SIZE..BASE_BLOCK_SIZE + bins_len as usize];
+        let u32_at =
+            |at: usize| u32::from_le_bytes([bins[at], bins[at + 1], bins[at + 2], bins[at + 3]]);
+
+        let mut offset = 0u32;
+        while offset < bins_len {
+            let at = offset as usize;
+            if at + HBIN_HEADER_SIZE as usize > bins.len() || &bins[at..at + 4] != b"hbin" {
+                return Err(Error::Corrupt(offset, "invalid hive bin header"));
+            }
+
+            let size = u32_at(at + 8);
+            if size < PAGE_SIZE || size & (PAGE_SIZE - 1) != 0 || offset + size > bins_len {
+                return Err(Error::Corrupt(offset, "invalid hive bin size"));
+            }
+
+            let mut cell = offset + HBIN_HEADER_SIZE;
+            while cell < offset + size {
+                let raw = u32_at(cell as usize) as i32;
+                let len = raw.unsigned_abs();
+                if len < 8 || len & 7 != 0 || cell + len > offset + size {
+                    return Err(Error::Corrupt(cell, "invalid cell size"));
+                }
+                if raw > 0 {
+                    store.free.insert(cell, len);
+                    store.free_sizes.insert((len, cell));
+                }
+                cell += len;
+            }
+
+            store.bins.insert(offset, size);
+            offset += size;
+        }
+
+        Ok(store)
+    }
+
+    #[inline]
+    pub(crate) fn bins_len(&self) -> u32 {
+        (self.data.len() - BASE_BLOCK_SIZE) as u32
+    }
+
+    #[inline]
+    pub(crate) fn cells(&self) -> Cells<'_> {
+        Cells::new(&self.data[BASE_BLOCK_SIZE..], self.minor_version)
+    }
+
+    fn mark(&mut self, offset: u32, len: u32) {
+        let first = offset / PAGE_SIZE;
+        let last = (offset + len.max(1) - 1) / PAGE_SIZE;
+        self.dirty.extend(first..=last);
+    }
+
+    fn write_raw(&mut self, offset: u32, bytes: &[u8]) {
+        let start = BASE_BLOCK_SIZE + offset as usize;
+        self.data[start..start + bytes.len()].copy_from_slice(bytes);
+        self.mark(offset, bytes.len() as u32);
+    }
+
+    /// Overwrites part of an allocated cell's data.
+    #[inline]
+    pub(crate) fn write(&mut self, cell: u32, at: usize, bytes: &[u8]) {
+        self.write_raw(cell + 4 + at as u32, bytes);
+    }
+
+    fn insert_free(&mut self, offset: u32, len: u32) {
+        self.free.insert(offset, len);
+        self.free_sizes.insert((len, offset));
+    }
+
+    fn remove_free(&mut self, offset: u32) -> Option<u32> {
+        let len = self.free.remove(&offset)?;
+        self.free_sizes.remove(&(len, offset));
+        Some(len)
+    }
+
+    fn bin_of(&self, offset: u32) -> Option<(u32, u32)> {
+        self.bins
+            .range(..=offset)
+            .next_back()
+            .map(|(start, size)| (*start, *size))
+    }
+
+    /// Allocates a cell holding the given bytes, returning its offset.
+    pub(crate) fn alloc(&mut self, bytes: &[u8]) -> u32 {
+        let size = ((bytes.len() as u32 + 4 + 7) & !7).max(8);
+
+        // The smallest free cell that fits, lowest first among cells of the same size.
+        let found = self
+            .free_sizes
+            .range((size, 0)..)
+            .next()
+            .map(|(len, offset)| (*offset, *len));
+
+        let (offset, len) = match found {
+            Some(v) => v,
+            None => self.grow(size),
+        };
+        self.remove_free(offset);
+
+        // Split off the remainder as a new free cell, if it is big enough to be one.
+        let size = if len - size >= 8 {
+            self.insert_free(offset + size, len - size);
+            self.write_raw(offset + size, &((len - size) as i32).to_le_bytes());
+            size
+        } else {
+            len
+        };
+
+        let mut cell = Vec::with_capacity(size as usize);
+        cell.extend_from_slice(&(-(size as i32)).to_le_bytes());
+        cell.extend_from_slice(bytes);
+        cell.resize(size as usize, 0);
+        self.write_raw(offset, &cell);
+        offset
+    }
+
+    /// Appends a new hive bin big enough for a cell of `size` bytes, returning its free cell.
+    fn grow(&mut self, size: u32) -> (u32, u32) {
+        let offset = self.bins_len();
+        let bin_size = (size + HBIN_HEADER_SIZE + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+        let mut header = vec![0u8; HBIN_HEADER_SIZE as usize];
+        header[0..4].copy_from_slice(b"hbin");
+        header[4..8].copy_from_slice(&offset.to_le_bytes());
+        header[8..12].copy_from_slice(&bin_size.to_le_bytes());
+        header[20..28].copy_from_slice(&FileTime::now().to_le_bytes());
+
+        self.data.resize(self.data.len() + bin_size as usize, 0);
+        self.write_raw(offset, &header);
+        self.bins.insert(offset, bin_size);
+
+        let cell = offset + HBIN_HEADER_SIZE;
+        let len = bin_size - HBIN_HEADER_SIZE;
+        self.write_raw(cell, &(len as i32).to_le_bytes());
+        self.insert_free(cell, len);
+        (cell, len)
+    }
+
+    /// Frees an allocated cell, merging it with free neighbours in the same hive bin.
+    pub(crate) fn free(&mut self, offset: u32) {
+        let start = BASE_BLOCK_SIZE + offset as usize;
+        let raw = i32::from_le_bytes([
+            self.data[start],
+            self.data[start + 1],
+            self.data[start + 2],
+            self.data[start + 3],
+        ]);
+        if raw >= 0 {
+            return;
+        }
+
+        let bin = self.bin_of(offset);
+        let same_bin = |other: u32| {
+            bin.map(|(b, s)| other >= b && other < b + s)
+                .unwrap_or(false)
+        };
+
+        let mut offset = offset;
+        let mut len = raw.unsigned_abs();
+
+        if self.free.contains_key(&(offset + len)) && same_bin(offset + len) {
+            len += self.remove_free(offset + len).unwrap_or(0);
+        }
+
+        let prev = self.free.range(..offset).next_back().map(|(o, l)| (*o, *l));
+        if let Some((prev, prev_len)) = prev {
+            if prev + prev_len == offset && same_bin(prev) {
+                self.remove_free(prev);
+                offset = prev;
+                len += prev_len;
+            }
+        }
+
+        self.insert_free(offset, len);
+        self.write_raw(offset, &(len as i32).to_le_bytes());
+    }
+
+    /// Writes a subkey list for the given key nodes, which must already be sorted, using
+    /// an index root if they need more than one leaf.
+    pub(crate) fn write_subkey_list(
+        &mut self,
+        entries: &[u32],
+        leaf_capacity: usize,
+    ) -> Result<u32, Error> {
+        if entries.is_empty() {
+            return Ok(NONE);
+        }
+
+        let names = entries
+            .iter()
+            .map(|x| {
+                Ok(Key::new(self.cells(), *x)?
+                    .raw_name()?
+                    .units()
+                    .collect::<Vec<_>>())
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let signature: &[u8] = match self.minor_version {
+            0..=2 => b"li",
+            3..=4 => b"lf",
+            _ => b"lh",
+        };
+
+        let mut leaves = vec![];
+        for (offsets, names) in entries
+            .chunks(leaf_capacity)
+            .zip(names.chunks(leaf_capacity))
+        {
+            let mut leaf = signature.to_vec();
+            leaf.extend_from_slice(&(offsets.len() as u16).to_le_bytes());
+            for (offset, name) in offsets.iter().zip(names) {
+                leaf.extend_from_slice(&offset.to_le_bytes());
+                match signature {
+                    b"lh" => leaf.extend_from_slice(&cell::hash_name(name).to_le_bytes()),
+                    b"lf" => leaf.extend_from_slice(&cell::hint_name(name)),
+                    _ => {}
+                }
+            }
+            leaves.push(self.alloc(&leaf));
+        }
+
+        if leaves.len() == 1 {
+            return Ok(leaves[0]);
+        }
+
+        let mut root = b"ri".to_vec();
+        root.extend_from_slice(&(leaves.len() as u16).to_le_bytes());
+        for leaf in leaves {
+            root.extend_from_slice(&leaf.to_le_bytes());
+        }
+        Ok(self.alloc(&root))
+    }
+
+    /// Frees a subkey list, including the leaves of an index root.
+    pub(crate) fn free_subkey_list(&mut self, offset: u32) -> Result<(), Error> {
+        if offset == NONE {
+            return Ok(());
+        }
+
+        let leaves = {
+            let list = self.cells().cell(offset)?;
+            match list.bytes(0, 2)? {
+                b"ri" => (0..list.u16(0x02)? as usize)
+                    .map(|i| list.u32(0x04 + i * 4))
+                    .collect::<Result<Vec<_>, _>>()?,
+                _ => vec![],
+            }
+        };
+
+        for leaf in leaves {
+            self.free(leaf);
+        }
+        self.free(offset);
+        Ok(())
+    }
+
+    /// Stores value data, returning the size and offset fields for its value node.
+    pub(crate) fn write_data(&mut self, data: &[u8]) -> (u32, u32) {
+        if data.len() <= 4 {
+            let mut inline = [0u8; 4];
+            inline[..data.len()].copy_from_slice(data);
+            return (data.len() as u32 | 0x8000_0000, u32::from_le_bytes(inline));
+        }
+
+        if data.len() <= BIG_DATA_SEGMENT_SIZE || !self.cells().has_big_data() {
+            return (data.len() as u32, self.alloc(data));
+        }
+
+        let segments = data
+            .chunks(BIG_DATA_SEGMENT_SIZE)
+            .map(|x| self.alloc(x))
+            .flat_map(|x| x.to_le_bytes().to_vec())
+            .collect::<Vec<_>>();
+        let list = self.alloc(&segments);
+
+        let mut db = b"db".to_vec();
+        db.extend_from_slice(&((segments.len() / 4) as u16).to_le_bytes());
+        db.extend_from_slice(&list.to_le_bytes());
+        (data.len() as u32, self.alloc(&db))
+    }
+
+    /// Frees the data of a value node, if it is not stored inline.
+    pub(crate) fn free_data(&mut self, value: u32) -> Result<(), Error> {
+        let (size, offset) = {
+            let vk = self.cells().cell(value)?;
+            (vk.u32(0x04)?, vk.u32(0x08)?)
+        };
+        if size & 0x8000_0000 != 0 || size == 0 || offset == NONE {
+            return Ok(());
+        }
+
+        if size as usize > BIG_DATA_SEGMENT_SIZE && self.cells().has_big_data() {
+            let (list, segments) = {
+                let cells = self.cells();
+                let db = cells.cell_with_signature(offset, b"db")?;
+                let list = cells.cell(db.u32(0x04)?)?;
+                let segments = (0..db.u16(0x02)? as usize)
+                    .map(|i| list.u32(i * 4))
+                    .collect::<Result<Vec<_>, _>>()?;
+                (list.offset, segments)
+            };
+            for segment in segments {
+                self.free(segment);
+            }
+            self.free(list);
+        }
+
+        self.free(offset);
+        Ok(())
+    }
+
+    /// The pages written since the last save, as runs of `(offset, len)` within the hive bins.
+    pub(crate) fn dirty(&self) -> Vec<(u32, u32)> {
+        let mut runs: Vec<(u32, u32)> = vec![];
+        for page in &self.dirty {
+            let offset = page * PAGE_SIZE;
+            match runs.last_mut() {
+                Some((start, len)) if *start + *len == offset => *len += PAGE_SIZE,
+                _ => runs.push((offset, PAGE_SIZE)),
+            }
+        }
+        runs
+    }
+
+    #[inline]
+    pub(crate) fn clear_dirty(&mut self) {
+        self.dirty.clear();
+    }
+}
@@ -0,0 +1,363 @@
+//! Bounds-checked access to the cells of a hive file.
+//!
+//! Nothing here allocates: cells are borrowed straight out of the underlying buffer and
+//! decoded on demand.
+
+use std::{borrow::Cow, cmp::Ordering};
+
+use utfx::U16CString;
+
+use super::Error;
+
+/// The offset used by hive files to mean "no cell".
+pub(crate) const NONE: u32 = 0xffff_ffff;
+
+/// The size of the base block at the start of a hive file, which cell offsets are relative to.
+pub(crate) const BASE_BLOCK_SIZE: usize = 4096;
+
+/// The largest amount of data stored in a single cell before big data (`db`) cells are used.
+pub(crate) const BIG_DATA_SEGMENT_SIZE: usize = 16344;
+
+/// The most entries written to a single subkey list leaf before an index root is used.
+pub(crate) const MAX_LEAF_LEN: usize = 1012;
+
+pub(crate) const KEY_HIVE_ENTRY: u16 = 0x0004;
+pub(crate) const KEY_NO_DELETE: u16 = 0x0008;
+pub(crate) const KEY_COMP_NAME: u16 = 0x0020;
+pub(crate) const VALUE_COMP_NAME: u16 = 0x0001;
+
+/// A view over the hive bins of a hive file, used to resolve cell offsets.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Cells<'a> {
+    bins: &'a [u8],
+    minor_version: u32,
+}
+
+impl<'a> Cells<'a> {
+    pub(crate) fn new(bins: &'a [u8], minor_version: u32) -> Cells<'a> {
+        Cells {
+            bins,
+            minor_version,
+        }
+    }
+
+    /// Whether values larger than a single segment are stored in big data cells.
+    #[inline]
+    pub(crate) fn has_big_data(&self) -> bool {
+        self.minor_version >= 4
+    }
+
+    /// Resolves the allocated cell at the given offset.
+    pub(crate) fn cell(&self, offset: u32) -> Result<Cell<'a>, Error> {
+        let start = offset as usize;
+        if offset == NONE || start & 7 != 0 || start + 4 > self.bins.len() {
+            return Err(Error::Corrupt(offset, "invalid cell offset"));
+        }
+
+        let size = i32::from_le_bytes([
+            self.bins[start],
+            self.bins[start + 1],
+            self.bins[start + 2],
+            self.bins[start + 3],
+        ]);
+
+        if size >= 0 {
+            return Err(Error::Corrupt(offset, "cell is not allocated"));
+        }
+
+        let end = start + size.unsigned_abs() as usize;
+        if end < start + 4 || end > self.bins.len() {
+            return Err(Error::Corrupt(offset, "cell size out of bounds"));
+        }
+
+        Ok(Cell {
+            offset,
+            data: &self.bins[start + 4..end],
+        })
+    }
+
+    /// Resolves a cell and checks its two byte signature.
+    pub(crate) fn cell_with_signature(
+        &self,
+        offset: u32,
+        signature: &[u8; 2],
+    ) -> Result<Cell<'a>, Error> {
+        let cell = self.cell(offset)?;
+        if cell.bytes(0, 2)? != signature {
+            return Err(Error::Corrupt(offset, "unexpected cell signature"));
+        }
+        Ok(cell)
+    }
+
+    /// Reads `len` bytes of data stored at `offset`, following big data cells if required.
+    pub(crate) fn data(&self, offset: u32, len: usize) -> Result<Cow<'a, [u8]>, Error> {
+        if len == 0 {
+            return Ok(Cow::Borrowed(&[]));
+        }
+
+        if len <= BIG_DATA_SEGMENT_SIZE || !self.has_big_data() {
+            return Ok(Cow::Borrowed(self.cell(offset)?.bytes(0, len)?));
+        }
+
+        let db = self.cell_with_signature(offset, b"db")?;
+        let count = db.u16(0x02)? as usize;
+        let segments = self.cell(db.u32(0x04)?)?;
+
+        let mut buf = Vec::with_capacity(len);
+        for i in 0..count {
+            let remaining = len - buf.len();
+            if remaining == 0 {
+                break;
+            }
+            let segment = self.cell(segments.u32(i * 4)?)?;
+            let take = remaining.min(BIG_DATA_SEGMENT_SIZE);
+            buf.extend_from_slice(segment.bytes(0, take)?);
+        }
+
+        if buf.len() != len {
+            return Err(Error::Corrupt(offset, "big data is shorter than its value"));
+        }
+
+        Ok(Cow::Owned(buf))
+    }
+}
+
+/// The data of a single allocated cell, excluding its size field.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Cell<'a> {
+    pub(crate) offset: u32,
+    pub(crate) data: &'a [u8],
+}
+
+impl<'a> Cell<'a> {
+    #[inline]
+    pub(crate) fn bytes(&self, at: usize, len: usize) -> Result<&'a [u8], Error> {
+        self.data
+            .get(at..at + len)
+            .ok_or(Error::Corrupt(self.offset, "field out of bounds"))
+    }
+
+    #[inline]
+    pub(crate) fn u16(&self, at: usize) -> Result<u16, Error> {
+        let b = self.bytes(at, 2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    #[inline]
+    pub(crate) fn u32(&self, at: usize) -> Result<u32, Error> {
+        let b = self.bytes(at, 4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    #[inline]
+    pub(crate) fn u64(&self, at: usize) -> Result<u64, Error> {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(self.bytes(at, 8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+}
+
+/// A key or value name as stored in a hive: either one byte per character (Latin-1) when
+/// the compressed flag is set, or UTF-16LE.
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum Name<'a> {
+    Compressed(&'a [u8]),
+    Utf16(&'a [u8]),
+}
+
+impl<'a> Name<'a> {
+    pub(crate) fn new(bytes: &'a [u8], compressed: bool) -> Name<'a> {
+        if compressed {
+            Name::Compressed(bytes)
+        } else {
+            Name::Utf16(bytes)
+        }
+    }
+
+    pub(crate) fn units(&self) -> impl Iterator<Item = u16> + 'a {
+        let (bytes, step) = match *self {
+            Name::Compressed(b) => (b, 1),
+            Name::Utf16(b) => (b, 2),
+        };
+
+        bytes.chunks_exact(step).map(move |x| match step {
+            1 => u16::from(x[0]),
+            _ => u16::from_le_bytes([x[0], x[1]]),
+        })
+    }
+
+    /// Compares this name to another, ignoring case, in the order Windows sorts subkey lists.
+    pub(crate) fn cmp_ignore_case(&self, other: &[u16]) -> Ordering {
+        self.units()
+            .map(upcase)
+            .cmp(other.iter().copied().map(upcase))
+    }
+
+    pub(crate) fn to_ucstring(self) -> U16CString {
+        let mut vec = self.units().collect::<Vec<_>>();
+        if let Some(len) = vec.iter().position(|x| *x == 0) {
+            vec.truncate(len);
+        }
+        // SAFETY: truncated at the first null, if there was one.
+        unsafe { U16CString::from_vec_unchecked(vec) }
+    }
+}
+
+/// Converts a UTF-16 code unit to upper case the way the registry does when comparing names:
+/// one code unit at a time, leaving characters without a single-unit upper case form as-is.
+pub(crate) fn upcase(c: u16) -> u16 {
+    match c {
+        0x61..=0x7a => c - 0x20,
+        0..=0x7f => c,
+        _ => {
+            let ch = match char::from_u32(u32::from(c)) {
+                Some(ch) => ch,
+                None => return c,
+            };
+            let mut upper = ch.to_uppercase();
+            match (upper.next(), upper.next()) {
+                (Some(u), None) if (u as u32) <= 0xffff => u as u16,
+                _ => c,
+            }
+        }
+    }
+}
+
+/// Encodes a name for storage: one byte per character if every character fits in Latin-1
+/// (a compressed name), otherwise UTF-16LE. Returns the bytes and whether they are compressed.
+pub(crate) fn encode_name(name: &[u16]) -> (Vec<u8>, bool) {
+    if name.iter().all(|c| *c < 0x100) {
+        (name.iter().map(|c| *c as u8).collect(), true)
+    } else {
+        (
+            name.iter().flat_map(|c| c.to_le_bytes().to_vec()).collect(),
+            false,
+        )
+    }
+}
+
+/// The hash stored alongside each entry of a hash leaf (`lh`).
+pub(crate) fn hash_name(name: &[u16]) -> u32 {
+    name.iter().fold(0u32, |acc, c| {
+        acc.wrapping_mul(37).wrapping_add(u32::from(upcase(*c)))
+    })
+}
+
+/// The hint stored alongside each entry of a fast leaf (`lf`): the first four characters.
+pub(crate) fn hint_name(name: &[u16]) -> [u8; 4] {
+    let mut hint = [0u8; 4];
+    for (h, c) in hint.iter_mut().zip(name) {
+        *h = *c as u8;
+    }
+    hint
+}
+
+/// A list of subkeys, as referenced from a key node. Index roots (`ri`) are flattened so
+/// that callers only see the leaves.
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum SubkeyList<'a> {
+    Empty,
+    Leaf(Leaf<'a>),
+    Root(Cell<'a>, usize),
+}
+
+impl<'a> SubkeyList<'a> {
+    pub(crate) fn new(cells: Cells<'a>, offset: u32, count: u32) -> Result<SubkeyList<'a>, Error> {
+        if count == 0 || offset == NONE {
+            return Ok(SubkeyList::Empty);
+        }
+
+        let cell = cells.cell(offset)?;
+        match cell.bytes(0, 2)? {
+            b"ri" => Ok(SubkeyList::Root(cell, cell.u16(0x02)? as usize)),
+            _ => Leaf::new(cell).map(SubkeyList::Leaf),
+        }
+    }
+
+    pub(crate) fn leaf_count(&self) -> usize {
+        match self {
+            SubkeyList::Empty => 0,
+            SubkeyList::Leaf(_) => 1,
+            SubkeyList::Root(_, count) => *count,
+        }
+    }
+
+    pub(crate) fn leaf(&self, cells: Cells<'a>, index: usize) -> Result<Leaf<'a>, Error> {
+        match self {
+            SubkeyList::Empty => Err(Error::Corrupt(NONE, "empty subkey list")),
+            SubkeyList::Leaf(leaf) => Ok(*leaf),
+            SubkeyList::Root(cell, _) => {
+                let leaf = cells.cell(cell.u32(0x04 + index * 4)?)?;
+                if leaf.bytes(0, 2)? == b"ri" {
+                    return Err(Error::Corrupt(leaf.offset, "nested index root"));
+                }
+                Leaf::new(leaf)
+            }
+        }
+    }
+}
+
+/// A leaf of a subkey list (`li`, `lf` or `lh`), holding offsets of key nodes.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Leaf<'a> {
+    cell: Cell<'a>,
+    stride: usize,
+    len: usize,
+}
+
+impl<'a> Leaf<'a> {
+    fn new(cell: Cell<'a>) -> Result<Leaf<'a>, Error> {
+        let stride = match cell.bytes(0, 2)? {
+            b"li" => 4,
+            b"lf" | b"lh" => 8,
+            _ => return Err(Error::Corrupt(cell.offset, "unknown subkey list type")),
+        };
+
+        Ok(Leaf {
+            cell,
+            stride,
+            len: cell.u16(0x02)? as usize,
+        })
+    }
+
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub(crate) fn get(&self, index: usize) -> Result<u32, Error> {
+        self.cell.u32(0x04 + index * self.stride)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upcase_matches_registry() {
+        assert_eq!(upcase(b'a' as u16), b'A' as u16);
+        assert_eq!(upcase(b'_' as u16), b'_' as u16);
+        assert_eq!(upcase(0xe9), 0xc9); // é
+        assert_eq!(upcase(0xdf), 0xdf); // ß has no single character upper case
+        assert_eq!(upcase(0xd800), 0xd800); // lone surrogate
+    }
+
+    #[test]
+    fn name_comparison() {
+        let other = "SOFTWARE".encode_utf16().collect::<Vec<_>>();
+        assert_eq!(
+            Name::Compressed(b"Software").cmp_ignore_case(&other),
+            Ordering::Equal
+        );
+        assert_eq!(
+            Name::Utf16(&[b'S', 0, b'Y', 0]).cmp_ignore_case(&other),
+            Ordering::Greater
+        );
+        assert_eq!(
+            Name::Compressed(b"Soft").cmp_ignore_case(&other),
+            Ordering::Less
+        );
+    }
+}
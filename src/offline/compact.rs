@@ -0,0 +1,349 @@
+//! Rewriting a hive into a freshly laid out file, dropping free space left by earlier edits.
+
+use std::collections::{HashMap, HashSet};
+use std::{fs::File, io::Write, path::Path};
+
+use super::alloc::Store;
+use super::cell::{BASE_BLOCK_SIZE, MAX_LEAF_LEN, NONE};
+use super::{checksum, Error, Hive, Key};
+
+/// A compacted copy of a hive, as produced by [`Hive::compact`](struct.Hive.html#method.compact).
+#[derive(Debug, Clone)]
+pub struct Compaction {
+    data: Vec<u8>,
+    original_len: u64,
+}
+
+impl Compaction {
+    /// The contents of the compacted hive file.
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    #[inline]
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// The size of the original hive file in bytes.
+    #[inline]
+    pub fn original_len(&self) -> u64 {
+        self.original_len
+    }
+
+    /// The size of the compacted hive file in bytes.
+    #[inline]
+    pub fn compacted_len(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    /// The number of bytes saved by compacting.
+    #[inline]
+    pub fn reclaimed(&self) -> u64 {
+        self.original_len.saturating_sub(self.compacted_len())
+    }
+
+    /// Writes the compacted hive to a file, waiting for it to reach the disk.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let mut file = File::create(path)?;
+        file.write_all(&self.data)?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Copies keys from a source hive into a new store, sharing security cells the same way.
+struct Writer {
+    store: Store,
+    /// New security cells by the offset of the source cell, with their reference counts.
+    security: HashMap<u32, (u32, u32)>,
+    /// New security cells in the order they were written.
+    security_order: Vec<u32>,
+    /// The offsets of the source keys copied so far, so a corrupt hive whose subkey lists
+    /// lead back to a key is reported instead of copied forever.
+    visited: HashSet<u32>,
+}
+
+impl Writer {
+    fn copy_security(&mut self, source: &Key<'_>) -> Result<u32, Error> {
+        let offset = source.security_offset()?;
+        if offset == NONE {
+            return Ok(NONE);
+        }
+
+        if let Some((new, count)) = self.security.get_mut(&offset) {
+            *count += 1;
+            return Ok(*new);
+        }
+
+        let descriptor = source.raw_security_descriptor()?.unwrap_or_default();
+        let mut sk = b"sk\0\0".to_vec();
+        sk.extend_from_slice(&[0u8; 12]);
+        sk.extend_from_slice(&(descriptor.len() as u32).to_le_bytes());
+        sk.extend_from_slice(descriptor);
+        let new = self.store.alloc(&sk);
+
+        self.security.insert(offset, (new, 1));
+        self.security_order.push(new);
+        Ok(new)
+    }
+
+    fn copy_key(&mut self, source: Key<'_>, parent: u32) -> Result<u32, Error> {
+        if !self.visited.insert(source.offset()) {
+            return Err(Error::Corrupt(
+                source.offset(),
+                "key is its own ancestor or shared",
+            ));
+        }
+
+        let name_len = source.cell.u16(0x48)? as usize;
+        let offset = self.store.alloc(source.cell.bytes(0, 0x4c + name_len)?);
+
+        // Volatile subkeys only exist in memory, so never belong in a file.
+        self.store.write(offset, 0x10, &parent.to_le_bytes());
+        self.store.write(offset, 0x18, &0u32.to_le_bytes());
+        self.store.write(offset, 0x20, &NONE.to_le_bytes());
+
+        let security = self.copy_security(&source)?;
+        self.store.write(offset, 0x2c, &security.to_le_bytes());
+
+        let class_len = source.cell.u16(0x4a)? as usize;
+        let class = match source.cell.u32(0x30)? {
+            NONE => NONE,
+            _ if class_len == 0 => NONE,
+            class => {
+                let bytes = source.cells.cell(class)?.bytes(0, class_len)?;
+                self.store.alloc(bytes)
+            }
+        };
+        self.store.write(offset, 0x30, &class.to_le_bytes());
+
+        let mut values = vec![];
+        for value in source.values()? {
+            let value = value?;
+            let name_len = value.cell.u16(0x02)? as usize;
+            let vk = self.store.alloc(value.cell.bytes(0, 0x14 + name_len)?);
+            if value.cell.u32(0x04)? & 0x8000_0000 == 0 {
+                let (size, data) = self.store.write_data(&value.raw_data()?);
+                self.store.write(vk, 0x04, &size.to_le_bytes());
+                self.store.write(vk, 0x08, &data.to_le_bytes());
+            }
+            values.push(vk);
+        }
+
+        let value_list = match values.len() {
+            0 => NONE,
+            _ => self.store.alloc(
+                &values
+                    .iter()
+                    .flat_map(|x| x.to_le_bytes().to_vec())
+                    .collect::<Vec<_>>(),
+            ),
+        };
+        self.store
+            .write(offset, 0x24, &(values.len() as u32).to_le_bytes());
+        self.store.write(offset, 0x28, &value_list.to_le_bytes());
+
+        // Subkeys are already stored in sorted order, so they are copied as they come.
+        let mut subkeys = vec![];
+        for subkey in source.keys()? {
+            subkeys.push(self.copy_key(subkey?, offset)?);
+        }
+        let subkey_list = self.store.write_subkey_list(&subkeys, MAX_LEAF_LEN)?;
+        self.store
+            .write(offset, 0x14, &(subkeys.len() as u32).to_le_bytes());
+        self.store.write(offset, 0x1c, &subkey_list.to_le_bytes());
+
+        Ok(offset)
+    }
+
+    /// Links the security cells into a ring, as the hive format expects, and sets their
+    /// reference counts.
+    fn finish_security(&mut self) {
+        let counts = self
+            .security
+            .values()
+            .copied()
+            .collect::<HashMap<u32, u32>>();
+        let len = self.security_order.len();
+
+        for (i, sk) in self.security_order.iter().enumerate() {
+            let next = self.security_order[(i + 1) % len];
+            let prev = self.security_order[(i + len - 1) % len];
+            self.store.write(*sk, 0x04, &next.to_le_bytes());
+            self.store.write(*sk, 0x08, &prev.to_le_bytes());
+            self.store.write(*sk, 0x0c, &counts[sk].to_le_bytes());
+        }
+    }
+}
+
+impl<B: AsRef<[u8]>> Hive<B> {
+    /// Rewrites the hive into a new, compact file.
+    ///
+    /// Cells are laid out afresh in the order the tree is walked, so free space left behind
+    /// by edits and partly empty hive bins are dropped. Keys, values, classes, timestamps and
+    /// security descriptors are copied unchanged, as is the base block apart from the
+    /// layout fields.
+    ///
+    /// Hives with an interrupted write are rejected with `Error::Dirty`, as their contents
+    /// may be half written; recover these with
+    /// [`Editor::load_with_log`](struct.Editor.html#method.load_with_log) first.
+    pub fn compact(&self) -> Result<Compaction, Error> {
+        let (primary, secondary) = self.sequence_numbers();
+        if primary != secondary {
+            return Err(Error::Dirty);
+        }
+
+        let base = self.bytes()[..BASE_BLOCK_SIZE].to_vec();
+        let mut writer = Writer {
+            store: Store::new(base, 0, self.minor_version)?,
+            security: HashMap::new(),
+            security_order: vec![],
+            visited: HashSet::new(),
+        };
+
+        let root = writer.copy_key(self.root()?, 0)?;
+        writer.finish_security();
+
+        let bins_len = writer.store.bins_len();
+        let mut data = writer.store.data;
+        data[0x04..0x08].copy_from_slice(&primary.to_le_bytes());
+        data[0x08..0x0c].copy_from_slice(&primary.to_le_bytes());
+        data[0x24..0x28].copy_from_slice(&root.to_le_bytes());
+        data[0x28..0x2c].copy_from_slice(&bins_len.to_le_bytes());
+        let sum = checksum(&data);
+        data[0x1fc..0x200].copy_from_slice(&sum.to_le_bytes());
+
+        Ok(Compaction {
+            data,
+            original_len: self.bytes().len() as u64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::super::fixture::{self, FixtureKey, FixtureValue};
+    use super::super::Editor;
+    use super::*;
+    use crate::Data;
+
+    fn assert_same(a: Key<'_>, b: Key<'_>) {
+        assert_eq!(a.name().unwrap(), b.name().unwrap());
+        assert_eq!(a.info().unwrap(), b.info().unwrap());
+        assert_eq!(
+            a.raw_security_descriptor().unwrap(),
+            b.raw_security_descriptor().unwrap()
+        );
+
+        let values = |k: Key<'_>| {
+            k.values()
+                .unwrap()
+                .map(|v| {
+                    let v = v.unwrap();
+                    (
+                        v.name().unwrap(),
+                        v.data_type().unwrap(),
+                        v.raw_data().unwrap().into_owned(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(values(a), values(b));
+
+        fn keys(k: Key<'_>) -> Vec<Key<'_>> {
+            k.keys().unwrap().map(Result::unwrap).collect()
+        }
+        let (a, b) = (keys(a), keys(b));
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.into_iter().zip(b) {
+            assert_same(a, b);
+        }
+    }
+
+    #[test]
+    fn compacts_churned_hive() {
+        let mut software = FixtureKey::new("Software").class("SoftwareClass");
+        for i in 0..20 {
+            software = software.key(
+                FixtureKey::new(&format!("Vendor{:02}", i))
+                    .value(FixtureValue::new("Large", Data::Binary(vec![i; 20000])))
+                    .value(FixtureValue::new("Small", Data::U32(i.into()))),
+            );
+        }
+        let root = FixtureKey::new("ROOT").key(software);
+
+        let mut editor = Editor::from_bytes(fixture::build(&root, 8)).unwrap();
+        for i in 0..20 {
+            let path = format!(r"Software\Vendor{:02}", i);
+            if i % 2 == 0 {
+                editor.delete(&*path, false).unwrap();
+            } else {
+                let mut key = editor.open(&*path).unwrap();
+                key.delete_value("Large").unwrap();
+                key.set_value("Name", &Data::String("x".try_into().unwrap()))
+                    .unwrap();
+            }
+        }
+        editor.create(r"Software\Extra\Nested").unwrap();
+
+        let churned = editor.into_bytes();
+        let hive = Hive::from_bytes(&churned[..]).unwrap();
+        let compaction = hive.compact().unwrap();
+        assert_eq!(compaction.original_len(), churned.len() as u64);
+        assert!(compaction.reclaimed() > 200_000);
+
+        let compacted = Hive::from_bytes(compaction.as_bytes()).unwrap();
+        assert!(compacted.is_checksum_valid());
+        assert_eq!(compacted.sequence_numbers(), hive.sequence_numbers());
+        assert_eq!(compacted.last_write_time(), hive.last_write_time());
+        assert_same(hive.root().unwrap(), compacted.root().unwrap());
+
+        // The result is a well formed hive that can be edited further.
+        let mut editor = Editor::from_bytes(compaction.into_bytes()).unwrap();
+        editor.delete(r"Software\Vendor01", false).unwrap();
+    }
+
+    #[test]
+    fn keeps_big_data_and_shared_security() {
+        let root = FixtureKey::new("ROOT")
+            .key(FixtureKey::new("A").value(FixtureValue::new("Big", Data::Binary(vec![7; 50000]))))
+            .key(FixtureKey::new("B"));
+        let hive = Hive::from_bytes(fixture::build(&root, 8)).unwrap();
+        let compaction = hive.compact().unwrap();
+        let compacted = Hive::from_bytes(compaction.as_bytes()).unwrap();
+        assert_same(hive.root().unwrap(), compacted.root().unwrap());
+
+        let root = compacted.root().unwrap();
+        let sk = root.security_offset().unwrap();
+        let cell = compacted.cells().cell(sk).unwrap();
+        assert_eq!(cell.u32(0x04).unwrap(), sk);
+        assert_eq!(cell.u32(0x0c).unwrap(), 3);
+        assert_eq!(compacted.open("a").unwrap().security_offset().unwrap(), sk);
+    }
+
+    #[test]
+    fn rejects_cycles_and_dirty_hives() {
+        let root = FixtureKey::new("ROOT").key(FixtureKey::new("A"));
+        let bytes = fixture::build(&root, 8);
+
+        // Give A the root's subkey list, so that A is its own subkey.
+        let mut cyclic = bytes.clone();
+        let hive = Hive::from_bytes(&bytes[..]).unwrap();
+        let a = hive.open("A").unwrap().offset();
+        let list = hive.root().unwrap().cell.u32(0x1c).unwrap();
+        let at = BASE_BLOCK_SIZE + a as usize + 4;
+        cyclic[at + 0x14..at + 0x18].copy_from_slice(&1u32.to_le_bytes());
+        cyclic[at + 0x1c..at + 0x20].copy_from_slice(&list.to_le_bytes());
+        let hive = Hive::from_bytes(&cyclic[..]).unwrap();
+        assert!(matches!(hive.compact(), Err(Error::Corrupt(x, _)) if x == a));
+
+        let mut dirty = bytes;
+        dirty[0x04] += 1;
+        let hive = Hive::from_bytes(&dirty[..]).unwrap();
+        assert!(matches!(hive.compact(), Err(Error::Dirty)));
+    }
+}
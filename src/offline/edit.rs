@@ -0,0 +1,1176 @@
+//! Modifying hive files: creating and deleting keys, setting and deleting values, and
+//! saving the result, optionally through a transaction log.
+
+use std::{
+    convert::TryInto,
+    fmt::Debug,
+    fs::{self, OpenOptions},
+    io::{self, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use utfx::U16CString;
+
+use super::alloc::Store;
+use super::cell::{self, BASE_BLOCK_SIZE, MAX_LEAF_LEN, NONE};
+use super::{checksum, log, Error, Hive, Key};
+use crate::info::FileTime;
+use crate::Data;
+
+/// The longest key name allowed, in UTF-16 code units.
+const MAX_KEY_NAME_LEN: usize = 255;
+
+/// The longest value name allowed, in UTF-16 code units.
+const MAX_VALUE_NAME_LEN: usize = 16383;
+
+/// The security descriptor given to the root of a new hive: owner Administrators, group
+/// SYSTEM, full control for Administrators and read access for Users.
+#[rustfmt::skip]
+pub(crate) const DEFAULT_SECURITY_DESCRIPTOR: &[u8] = &[
+    // Revision, Sbz1, Control (SE_SELF_RELATIVE | SE_DACL_PRESENT)
+    0x01, 0x00, 0x04, 0x80,
+    // Owner, Group, Sacl, Dacl offsets
+    0x14, 0x00, 0x00, 0x00, 0x24, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x30, 0x00, 0x00, 0x00,
+    // Owner: S-1-5-32-544
+    0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x20, 0x00, 0x00, 0x00, 0x20, 0x02, 0x00, 0x00,
+    // Group: S-1-5-18
+    0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x12, 0x00, 0x00, 0x00,
+    // DACL: revision 2, size 0x38, 2 ACEs
+    0x02, 0x00, 0x38, 0x00, 0x02, 0x00, 0x00, 0x00,
+    // ACCESS_ALLOWED_ACE, inherit to containers, KEY_ALL_ACCESS, S-1-5-32-544
+    0x00, 0x02, 0x18, 0x00, 0x3f, 0x00, 0x0f, 0x00,
+    0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x20, 0x00, 0x00, 0x00, 0x20, 0x02, 0x00, 0x00,
+    // ACCESS_ALLOWED_ACE, inherit to containers, KEY_READ, S-1-5-32-545
+    0x00, 0x02, 0x18, 0x00, 0x19, 0x00, 0x02, 0x00,
+    0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x20, 0x00, 0x00, 0x00, 0x21, 0x02, 0x00, 0x00,
+];
+
+fn components(path: &[u16]) -> impl Iterator<Item = &[u16]> {
+    path.split(|c| *c == u16::from(b'\\'))
+        .filter(|x| !x.is_empty())
+}
+
+/// Replaces the file at `path` with what `write` writes to a new file beside it, so that
+/// failing or crashing partway leaves the old file as it was.
+fn replace<F>(path: &Path, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut fs::File) -> io::Result<()>,
+{
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+
+    let written = fs::File::create(&temp).and_then(|mut file| {
+        write(&mut file)?;
+        file.sync_all()
+    });
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    fs::rename(&temp, path)?;
+
+    // Make the rename itself durable, where directories can be synced.
+    #[cfg(unix)]
+    {
+        if let Some(dir) = path.parent().filter(|x| !x.as_os_str().is_empty()) {
+            fs::File::open(dir)?.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+fn check_name(name: &[u16], max: usize) -> Result<(), Error> {
+    if name.len() > max {
+        return Err(Error::InvalidName(String::from_utf16_lossy(name)));
+    }
+    Ok(())
+}
+
+/// Builds a key node with no subkeys, values or class.
+fn key_node(name: &[u16], flags: u16, parent: u32, security: u32) -> Vec<u8> {
+    let (name, compressed) = cell::encode_name(name);
+    let flags = if compressed {
+        flags | cell::KEY_COMP_NAME
+    } else {
+        flags
+    };
+
+    let mut nk = vec![0u8; 0x4c];
+    let mut put = |at: usize, bytes: &[u8]| nk[at..at + bytes.len()].copy_from_slice(bytes);
+    put(0x00, b"nk");
+    put(0x02, &flags.to_le_bytes());
+    put(0x04, &FileTime::now().to_le_bytes());
+    put(0x10, &parent.to_le_bytes());
+    put(0x1c, &NONE.to_le_bytes());
+    put(0x20, &NONE.to_le_bytes());
+    put(0x28, &NONE.to_le_bytes());
+    put(0x2c, &security.to_le_bytes());
+    put(0x30, &NONE.to_le_bytes());
+    put(0x48, &(name.len() as u16).to_le_bytes());
+    nk.extend_from_slice(&name);
+    nk
+}
+
+/// A hive file loaded for editing.
+///
+/// The whole file is held in memory and changes are made there; nothing is written until
+/// [`save`](#method.save) is called. Saving only writes the pages that changed, following
+/// the same protocol as Windows: the primary sequence number is bumped before the pages are
+/// written and the secondary one after, so an interrupted save can be detected. If a
+/// transaction log is used, the changed pages are written to it first so that the save can
+/// be completed when the hive is next loaded.
+///
+/// ```no_run
+/// use registry::{offline::Editor, Data};
+///
+/// let mut editor = Editor::load_with_log("NTUSER.DAT", "NTUSER.DAT.LOG1")?;
+/// let mut key = editor.create(r"Software\Contoso")?;
+/// key.set_value("Version", &Data::U32(2))?;
+/// editor.save()?;
+/// # Ok::<(), registry::offline::Error>(())
+/// ```
+pub struct Editor {
+    store: Store,
+    root: u32,
+    minor_version: u32,
+    leaf_capacity: usize,
+    path: Option<PathBuf>,
+    log: Option<PathBuf>,
+}
+
+impl Debug for Editor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Editor")
+            .field("path", &self.path)
+            .field("log", &self.log)
+            .field("len", &self.store.data.len())
+            .finish()
+    }
+}
+
+impl Default for Editor {
+    fn default() -> Self {
+        Editor::new()
+    }
+}
+
+impl Editor {
+    /// Creates an empty hive, holding only a root key named `ROOT`.
+    pub fn new() -> Editor {
+        let mut base = vec![0u8; BASE_BLOCK_SIZE];
+        let mut put = |at: usize, bytes: &[u8]| base[at..at + bytes.len()].copy_from_slice(bytes);
+        put(0x00, b"regf");
+        put(0x04, &1u32.to_le_bytes());
+        put(0x08, &1u32.to_le_bytes());
+        put(0x14, &1u32.to_le_bytes());
+        put(0x18, &5u32.to_le_bytes());
+        put(0x20, &1u32.to_le_bytes());
+        put(0x2c, &1u32.to_le_bytes());
+
+        let mut store = Store::new(base, 0, 5).expect("an empty hive has no cells to index");
+
+        let mut sk = b"sk\0\0".to_vec();
+        sk.extend_from_slice(&[0u8; 8]);
+        sk.extend_from_slice(&1u32.to_le_bytes());
+        sk.extend_from_slice(&(DEFAULT_SECURITY_DESCRIPTOR.len() as u32).to_le_bytes());
+        sk.extend_from_slice(DEFAULT_SECURITY_DESCRIPTOR);
+        let security = store.alloc(&sk);
+        store.write(security, 0x04, &security.to_le_bytes());
+        store.write(security, 0x08, &security.to_le_bytes());
+
+        let name = "ROOT".encode_utf16().collect::<Vec<_>>();
+        let flags = cell::KEY_HIVE_ENTRY | cell::KEY_NO_DELETE;
+        let root = store.alloc(&key_node(&name, flags, 0, security));
+
+        let mut editor = Editor {
+            store,
+            root,
+            minor_version: 5,
+            leaf_capacity: MAX_LEAF_LEN,
+            path: None,
+            log: None,
+        };
+        editor.store.data[0x24..0x28].copy_from_slice(&root.to_le_bytes());
+        editor.update_base_block(1, 1);
+        editor
+    }
+
+    /// Takes a whole hive file for editing.
+    ///
+    /// Hives with an interrupted write are rejected with `Error::Dirty`; load these with
+    /// [`load_with_log`](#method.load_with_log) instead.
+    pub fn from_bytes(mut data: Vec<u8>) -> Result<Editor, Error> {
+        let (root, bins_len, minor_version) = {
+            let hive = Hive::from_bytes(&data[..])?;
+            let (primary, secondary) = hive.sequence_numbers();
+            if primary != secondary {
+                return Err(Error::Dirty);
+            }
+            (hive.root, hive.bins_len, hive.minor_version)
+        };
+
+        data.truncate(BASE_BLOCK_SIZE + bins_len);
+        Ok(Editor {
+            store: Store::new(data, bins_len as u32, minor_version)?,
+            root,
+            minor_version,
+            leaf_capacity: MAX_LEAF_LEN,
+            path: None,
+            log: None,
+        })
+    }
+
+    /// Loads the hive file at the given path for editing, to be saved back in place.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Editor, Error> {
+        let mut editor = Editor::from_bytes(fs::read(&path)?)?;
+        editor.path = Some(path.as_ref().to_path_buf());
+        Ok(editor)
+    }
+
+    /// Loads the hive file at the given path for editing, using a transaction log for saves.
+    ///
+    /// If the last save to the hive was interrupted, it is first completed from the log
+    /// and the recovered hive written back to disk.
+    pub fn load_with_log<P, L>(path: P, log_path: L) -> Result<Editor, Error>
+    where
+        P: AsRef<Path>,
+        L: AsRef<Path>,
+    {
+        let mut data = fs::read(&path)?;
+        match fs::read(&log_path) {
+            Ok(log) => {
+                // The log is only cleared once the recovered hive has replaced the old one.
+                if log::recover(&mut data, &log)? {
+                    replace(path.as_ref(), |file| file.write_all(&data))?;
+                    log::write(log_path.as_ref(), &[])?;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut editor = Editor::from_bytes(data)?;
+        editor.path = Some(path.as_ref().to_path_buf());
+        editor.log = Some(log_path.as_ref().to_path_buf());
+        Ok(editor)
+    }
+
+    /// The file that [`save`](#method.save) writes to, if any.
+    #[inline]
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// A read-only view of the hive as it currently stands, including unsaved changes.
+    pub fn hive(&self) -> Hive<&[u8]> {
+        Hive {
+            data: &self.store.data[..],
+            root: self.root,
+            bins_len: self.store.bins_len() as usize,
+            minor_version: self.minor_version,
+        }
+    }
+
+    /// The root key of the hive.
+    #[inline]
+    pub fn root(&mut self) -> KeyMut<'_> {
+        let offset = self.root;
+        KeyMut {
+            editor: self,
+            offset,
+        }
+    }
+
+    /// Opens a key by its backslash separated path from the root of the hive, ignoring case.
+    pub fn open<P>(&mut self, path: P) -> Result<KeyMut<'_>, Error>
+    where
+        P: TryInto<U16CString>,
+        P::Error: Into<Error>,
+    {
+        self.root().into_open(path)
+    }
+
+    /// Opens a key, creating it and any missing keys along its path.
+    pub fn create<P>(&mut self, path: P) -> Result<KeyMut<'_>, Error>
+    where
+        P: TryInto<U16CString>,
+        P::Error: Into<Error>,
+    {
+        self.root().into_create(path)
+    }
+
+    /// Deletes a key. Keys with subkeys are only deleted if `is_recursive` is set.
+    pub fn delete<P>(&mut self, path: P, is_recursive: bool) -> Result<(), Error>
+    where
+        P: TryInto<U16CString>,
+        P::Error: Into<Error>,
+    {
+        self.root().delete(path, is_recursive)
+    }
+
+    /// Saves the changes made since the last save to the file the hive was loaded from.
+    pub fn save(&mut self) -> Result<(), Error> {
+        let path = self.path.clone().ok_or(Error::InvalidOperation(
+            "the hive was not loaded from a file",
+        ))?;
+
+        let committed = self.sequence();
+        let next = committed.wrapping_add(1);
+        let dirty = self.store.dirty();
+
+        self.update_base_block(committed, committed);
+        if let Some(log_path) = &self.log {
+            log::write(log_path, &log::build(&self.store.data, &dirty, committed))?;
+        }
+
+        let mut file = OpenOptions::new().write(true).open(&path)?;
+        self.update_base_block(next, committed);
+        file.write_all(&self.store.data[..BASE_BLOCK_SIZE])?;
+        for (offset, len) in &dirty {
+            let start = BASE_BLOCK_SIZE + *offset as usize;
+            file.seek(SeekFrom::Start(start as u64))?;
+            file.write_all(&self.store.data[start..start + *len as usize])?;
+        }
+        file.set_len(self.store.data.len() as u64)?;
+        file.sync_all()?;
+
+        self.update_base_block(next, next);
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&self.store.data[..BASE_BLOCK_SIZE])?;
+        file.sync_all()?;
+
+        self.store.clear_dirty();
+        Ok(())
+    }
+
+    /// Writes the whole hive to a new file, which later calls to [`save`](#method.save)
+    /// will write to. A transaction log set for the previous file is no longer used.
+    pub fn save_as<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        let sequence = self.sequence();
+        self.update_base_block(sequence, sequence);
+
+        replace(path.as_ref(), |file| file.write_all(&self.store.data))?;
+
+        self.store.clear_dirty();
+        self.path = Some(path.as_ref().to_path_buf());
+        self.log = None;
+        Ok(())
+    }
+
+    /// The contents of the hive file, including unsaved changes.
+    pub fn into_bytes(mut self) -> Vec<u8> {
+        let sequence = self.sequence();
+        self.update_base_block(sequence, sequence);
+        self.store.data
+    }
+
+    #[inline]
+    fn sequence(&self) -> u32 {
+        let b = &self.store.data;
+        u32::from_le_bytes([b[0x08], b[0x09], b[0x0a], b[0x0b]])
+    }
+
+    fn update_base_block(&mut self, primary: u32, secondary: u32) {
+        let bins_len = self.store.bins_len();
+        let base = &mut self.store.data[..BASE_BLOCK_SIZE];
+        base[0x04..0x08].copy_from_slice(&primary.to_le_bytes());
+        base[0x08..0x0c].copy_from_slice(&secondary.to_le_bytes());
+        base[0x0c..0x14].copy_from_slice(&FileTime::now().to_le_bytes());
+        base[0x28..0x2c].copy_from_slice(&bins_len.to_le_bytes());
+        let sum = checksum(base);
+        base[0x1fc..0x200].copy_from_slice(&sum.to_le_bytes());
+    }
+
+    #[inline]
+    pub(crate) fn key(&self, offset: u32) -> Result<Key<'_>, Error> {
+        Key::new(self.store.cells(), offset)
+    }
+
+    /// The key at the given offset, which must have been checked to be a key node.
+    #[inline]
+    pub(crate) fn key_mut(&mut self, offset: u32) -> KeyMut<'_> {
+        KeyMut {
+            editor: self,
+            offset,
+        }
+    }
+
+    #[inline]
+    fn field(&self, cell: u32, at: usize) -> Result<u32, Error> {
+        self.store.cells().cell(cell)?.u32(at)
+    }
+
+    #[inline]
+    fn set_field(&mut self, cell: u32, at: usize, value: u32) {
+        self.store.write(cell, at, &value.to_le_bytes());
+    }
+
+    /// Raises a maximum length field of a key node to at least `len`, keeping the flags
+    /// stored in its upper half.
+    fn raise_max(&mut self, key: u32, at: usize, len: u32, mask: u32) -> Result<(), Error> {
+        let field = self.field(key, at)?;
+        if field & mask < len {
+            self.set_field(key, at, (field & !mask) | len);
+        }
+        Ok(())
+    }
+
+    fn touch(&mut self, key: u32) {
+        self.store.write(key, 0x04, &FileTime::now().to_le_bytes());
+    }
+
+    fn find_path(&self, from: u32, path: &[u16]) -> Result<u32, Error> {
+        let mut key = self.key(from)?;
+        for component in components(path) {
+            key = match key.find_subkey(component)? {
+                Some(v) => v,
+                None => return Err(Error::NotFound(String::from_utf16_lossy(path))),
+            };
+        }
+        Ok(key.offset())
+    }
+
+    fn create_path(&mut self, from: u32, path: &[u16]) -> Result<u32, Error> {
+        let mut offset = from;
+        for component in components(path) {
+            offset = match self.key(offset)?.find_subkey(component)? {
+                Some(v) => v.offset(),
+                None => self.create_subkey(offset, component)?,
+            };
+        }
+        Ok(offset)
+    }
+
+    fn subkeys(&self, key: u32) -> Result<Vec<u32>, Error> {
+        self.key(key)?
+            .keys()?
+            .map(|k| k.map(|k| k.offset()))
+            .collect()
+    }
+
+    fn create_subkey(&mut self, parent: u32, name: &[u16]) -> Result<u32, Error> {
+        check_name(name, MAX_KEY_NAME_LEN)?;
+
+        let security = self.key(parent)?.security_offset()?;
+        self.retain_security(security)?;
+        let offset = self.store.alloc(&key_node(name, 0, parent, security));
+
+        if self.key(parent)?.subkey_count()? == 0 {
+            self.replace_subkeys(parent, &[offset])?;
+        } else {
+            self.insert_subkey(parent, name, offset)?;
+        }
+
+        self.raise_max(parent, 0x34, name.len() as u32 * 2, 0xffff)?;
+        self.touch(parent);
+        Ok(offset)
+    }
+
+    /// Inserts a key node into a non-empty subkey list where a binary search by name puts it,
+    /// rewriting only the leaf it goes into, split in two if it is full, and any index root.
+    fn insert_subkey(&mut self, key: u32, name: &[u16], offset: u32) -> Result<(), Error> {
+        let (list, count, (leaf_index, index)) = {
+            let key = self.key(key)?;
+            (
+                self.field(key.offset(), 0x1c)?,
+                key.subkey_count()?,
+                key.subkey_position(name)?,
+            )
+        };
+
+        let (mut leaves, is_root) = {
+            let cell = self.store.cells().cell(list)?;
+            match cell.bytes(0, 2)? {
+                b"ri" => (
+                    (0..cell.u16(0x02)? as usize)
+                        .map(|i| cell.u32(0x04 + i * 4))
+                        .collect::<Result<Vec<_>, _>>()?,
+                    true,
+                ),
+                _ => (vec![list], false),
+            }
+        };
+
+        let old_leaf = leaves[leaf_index];
+        let (signature, mut elements) = {
+            let cell = self.store.cells().cell(old_leaf)?;
+            let signature = cell.bytes(0, 2)?.to_vec();
+            let size = if signature == b"li" { 4 } else { 8 };
+            let len = cell.u16(0x02)? as usize;
+            let elements = cell
+                .bytes(0x04, len * size)?
+                .chunks(size)
+                .map(|x| x.to_vec())
+                .collect::<Vec<_>>();
+            (signature, elements)
+        };
+
+        let mut element = offset.to_le_bytes().to_vec();
+        match &signature[..] {
+            b"lh" => element.extend_from_slice(&cell::hash_name(name).to_le_bytes()),
+            b"lf" => element.extend_from_slice(&cell::hint_name(name)),
+            _ => {}
+        }
+        elements.insert(index, element);
+
+        let leaf = |elements: &[Vec<u8>]| {
+            let mut leaf = signature.clone();
+            leaf.extend_from_slice(&(elements.len() as u16).to_le_bytes());
+            for element in elements {
+                leaf.extend_from_slice(element);
+            }
+            leaf
+        };
+        self.store.free(old_leaf);
+        let new_leaves = if elements.len() > self.leaf_capacity {
+            let (first, second) = elements.split_at(elements.len() / 2);
+            vec![
+                self.store.alloc(&leaf(first)),
+                self.store.alloc(&leaf(second)),
+            ]
+        } else {
+            vec![self.store.alloc(&leaf(&elements))]
+        };
+        leaves.splice(leaf_index..=leaf_index, new_leaves);
+
+        if is_root {
+            self.store.free(list);
+        }
+        let list = match leaves.len() {
+            1 => leaves[0],
+            _ => {
+                let mut root = b"ri".to_vec();
+                root.extend_from_slice(&(leaves.len() as u16).to_le_bytes());
+                for leaf in &leaves {
+                    root.extend_from_slice(&leaf.to_le_bytes());
+                }
+                self.store.alloc(&root)
+            }
+        };
+        self.set_field(key, 0x14, count + 1);
+        self.set_field(key, 0x1c, list);
+        Ok(())
+    }
+
+    fn delete_subkey(&mut self, key: u32, is_recursive: bool) -> Result<(), Error> {
+        let (parent, subkey_count, name) = {
+            let key = self.key(key)?;
+            let parent = match key.parent()? {
+                Some(v) => v.offset(),
+                None => return Err(Error::InvalidOperation("the root key cannot be deleted")),
+            };
+            (parent, key.subkey_count()?, key.to_string())
+        };
+
+        if subkey_count > 0 && !is_recursive {
+            return Err(Error::HasSubkeys(name));
+        }
+
+        let mut entries = self.subkeys(parent)?;
+        entries.retain(|x| *x != key);
+        self.free_key(key)?;
+        self.replace_subkeys(parent, &entries)?;
+        self.touch(parent);
+        Ok(())
+    }
+
+    /// Frees a key node and everything it owns, including its subkeys.
+    fn free_key(&mut self, offset: u32) -> Result<(), Error> {
+        for child in self.subkeys(offset)? {
+            self.free_key(child)?;
+        }
+
+        let (subkey_list, values, value_list, class, security) = {
+            let key = self.key(offset)?;
+            let subkey_list = match key.subkey_count()? {
+                0 => NONE,
+                _ => self.field(offset, 0x1c)?,
+            };
+            let value_list = match key.value_count()? {
+                0 => NONE,
+                _ => self.field(offset, 0x28)?,
+            };
+            let values = key
+                .values()?
+                .map(|v| v.map(|v| v.offset()))
+                .collect::<Result<Vec<_>, _>>()?;
+            (
+                subkey_list,
+                values,
+                value_list,
+                self.field(offset, 0x30)?,
+                key.security_offset()?,
+            )
+        };
+
+        self.store.free_subkey_list(subkey_list)?;
+        for value in values {
+            self.store.free_data(value)?;
+            self.store.free(value);
+        }
+        for cell in &[value_list, class] {
+            if *cell != NONE {
+                self.store.free(*cell);
+            }
+        }
+        self.release_security(security)?;
+        self.store.free(offset);
+        Ok(())
+    }
+
+    /// Replaces the subkey list of a key with one holding the given key nodes, which must
+    /// already be sorted.
+    fn replace_subkeys(&mut self, key: u32, entries: &[u32]) -> Result<(), Error> {
+        if self.key(key)?.subkey_count()? > 0 {
+            let old = self.field(key, 0x1c)?;
+            self.store.free_subkey_list(old)?;
+        }
+
+        let list = self.store.write_subkey_list(entries, self.leaf_capacity)?;
+        self.set_field(key, 0x14, entries.len() as u32);
+        self.set_field(key, 0x1c, list);
+        Ok(())
+    }
+
+    fn retain_security(&mut self, offset: u32) -> Result<(), Error> {
+        if offset == NONE {
+            return Ok(());
+        }
+        let count = self
+            .store
+            .cells()
+            .cell_with_signature(offset, b"sk")?
+            .u32(0x0c)?;
+        self.set_field(offset, 0x0c, count.saturating_add(1));
+        Ok(())
+    }
+
+    /// Drops a reference to a security cell, unlinking and freeing it once unused.
+    fn release_security(&mut self, offset: u32) -> Result<(), Error> {
+        if offset == NONE {
+            return Ok(());
+        }
+
+        let (count, next, prev) = {
+            let sk = self.store.cells().cell_with_signature(offset, b"sk")?;
+            (sk.u32(0x0c)?, sk.u32(0x04)?, sk.u32(0x08)?)
+        };
+
+        if count > 1 {
+            self.set_field(offset, 0x0c, count - 1);
+            return Ok(());
+        }
+
+        if next != offset {
+            self.set_field(prev, 0x04, next);
+            self.set_field(next, 0x08, prev);
+        }
+        self.store.free(offset);
+        Ok(())
+    }
+
+    fn values(&self, key: u32) -> Result<Vec<u32>, Error> {
+        self.key(key)?
+            .values()?
+            .map(|v| v.map(|v| v.offset()))
+            .collect()
+    }
+
+    fn replace_values(&mut self, key: u32, entries: &[u32]) -> Result<(), Error> {
+        if self.key(key)?.value_count()? > 0 {
+            let old = self.field(key, 0x28)?;
+            self.store.free(old);
+        }
+
+        let list = match entries.len() {
+            0 => NONE,
+            _ => self.store.alloc(
+                &entries
+                    .iter()
+                    .flat_map(|x| x.to_le_bytes().to_vec())
+                    .collect::<Vec<_>>(),
+            ),
+        };
+        self.set_field(key, 0x24, entries.len() as u32);
+        self.set_field(key, 0x28, list);
+        Ok(())
+    }
+
+    fn set_value(&mut self, key: u32, name: &[u16], ty: u32, data: &[u8]) -> Result<(), Error> {
+        check_name(name, MAX_VALUE_NAME_LEN)?;
+
+        let existing = self.key(key)?.find_value(name)?.map(|v| v.offset());
+        match existing {
+            Some(value) => {
+                self.store.free_data(value)?;
+                let (size, offset) = self.store.write_data(data);
+                self.set_field(value, 0x04, size);
+                self.set_field(value, 0x08, offset);
+                self.set_field(value, 0x0c, ty);
+            }
+            None => {
+                let (size, offset) = self.store.write_data(data);
+                let (encoded, compressed) = cell::encode_name(name);
+                let flags = if compressed { cell::VALUE_COMP_NAME } else { 0 };
+
+                let mut vk = b"vk".to_vec();
+                vk.extend_from_slice(&(encoded.len() as u16).to_le_bytes());
+                vk.extend_from_slice(&size.to_le_bytes());
+                vk.extend_from_slice(&offset.to_le_bytes());
+                vk.extend_from_slice(&ty.to_le_bytes());
+                vk.extend_from_slice(&flags.to_le_bytes());
+                vk.extend_from_slice(&[0, 0]);
+                vk.extend_from_slice(&encoded);
+                let value = self.store.alloc(&vk);
+
+                let mut entries = self.values(key)?;
+                entries.push(value);
+                self.replace_values(key, &entries)?;
+            }
+        }
+
+        self.raise_max(key, 0x3c, name.len() as u32 * 2, !0)?;
+        self.raise_max(key, 0x40, data.len() as u32, !0)?;
+        self.touch(key);
+        Ok(())
+    }
+
+    fn delete_value(&mut self, key: u32, name: &[u16]) -> Result<(), Error> {
+        let value = match self.key(key)?.find_value(name)? {
+            Some(v) => v.offset(),
+            None => return Err(Error::NotFound(String::from_utf16_lossy(name))),
+        };
+
+        let mut entries = self.values(key)?;
+        entries.retain(|x| *x != value);
+        self.store.free_data(value)?;
+        self.store.free(value);
+        self.replace_values(key, &entries)?;
+        self.touch(key);
+        Ok(())
+    }
+}
+
+/// A key within an [`Editor`](struct.Editor.html), mirroring the modifying methods of
+/// [`RegKey`](../struct.RegKey.html).
+pub struct KeyMut<'a> {
+    editor: &'a mut Editor,
+    offset: u32,
+}
+
+impl Debug for KeyMut<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.key() {
+            Ok(key) => Debug::fmt(&key, f),
+            Err(_) => f.write_str("<invalid>"),
+        }
+    }
+}
+
+impl<'a> KeyMut<'a> {
+    /// The offset of this key's cell within the hive bins.
+    #[inline]
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    /// A read-only view of this key, for enumerating its subkeys and values.
+    #[inline]
+    pub fn key(&self) -> Result<Key<'_>, Error> {
+        self.editor.key(self.offset)
+    }
+
+    /// Opens a descendant of this key by a backslash separated path, ignoring case.
+    pub fn open<P>(&mut self, path: P) -> Result<KeyMut<'_>, Error>
+    where
+        P: TryInto<U16CString>,
+        P::Error: Into<Error>,
+    {
+        let path = path.try_into().map_err(Into::into)?;
+        let offset = self.editor.find_path(self.offset, path.as_slice())?;
+        Ok(KeyMut {
+            editor: self.editor,
+            offset,
+        })
+    }
+
+    fn into_open<P>(self, path: P) -> Result<KeyMut<'a>, Error>
+    where
+        P: TryInto<U16CString>,
+        P::Error: Into<Error>,
+    {
+        let path = path.try_into().map_err(Into::into)?;
+        let offset = self.editor.find_path(self.offset, path.as_slice())?;
+        Ok(KeyMut {
+            editor: self.editor,
+            offset,
+        })
+    }
+
+    /// Opens a descendant of this key, creating it and any missing keys along its path.
+    pub fn create<P>(&mut self, path: P) -> Result<KeyMut<'_>, Error>
+    where
+        P: TryInto<U16CString>,
+        P::Error: Into<Error>,
+    {
+        let path = path.try_into().map_err(Into::into)?;
+        let offset = self.editor.create_path(self.offset, path.as_slice())?;
+        Ok(KeyMut {
+            editor: self.editor,
+            offset,
+        })
+    }
+
+    fn into_create<P>(self, path: P) -> Result<KeyMut<'a>, Error>
+    where
+        P: TryInto<U16CString>,
+        P::Error: Into<Error>,
+    {
+        let path = path.try_into().map_err(Into::into)?;
+        let offset = self.editor.create_path(self.offset, path.as_slice())?;
+        Ok(KeyMut {
+            editor: self.editor,
+            offset,
+        })
+    }
+
+    /// Deletes a descendant of this key. Keys with subkeys are only deleted if
+    /// `is_recursive` is set.
+    pub fn delete<P>(&mut self, path: P, is_recursive: bool) -> Result<(), Error>
+    where
+        P: TryInto<U16CString>,
+        P::Error: Into<Error>,
+    {
+        let path = path.try_into().map_err(Into::into)?;
+        let offset = self.editor.find_path(self.offset, path.as_slice())?;
+        self.editor.delete_subkey(offset, is_recursive)
+    }
+
+    /// Deletes this key. Keys with subkeys are only deleted if `is_recursive` is set.
+    pub fn delete_self(self, is_recursive: bool) -> Result<(), Error> {
+        self.editor.delete_subkey(self.offset, is_recursive)
+    }
+
+    /// Reads the value with the given name, ignoring case.
+    pub fn value<S>(&self, name: S) -> Result<Data, Error>
+    where
+        S: TryInto<U16CString>,
+        S::Error: Into<Error>,
+    {
+        self.key()?.value(name)?.data()
+    }
+
+    /// Sets a value, replacing any existing value with the same name.
+    pub fn set_value<S>(&mut self, name: S, data: &Data) -> Result<(), Error>
+    where
+        S: TryInto<U16CString>,
+        S::Error: Into<Error>,
+    {
+        self.set_raw_value(name, data.ty(), &data.to_bytes())
+    }
+
+    /// Sets a value from its raw type code and bytes, for data that `Data` cannot represent.
+    pub fn set_raw_value<S>(&mut self, name: S, ty: u32, data: &[u8]) -> Result<(), Error>
+    where
+        S: TryInto<U16CString>,
+        S::Error: Into<Error>,
+    {
+        let name = name.try_into().map_err(Into::into)?;
+        self.editor
+            .set_value(self.offset, name.as_slice(), ty, data)
+    }
+
+    /// Deletes the value with the given name, ignoring case.
+    pub fn delete_value<S>(&mut self, name: S) -> Result<(), Error>
+    where
+        S: TryInto<U16CString>,
+        S::Error: Into<Error>,
+    {
+        let name = name.try_into().map_err(Into::into)?;
+        self.editor.delete_value(self.offset, name.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fixture::{self, FixtureKey, FixtureValue};
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("registry-rs-{}-{}", std::process::id(), name))
+    }
+
+    fn names(key: Key<'_>) -> Vec<String> {
+        key.keys()
+            .unwrap()
+            .map(|k| k.unwrap().to_string())
+            .collect()
+    }
+
+    fn security_refs(hive: &Hive<&[u8]>) -> u32 {
+        let sk = hive.root().unwrap().security_offset().unwrap();
+        hive.cells().cell(sk).unwrap().u32(0x0c).unwrap()
+    }
+
+    #[test]
+    fn creates_keys_and_values() {
+        let mut editor = Editor::new();
+        {
+            let mut key = editor.create(r"Software\Contoso").unwrap();
+            key.set_value("", &Data::String("Default".try_into().unwrap()))
+                .unwrap();
+            key.set_value("Version", &Data::U32(3)).unwrap();
+            key.set_value("Large", &Data::Binary(vec![0xab; 40000]))
+                .unwrap();
+            key.set_value("Ünïcødé ☃", &Data::U64(7)).unwrap();
+        }
+        editor.create(r"Software\Zeta\Nested").unwrap();
+        editor.create(r"Software\alpha").unwrap();
+
+        let bytes = editor.into_bytes();
+        let hive = Hive::from_bytes(&bytes[..]).unwrap();
+        assert!(hive.is_checksum_valid());
+        assert_eq!(
+            names(hive.open("Software").unwrap()),
+            vec!["alpha", "Contoso", "Zeta"]
+        );
+
+        let key = hive.open(r"software\contoso").unwrap();
+        assert_eq!(key.value("version").unwrap().data().unwrap(), Data::U32(3));
+        assert_eq!(
+            key.value("Large").unwrap().data().unwrap(),
+            Data::Binary(vec![0xab; 40000])
+        );
+        assert_eq!(
+            key.value("ÜNÏCØDÉ ☃").unwrap().data().unwrap(),
+            Data::U64(7)
+        );
+
+        let info = key.info().unwrap();
+        assert_eq!(info.value_count(), 4);
+        assert_eq!(info.max_value_name_len(), 9);
+        assert_eq!(info.max_value_data_len(), 40000);
+        assert_eq!(
+            key.raw_security_descriptor().unwrap().unwrap(),
+            DEFAULT_SECURITY_DESCRIPTOR
+        );
+        assert_eq!(key.parent().unwrap().unwrap().to_string(), "Software");
+    }
+
+    #[test]
+    fn edits_existing_hive() {
+        let root = FixtureKey::new("ROOT").key(
+            FixtureKey::new("Software")
+                .key(FixtureKey::new("Contoso").value(FixtureValue::new("Version", Data::U32(1))))
+                .key(FixtureKey::new("Fabrikam").key(FixtureKey::new("Child"))),
+        );
+        let mut editor = Editor::from_bytes(fixture::build(&root, 8)).unwrap();
+        let refs = security_refs(&editor.hive());
+
+        {
+            let mut key = editor.open(r"Software\Contoso").unwrap();
+            key.set_value("Version", &Data::String("two".try_into().unwrap()))
+                .unwrap();
+            key.set_value("Extra", &Data::U32(5)).unwrap();
+            key.delete_value("extra").unwrap();
+            assert!(matches!(
+                key.delete_value("Missing"),
+                Err(Error::NotFound(_))
+            ));
+            assert_eq!(
+                key.value("VERSION").unwrap(),
+                Data::String("two".try_into().unwrap())
+            );
+        }
+
+        assert!(matches!(
+            editor.delete(r"Software\Fabrikam", false),
+            Err(Error::HasSubkeys(_))
+        ));
+        editor.delete(r"Software\Fabrikam", true).unwrap();
+        assert!(matches!(
+            editor.delete("", true),
+            Err(Error::InvalidOperation(_))
+        ));
+
+        editor
+            .create(r"Software\New")
+            .unwrap()
+            .delete_self(false)
+            .unwrap();
+        assert_eq!(security_refs(&editor.hive()), refs - 2);
+
+        let bytes = editor.into_bytes();
+        let hive = Hive::from_bytes(&bytes[..]).unwrap();
+        assert_eq!(names(hive.open("Software").unwrap()), vec!["Contoso"]);
+        let key = hive.open(r"Software\Contoso").unwrap();
+        assert_eq!(key.value_count().unwrap(), 1);
+        assert!(matches!(
+            hive.open(r"Software\Fabrikam"),
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn splits_subkey_lists() {
+        let mut editor = Editor::new();
+        editor.leaf_capacity = 4;
+
+        let mut expected = vec![];
+        for i in (0..30).rev() {
+            let name = format!("Key{:02}", (i * 7) % 30);
+            editor.root().create(&*name).unwrap();
+            expected.push(name);
+        }
+        expected.sort();
+
+        let hive = editor.hive();
+        let root = hive.root().unwrap();
+        assert_eq!(names(root), expected);
+        for name in &expected {
+            let key = root.subkey(&*name.to_lowercase()).unwrap().unwrap();
+            assert_eq!(&key.to_string(), name);
+        }
+        let list = root.subkey_list().unwrap();
+        assert!(matches!(list, cell::SubkeyList::Root(..)));
+        for i in 0..list.leaf_count() {
+            assert!(list.leaf(hive.cells(), i).unwrap().len() <= 4);
+        }
+
+        for name in &expected[4..] {
+            editor.delete(&**name, false).unwrap();
+        }
+        let hive = editor.hive();
+        assert_eq!(names(hive.root().unwrap()), &expected[..4]);
+        assert!(matches!(
+            hive.root().unwrap().subkey_list().unwrap(),
+            cell::SubkeyList::Leaf(_)
+        ));
+    }
+
+    #[test]
+    fn reuses_freed_cells() {
+        let mut editor = Editor::new();
+        editor.create("Key").unwrap();
+        let mut len = 0;
+        for i in 0..20 {
+            let mut key = editor.open("Key").unwrap();
+            key.set_value("Large", &Data::Binary(vec![i; 30000]))
+                .unwrap();
+            key.set_value("Small", &Data::Binary(vec![i; 100])).unwrap();
+            key.delete_value("Small").unwrap();
+            if i == 1 {
+                len = editor.store.bins_len();
+            }
+        }
+        assert_eq!(editor.store.bins_len(), len);
+        assert_eq!(
+            editor.open("Key").unwrap().value("Large").unwrap(),
+            Data::Binary(vec![19; 30000])
+        );
+    }
+
+    #[test]
+    fn allocates_the_best_fitting_cell() {
+        let mut editor = Editor::new();
+        let store = &mut editor.store;
+        let large = store.alloc(&[0; 196]);
+        store.alloc(&[0; 4]);
+        let small = store.alloc(&[0; 36]);
+        store.alloc(&[0; 4]);
+
+        store.free(large);
+        store.free(small);
+        assert_eq!(store.alloc(&[1; 36]), small);
+        assert_eq!(store.alloc(&[1; 196]), large);
+    }
+
+    #[test]
+    fn saves_and_recovers_from_log() {
+        let path = temp_path("edit.dat");
+        let log_path = temp_path("edit.dat.LOG1");
+        let _ = fs::remove_file(&log_path);
+
+        Editor::new().save_as(&path).unwrap();
+        let before = fs::read(&path).unwrap();
+
+        let mut editor = Editor::load_with_log(&path, &log_path).unwrap();
+        editor
+            .create(r"Software\Contoso")
+            .unwrap()
+            .set_value("Data", &Data::Binary(vec![1; 10000]))
+            .unwrap();
+        editor.save().unwrap();
+
+        let after = fs::read(&path).unwrap();
+        let hive = Hive::from_bytes(&after[..]).unwrap();
+        assert_eq!(hive.sequence_numbers(), (2, 2));
+        assert!(hive.is_checksum_valid());
+        assert!(hive.open(r"Software\Contoso").is_ok());
+
+        // Put back the old file, as if the save was interrupted before any page was written.
+        let mut crashed = before;
+        crashed[0x04..0x08].copy_from_slice(&2u32.to_le_bytes());
+        fs::write(&path, &crashed).unwrap();
+        assert!(matches!(Editor::load(&path), Err(Error::Dirty)));
+
+        let mut editor = Editor::load_with_log(&path, &log_path).unwrap();
+        assert_eq!(
+            editor
+                .open(r"Software\Contoso")
+                .unwrap()
+                .value("data")
+                .unwrap(),
+            Data::Binary(vec![1; 10000])
+        );
+        assert_eq!(editor.hive().sequence_numbers(), (2, 2));
+
+        editor.delete("Software", true).unwrap();
+        editor.save().unwrap();
+        let hive_bytes = fs::read(&path).unwrap();
+        let hive = Hive::from_bytes(&hive_bytes[..]).unwrap();
+        assert_eq!(hive.sequence_numbers(), (3, 3));
+        assert!(hive.open("Software").is_err());
+
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&log_path);
+    }
+
+    #[test]
+    fn keeps_the_hive_and_log_when_recovery_fails() {
+        let path = temp_path("recover.dat");
+        let log_path = temp_path("recover.dat.LOG1");
+        let _ = fs::remove_file(&log_path);
+
+        Editor::new().save_as(&path).unwrap();
+        let before = fs::read(&path).unwrap();
+        let mut editor = Editor::load_with_log(&path, &log_path).unwrap();
+        editor.create("Software").unwrap();
+        editor.save().unwrap();
+
+        let mut crashed = before;
+        crashed[0x04..0x08].copy_from_slice(&2u32.to_le_bytes());
+        fs::write(&path, &crashed).unwrap();
+        let log = fs::read(&log_path).unwrap();
+
+        // Fail halfway through writing the recovered hive.
+        let mut recovered = crashed.clone();
+        assert!(log::recover(&mut recovered, &log).unwrap());
+        let result = replace(&path, |file| {
+            file.write_all(&recovered[..recovered.len() / 2])?;
+            Err(io::ErrorKind::WriteZero.into())
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read(&path).unwrap(), crashed);
+        assert_eq!(fs::read(&log_path).unwrap(), log);
+
+        let mut editor = Editor::load_with_log(&path, &log_path).unwrap();
+        assert!(editor.open("Software").is_ok());
+        assert_eq!(fs::read(&path).unwrap(), recovered);
+        assert!(fs::read(&log_path).unwrap().is_empty());
+
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&log_path);
+    }
+}
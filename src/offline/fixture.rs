@@ -0,0 +1,308 @@
+//! Builds small hive files in memory for tests.
+
+use super::cell::{self, BASE_BLOCK_SIZE, BIG_DATA_SEGMENT_SIZE, NONE};
+use crate::info::FileTime;
+use crate::Data;
+
+/// The last write time given to every key and to the base block.
+pub(crate) const TIMESTAMP: FileTime = FileTime::new(132_686_208_000_000_000);
+
+/// The security descriptor given to every key.
+pub(crate) const SECURITY_DESCRIPTOR: &[u8] = super::edit::DEFAULT_SECURITY_DESCRIPTOR;
+
+pub(crate) struct FixtureValue {
+    name: String,
+    ty: u32,
+    data: Vec<u8>,
+}
+
+impl FixtureValue {
+    pub(crate) fn new(name: &str, data: Data) -> FixtureValue {
+        FixtureValue {
+            name: name.to_string(),
+            ty: data.ty(),
+            data: data.to_bytes(),
+        }
+    }
+}
+
+pub(crate) struct FixtureKey {
+    name: String,
+    class: Option<String>,
+    values: Vec<FixtureValue>,
+    keys: Vec<FixtureKey>,
+}
+
+impl FixtureKey {
+    pub(crate) fn new(name: &str) -> FixtureKey {
+        FixtureKey {
+            name: name.to_string(),
+            class: None,
+            values: vec![],
+            keys: vec![],
+        }
+    }
+
+    pub(crate) fn class(mut self, class: &str) -> FixtureKey {
+        self.class = Some(class.to_string());
+        self
+    }
+
+    pub(crate) fn value(mut self, value: FixtureValue) -> FixtureKey {
+        self.values.push(value);
+        self
+    }
+
+    pub(crate) fn key(mut self, key: FixtureKey) -> FixtureKey {
+        self.keys.push(key);
+        self
+    }
+}
+
+fn encode_name(name: &str) -> (Vec<u8>, bool) {
+    cell::encode_name(&name.encode_utf16().collect::<Vec<_>>())
+}
+
+fn utf16_bytes(s: &str) -> Vec<u8> {
+    s.encode_utf16()
+        .flat_map(|x| x.to_le_bytes().to_vec())
+        .collect()
+}
+
+fn hash_name(name: &str) -> u32 {
+    cell::hash_name(&name.encode_utf16().collect::<Vec<_>>())
+}
+
+fn sort_key(name: &str) -> Vec<u16> {
+    name.encode_utf16().map(cell::upcase).collect()
+}
+
+struct Builder {
+    bins: Vec<u8>,
+    leaf_capacity: usize,
+    security: u32,
+    key_count: u32,
+}
+
+impl Builder {
+    fn alloc(&mut self, data: &[u8]) -> u32 {
+        let offset = self.bins.len() as u32;
+        let size = (data.len() + 4 + 7) & !7;
+        self.bins.extend_from_slice(&(-(size as i32)).to_le_bytes());
+        self.bins.extend_from_slice(data);
+        self.bins.resize(offset as usize + size, 0);
+        offset
+    }
+
+    fn patch(&mut self, cell: u32, at: usize, bytes: &[u8]) {
+        let start = cell as usize + 4 + at;
+        self.bins[start..start + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn write_data(&mut self, data: &[u8]) -> u32 {
+        if data.len() <= BIG_DATA_SEGMENT_SIZE {
+            return self.alloc(data);
+        }
+
+        let segments = data
+            .chunks(BIG_DATA_SEGMENT_SIZE)
+            .map(|x| self.alloc(x))
+            .collect::<Vec<_>>();
+        let list = self.alloc(
+            &segments
+                .iter()
+                .flat_map(|x| x.to_le_bytes().to_vec())
+                .collect::<Vec<_>>(),
+        );
+
+        let mut db = b"db".to_vec();
+        db.extend_from_slice(&(segments.len() as u16).to_le_bytes());
+        db.extend_from_slice(&list.to_le_bytes());
+        self.alloc(&db)
+    }
+
+    fn write_value(&mut self, value: &FixtureValue) -> u32 {
+        let (name, compressed) = encode_name(&value.name);
+        let mut vk = b"vk".to_vec();
+        vk.extend_from_slice(&(name.len() as u16).to_le_bytes());
+
+        if value.data.len() <= 4 {
+            vk.extend_from_slice(&(value.data.len() as u32 | 0x8000_0000).to_le_bytes());
+            let mut inline = value.data.clone();
+            inline.resize(4, 0);
+            vk.extend_from_slice(&inline);
+        } else {
+            let offset = self.write_data(&value.data);
+            vk.extend_from_slice(&(value.data.len() as u32).to_le_bytes());
+            vk.extend_from_slice(&offset.to_le_bytes());
+        }
+
+        vk.extend_from_slice(&value.ty.to_le_bytes());
+        let flags = if compressed { cell::VALUE_COMP_NAME } else { 0 };
+        vk.extend_from_slice(&flags.to_le_bytes());
+        vk.extend_from_slice(&[0, 0]);
+        vk.extend_from_slice(&name);
+        self.alloc(&vk)
+    }
+
+    fn write_subkey_list(&mut self, keys: &[(String, u32)]) -> u32 {
+        if keys.is_empty() {
+            return NONE;
+        }
+
+        let leaves = keys
+            .chunks(self.leaf_capacity)
+            .map(|chunk| {
+                let mut lh = b"lh".to_vec();
+                lh.extend_from_slice(&(chunk.len() as u16).to_le_bytes());
+                for (name, offset) in chunk {
+                    lh.extend_from_slice(&offset.to_le_bytes());
+                    lh.extend_from_slice(&hash_name(name).to_le_bytes());
+                }
+                self.alloc(&lh)
+            })
+            .collect::<Vec<_>>();
+
+        if leaves.len() == 1 {
+            return leaves[0];
+        }
+
+        let mut ri = b"ri".to_vec();
+        ri.extend_from_slice(&(leaves.len() as u16).to_le_bytes());
+        for leaf in leaves {
+            ri.extend_from_slice(&leaf.to_le_bytes());
+        }
+        self.alloc(&ri)
+    }
+
+    fn write_key(&mut self, key: &FixtureKey, parent: u32, is_root: bool) -> u32 {
+        self.key_count += 1;
+        let (name, compressed) = encode_name(&key.name);
+        let mut nk = vec![0u8; 0x4c];
+        nk[0..2].copy_from_slice(b"nk");
+        nk.extend_from_slice(&name);
+        let offset = self.alloc(&nk);
+
+        let mut children = key.keys.iter().collect::<Vec<_>>();
+        children.sort_by_key(|k| sort_key(&k.name));
+        let children = children
+            .into_iter()
+            .map(|k| (k.name.clone(), self.write_key(k, offset, false)))
+            .collect::<Vec<_>>();
+        let subkey_list = self.write_subkey_list(&children);
+
+        let values = key
+            .values
+            .iter()
+            .map(|v| self.write_value(v))
+            .flat_map(|x| x.to_le_bytes().to_vec())
+            .collect::<Vec<_>>();
+        let value_list = match values.len() {
+            0 => NONE,
+            _ => self.alloc(&values),
+        };
+
+        let class = key.class.as_deref().map(utf16_bytes).unwrap_or_default();
+        let class_offset = match class.len() {
+            0 => NONE,
+            _ => self.alloc(&class),
+        };
+
+        let mut flags = if compressed { cell::KEY_COMP_NAME } else { 0 };
+        if is_root {
+            flags |= cell::KEY_HIVE_ENTRY | cell::KEY_NO_DELETE;
+        }
+
+        let max =
+            |f: &dyn Fn(&FixtureKey) -> usize| key.keys.iter().map(f).max().unwrap_or(0) as u32;
+        let max_subkey_name = max(&|k| k.name.encode_utf16().count() * 2);
+        let max_subkey_class = max(&|k| {
+            k.class
+                .as_deref()
+                .map(|c| c.encode_utf16().count() * 2)
+                .unwrap_or(0)
+        });
+        let max_value_name = key
+            .values
+            .iter()
+            .map(|v| v.name.encode_utf16().count() * 2)
+            .max()
+            .unwrap_or(0) as u32;
+        let max_value_data = key.values.iter().map(|v| v.data.len()).max().unwrap_or(0) as u32;
+
+        self.patch(offset, 0x02, &flags.to_le_bytes());
+        self.patch(offset, 0x04, &TIMESTAMP.to_le_bytes());
+        self.patch(offset, 0x10, &parent.to_le_bytes());
+        self.patch(offset, 0x14, &(children.len() as u32).to_le_bytes());
+        self.patch(offset, 0x1c, &subkey_list.to_le_bytes());
+        self.patch(offset, 0x20, &NONE.to_le_bytes());
+        self.patch(offset, 0x24, &(key.values.len() as u32).to_le_bytes());
+        self.patch(offset, 0x28, &value_list.to_le_bytes());
+        self.patch(offset, 0x2c, &self.security.to_le_bytes());
+        self.patch(offset, 0x30, &class_offset.to_le_bytes());
+        self.patch(offset, 0x34, &max_subkey_name.to_le_bytes());
+        self.patch(offset, 0x38, &max_subkey_class.to_le_bytes());
+        self.patch(offset, 0x3c, &max_value_name.to_le_bytes());
+        self.patch(offset, 0x40, &max_value_data.to_le_bytes());
+        self.patch(offset, 0x48, &(name.len() as u16).to_le_bytes());
+        self.patch(offset, 0x4a, &(class.len() as u16).to_le_bytes());
+        offset
+    }
+}
+
+/// Lays out a hive holding the given tree, splitting subkey lists into leaves of at most
+/// `leaf_capacity` entries.
+pub(crate) fn build(root: &FixtureKey, leaf_capacity: usize) -> Vec<u8> {
+    let mut builder = Builder {
+        // Space for the hive bin header.
+        bins: vec![0u8; 32],
+        leaf_capacity,
+        security: NONE,
+        key_count: 0,
+    };
+
+    let mut sk = b"sk\0\0".to_vec();
+    sk.extend_from_slice(&[0u8; 12]);
+    sk.extend_from_slice(&(SECURITY_DESCRIPTOR.len() as u32).to_le_bytes());
+    sk.extend_from_slice(SECURITY_DESCRIPTOR);
+    let security = builder.alloc(&sk);
+    builder.patch(security, 0x04, &security.to_le_bytes());
+    builder.patch(security, 0x08, &security.to_le_bytes());
+    builder.security = security;
+
+    let root_offset = builder.write_key(root, 0, true);
+    let key_count = builder.key_count;
+    builder.patch(security, 0x0c, &key_count.to_le_bytes());
+
+    // Fill the rest of the bin with a free cell.
+    let mut bins = builder.bins;
+    let len = (bins.len() + 4095) & !4095;
+    let free = len - bins.len();
+    if free > 0 {
+        let start = bins.len();
+        bins.resize(len, 0);
+        bins[start..start + 4].copy_from_slice(&(free as i32).to_le_bytes());
+    }
+
+    bins[0..4].copy_from_slice(b"hbin");
+    bins[8..12].copy_from_slice(&(len as u32).to_le_bytes());
+    bins[20..28].copy_from_slice(&TIMESTAMP.to_le_bytes());
+
+    let mut base = vec![0u8; BASE_BLOCK_SIZE];
+    let mut put = |at: usize, bytes: &[u8]| base[at..at + bytes.len()].copy_from_slice(bytes);
+    put(0x00, b"regf");
+    put(0x04, &1u32.to_le_bytes());
+    put(0x08, &1u32.to_le_bytes());
+    put(0x0c, &TIMESTAMP.to_le_bytes());
+    put(0x14, &1u32.to_le_bytes());
+    put(0x18, &5u32.to_le_bytes());
+    put(0x20, &1u32.to_le_bytes());
+    put(0x24, &root_offset.to_le_bytes());
+    put(0x28, &(len as u32).to_le_bytes());
+    put(0x2c, &1u32.to_le_bytes());
+    let sum = super::checksum(&base);
+    base[0x1fc..0x200].copy_from_slice(&sum.to_le_bytes());
+
+    base.extend_from_slice(&bins);
+    base
+}
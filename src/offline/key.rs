@@ -0,0 +1,388 @@
+use std::{
+    cmp::Ordering,
+    convert::TryInto,
+    fmt::{Debug, Display},
+};
+
+use utfx::{U16CStr, U16CString};
+
+use super::cell::{self, Cell, Cells, Leaf, Name, SubkeyList, NONE};
+use super::value::{Value, Values};
+use super::Error;
+use crate::descriptor::SecurityDescriptor;
+use crate::info::{self, FileTime, KeyInfo};
+
+/// A key node (`nk` cell) within an offline hive.
+///
+/// A `Key` is only an offset into the hive: nothing is decoded or allocated until it is
+/// asked for.
+#[derive(Copy, Clone)]
+pub struct Key<'a> {
+    pub(crate) cells: Cells<'a>,
+    pub(crate) cell: Cell<'a>,
+}
+
+impl Display for Key<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.raw_name() {
+            Ok(name) => f.write_str(&name.to_ucstring().to_string_lossy()),
+            Err(_) => f.write_str("<invalid>"),
+        }
+    }
+}
+
+impl Debug for Key<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Key")
+            .field(&self.to_string())
+            .field(&format_args!("{:#x}", self.cell.offset))
+            .finish()
+    }
+}
+
+impl<'a> Key<'a> {
+    pub(crate) fn new(cells: Cells<'a>, offset: u32) -> Result<Key<'a>, Error> {
+        Ok(Key {
+            cells,
+            cell: cells.cell_with_signature(offset, b"nk")?,
+        })
+    }
+
+    /// The offset of this key's cell within the hive bins.
+    #[inline]
+    pub fn offset(&self) -> u32 {
+        self.cell.offset
+    }
+
+    #[inline]
+    pub(crate) fn flags(&self) -> Result<u16, Error> {
+        self.cell.u16(0x02)
+    }
+
+    pub(crate) fn raw_name(&self) -> Result<Name<'a>, Error> {
+        let len = self.cell.u16(0x48)? as usize;
+        let compressed = self.flags()? & cell::KEY_COMP_NAME != 0;
+        Ok(Name::new(self.cell.bytes(0x4c, len)?, compressed))
+    }
+
+    /// The name of this key.
+    pub fn name(&self) -> Result<U16CString, Error> {
+        self.raw_name().map(|x| x.to_ucstring())
+    }
+
+    #[inline]
+    pub fn last_write_time(&self) -> Result<FileTime, Error> {
+        self.cell.u64(0x04).map(FileTime::new)
+    }
+
+    #[inline]
+    pub fn subkey_count(&self) -> Result<u32, Error> {
+        self.cell.u32(0x14)
+    }
+
+    #[inline]
+    pub fn value_count(&self) -> Result<u32, Error> {
+        self.cell.u32(0x24)
+    }
+
+    /// Whether this is the root key of the hive.
+    #[inline]
+    pub fn is_root(&self) -> Result<bool, Error> {
+        Ok(self.flags()? & cell::KEY_HIVE_ENTRY != 0)
+    }
+
+    /// The parent of this key, or `None` for the root key.
+    pub fn parent(&self) -> Result<Option<Key<'a>>, Error> {
+        if self.is_root()? {
+            return Ok(None);
+        }
+        Key::new(self.cells, self.cell.u32(0x10)?).map(Some)
+    }
+
+    pub(crate) fn security_offset(&self) -> Result<u32, Error> {
+        self.cell.u32(0x2c)
+    }
+
+    /// The security cell (`sk`) of this key. These are shared between all keys with the
+    /// same descriptor.
+    fn security_cell(&self) -> Result<Option<Cell<'a>>, Error> {
+        match self.security_offset()? {
+            NONE => Ok(None),
+            offset => self.cells.cell_with_signature(offset, b"sk").map(Some),
+        }
+    }
+
+    /// The self-relative security descriptor of this key, as stored in the hive.
+    pub fn raw_security_descriptor(&self) -> Result<Option<&'a [u8]>, Error> {
+        match self.security_cell()? {
+            Some(sk) => sk.bytes(0x14, sk.u32(0x10)? as usize).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// The parsed security descriptor of this key, giving its owner, group and ACLs.
+    pub fn security_descriptor(&self) -> Result<Option<SecurityDescriptor>, Error> {
+        match self.raw_security_descriptor()? {
+            Some(bytes) => Ok(Some(SecurityDescriptor::from_bytes(bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The class name of this key, if it has one.
+    pub fn class(&self) -> Result<Option<U16CString>, Error> {
+        let offset = self.cell.u32(0x30)?;
+        let len = self.cell.u16(0x4a)? as usize;
+        if offset == NONE || len == 0 {
+            return Ok(None);
+        }
+        let bytes = self.cells.cell(offset)?.bytes(0, len)?;
+        Ok(info::decode_class_bytes(bytes))
+    }
+
+    /// Reads the information stored in this key's node, in the same form as
+    /// [`RegKey::info`](../struct.RegKey.html#method.info) reports it.
+    pub fn info(&self) -> Result<KeyInfo, Error> {
+        let security_descriptor_len = match self.security_cell()? {
+            Some(sk) => sk.u32(0x10)?,
+            None => 0,
+        };
+
+        // Name lengths are stored in bytes, with flags in the upper half of the subkey field.
+        Ok(KeyInfo {
+            class: self.class()?,
+            last_write_time: self.last_write_time()?,
+            subkey_count: self.subkey_count()?,
+            max_subkey_name_len: (self.cell.u32(0x34)? & 0xffff) / 2,
+            max_class_len: self.cell.u32(0x38)? / 2,
+            value_count: self.value_count()?,
+            max_value_name_len: self.cell.u32(0x3c)? / 2,
+            max_value_data_len: self.cell.u32(0x40)?,
+            security_descriptor_len,
+        })
+    }
+
+    pub(crate) fn subkey_list(&self) -> Result<SubkeyList<'a>, Error> {
+        SubkeyList::new(self.cells, self.cell.u32(0x1c)?, self.subkey_count()?)
+    }
+
+    /// Iterates the subkeys of this key in the order they are stored, which is sorted by
+    /// upper case name.
+    pub fn keys(&self) -> Result<Keys<'a>, Error> {
+        Ok(Keys {
+            cells: self.cells,
+            list: self.subkey_list()?,
+            leaf: None,
+            leaf_index: 0,
+            index: 0,
+        })
+    }
+
+    /// The subkey at a position in the order `keys` gives, skipping whole leaves of the
+    /// subkey list on the way to it.
+    pub(crate) fn subkey_at(&self, index: usize) -> Result<Option<Key<'a>>, Error> {
+        let list = self.subkey_list()?;
+        let mut index = index;
+        for i in 0..list.leaf_count() {
+            let leaf = list.leaf(self.cells, i)?;
+            if index < leaf.len() {
+                return Key::new(self.cells, leaf.get(index)?).map(Some);
+            }
+            index -= leaf.len();
+        }
+        Ok(None)
+    }
+
+    /// Finds the immediate subkey with the given name, ignoring case.
+    ///
+    /// Subkey lists are kept sorted by Windows, so this is a binary search over the list
+    /// which only touches the key nodes it compares against.
+    pub fn subkey<S>(&self, name: S) -> Result<Option<Key<'a>>, Error>
+    where
+        S: TryInto<U16CString>,
+        S::Error: Into<Error>,
+    {
+        let name = name.try_into().map_err(Into::into)?;
+        self.find_subkey(name.as_slice())
+    }
+
+    pub(crate) fn find_subkey(&self, name: &[u16]) -> Result<Option<Key<'a>>, Error> {
+        let list = self.subkey_list()?;
+        if list.leaf_count() == 0 {
+            return Ok(None);
+        }
+
+        let (leaf, index) = self.subkey_position(name)?;
+        let leaf = list.leaf(self.cells, leaf)?;
+        if index < leaf.len() && self.compare_entry(&leaf, index, name)? == Ordering::Equal {
+            return Key::new(self.cells, leaf.get(index)?).map(Some);
+        }
+        Ok(None)
+    }
+
+    /// Where a subkey with the given name is, or would go, in the subkey list: the index of
+    /// its leaf and its index in that leaf, found by binary search. A name after every subkey
+    /// goes at the end of the last leaf.
+    pub(crate) fn subkey_position(&self, name: &[u16]) -> Result<(usize, usize), Error> {
+        let list = self.subkey_list()?;
+        let leaf_count = list.leaf_count();
+        if leaf_count == 0 {
+            return Ok((0, 0));
+        }
+
+        // Find the first leaf whose last entry is not less than the name.
+        let (mut lo, mut hi) = (0, leaf_count);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            let leaf = list.leaf(self.cells, mid)?;
+            let ordering = match leaf.len() {
+                0 => Ordering::Less,
+                len => self.compare_entry(&leaf, len - 1, name)?,
+            };
+            if ordering == Ordering::Less {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+
+        if lo == leaf_count {
+            let last = list.leaf(self.cells, leaf_count - 1)?;
+            return Ok((leaf_count - 1, last.len()));
+        }
+
+        let leaf_index = lo;
+        let leaf = list.leaf(self.cells, leaf_index)?;
+        let (mut lo, mut hi) = (0, leaf.len());
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.compare_entry(&leaf, mid, name)? == Ordering::Less {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        Ok((leaf_index, lo))
+    }
+
+    fn compare_entry(
+        &self,
+        leaf: &Leaf<'a>,
+        index: usize,
+        name: &[u16],
+    ) -> Result<Ordering, Error> {
+        let key = Key::new(self.cells, leaf.get(index)?)?;
+        Ok(key.raw_name()?.cmp_ignore_case(name))
+    }
+
+    /// Opens a descendant of this key by a backslash separated path, ignoring case.
+    pub fn open<P>(&self, path: P) -> Result<Key<'a>, Error>
+    where
+        P: TryInto<U16CString>,
+        P::Error: Into<Error>,
+    {
+        let path = path.try_into().map_err(Into::into)?;
+        self.open_path(&path)
+    }
+
+    pub(crate) fn open_path(&self, path: &U16CStr) -> Result<Key<'a>, Error> {
+        let mut key = *self;
+        for component in path
+            .as_slice()
+            .split(|c| *c == u16::from(b'\\'))
+            .filter(|x| !x.is_empty())
+        {
+            key = match key.find_subkey(component)? {
+                Some(v) => v,
+                None => return Err(Error::NotFound(path.to_string_lossy())),
+            };
+        }
+        Ok(key)
+    }
+
+    /// Iterates the values of this key in the order they are stored.
+    pub fn values(&self) -> Result<Values<'a>, Error> {
+        let count = self.value_count()? as usize;
+        let list = match count {
+            0 => None,
+            _ => Some(self.cells.cell(self.cell.u32(0x28)?)?),
+        };
+        Ok(Values::new(self.cells, list, count))
+    }
+
+    /// The value at a position in the order `values` gives.
+    pub(crate) fn value_at(&self, index: usize) -> Result<Option<Value<'a>>, Error> {
+        if index >= self.value_count()? as usize {
+            return Ok(None);
+        }
+        let list = self.cells.cell(self.cell.u32(0x28)?)?;
+        Value::new(self.cells, list.u32(index * 4)?).map(Some)
+    }
+
+    /// Finds the value with the given name, ignoring case. The empty name refers to the
+    /// default value of the key.
+    pub fn value<S>(&self, name: S) -> Result<Value<'a>, Error>
+    where
+        S: TryInto<U16CString>,
+        S::Error: Into<Error>,
+    {
+        let name = name.try_into().map_err(Into::into)?;
+        match self.find_value(name.as_slice())? {
+            Some(v) => Ok(v),
+            None => Err(Error::NotFound(name.to_string_lossy())),
+        }
+    }
+
+    pub(crate) fn find_value(&self, name: &[u16]) -> Result<Option<Value<'a>>, Error> {
+        for value in self.values()? {
+            let value = value?;
+            if value.raw_name()?.cmp_ignore_case(name) == Ordering::Equal {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// An iterator over the subkeys of an offline [`Key`](struct.Key.html).
+#[derive(Debug)]
+pub struct Keys<'a> {
+    cells: Cells<'a>,
+    list: SubkeyList<'a>,
+    leaf: Option<Leaf<'a>>,
+    leaf_index: usize,
+    index: usize,
+}
+
+impl<'a> Iterator for Keys<'a> {
+    type Item = Result<Key<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let leaf = match self.leaf {
+                Some(leaf) => leaf,
+                None => {
+                    if self.leaf_index >= self.list.leaf_count() {
+                        return None;
+                    }
+                    match self.list.leaf(self.cells, self.leaf_index) {
+                        Ok(leaf) => *self.leaf.insert(leaf),
+                        Err(e) => {
+                            self.leaf_index = self.list.leaf_count();
+                            return Some(Err(e));
+                        }
+                    }
+                }
+            };
+
+            if self.index < leaf.len() {
+                let offset = leaf.get(self.index);
+                self.index += 1;
+                return Some(offset.and_then(|x| Key::new(self.cells, x)));
+            }
+
+            self.leaf = None;
+            self.leaf_index += 1;
+            self.index = 0;
+        }
+    }
+}
@@ -0,0 +1,212 @@
+//! Transaction logs (`.LOG1`) in the format used since Windows 8.1, which hold the pages
+//! of a write to the primary file until it is known to be complete.
+
+use std::{fs::File, io::Write, path::Path};
+
+use super::alloc::PAGE_SIZE;
+use super::cell::BASE_BLOCK_SIZE;
+use super::{checksum, Error};
+
+/// The seed used for the hashes of log entries.
+const SEED: u64 = 0x82EF_4D88_7A4E_55C5;
+
+/// The size of a log entry header, before its dirty page references.
+const ENTRY_HEADER_SIZE: usize = 0x28;
+
+/// The file type of a log in the current format.
+const FILE_TYPE_LOG: u32 = 6;
+
+#[inline]
+fn u32_at(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]])
+}
+
+#[inline]
+fn u64_at(b: &[u8], at: usize) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&b[at..at + 8]);
+    u64::from_le_bytes(buf)
+}
+
+/// The Marvin32 hash, as used to check log entries.
+pub(crate) fn marvin32(seed: u64, data: &[u8]) -> u64 {
+    fn block(p0: &mut u32, p1: &mut u32) {
+        *p1 ^= *p0;
+        *p0 = p0.rotate_left(20);
+        *p0 = p0.wrapping_add(*p1);
+        *p1 = p1.rotate_left(9);
+        *p1 ^= *p0;
+        *p0 = p0.rotate_left(27);
+        *p0 = p0.wrapping_add(*p1);
+        *p1 = p1.rotate_left(19);
+    }
+
+    let mut p0 = seed as u32;
+    let mut p1 = (seed >> 32) as u32;
+
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        p0 = p0.wrapping_add(u32_at(chunk, 0));
+        block(&mut p0, &mut p1);
+    }
+
+    let rest = chunks.remainder();
+    let last = match rest.len() {
+        0 => 0x80,
+        1 => 0x8000 | u32::from(rest[0]),
+        2 => 0x80_0000 | u32::from(u16::from_le_bytes([rest[0], rest[1]])),
+        _ => {
+            0x8000_0000
+                | u32::from(rest[2]) << 16
+                | u32::from(u16::from_le_bytes([rest[0], rest[1]]))
+        }
+    };
+    p0 = p0.wrapping_add(last);
+    block(&mut p0, &mut p1);
+    block(&mut p0, &mut p1);
+
+    u64::from(p1) << 32 | u64::from(p0)
+}
+
+/// Builds a log holding a single entry with the given dirty ranges of the hive bins.
+///
+/// `data` is the whole hive file as it will be once the write completes, and `sequence` is
+/// the secondary sequence number of the primary file before the write.
+pub(crate) fn build(data: &[u8], dirty: &[(u32, u32)], sequence: u32) -> Vec<u8> {
+    let bins_len = (data.len() - BASE_BLOCK_SIZE) as u32;
+
+    let mut log = data[..BASE_BLOCK_SIZE].to_vec();
+    log[0x04..0x08].copy_from_slice(&sequence.to_le_bytes());
+    log[0x08..0x0c].copy_from_slice(&sequence.to_le_bytes());
+    log[0x1c..0x20].copy_from_slice(&FILE_TYPE_LOG.to_le_bytes());
+    let sum = checksum(&log);
+    log[0x1fc..0x200].copy_from_slice(&sum.to_le_bytes());
+
+    let mut entry = vec![0u8; ENTRY_HEADER_SIZE];
+    entry[0..4].copy_from_slice(b"HvLE");
+    entry[0x0c..0x10].copy_from_slice(&sequence.to_le_bytes());
+    entry[0x10..0x14].copy_from_slice(&bins_len.to_le_bytes());
+    entry[0x14..0x18].copy_from_slice(&(dirty.len() as u32).to_le_bytes());
+    for (offset, len) in dirty {
+        entry.extend_from_slice(&offset.to_le_bytes());
+        entry.extend_from_slice(&len.to_le_bytes());
+    }
+    for (offset, len) in dirty {
+        let start = BASE_BLOCK_SIZE + *offset as usize;
+        entry.extend_from_slice(&data[start..start + *len as usize]);
+    }
+    entry.resize((entry.len() + 511) & !511, 0);
+
+    let len = entry.len() as u32;
+    entry[0x04..0x08].copy_from_slice(&len.to_le_bytes());
+    let hash = marvin32(SEED, &entry[ENTRY_HEADER_SIZE..]);
+    entry[0x18..0x20].copy_from_slice(&hash.to_le_bytes());
+    let hash = marvin32(SEED, &entry[..0x20]);
+    entry[0x20..0x28].copy_from_slice(&hash.to_le_bytes());
+
+    log.extend_from_slice(&entry);
+    log
+}
+
+/// Writes a log, replacing any previous one, and waits for it to reach the disk.
+pub(crate) fn write(path: &Path, log: &[u8]) -> Result<(), Error> {
+    let mut file = File::create(path)?;
+    file.write_all(log)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Replays the entries of a log onto a primary file whose last write was interrupted.
+///
+/// Returns whether anything was recovered. Logs that are invalid, or that belong to a
+/// different write than the interrupted one, are ignored.
+pub(crate) fn recover(data: &mut Vec<u8>, log: &[u8]) -> Result<bool, Error> {
+    if data.len() < BASE_BLOCK_SIZE {
+        return Err(Error::InvalidHeader("file is shorter than the base block"));
+    }
+
+    let (primary, secondary) = (u32_at(data, 0x04), u32_at(data, 0x08));
+    if primary == secondary {
+        return Ok(false);
+    }
+
+    if log.len() < BASE_BLOCK_SIZE
+        || &log[0..4] != b"regf"
+        || u32_at(log, 0x1c) != FILE_TYPE_LOG
+        || checksum(log) != u32_at(log, 0x1fc)
+        || u32_at(log, 0x08) != secondary
+    {
+        return Ok(false);
+    }
+
+    let mut sequence = secondary;
+    let mut bins_len = None;
+    let mut at = BASE_BLOCK_SIZE;
+
+    while at + ENTRY_HEADER_SIZE <= log.len() && &log[at..at + 4] == b"HvLE" {
+        let size = u32_at(log, at + 0x04) as usize;
+        if size < ENTRY_HEADER_SIZE || size & 511 != 0 || at + size > log.len() {
+            break;
+        }
+
+        let entry = &log[at..at + size];
+        if u32_at(entry, 0x0c) != sequence
+            || marvin32(SEED, &entry[ENTRY_HEADER_SIZE..]) != u64_at(entry, 0x18)
+            || marvin32(SEED, &entry[..0x20]) != u64_at(entry, 0x20)
+        {
+            break;
+        }
+
+        let len = u32_at(entry, 0x10) as usize;
+        let count = u32_at(entry, 0x14) as usize;
+        let refs = entry
+            .get(ENTRY_HEADER_SIZE..ENTRY_HEADER_SIZE + count * 8)
+            .ok_or(Error::InvalidHeader("log entry is truncated"))?;
+
+        let mut source = ENTRY_HEADER_SIZE + count * 8;
+        data.resize(BASE_BLOCK_SIZE + len, 0);
+        for r in refs.chunks_exact(8) {
+            let offset = u32_at(r, 0) as usize;
+            let page_len = u32_at(r, 4) as usize;
+            if page_len & (PAGE_SIZE as usize - 1) != 0
+                || offset + page_len > len
+                || source + page_len > entry.len()
+            {
+                return Err(Error::InvalidHeader("log entry refers outside the hive"));
+            }
+
+            let target = BASE_BLOCK_SIZE + offset;
+            data[target..target + page_len].copy_from_slice(&entry[source..source + page_len]);
+            source += page_len;
+        }
+
+        bins_len = Some(len as u32);
+        sequence = sequence.wrapping_add(1);
+        at += size;
+    }
+
+    let bins_len = match bins_len {
+        Some(v) => v,
+        None => return Ok(false),
+    };
+
+    data[0x04..0x08].copy_from_slice(&sequence.to_le_bytes());
+    data[0x08..0x0c].copy_from_slice(&sequence.to_le_bytes());
+    data[0x28..0x2c].copy_from_slice(&bins_len.to_le_bytes());
+    let sum = checksum(data);
+    data[0x1fc..0x200].copy_from_slice(&sum.to_le_bytes());
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marvin32_vectors() {
+        let seed = 0x004F_B61A_001B_DBCC;
+        assert_eq!(marvin32(seed, &[]), 0x30ED_35C1_00CD_3C7D);
+        assert_eq!(marvin32(seed, &[0xaf]), 0x48E7_3FC7_7D75_DDC1);
+        assert_eq!(marvin32(seed, &[0xe7, 0x0f]), 0xB5F6_E1FC_485D_BFF8);
+    }
+}
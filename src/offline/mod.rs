@@ -0,0 +1,442 @@
+//! Reading and editing hive files (`regf`) directly, without the Windows registry API.
+//!
+//! This works on any platform, and is intended for collected hives such as `NTUSER.DAT` or
+//! `SOFTWARE`. A [`Hive`](struct.Hive.html) works over any byte buffer, typically a
+//! memory-mapped file, and keys and values are resolved lazily from it: opening a path only
+//! touches the cells along the way, and subkeys are found by binary search of the sorted
+//! subkey lists.
+//!
+//! ```no_run
+//! use registry::offline::Hive;
+//!
+//! let hive = unsafe { Hive::map(r"C:\Windows\System32\config\SOFTWARE") }?;
+//! let key = hive.open(r"Microsoft\Windows NT\CurrentVersion")?;
+//! println!("{:?}", key.value("ProductName")?.data()?);
+//! # Ok::<(), registry::offline::Error>(())
+//! ```
+//!
+//! Hives are modified through an [`Editor`](struct.Editor.html).
+
+use std::{convert::TryInto, fs::File, io, path::Path};
+
+use memmap2::Mmap;
+use utfx::U16CString;
+
+mod alloc;
+mod cell;
+mod compact;
+mod edit;
+mod key;
+mod log;
+mod value;
+
+#[cfg(test)]
+pub(crate) mod fixture;
+
+pub(crate) use cell::upcase;
+pub use compact::Compaction;
+pub use edit::{Editor, KeyMut};
+pub use key::{Key, Keys};
+pub use value::{Value, Values};
+
+use crate::info::FileTime;
+use cell::{Cells, BASE_BLOCK_SIZE};
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("An IO error occurred while reading or writing the hive")]
+    Io(#[from] io::Error),
+
+    #[error("Invalid hive file: {0}")]
+    InvalidHeader(&'static str),
+
+    #[error("Corrupt cell at offset {0:#x}: {1}")]
+    Corrupt(u32, &'static str),
+
+    #[error("Provided path not found: {0:?}")]
+    NotFound(String),
+
+    #[error("Invalid null found in provided path")]
+    InvalidNul(#[from] utfx::NulError<u16>),
+
+    #[error("Error parsing data")]
+    Data(#[from] crate::value::Error),
+
+    #[error("Error parsing security descriptor")]
+    Descriptor(#[from] crate::descriptor::Error),
+
+    #[error("The last write to the hive was interrupted")]
+    Dirty,
+
+    #[error("Key has subkeys and was not deleted recursively: {0:?}")]
+    HasSubkeys(String),
+
+    #[error("Invalid key or value name: {0:?}")]
+    InvalidName(String),
+
+    #[error("Invalid operation: {0}")]
+    InvalidOperation(&'static str),
+}
+
+impl From<std::convert::Infallible> for Error {
+    fn from(_: std::convert::Infallible) -> Self {
+        unsafe { std::hint::unreachable_unchecked() }
+    }
+}
+
+/// A hive file, read from a buffer holding its contents.
+#[derive(Debug)]
+pub struct Hive<B = Mmap> {
+    data: B,
+    root: u32,
+    bins_len: usize,
+    minor_version: u32,
+}
+
+impl Hive<Mmap> {
+    /// Memory-maps the hive file at the given path.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified, by this process or any other, while the hive is in use.
+    pub unsafe fn map<P: AsRef<Path>>(path: P) -> Result<Hive<Mmap>, Error> {
+        let file = File::open(path)?;
+        Hive::from_bytes(Mmap::map(&file)?)
+    }
+}
+
+impl<B: AsRef<[u8]>> Hive<B> {
+    /// Reads the hive from a buffer holding the whole file, validating its base block.
+    pub fn from_bytes(data: B) -> Result<Hive<B>, Error> {
+        let bytes = data.as_ref();
+        if bytes.len() < BASE_BLOCK_SIZE {
+            return Err(Error::InvalidHeader("file is shorter than the base block"));
+        }
+
+        let u32_at = |at: usize| {
+            u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+        };
+
+        if &bytes[0..4] != b"regf" {
+            return Err(Error::InvalidHeader("missing regf signature"));
+        }
+        if u32_at(0x14) != 1 {
+            return Err(Error::InvalidHeader("unsupported major version"));
+        }
+        if u32_at(0x1c) != 0 {
+            return Err(Error::InvalidHeader("not a primary hive file"));
+        }
+
+        let bins_len = u32_at(0x28) as usize;
+        if BASE_BLOCK_SIZE + bins_len > bytes.len() {
+            return Err(Error::InvalidHeader(
+                "hive bins extend past the end of the file",
+            ));
+        }
+
+        let hive = Hive {
+            root: u32_at(0x24),
+            bins_len,
+            minor_version: u32_at(0x18),
+            data,
+        };
+
+        // Make sure the root is reachable up front, so `root` only fails on later corruption.
+        hive.root()?;
+        Ok(hive)
+    }
+
+    #[inline]
+    fn bytes(&self) -> &[u8] {
+        self.data.as_ref()
+    }
+
+    #[inline]
+    fn u32_at(&self, at: usize) -> u32 {
+        let b = self.bytes();
+        u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]])
+    }
+
+    #[inline]
+    pub(crate) fn cells(&self) -> Cells<'_> {
+        Cells::new(
+            &self.bytes()[BASE_BLOCK_SIZE..BASE_BLOCK_SIZE + self.bins_len],
+            self.minor_version,
+        )
+    }
+
+    /// The underlying buffer.
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        self.bytes()
+    }
+
+    pub fn into_inner(self) -> B {
+        self.data
+    }
+
+    /// The format version of the hive, as `(major, minor)`.
+    #[inline]
+    pub fn version(&self) -> (u32, u32) {
+        (self.u32_at(0x14), self.minor_version)
+    }
+
+    /// The primary and secondary sequence numbers. These differ when a write to the hive
+    /// was interrupted.
+    #[inline]
+    pub fn sequence_numbers(&self) -> (u32, u32) {
+        (self.u32_at(0x04), self.u32_at(0x08))
+    }
+
+    /// The time the hive was last written, according to its base block.
+    pub fn last_write_time(&self) -> FileTime {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(&self.bytes()[0x0c..0x14]);
+        FileTime::from_le_bytes(buf)
+    }
+
+    /// Whether the checksum of the base block matches its contents.
+    pub fn is_checksum_valid(&self) -> bool {
+        checksum(self.bytes()) == self.u32_at(0x1fc)
+    }
+
+    /// The root key of the hive.
+    #[inline]
+    pub fn root(&self) -> Result<Key<'_>, Error> {
+        Key::new(self.cells(), self.root)
+    }
+
+    /// Opens a key by its backslash separated path from the root of the hive, ignoring case.
+    pub fn open<P>(&self, path: P) -> Result<Key<'_>, Error>
+    where
+        P: TryInto<U16CString>,
+        P::Error: Into<Error>,
+    {
+        self.root()?.open(path)
+    }
+}
+
+/// Computes the checksum of a base block: the XOR of its first 508 bytes as `u32`s, with
+/// the values 0 and -1 reserved.
+pub(crate) fn checksum(base_block: &[u8]) -> u32 {
+    let sum = base_block[..0x1fc]
+        .chunks_exact(4)
+        .map(|x| u32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+        .fold(0, |acc, x| acc ^ x);
+
+    match sum {
+        0xffff_ffff => 0xffff_fffe,
+        0 => 1,
+        x => x,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixture::{FixtureKey, FixtureValue};
+    use super::*;
+    use crate::{Data, Security};
+
+    fn sample() -> FixtureKey {
+        let mut software = FixtureKey::new("Software").class("SoftwareClass");
+        for i in 0..40 {
+            software = software.key(FixtureKey::new(&format!("Vendor{:02}", i)));
+        }
+        software = software.key(
+            FixtureKey::new("Contoso")
+                .value(FixtureValue::new(
+                    "",
+                    Data::String("Default".try_into().unwrap()),
+                ))
+                .value(FixtureValue::new("Version", Data::U32(3)))
+                .value(FixtureValue::new("Large", Data::Binary(vec![0xab; 40000])))
+                .value(FixtureValue::new(
+                    "Name",
+                    Data::String("Widget".try_into().unwrap()),
+                )),
+        );
+
+        FixtureKey::new("ROOT")
+            .key(software)
+            .key(FixtureKey::new("System").key(FixtureKey::new("Ünïcødé ☃")))
+    }
+
+    #[test]
+    fn reads_header() {
+        let hive = Hive::from_bytes(fixture::build(&sample(), 8)).unwrap();
+        assert_eq!(hive.version(), (1, 5));
+        assert_eq!(hive.sequence_numbers(), (1, 1));
+        assert!(hive.is_checksum_valid());
+        assert_eq!(
+            hive.root().unwrap().name().unwrap().to_string_lossy(),
+            "ROOT"
+        );
+        assert!(hive.root().unwrap().is_root().unwrap());
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(matches!(
+            Hive::from_bytes(vec![0u8; 100]),
+            Err(Error::InvalidHeader(_))
+        ));
+        let mut bytes = fixture::build(&sample(), 8);
+        bytes[0] = b'x';
+        assert!(matches!(
+            Hive::from_bytes(bytes),
+            Err(Error::InvalidHeader(_))
+        ));
+    }
+
+    #[test]
+    fn enumerates_in_sorted_order() {
+        let hive = Hive::from_bytes(fixture::build(&sample(), 8)).unwrap();
+        let names = hive
+            .open("software")
+            .unwrap()
+            .keys()
+            .unwrap()
+            .map(|k| k.unwrap().to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(names.len(), 41);
+        assert_eq!(names[0], "Contoso");
+        assert_eq!(names[1], "Vendor00");
+        assert_eq!(names[40], "Vendor39");
+    }
+
+    #[test]
+    fn finds_subkeys_through_index_roots() {
+        // A leaf capacity of 8 splits the 41 subkeys of Software across an `ri` list.
+        let hive = Hive::from_bytes(fixture::build(&sample(), 8)).unwrap();
+        let software = hive.open("SOFTWARE").unwrap();
+
+        for i in 0..40 {
+            let name = format!("vendor{:02}", i);
+            let key = software.subkey(&*name).unwrap().unwrap();
+            assert_eq!(key.to_string(), format!("Vendor{:02}", i));
+            assert_eq!(key.parent().unwrap().unwrap().offset(), software.offset());
+        }
+
+        let keys = software
+            .keys()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(
+                software.subkey_at(i).unwrap().unwrap().offset(),
+                key.offset()
+            );
+        }
+        assert!(software.subkey_at(keys.len()).unwrap().is_none());
+
+        let contoso = software.subkey("Contoso").unwrap().unwrap();
+        assert_eq!(
+            contoso
+                .value_at(3)
+                .unwrap()
+                .unwrap()
+                .name()
+                .unwrap()
+                .to_string_lossy(),
+            "Name"
+        );
+        assert!(contoso.value_at(4).unwrap().is_none());
+
+        assert!(software.subkey("Vendor40").unwrap().is_none());
+        assert!(software.subkey("Aardvark").unwrap().is_none());
+        assert!(software.subkey("Zebra").unwrap().is_none());
+        assert!(matches!(
+            hive.open(r"Software\Missing"),
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn reads_utf16_names() {
+        let hive = Hive::from_bytes(fixture::build(&sample(), 8)).unwrap();
+        let key = hive.open(r"System\ÜNÏCØDÉ ☃").unwrap();
+        assert_eq!(key.name().unwrap().to_string_lossy(), "Ünïcødé ☃");
+    }
+
+    #[test]
+    fn reads_values() {
+        let hive = Hive::from_bytes(fixture::build(&sample(), 8)).unwrap();
+        let key = hive.open(r"\Software\CONTOSO\").unwrap();
+
+        let names = key
+            .values()
+            .unwrap()
+            .map(|v| v.unwrap().name().unwrap().to_string_lossy())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["", "Version", "Large", "Name"]);
+
+        assert_eq!(
+            key.value("").unwrap().data().unwrap(),
+            Data::String("Default".try_into().unwrap())
+        );
+        assert_eq!(key.value("version").unwrap().data().unwrap(), Data::U32(3));
+        assert_eq!(
+            key.value("Large").unwrap().data().unwrap(),
+            Data::Binary(vec![0xab; 40000])
+        );
+        assert!(matches!(key.value("Missing"), Err(Error::NotFound(_))));
+    }
+
+    #[test]
+    fn reads_key_info() {
+        let hive = Hive::from_bytes(fixture::build(&sample(), 8)).unwrap();
+        let software = hive.open("Software").unwrap();
+        let info = software.info().unwrap();
+
+        assert_eq!(info.class().unwrap().to_string_lossy(), "SoftwareClass");
+        assert_eq!(info.subkey_count(), 41);
+        assert_eq!(info.value_count(), 0);
+        assert_eq!(info.max_subkey_name_len(), 8);
+        assert_eq!(info.last_write_time(), fixture::TIMESTAMP);
+        assert!(info.security_descriptor_len() > 0);
+
+        let info = hive.open(r"Software\Contoso").unwrap().info().unwrap();
+        assert_eq!(info.class(), None);
+        assert_eq!(info.value_count(), 4);
+        assert_eq!(info.max_value_name_len(), 7);
+        assert_eq!(info.max_value_data_len(), 40000);
+    }
+
+    #[test]
+    fn reads_security_descriptors() {
+        let hive = Hive::from_bytes(fixture::build(&sample(), 8)).unwrap();
+        let key = hive.open(r"Software\Contoso").unwrap();
+
+        assert_eq!(
+            key.raw_security_descriptor().unwrap().unwrap(),
+            fixture::SECURITY_DESCRIPTOR
+        );
+
+        let sd = key.security_descriptor().unwrap().unwrap();
+        assert_eq!(sd.owner().unwrap().to_string(), "S-1-5-32-544");
+        let writers = sd
+            .dacl()
+            .unwrap()
+            .aces()
+            .iter()
+            .filter(|x| x.security().intersects(Security::SetValue))
+            .map(|x| x.sid().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(writers, vec!["S-1-5-32-544"]);
+    }
+
+    #[test]
+    fn reports_corruption() {
+        let mut bytes = fixture::build(&sample(), 8);
+        let root = Hive::from_bytes(&bytes[..]).unwrap().root;
+        // Point the root's subkey list at the root itself.
+        let at = BASE_BLOCK_SIZE + root as usize + 4 + 0x1c;
+        bytes[at..at + 4].copy_from_slice(&root.to_le_bytes());
+
+        let hive = Hive::from_bytes(bytes).unwrap();
+        assert!(matches!(hive.open("Software"), Err(Error::Corrupt(_, _))));
+    }
+}
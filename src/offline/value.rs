@@ -0,0 +1,130 @@
+use std::{borrow::Cow, fmt::Debug};
+
+use utfx::U16CString;
+
+use super::cell::{self, Cell, Cells, Name};
+use super::Error;
+use crate::Data;
+
+/// A value node (`vk` cell) within an offline hive.
+///
+/// As with [`Key`](struct.Key.html), the name and data are only decoded when requested.
+#[derive(Copy, Clone)]
+pub struct Value<'a> {
+    pub(crate) cells: Cells<'a>,
+    pub(crate) cell: Cell<'a>,
+}
+
+impl Debug for Value<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = self
+            .raw_name()
+            .map(|x| x.to_ucstring().to_string_lossy())
+            .unwrap_or_else(|_| "<invalid>".into());
+        f.debug_tuple("Value")
+            .field(&name)
+            .field(&format_args!("{:#x}", self.cell.offset))
+            .finish()
+    }
+}
+
+impl<'a> Value<'a> {
+    pub(crate) fn new(cells: Cells<'a>, offset: u32) -> Result<Value<'a>, Error> {
+        Ok(Value {
+            cells,
+            cell: cells.cell_with_signature(offset, b"vk")?,
+        })
+    }
+
+    /// The offset of this value's cell within the hive bins.
+    #[inline]
+    pub fn offset(&self) -> u32 {
+        self.cell.offset
+    }
+
+    pub(crate) fn raw_name(&self) -> Result<Name<'a>, Error> {
+        let len = self.cell.u16(0x02)? as usize;
+        let compressed = self.cell.u16(0x10)? & cell::VALUE_COMP_NAME != 0;
+        Ok(Name::new(self.cell.bytes(0x14, len)?, compressed))
+    }
+
+    /// The name of this value. The default value of a key has an empty name.
+    pub fn name(&self) -> Result<U16CString, Error> {
+        self.raw_name().map(|x| x.to_ucstring())
+    }
+
+    /// The raw type code of the data, such as `REG_SZ`.
+    #[inline]
+    pub fn data_type(&self) -> Result<u32, Error> {
+        self.cell.u32(0x0c)
+    }
+
+    /// The size of the data in bytes.
+    #[inline]
+    pub fn data_len(&self) -> Result<u32, Error> {
+        Ok(self.cell.u32(0x04)? & 0x7fff_ffff)
+    }
+
+    /// The data of this value as raw bytes. The data is borrowed from the hive unless it
+    /// had to be reassembled from big data segments.
+    pub fn raw_data(&self) -> Result<Cow<'a, [u8]>, Error> {
+        let size = self.cell.u32(0x04)?;
+        let len = (size & 0x7fff_ffff) as usize;
+
+        // Data of up to four bytes is stored in place of the offset.
+        if size & 0x8000_0000 != 0 {
+            if len > 4 {
+                return Err(Error::Corrupt(self.cell.offset, "inline data too long"));
+            }
+            return Ok(Cow::Borrowed(self.cell.bytes(0x08, len)?));
+        }
+
+        self.cells.data(self.cell.u32(0x08)?, len)
+    }
+
+    /// The data of this value, parsed according to its type.
+    pub fn data(&self) -> Result<Data, Error> {
+        let data = self.raw_data()?;
+        Ok(Data::from_bytes(self.data_type()?, &data)?)
+    }
+}
+
+/// An iterator over the values of an offline [`Key`](struct.Key.html).
+#[derive(Debug)]
+pub struct Values<'a> {
+    cells: Cells<'a>,
+    list: Option<Cell<'a>>,
+    count: usize,
+    index: usize,
+}
+
+impl<'a> Values<'a> {
+    pub(crate) fn new(cells: Cells<'a>, list: Option<Cell<'a>>, count: usize) -> Values<'a> {
+        Values {
+            cells,
+            list,
+            count,
+            index: 0,
+        }
+    }
+}
+
+impl<'a> Iterator for Values<'a> {
+    type Item = Result<Value<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let list = self.list?;
+        if self.index >= self.count {
+            return None;
+        }
+
+        let offset = list.u32(self.index * 4);
+        self.index += 1;
+        Some(offset.and_then(|x| Value::new(self.cells, x)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.count.saturating_sub(self.index);
+        (len, Some(len))
+    }
+}
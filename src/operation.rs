@@ -0,0 +1,683 @@
+//! A common model for registry changes, as read from `.reg` files and other formats that
+//! describe changes rather than whole trees, and applying them to any
+//! [`KeyTarget`](../tree/trait.KeyTarget.html).
+//!
+//! ```
+//! use registry::{operation::{self, Options}, regfile::RegFile, tree::Tree, Data};
+//!
+//! let file = RegFile::parse(r#"Windows Registry Editor Version 5.00
+//!
+//! [HKEY_LOCAL_MACHINE\SOFTWARE\Contoso]
+//! "Version"=dword:00000002
+//! "#)?;
+//!
+//! // Install into a different hive, mounted at a test tree.
+//! let options = Options::new("HKEY_CURRENT_USER").remap("HKEY_LOCAL_MACHINE", "HKEY_CURRENT_USER");
+//! let mut tree = Tree::new();
+//! let outcome = operation::apply(&mut tree, file.operations(), &options).unwrap();
+//! assert_eq!(tree.key(r"SOFTWARE\Contoso").unwrap().value("Version"), Some(&Data::U32(2)));
+//!
+//! outcome.rollback().apply(&mut tree).unwrap();
+//! assert_eq!(tree, Tree::new());
+//! # Ok::<(), registry::regfile::Error>(())
+//! ```
+
+use std::collections::BTreeMap;
+
+use crate::tree::{components, fold, KeyTarget, Tree};
+use crate::Data;
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error<E> {
+    #[error("Path is outside of the target: {0:?}")]
+    OutsideTarget(String),
+
+    /// The target failed part way through. The rollback undoes the operations that were
+    /// applied before the failure.
+    #[error("An error occurred changing the target")]
+    Target {
+        #[source]
+        source: E,
+        rollback: Rollback,
+    },
+}
+
+/// A single change to the registry.
+///
+/// Key paths are backslash separated and kept exactly as they were written, including any
+/// hive prefix such as `HKEY_LOCAL_MACHINE`. The default value of a key has an empty name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operation {
+    /// Creates a key, along with any missing keys on its path.
+    CreateKey { path: String },
+
+    /// Deletes a key and everything below it.
+    DeleteKey { path: String },
+
+    /// Sets a value, replacing any existing value with the same name.
+    SetValue {
+        path: String,
+        name: String,
+        data: Data,
+    },
+
+    /// Deletes a value.
+    DeleteValue { path: String, name: String },
+}
+
+impl Operation {
+    /// The path of the key this operation applies to.
+    pub fn path(&self) -> &str {
+        match self {
+            Operation::CreateKey { path }
+            | Operation::DeleteKey { path }
+            | Operation::SetValue { path, .. }
+            | Operation::DeleteValue { path, .. } => path,
+        }
+    }
+}
+
+/// How operations are mapped onto a target by [`apply`](fn.apply.html).
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    root: String,
+    remaps: Vec<(String, String)>,
+    is_dry_run: bool,
+}
+
+impl Options {
+    /// Options for a target found at `root`, such as `HKEY_LOCAL_MACHINE` for a `Hive` or
+    /// the full path of a `RegKey`. Operation paths are made relative to it, and operations
+    /// outside of it are rejected. An empty root takes operation paths as they are.
+    pub fn new(root: &str) -> Options {
+        Options {
+            root: root.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Rewrites operation paths starting with `from` to start with `to` instead, before they
+    /// are matched against the root. The first matching remapping is used, and prefixes only
+    /// match whole path components, ignoring case.
+    pub fn remap(mut self, from: &str, to: &str) -> Options {
+        self.remaps.push((from.to_string(), to.to_string()));
+        self
+    }
+
+    /// Whether to only report the changes that would be made, leaving the target untouched.
+    pub fn dry_run(mut self, is_dry_run: bool) -> Options {
+        self.is_dry_run = is_dry_run;
+        self
+    }
+
+    /// Maps an operation path to a path relative to the target.
+    fn resolve(&self, path: &str) -> Option<String> {
+        let mut path = path.to_string();
+        if let Some((from, to)) = self
+            .remaps
+            .iter()
+            .find(|(from, _)| strip(&path, from).is_some())
+        {
+            let rest = strip(&path, from).unwrap_or_default();
+            path = join(to, &rest);
+        }
+        strip(&path, &self.root)
+    }
+}
+
+/// The path with `prefix` removed from its start, if it starts with those components.
+fn strip(path: &str, prefix: &str) -> Option<String> {
+    let mut path = components(path);
+    for expected in components(prefix) {
+        if fold(path.next()?) != fold(expected) {
+            return None;
+        }
+    }
+    Some(path.collect::<Vec<_>>().join("\\"))
+}
+
+pub(crate) fn join(path: &str, name: &str) -> String {
+    match (path.is_empty(), name.is_empty()) {
+        (true, _) => name.to_string(),
+        (_, true) => path.to_string(),
+        _ => format!("{}\\{}", path, name),
+    }
+}
+
+/// The operations that restore the state of a target from before [`apply`](fn.apply.html)
+/// changed it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Rollback {
+    operations: Vec<Operation>,
+}
+
+impl Rollback {
+    /// The operations to undo the changes, with paths relative to the target, in the order
+    /// they are to be applied.
+    #[inline]
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+
+    #[inline]
+    pub fn into_operations(self) -> Vec<Operation> {
+        self.operations
+    }
+
+    /// Restores the target to its state before the changes were made.
+    pub fn apply<T: KeyTarget>(&self, target: &mut T) -> Result<(), T::Error> {
+        for operation in &self.operations {
+            execute(target, operation)?;
+        }
+        Ok(())
+    }
+}
+
+/// The result of [`apply`](fn.apply.html).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Outcome {
+    changes: Vec<Operation>,
+    rollback: Rollback,
+}
+
+impl Outcome {
+    /// The operations that changed the target, or would have in a dry run, with paths
+    /// relative to the target. Operations with no effect, such as setting a value to the
+    /// data it already has, are left out.
+    #[inline]
+    pub fn changes(&self) -> &[Operation] {
+        &self.changes
+    }
+
+    /// The record of the previous state of everything that was changed.
+    #[inline]
+    pub fn rollback(&self) -> &Rollback {
+        &self.rollback
+    }
+
+    #[inline]
+    pub fn into_rollback(self) -> Rollback {
+        self.rollback
+    }
+}
+
+pub(crate) fn execute<T: KeyTarget>(target: &mut T, operation: &Operation) -> Result<(), T::Error> {
+    match operation {
+        Operation::CreateKey { path } => target.create_key(path),
+        Operation::DeleteKey { path } => target.delete_key(path),
+        Operation::SetValue { path, name, data } => target.set_value(path, name, data),
+        Operation::DeleteValue { path, name } => target.delete_value(path, name),
+    }
+}
+
+/// Applies operations to a target, returning the changes made and a record for undoing them.
+///
+/// All paths are resolved before anything is changed, so an operation outside of the target
+/// leaves it untouched. Values are set on keys that do not yet exist by creating them first.
+pub fn apply<T: KeyTarget>(
+    target: &mut T,
+    operations: &[Operation],
+    options: &Options,
+) -> Result<Outcome, Error<T::Error>> {
+    let operations = operations
+        .iter()
+        .map(|operation| {
+            let path = options
+                .resolve(operation.path())
+                .ok_or_else(|| Error::OutsideTarget(operation.path().to_string()))?;
+            Ok(match operation.clone() {
+                Operation::CreateKey { .. } => Operation::CreateKey { path },
+                Operation::DeleteKey { .. } => Operation::DeleteKey { path },
+                Operation::SetValue { name, data, .. } => Operation::SetValue { path, name, data },
+                Operation::DeleteValue { name, .. } => Operation::DeleteValue { path, name },
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if options.is_dry_run {
+        run(&mut DryRun::new(target), &operations)
+    } else {
+        run(target, &operations)
+    }
+}
+
+fn run<T: KeyTarget>(target: &mut T, operations: &[Operation]) -> Result<Outcome, Error<T::Error>> {
+    let mut outcome = Outcome::default();
+    // The undo operations of each change, which are applied in reverse order.
+    let mut undo = vec![];
+
+    for operation in operations {
+        let result = change(target, operation, &mut outcome.changes, &mut undo);
+        if let Err(source) = result {
+            return Err(Error::Target {
+                source,
+                rollback: finish(undo),
+            });
+        }
+    }
+
+    outcome.rollback = finish(undo);
+    Ok(outcome)
+}
+
+fn finish(undo: Vec<Vec<Operation>>) -> Rollback {
+    Rollback {
+        operations: undo.into_iter().rev().flatten().collect(),
+    }
+}
+
+/// Makes a single change if it has any effect, recording it and how to undo it.
+fn change<T: KeyTarget>(
+    target: &mut T,
+    operation: &Operation,
+    changes: &mut Vec<Operation>,
+    undo: &mut Vec<Vec<Operation>>,
+) -> Result<(), T::Error> {
+    match operation {
+        Operation::CreateKey { path } => create(target, path, changes, undo)?,
+        Operation::DeleteKey { path } => {
+            // Emptying an already empty root has no effect.
+            let is_empty = |x: &Tree| x.keys().next().is_none() && x.values().is_empty();
+            match target.read_key(path)? {
+                Some(tree) if components(path).next().is_some() || !is_empty(&tree) => {
+                    target.delete_key(path)?;
+                    let mut restore = vec![];
+                    recreate_into(path, &tree, &mut restore);
+                    undo.push(restore);
+                    changes.push(operation.clone());
+                }
+                _ => {}
+            }
+        }
+        Operation::SetValue { path, name, data } => {
+            create(target, path, changes, undo)?;
+            let old = target.read_value(path, name)?;
+            if old.as_ref() != Some(data) {
+                target.set_value(path, name, data)?;
+                undo.push(vec![match old {
+                    Some(data) => Operation::SetValue {
+                        path: path.clone(),
+                        name: name.clone(),
+                        data,
+                    },
+                    None => Operation::DeleteValue {
+                        path: path.clone(),
+                        name: name.clone(),
+                    },
+                }]);
+                changes.push(operation.clone());
+            }
+        }
+        Operation::DeleteValue { path, name } => {
+            if let Some(data) = target.read_value(path, name)? {
+                target.delete_value(path, name)?;
+                undo.push(vec![Operation::SetValue {
+                    path: path.clone(),
+                    name: name.clone(),
+                    data,
+                }]);
+                changes.push(operation.clone());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Creates a key if it is missing, undone by deleting the first key on its path that did not
+/// already exist.
+fn create<T: KeyTarget>(
+    target: &mut T,
+    path: &str,
+    changes: &mut Vec<Operation>,
+    undo: &mut Vec<Vec<Operation>>,
+) -> Result<(), T::Error> {
+    if target.key_exists(path)? {
+        return Ok(());
+    }
+
+    let mut first = String::new();
+    for name in components(path) {
+        first = join(&first, name);
+        if !target.key_exists(&first)? {
+            break;
+        }
+    }
+
+    target.create_key(path)?;
+    undo.push(vec![Operation::DeleteKey { path: first }]);
+    changes.push(Operation::CreateKey {
+        path: path.to_string(),
+    });
+    Ok(())
+}
+
+/// The operations that recreate a tree at the given path, such as one imported from a
+/// [`document`](../document/index.html), creating each key before its values and subkeys.
+///
+/// Anything already at the path is left in place, so delete it first for an exact copy.
+pub fn recreate(path: &str, tree: &Tree) -> Vec<Operation> {
+    let mut operations = vec![];
+    recreate_into(path, tree, &mut operations);
+    operations
+}
+
+fn recreate_into(path: &str, tree: &Tree, operations: &mut Vec<Operation>) {
+    operations.push(Operation::CreateKey {
+        path: path.to_string(),
+    });
+    for (name, data) in tree.values() {
+        operations.push(Operation::SetValue {
+            path: path.to_string(),
+            name: name.clone(),
+            data: data.clone(),
+        });
+    }
+    for (name, key) in tree.keys() {
+        recreate_into(&join(path, name), key, operations);
+    }
+}
+
+/// Stands in for a target during a dry run, reading through to it while recording changes
+/// instead of making them.
+pub(crate) struct DryRun<'a, T> {
+    target: &'a T,
+    /// Deleted keys, by upper case path. Anything the target holds below them is hidden.
+    deleted: Vec<String>,
+    /// Keys created since any deletion covering them, by upper case path.
+    created: BTreeMap<String, String>,
+    /// Values set or deleted since any deletion covering them, by upper case path and name.
+    values: BTreeMap<(String, String), (String, String, Option<Data>)>,
+}
+
+/// A path converted to upper case with empty components removed, for comparisons.
+fn fold_path(path: &str) -> String {
+    String::from_utf16_lossy(&fold(&components(path).collect::<Vec<_>>().join("\\")))
+}
+
+/// Whether an upper case path is, or is below, another.
+fn is_within(path: &str, ancestor: &str) -> bool {
+    ancestor.is_empty()
+        || path == ancestor
+        || (path.starts_with(ancestor) && path[ancestor.len()..].starts_with('\\'))
+}
+
+impl<'a, T: KeyTarget> DryRun<'a, T> {
+    pub(crate) fn new(target: &'a T) -> DryRun<'a, T> {
+        DryRun {
+            target,
+            deleted: vec![],
+            created: BTreeMap::new(),
+            values: BTreeMap::new(),
+        }
+    }
+
+    fn is_hidden(&self, path: &str) -> bool {
+        self.deleted.iter().any(|x| is_within(path, x))
+    }
+}
+
+impl<'a, T: KeyTarget> KeyTarget for DryRun<'a, T> {
+    type Error = T::Error;
+
+    fn key_exists(&self, path: &str) -> Result<bool, T::Error> {
+        let folded = fold_path(path);
+        if folded.is_empty() || self.created.contains_key(&folded) {
+            return Ok(true);
+        }
+        if self.is_hidden(&folded) {
+            return Ok(false);
+        }
+        self.target.key_exists(path)
+    }
+
+    fn read_value(&self, path: &str, name: &str) -> Result<Option<Data>, T::Error> {
+        let folded = fold_path(path);
+        let key = (folded, String::from_utf16_lossy(&fold(name)));
+        if let Some((_, _, data)) = self.values.get(&key) {
+            return Ok(data.clone());
+        }
+        if self.is_hidden(&key.0) {
+            return Ok(None);
+        }
+        self.target.read_value(path, name)
+    }
+
+    fn read_key(&self, path: &str) -> Result<Option<Tree>, T::Error> {
+        let folded = fold_path(path);
+        let mut tree = match self.is_hidden(&folded) {
+            true => None,
+            false => self.target.read_key(path)?,
+        };
+        if folded.is_empty() && tree.is_none() {
+            tree = Some(Tree::new());
+        }
+
+        let depth = components(path).count();
+        let relative = |x: &str| components(x).skip(depth).collect::<Vec<_>>().join("\\");
+
+        if let Some(tree) = &mut tree {
+            for deleted in self.deleted.iter().filter(|x| is_within(x, &folded)) {
+                tree.remove_key(&relative(deleted));
+            }
+        }
+        for (_, created) in self.created.iter().filter(|(x, _)| is_within(x, &folded)) {
+            tree.get_or_insert_with(Tree::new)
+                .create_key(&relative(created));
+        }
+        for ((key, _), (path, name, data)) in &self.values {
+            if let (true, Some(tree)) = (is_within(key, &folded), &mut tree) {
+                let key = tree.create_key(&relative(path));
+                match data {
+                    Some(data) => key.set_value(name, data.clone()),
+                    None => key.remove_value(name),
+                };
+            }
+        }
+        Ok(tree)
+    }
+
+    fn create_key(&mut self, path: &str) -> Result<(), T::Error> {
+        let mut prefix = String::new();
+        for name in components(path) {
+            prefix = join(&prefix, name);
+            self.created.insert(fold_path(&prefix), prefix.clone());
+        }
+        Ok(())
+    }
+
+    fn delete_key(&mut self, path: &str) -> Result<(), T::Error> {
+        let folded = fold_path(path);
+        self.created.retain(|x, _| !is_within(x, &folded));
+        self.values.retain(|(x, _), _| !is_within(x, &folded));
+        self.deleted.retain(|x| !is_within(x, &folded));
+        self.deleted.push(folded);
+        Ok(())
+    }
+
+    fn set_value(&mut self, path: &str, name: &str, data: &Data) -> Result<(), T::Error> {
+        self.values.insert(
+            (fold_path(path), String::from_utf16_lossy(&fold(name))),
+            (path.to_string(), name.to_string(), Some(data.clone())),
+        );
+        Ok(())
+    }
+
+    fn delete_value(&mut self, path: &str, name: &str) -> Result<(), T::Error> {
+        self.values.insert(
+            (fold_path(path), String::from_utf16_lossy(&fold(name))),
+            (path.to_string(), name.to_string(), None),
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+    use crate::offline::Editor;
+    use crate::regfile::RegFile;
+
+    fn string(s: &str) -> Data {
+        Data::String(s.try_into().unwrap())
+    }
+
+    fn existing() -> Tree {
+        let mut tree = Tree::new();
+        let key = tree.create_key(r"Software\Contoso");
+        key.set_value("Version", Data::U32(1));
+        key.set_value("Name", string("Contoso"));
+        tree.create_key(r"Software\Old\Nested")
+            .set_value("", string("x"));
+        tree
+    }
+
+    const FILE: &str = r#"Windows Registry Editor Version 5.00
+
+[HKEY_LOCAL_MACHINE\SOFTWARE\Contoso]
+"Version"=dword:00000002
+"Name"="Contoso"
+"Missing"=-
+
+[HKEY_LOCAL_MACHINE\SOFTWARE\Contoso\New\Deep]
+@="new"
+
+[-HKEY_LOCAL_MACHINE\SOFTWARE\Old]
+
+[HKEY_LOCAL_MACHINE\SOFTWARE\Old]
+"Fresh"=dword:00000001
+"#;
+
+    #[test]
+    fn applies_and_rolls_back() {
+        let file = RegFile::parse(FILE).unwrap();
+        let mut tree = existing();
+        let outcome = apply(
+            &mut tree,
+            file.operations(),
+            &Options::new("HKEY_LOCAL_MACHINE"),
+        )
+        .unwrap();
+
+        let contoso = tree.key(r"software\contoso").unwrap();
+        assert_eq!(contoso.value("Version"), Some(&Data::U32(2)));
+        assert_eq!(
+            tree.key(r"Software\Contoso\New\Deep").unwrap().value(""),
+            Some(&string("new"))
+        );
+        assert!(tree.key(r"Software\Old\Nested").is_none());
+        assert_eq!(
+            tree.key(r"Software\Old").unwrap().value("Fresh"),
+            Some(&Data::U32(1))
+        );
+
+        // Setting a value to its current data and deleting a missing value change nothing.
+        assert_eq!(
+            outcome.changes(),
+            &[
+                Operation::SetValue {
+                    path: r"SOFTWARE\Contoso".into(),
+                    name: "Version".into(),
+                    data: Data::U32(2),
+                },
+                Operation::CreateKey {
+                    path: r"SOFTWARE\Contoso\New\Deep".into(),
+                },
+                Operation::SetValue {
+                    path: r"SOFTWARE\Contoso\New\Deep".into(),
+                    name: "".into(),
+                    data: string("new"),
+                },
+                Operation::DeleteKey {
+                    path: r"SOFTWARE\Old".into(),
+                },
+                Operation::CreateKey {
+                    path: r"SOFTWARE\Old".into(),
+                },
+                Operation::SetValue {
+                    path: r"SOFTWARE\Old".into(),
+                    name: "Fresh".into(),
+                    data: Data::U32(1),
+                },
+            ]
+        );
+
+        outcome.rollback().apply(&mut tree).unwrap();
+        assert_eq!(tree, existing());
+    }
+
+    #[test]
+    fn dry_run_reports_without_changing() {
+        let file = RegFile::parse(FILE).unwrap();
+        let options = Options::new("HKEY_LOCAL_MACHINE");
+
+        let mut tree = existing();
+        let expected = apply(&mut tree, file.operations(), &options).unwrap();
+
+        let mut tree = existing();
+        let outcome = apply(&mut tree, file.operations(), &options.dry_run(true)).unwrap();
+        assert_eq!(tree, existing());
+        assert_eq!(outcome, expected);
+    }
+
+    #[test]
+    fn remaps_and_rejects_paths_outside_the_target() {
+        let file = RegFile::parse(FILE).unwrap();
+        let mut tree = Tree::new();
+
+        let result = apply(
+            &mut tree,
+            file.operations(),
+            &Options::new("HKEY_CURRENT_USER"),
+        );
+        assert!(matches!(result, Err(Error::OutsideTarget(_))));
+        assert_eq!(tree, Tree::new());
+
+        let options = Options::new(r"HKEY_CURRENT_USER\Software").remap(
+            r"hkey_local_machine\software",
+            r"HKEY_CURRENT_USER\Software\Mirror",
+        );
+        apply(&mut tree, file.operations(), &options).unwrap();
+        assert!(tree.key(r"Mirror\Contoso\New\Deep").is_some());
+    }
+
+    #[test]
+    fn applies_to_offline_hive() {
+        let file = RegFile::parse(FILE).unwrap();
+        let options = Options::new("HKEY_LOCAL_MACHINE");
+        let mut tree = existing();
+
+        let mut editor = Editor::new();
+        apply(&mut editor, &recreate("", &tree), &Options::new("")).unwrap();
+        assert_eq!(
+            editor.read_key("").unwrap().map(without_times),
+            Some(existing())
+        );
+
+        let outcome = apply(&mut editor, file.operations(), &options).unwrap();
+        let expected = apply(&mut tree, file.operations(), &options).unwrap();
+        assert_eq!(outcome, expected);
+        assert_eq!(editor.read_key("").unwrap().map(without_times), Some(tree));
+
+        outcome.rollback().apply(&mut editor).unwrap();
+        assert_eq!(
+            editor.read_key("").unwrap().map(without_times),
+            Some(existing())
+        );
+    }
+
+    fn without_times(mut tree: Tree) -> Tree {
+        tree.set_last_write_time(None);
+        let names = tree
+            .keys()
+            .map(|(name, _)| name.to_string())
+            .collect::<Vec<_>>();
+        for name in names {
+            let key = tree.remove_key(&name).unwrap();
+            *tree.create_key(&name) = without_times(key);
+        }
+        tree
+    }
+}
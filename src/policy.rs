@@ -0,0 +1,558 @@
+//! Reading and writing Group Policy `Registry.pol` files, in the binary `PReg` format.
+//!
+//! A policy file is a list of records, each naming a key, a value and its data. Value names
+//! starting with `**` are directives, such as `**del.` to delete a value, which are parsed
+//! into their own [`Entry`](enum.Entry.html) variants.
+//!
+//! ```
+//! use registry::{policy::{Entry, PolicyFile}, Data};
+//!
+//! let file = PolicyFile::new(vec![Entry::SetValue {
+//!     key: r"Software\Policies\Contoso".into(),
+//!     name: "Enabled".into(),
+//!     data: Data::U32(1),
+//! }]);
+//! assert_eq!(PolicyFile::parse(&file.to_bytes())?, file);
+//! # Ok::<(), registry::policy::Error>(())
+//! ```
+
+use crate::operation::{execute, join, DryRun, Operation};
+use crate::tree::KeyTarget;
+use crate::Data;
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("Missing or unrecognised header")]
+    InvalidHeader,
+
+    #[error("Unsupported version {0}")]
+    UnsupportedVersion(u32),
+
+    #[error("Malformed record at offset {0:#x}: {1}")]
+    Syntax(usize, &'static str),
+
+    #[error("Unsupported value type {1:#x} at offset {0:#x}")]
+    UnsupportedType(usize, u32),
+
+    #[error("Invalid value data at offset {0:#x}")]
+    Data(usize, #[source] crate::value::Error),
+}
+
+const SIGNATURE: &[u8; 4] = b"PReg";
+const VERSION: u32 = 1;
+
+/// A record of a policy file.
+///
+/// Key paths are relative to the hive the policy applies to: `HKEY_LOCAL_MACHINE` for
+/// machine policy and `HKEY_CURRENT_USER` for user policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    /// Creates a key without setting any value, written as a record with an empty value
+    /// name and no data.
+    CreateKey { key: String },
+
+    SetValue {
+        key: String,
+        name: String,
+        data: Data,
+    },
+
+    /// `**soft.`: sets a value only if it does not already exist.
+    SoftSetValue {
+        key: String,
+        name: String,
+        data: Data,
+    },
+
+    /// `**del.`: deletes a value.
+    DeleteValue { key: String, name: String },
+
+    /// `**delvals.`: deletes every value of a key.
+    DeleteAllValues { key: String },
+
+    /// `**DeleteValues`: deletes a list of values.
+    DeleteValues { key: String, names: Vec<String> },
+
+    /// `**DeleteKeys`: deletes a list of subkeys.
+    DeleteKeys { key: String, names: Vec<String> },
+
+    /// Any other directive, such as `**SecureKey` or `**Comment:`, kept as its raw type
+    /// and data.
+    Directive {
+        key: String,
+        name: String,
+        ty: u32,
+        data: Vec<u8>,
+    },
+}
+
+impl Entry {
+    /// The path of the key this entry applies to.
+    pub fn key(&self) -> &str {
+        match self {
+            Entry::CreateKey { key }
+            | Entry::SetValue { key, .. }
+            | Entry::SoftSetValue { key, .. }
+            | Entry::DeleteValue { key, .. }
+            | Entry::DeleteAllValues { key }
+            | Entry::DeleteValues { key, .. }
+            | Entry::DeleteKeys { key, .. }
+            | Entry::Directive { key, .. } => key,
+        }
+    }
+
+    /// The raw value name, type and data of the record this entry is written as.
+    fn record(&self) -> (String, u32, Vec<u8>) {
+        // Deletions carry a single space as their data, as the policy editor writes them.
+        let space = || string_bytes(" ");
+        let list = |names: &[String]| string_bytes(&names.join(";"));
+
+        match self {
+            Entry::CreateKey { .. } => (String::new(), 0, vec![]),
+            Entry::SetValue { name, data, .. } => (name.clone(), data.ty(), data.to_bytes()),
+            Entry::SoftSetValue { name, data, .. } => {
+                (format!("**soft.{}", name), data.ty(), data.to_bytes())
+            }
+            Entry::DeleteValue { name, .. } => (format!("**del.{}", name), 1, space()),
+            Entry::DeleteAllValues { .. } => ("**delvals.".into(), 1, space()),
+            Entry::DeleteValues { names, .. } => ("**DeleteValues".into(), 1, list(names)),
+            Entry::DeleteKeys { names, .. } => ("**DeleteKeys".into(), 1, list(names)),
+            Entry::Directive { name, ty, data, .. } => (name.clone(), *ty, data.clone()),
+        }
+    }
+}
+
+/// A string as null terminated UTF-16LE bytes.
+fn string_bytes(s: &str) -> Vec<u8> {
+    s.encode_utf16()
+        .chain(Some(0))
+        .flat_map(|x| x.to_le_bytes().to_vec())
+        .collect()
+}
+
+/// The contents of a `Registry.pol` file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PolicyFile {
+    entries: Vec<Entry>,
+}
+
+impl PolicyFile {
+    #[inline]
+    pub fn new(entries: Vec<Entry>) -> PolicyFile {
+        PolicyFile { entries }
+    }
+
+    /// Parses the contents of a policy file.
+    pub fn parse(bytes: &[u8]) -> Result<PolicyFile, Error> {
+        if bytes.len() < 8 || &bytes[..4] != SIGNATURE {
+            return Err(Error::InvalidHeader);
+        }
+        let version = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let mut reader = Reader { bytes, offset: 8 };
+        let mut entries = vec![];
+        while reader.offset < bytes.len() {
+            entries.push(reader.entry()?);
+        }
+        Ok(PolicyFile { entries })
+    }
+
+    /// The file as it would be written to disk.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = SIGNATURE.to_vec();
+        out.extend_from_slice(&VERSION.to_le_bytes());
+
+        let separator = u16::from(b';').to_le_bytes();
+        for entry in &self.entries {
+            let (name, ty, data) = entry.record();
+            out.extend_from_slice(&u16::from(b'[').to_le_bytes());
+            out.extend(string_bytes(entry.key()));
+            out.extend_from_slice(&separator);
+            out.extend(string_bytes(&name));
+            out.extend_from_slice(&separator);
+            out.extend_from_slice(&ty.to_le_bytes());
+            out.extend_from_slice(&separator);
+            out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            out.extend_from_slice(&separator);
+            out.extend(data);
+            out.extend_from_slice(&u16::from(b']').to_le_bytes());
+        }
+        out
+    }
+
+    #[inline]
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    #[inline]
+    pub fn into_entries(self) -> Vec<Entry> {
+        self.entries
+    }
+
+    /// Converts the entries into operations on the hive found at `root`, such as
+    /// `HKEY_LOCAL_MACHINE`.
+    ///
+    /// Some entries depend on what is already there: `**delvals.` deletes whichever values
+    /// a key has, and `**soft.` only sets missing values. These are resolved against
+    /// `current`, the target the policy is for, taking earlier entries into account.
+    /// Directives without an effect on keys or values are left out.
+    pub fn to_operations<T: KeyTarget>(
+        &self,
+        root: &str,
+        current: &T,
+    ) -> Result<Vec<Operation>, T::Error> {
+        let mut state = DryRun::new(current);
+        let mut operations = vec![];
+
+        for entry in &self.entries {
+            let key = entry.key().to_string();
+            let mut changes = vec![];
+            match entry {
+                Entry::CreateKey { .. } => changes.push(Operation::CreateKey { path: key }),
+                Entry::SetValue { name, data, .. } => changes.push(Operation::SetValue {
+                    path: key,
+                    name: name.clone(),
+                    data: data.clone(),
+                }),
+                Entry::SoftSetValue { name, data, .. } => {
+                    if state.read_value(&key, name)?.is_none() {
+                        changes.push(Operation::SetValue {
+                            path: key,
+                            name: name.clone(),
+                            data: data.clone(),
+                        });
+                    }
+                }
+                Entry::DeleteValue { name, .. } => changes.push(Operation::DeleteValue {
+                    path: key,
+                    name: name.clone(),
+                }),
+                Entry::DeleteAllValues { .. } => {
+                    if let Some(tree) = state.read_key(&key)? {
+                        for (name, _) in tree.values() {
+                            changes.push(Operation::DeleteValue {
+                                path: key.clone(),
+                                name: name.clone(),
+                            });
+                        }
+                    }
+                }
+                Entry::DeleteValues { names, .. } => {
+                    for name in names {
+                        changes.push(Operation::DeleteValue {
+                            path: key.clone(),
+                            name: name.clone(),
+                        });
+                    }
+                }
+                Entry::DeleteKeys { names, .. } => {
+                    for name in names {
+                        changes.push(Operation::DeleteKey {
+                            path: join(&key, name),
+                        });
+                    }
+                }
+                Entry::Directive { .. } => {}
+            }
+
+            for change in changes {
+                execute(&mut state, &change)?;
+                operations.push(match change {
+                    Operation::CreateKey { path } => Operation::CreateKey {
+                        path: join(root, &path),
+                    },
+                    Operation::DeleteKey { path } => Operation::DeleteKey {
+                        path: join(root, &path),
+                    },
+                    Operation::SetValue { path, name, data } => Operation::SetValue {
+                        path: join(root, &path),
+                        name,
+                        data,
+                    },
+                    Operation::DeleteValue { path, name } => Operation::DeleteValue {
+                        path: join(root, &path),
+                        name,
+                    },
+                });
+            }
+        }
+        Ok(operations)
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u16(&mut self) -> Result<u16, Error> {
+        let bytes = self
+            .bytes
+            .get(self.offset..self.offset + 2)
+            .ok_or(Error::Syntax(self.offset, "unexpected end of file"))?;
+        self.offset += 2;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let bytes = self
+            .bytes
+            .get(self.offset..self.offset + 4)
+            .ok_or(Error::Syntax(self.offset, "unexpected end of file"))?;
+        self.offset += 4;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn expect(&mut self, c: u8, message: &'static str) -> Result<(), Error> {
+        let offset = self.offset;
+        match self.u16()? {
+            x if x == u16::from(c) => Ok(()),
+            _ => Err(Error::Syntax(offset, message)),
+        }
+    }
+
+    /// Reads a string up to its null terminator, which may only be followed by the separator.
+    /// Strings may contain the separator themselves.
+    fn string(&mut self) -> Result<String, Error> {
+        let start = self.offset;
+        let mut units = vec![];
+        loop {
+            match self.u16() {
+                Ok(0) => break,
+                Ok(x) => units.push(x),
+                Err(_) => return Err(Error::Syntax(start, "unterminated string")),
+            }
+        }
+        self.expect(b';', "expected ';'")?;
+        Ok(String::from_utf16_lossy(&units))
+    }
+
+    fn entry(&mut self) -> Result<Entry, Error> {
+        let start = self.offset;
+        self.expect(b'[', "expected '['")?;
+        let key = self.string()?;
+        let name = self.string()?;
+        let ty = self.u32()?;
+        self.expect(b';', "expected ';'")?;
+        let size = self.u32()? as usize;
+        self.expect(b';', "expected ';'")?;
+        let data = self
+            .bytes
+            .get(self.offset..self.offset + size)
+            .ok_or(Error::Syntax(
+                self.offset,
+                "data runs past the end of the file",
+            ))?
+            .to_vec();
+        self.offset += size;
+        self.expect(b']', "expected ']'")?;
+
+        let to_data = || match Data::from_bytes(ty, &data) {
+            Ok(data) => Ok(data),
+            Err(crate::value::Error::UnhandledType(ty)) => Err(Error::UnsupportedType(start, ty)),
+            Err(e) => Err(Error::Data(start, e)),
+        };
+        let list = || match Data::from_bytes(1, &data) {
+            Ok(Data::String(s)) => s
+                .to_string_lossy()
+                .split(';')
+                .filter(|x| !x.is_empty())
+                .map(str::to_string)
+                .collect(),
+            _ => vec![],
+        };
+
+        // Directive names are matched ignoring case, as Windows does.
+        let is = |directive: &str| name.eq_ignore_ascii_case(directive);
+        let strip = |prefix: &str| match name.get(..prefix.len()) {
+            Some(x) if x.eq_ignore_ascii_case(prefix) => Some(name[prefix.len()..].to_string()),
+            _ => None,
+        };
+
+        Ok(if name.is_empty() && ty == 0 && data.is_empty() {
+            Entry::CreateKey { key }
+        } else if is("**delvals.") {
+            Entry::DeleteAllValues { key }
+        } else if is("**DeleteValues") {
+            Entry::DeleteValues { key, names: list() }
+        } else if is("**DeleteKeys") {
+            Entry::DeleteKeys { key, names: list() }
+        } else if let Some(name) = strip("**del.") {
+            Entry::DeleteValue { key, name }
+        } else if let Some(name) = strip("**soft.") {
+            Entry::SoftSetValue {
+                key,
+                name,
+                data: to_data()?,
+            }
+        } else if name.starts_with("**") {
+            Entry::Directive {
+                key,
+                name,
+                ty,
+                data,
+            }
+        } else {
+            Entry::SetValue {
+                key,
+                name,
+                data: to_data()?,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+    use crate::tree::Tree;
+
+    const KEY: &str = r"Software\Policies\Contoso";
+
+    fn string(s: &str) -> Data {
+        Data::String(s.try_into().unwrap())
+    }
+
+    fn entries() -> Vec<Entry> {
+        vec![
+            Entry::CreateKey { key: KEY.into() },
+            Entry::DeleteAllValues { key: KEY.into() },
+            Entry::SetValue {
+                key: KEY.into(),
+                name: "Enabled".into(),
+                data: Data::U32(1),
+            },
+            Entry::SoftSetValue {
+                key: KEY.into(),
+                name: "Server".into(),
+                data: string("example.com"),
+            },
+            Entry::DeleteValue {
+                key: KEY.into(),
+                name: "Legacy".into(),
+            },
+            Entry::DeleteValues {
+                key: KEY.into(),
+                names: vec!["A".into(), "B".into()],
+            },
+            Entry::DeleteKeys {
+                key: KEY.into(),
+                names: vec!["Cache".into()],
+            },
+            Entry::Directive {
+                key: KEY.into(),
+                name: "**SecureKey".into(),
+                ty: 4,
+                data: vec![1, 0, 0, 0],
+            },
+        ]
+    }
+
+    #[test]
+    fn reads_and_writes_records() {
+        let file = PolicyFile::new(entries());
+        let bytes = file.to_bytes();
+        assert_eq!(&bytes[..8], b"PReg\x01\0\0\0");
+
+        // The first record, for creating the key.
+        let mut record = vec![b'[', 0];
+        record.extend(string_bytes(KEY));
+        record.extend_from_slice(&[b';', 0, 0, 0, b';', 0]);
+        record.extend_from_slice(&[0, 0, 0, 0, b';', 0, 0, 0, 0, 0, b';', 0, b']', 0]);
+        assert_eq!(&bytes[8..8 + record.len()], &record[..]);
+
+        assert_eq!(PolicyFile::parse(&bytes).unwrap(), file);
+
+        // Names may contain the separator.
+        let file = PolicyFile::new(vec![Entry::DeleteValue {
+            key: KEY.into(),
+            name: "a;b".into(),
+        }]);
+        assert_eq!(PolicyFile::parse(&file.to_bytes()).unwrap(), file);
+    }
+
+    #[test]
+    fn reports_malformed_files() {
+        assert!(matches!(
+            PolicyFile::parse(b"REGF"),
+            Err(Error::InvalidHeader)
+        ));
+        assert!(matches!(
+            PolicyFile::parse(b"PReg\x02\0\0\0"),
+            Err(Error::UnsupportedVersion(2))
+        ));
+
+        let mut bytes = PolicyFile::new(entries()).to_bytes();
+        bytes.truncate(bytes.len() - 2);
+        assert!(matches!(
+            PolicyFile::parse(&bytes),
+            Err(Error::Syntax(_, "unexpected end of file"))
+        ));
+
+        // A key missing its terminator runs on into the value name.
+        let mut bytes = b"PReg\x01\0\0\0[\0".to_vec();
+        bytes.extend(string_bytes("A"));
+        bytes.truncate(bytes.len() - 2);
+        bytes.extend_from_slice(&[b';', 0]);
+        bytes.extend(string_bytes("B"));
+        bytes.extend_from_slice(&[b';', 0]);
+        assert!(matches!(
+            PolicyFile::parse(&bytes),
+            Err(Error::Syntax(20, "unterminated string"))
+        ));
+    }
+
+    #[test]
+    fn converts_to_operations() {
+        let mut current = Tree::new();
+        let key = current.create_key(KEY);
+        key.set_value("Legacy", Data::U32(0));
+        key.set_value("Server", string("old.example.com"));
+        key.set_value("A", Data::U32(0));
+        current.create_key(&format!(r"{}\Cache", KEY));
+
+        let root = |path: &str| format!(r"HKEY_LOCAL_MACHINE\{}", path);
+        let operations = PolicyFile::new(entries())
+            .to_operations("HKEY_LOCAL_MACHINE", &current)
+            .unwrap();
+
+        // Values deleted by `**delvals.` are gone by the time `Server` is softly set.
+        let delete = |name: &str| Operation::DeleteValue {
+            path: root(KEY),
+            name: name.into(),
+        };
+        assert_eq!(
+            operations,
+            vec![
+                Operation::CreateKey { path: root(KEY) },
+                delete("Legacy"),
+                delete("Server"),
+                delete("A"),
+                Operation::SetValue {
+                    path: root(KEY),
+                    name: "Enabled".into(),
+                    data: Data::U32(1),
+                },
+                Operation::SetValue {
+                    path: root(KEY),
+                    name: "Server".into(),
+                    data: string("example.com"),
+                },
+                delete("Legacy"),
+                delete("A"),
+                delete("B"),
+                Operation::DeleteKey {
+                    path: root(&format!(r"{}\Cache", KEY)),
+                },
+            ]
+        );
+    }
+}
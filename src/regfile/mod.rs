@@ -0,0 +1,253 @@
+//! Reading and writing `.reg` files, as used by `regedit`, as lists of
+//! [`Operation`](../operation/enum.Operation.html)s.
+//!
+//! Both the `REGEDIT4` format, which is ANSI encoded, and the
+//! `Windows Registry Editor Version 5.00` format, which is UTF-16, are supported. Files are
+//! written exactly as `regedit` exports them, and any [`KeySource`](../tree/trait.KeySource.html)
+//! can be exported with [`RegFile::export`](struct.RegFile.html#method.export).
+//!
+//! ```
+//! use registry::{operation::Operation, regfile::RegFile, Data};
+//!
+//! let file = RegFile::parse(r#"Windows Registry Editor Version 5.00
+//!
+//! [HKEY_CURRENT_USER\Software\Contoso]
+//! "Version"=dword:00000002
+//! "#)?;
+//!
+//! assert_eq!(file.operations()[1], Operation::SetValue {
+//!     path: r"HKEY_CURRENT_USER\Software\Contoso".into(),
+//!     name: "Version".into(),
+//!     data: Data::U32(2),
+//! });
+//! # Ok::<(), registry::regfile::Error>(())
+//! ```
+
+use std::fmt::Display;
+
+use crate::operation::Operation;
+use crate::tree::KeySource;
+
+mod parse;
+mod write;
+
+pub(crate) use write::write_hex;
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("Missing or unrecognised header")]
+    InvalidHeader,
+
+    #[error("Syntax error on line {0}: {1}")]
+    Syntax(usize, &'static str),
+
+    #[error("Unsupported value type {1:#x} on line {0}")]
+    UnsupportedType(usize, u32),
+
+    #[error("Invalid value data on line {0}")]
+    Data(usize, #[source] crate::value::Error),
+}
+
+/// The format version of a `.reg` file, given by its header.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Version {
+    /// `REGEDIT4`, written in the ANSI code page.
+    Regedit4,
+
+    /// `Windows Registry Editor Version 5.00`, written in UTF-16.
+    V5,
+}
+
+impl Version {
+    /// The header line that starts a file of this version.
+    pub fn header(&self) -> &'static str {
+        match self {
+            Version::Regedit4 => "REGEDIT4",
+            Version::V5 => "Windows Registry Editor Version 5.00",
+        }
+    }
+}
+
+/// The contents of a `.reg` file: a list of operations, in the order they appear.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegFile {
+    version: Version,
+    operations: Vec<Operation>,
+}
+
+impl RegFile {
+    pub fn new(version: Version, operations: Vec<Operation>) -> RegFile {
+        RegFile {
+            version,
+            operations,
+        }
+    }
+
+    /// Parses the text of a `.reg` file.
+    pub fn parse(text: &str) -> Result<RegFile, Error> {
+        parse::parse(text)
+    }
+
+    /// Parses a `.reg` file from its raw bytes, detecting its encoding.
+    ///
+    /// Files starting with a byte order mark are read as UTF-16LE or UTF-8. Other files are
+    /// read as UTF-8 if they are valid, and as the Windows-1252 code page otherwise.
+    pub fn from_bytes(bytes: &[u8]) -> Result<RegFile, Error> {
+        RegFile::parse(&decode(bytes))
+    }
+
+    /// Reads a key and everything below it into a file that recreates it, as `regedit`
+    /// exports a key.
+    ///
+    /// `path` is the full path written for the key, such as
+    /// `HKEY_CURRENT_USER\Software\Contoso`, as sources do not know where they are
+    /// mounted. Keys and values are written in the order the source stores them.
+    pub fn export<S: KeySource>(
+        source: &S,
+        path: &str,
+        version: Version,
+    ) -> Result<RegFile, S::Error> {
+        let mut operations = vec![];
+        export_key(source, path, &mut operations)?;
+        Ok(RegFile::new(version, operations))
+    }
+
+    /// The file encoded as `regedit` writes it: UTF-16LE with a byte order mark for version
+    /// 5 files, and Windows-1252 for `REGEDIT4` files.
+    ///
+    /// Characters that Windows-1252 cannot represent are written as `?`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let text = self.to_string();
+        match self.version {
+            Version::Regedit4 => encode_ansi(&text),
+            Version::V5 => {
+                let mut bytes = vec![0xff, 0xfe];
+                bytes.extend(text.encode_utf16().flat_map(|x| x.to_le_bytes().to_vec()));
+                bytes
+            }
+        }
+    }
+
+    #[inline]
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    #[inline]
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+
+    #[inline]
+    pub fn into_operations(self) -> Vec<Operation> {
+        self.operations
+    }
+}
+
+/// Writes the text of the file, with the line endings `regedit` uses.
+impl Display for RegFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&write::write(self))
+    }
+}
+
+fn export_key<S: KeySource>(
+    source: &S,
+    path: &str,
+    operations: &mut Vec<Operation>,
+) -> Result<(), S::Error> {
+    operations.push(Operation::CreateKey { path: path.into() });
+    for (name, data) in source.values()? {
+        operations.push(Operation::SetValue {
+            path: path.into(),
+            name,
+            data,
+        });
+    }
+
+    for name in source.key_names()? {
+        // A key deleted since it was listed is skipped.
+        if let Some(key) = source.open_key(&name)? {
+            export_key(&key, &format!("{}\\{}", path, name), operations)?;
+        }
+    }
+    Ok(())
+}
+
+/// Decodes a text file, detecting a UTF-16LE or UTF-8 byte order mark and otherwise trying
+/// UTF-8 before falling back to Windows-1252.
+pub(crate) fn decode(bytes: &[u8]) -> String {
+    if let Some(rest) = bytes.strip_prefix(&[0xff, 0xfe]) {
+        let units = rest
+            .chunks_exact(2)
+            .map(|x| u16::from_le_bytes([x[0], x[1]]))
+            .collect::<Vec<_>>();
+        return String::from_utf16_lossy(&units);
+    }
+
+    if let Some(rest) = bytes.strip_prefix(&[0xef, 0xbb, 0xbf]) {
+        return String::from_utf8_lossy(rest).into_owned();
+    }
+
+    match std::str::from_utf8(bytes) {
+        Ok(s) => s.to_string(),
+        Err(_) => decode_ansi(bytes),
+    }
+}
+
+/// The characters of Windows-1252 that differ from Latin-1, for bytes 0x80 to 0x9f. Unused
+/// bytes map to the corresponding C1 control character, as Windows does.
+const CP1252_HIGH: [char; 32] = [
+    '\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2c6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}', '\u{8f}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2dc}', '\u{2122}', '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}', '\u{178}',
+];
+
+/// Decodes text in the Windows-1252 code page, which `REGEDIT4` files are written in.
+pub(crate) fn decode_ansi(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| match b {
+            0x80..=0x9f => CP1252_HIGH[(b - 0x80) as usize],
+            _ => char::from(*b),
+        })
+        .collect()
+}
+
+/// Encodes text in the Windows-1252 code page, replacing characters it lacks with `?`.
+pub(crate) fn encode_ansi(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| match c as u32 {
+            x @ 0..=0x7f | x @ 0xa0..=0xff => x as u8,
+            _ => CP1252_HIGH
+                .iter()
+                .position(|x| *x == c)
+                .map(|i| 0x80 + i as u8)
+                .unwrap_or(b'?'),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_encodings() {
+        let text = "REGEDIT4\r\n[HKEY_CURRENT_USER\\Caf\u{e9} \u{20ac}]\r\n";
+        let expected = RegFile::parse(text).unwrap();
+
+        let mut utf16 = vec![0xff, 0xfe];
+        utf16.extend(text.encode_utf16().flat_map(|x| x.to_le_bytes().to_vec()));
+        assert_eq!(RegFile::from_bytes(&utf16).unwrap(), expected);
+
+        let ansi = b"REGEDIT4\r\n[HKEY_CURRENT_USER\\Caf\xe9 \x80]\r\n";
+        assert_eq!(RegFile::from_bytes(ansi).unwrap(), expected);
+
+        let mut utf8 = vec![0xef, 0xbb, 0xbf];
+        utf8.extend_from_slice(text.as_bytes());
+        assert_eq!(RegFile::from_bytes(&utf8).unwrap(), expected);
+    }
+}
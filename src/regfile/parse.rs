@@ -0,0 +1,355 @@
+use utfx::U16CString;
+
+use super::{decode_ansi, Error, RegFile, Version};
+use crate::operation::Operation;
+use crate::Data;
+
+/// The type codes of the string types, which `REGEDIT4` files store as ANSI in hex data.
+const STRING_TYPES: [u32; 3] = [1, 2, 7];
+
+pub(super) fn parse(text: &str) -> Result<RegFile, Error> {
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    let mut lines = text.lines().enumerate().map(|(i, line)| (i + 1, line));
+
+    let version = loop {
+        match lines.next() {
+            Some((_, line)) if line.trim().is_empty() => continue,
+            Some((_, line)) => match line.trim() {
+                "REGEDIT4" => break Version::Regedit4,
+                "Windows Registry Editor Version 5.00" => break Version::V5,
+                _ => return Err(Error::InvalidHeader),
+            },
+            None => return Err(Error::InvalidHeader),
+        }
+    };
+
+    let mut operations = vec![];
+    // The key that values are currently being added to, if any.
+    let mut current: Option<String> = None;
+
+    while let Some((number, line)) = lines.next() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+
+        if line.starts_with('[') {
+            let end = line
+                .rfind(']')
+                .ok_or(Error::Syntax(number, "unterminated key path"))?;
+            let path = &line[1..end];
+
+            if let Some(path) = path.strip_prefix('-') {
+                if path.is_empty() {
+                    return Err(Error::Syntax(number, "empty key path"));
+                }
+                operations.push(Operation::DeleteKey { path: path.into() });
+                current = None;
+            } else {
+                if path.is_empty() {
+                    return Err(Error::Syntax(number, "empty key path"));
+                }
+                operations.push(Operation::CreateKey { path: path.into() });
+                current = Some(path.into());
+            }
+            continue;
+        }
+
+        if !line.starts_with('"') && !line.starts_with('@') {
+            return Err(Error::Syntax(number, "expected a key or value"));
+        }
+
+        let path = current
+            .clone()
+            .ok_or(Error::Syntax(number, "value outside of a key"))?;
+        let (name, rest) = parse_name(line, number)?;
+        let rest = rest.trim_start();
+        let rest = rest
+            .strip_prefix('=')
+            .ok_or(Error::Syntax(number, "expected '=' after value name"))?
+            .trim_start();
+
+        // Strings handle their own comments, as they may contain a `;`.
+        let rest = if rest.starts_with('"') {
+            rest
+        } else {
+            strip_comment(rest)
+        };
+
+        // Hex data may continue onto following lines, each ending with a backslash. The
+        // lines are kept apart so errors can be reported on the line they are on.
+        let mut data = vec![(number, rest.to_string())];
+        if rest.starts_with("hex") {
+            loop {
+                let last = &mut data.last_mut().unwrap().1;
+                if !last.ends_with('\\') {
+                    break;
+                }
+                last.truncate(last.len() - 1);
+                match lines.next() {
+                    Some((number, next)) => {
+                        data.push((number, strip_comment(next.trim()).to_string()))
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        match parse_data(&data, version)? {
+            Some(data) => operations.push(Operation::SetValue { path, name, data }),
+            None => operations.push(Operation::DeleteValue { path, name }),
+        }
+    }
+
+    Ok(RegFile {
+        version,
+        operations,
+    })
+}
+
+/// Parses a quoted string, handling escaped backslashes and quotes. Returns the string and
+/// the text after its closing quote.
+fn parse_quoted(s: &str, number: usize) -> Result<(String, &str), Error> {
+    let mut out = String::new();
+    let mut chars = s.char_indices().skip(1);
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((out, &s[i + 1..])),
+            '\\' => match chars.next() {
+                Some((_, c @ '\\')) | Some((_, c @ '"')) => out.push(c),
+                Some((_, c)) => {
+                    out.push('\\');
+                    out.push(c);
+                }
+                None => break,
+            },
+            c => out.push(c),
+        }
+    }
+
+    Err(Error::Syntax(number, "unterminated string"))
+}
+
+/// Removes a trailing `;` comment from dword or hex data, which cannot contain one.
+fn strip_comment(s: &str) -> &str {
+    match s.find(';') {
+        Some(i) => s[..i].trim_end(),
+        None => s.trim_end(),
+    }
+}
+
+fn parse_name(line: &str, number: usize) -> Result<(String, &str), Error> {
+    match line.strip_prefix('@') {
+        Some(rest) => Ok((String::new(), rest)),
+        None => parse_quoted(line, number),
+    }
+}
+
+/// Parses the data of a value, given as the line number and text of each line it is on,
+/// returning `None` if the value is to be deleted.
+fn parse_data(lines: &[(usize, String)], version: Version) -> Result<Option<Data>, Error> {
+    let (number, s) = (lines[0].0, lines[0].1.as_str());
+    if s.trim_end() == "-" {
+        return Ok(None);
+    }
+
+    if s.starts_with('"') {
+        let (value, rest) = parse_quoted(s, number)?;
+        let rest = rest.trim_start();
+        if !rest.is_empty() && !rest.starts_with(';') {
+            return Err(Error::Syntax(number, "unexpected text after string"));
+        }
+        let value = U16CString::from_str(value).map_err(|e| Error::Data(number, e.into()))?;
+        return Ok(Some(Data::String(value)));
+    }
+
+    if let Some(digits) = s.strip_prefix("dword:") {
+        let digits = digits.trim_end();
+        if digits.is_empty() || digits.len() > 8 {
+            return Err(Error::Syntax(number, "invalid dword"));
+        }
+        let value =
+            u32::from_str_radix(digits, 16).map_err(|_| Error::Syntax(number, "invalid dword"))?;
+        return Ok(Some(Data::U32(value)));
+    }
+
+    let (ty, list) = if let Some(list) = s.strip_prefix("hex:") {
+        (3, list)
+    } else if let Some(rest) = s.strip_prefix("hex(") {
+        let close = rest
+            .find("):")
+            .ok_or(Error::Syntax(number, "invalid hex type"))?;
+        let ty = u32::from_str_radix(&rest[..close], 16)
+            .map_err(|_| Error::Syntax(number, "invalid hex type"))?;
+        (ty, &rest[close + 2..])
+    } else {
+        return Err(Error::Syntax(number, "unrecognised value data"));
+    };
+
+    let rest = lines[1..].iter().map(|(number, x)| (*number, x.as_str()));
+    let mut bytes = vec![];
+    for (number, list) in Some((number, list)).into_iter().chain(rest) {
+        for x in list.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            let byte = match x.len() {
+                1 | 2 => u8::from_str_radix(x, 16).ok(),
+                _ => None,
+            };
+            bytes.push(byte.ok_or(Error::Syntax(number, "invalid hex byte"))?);
+        }
+    }
+
+    if version == Version::Regedit4 && STRING_TYPES.contains(&ty) {
+        bytes = decode_ansi(&bytes)
+            .encode_utf16()
+            .flat_map(|x| x.to_le_bytes().to_vec())
+            .collect();
+    }
+
+    match Data::from_bytes(ty, &bytes) {
+        Ok(data) => Ok(Some(data)),
+        Err(crate::value::Error::UnhandledType(ty)) => Err(Error::UnsupportedType(number, ty)),
+        Err(e) => Err(Error::Data(number, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    const KEY: &str = r"HKEY_LOCAL_MACHINE\SOFTWARE\Contoso";
+
+    fn set(name: &str, data: Data) -> Operation {
+        Operation::SetValue {
+            path: KEY.into(),
+            name: name.into(),
+            data,
+        }
+    }
+
+    #[test]
+    fn parses_v5() {
+        let text = r#"Windows Registry Editor Version 5.00
+
+; A comment
+[HKEY_LOCAL_MACHINE\SOFTWARE\Contoso]
+@="Default"
+"Quoted \"name\""="C:\\Program Files\\Contoso"
+"Dword"=dword:0000002a
+"Binary"=hex:01,02,ff
+"Empty"=hex:
+"Expand"=hex(2):25,00,50,00,41,00,54,00,48,00,25,00,00,00
+"Multi"=hex(7):61,00,00,00,62,00,63,00,00,00,00,00
+"Qword"=hex(b):01,00,00,00,00,00,00,00
+"Long"=hex:00,01,02,03,04,05,06,07,08,09,0a,0b,0c,0d,0e,0f,10,11,12,13,14,15,\
+  16,17,18,19
+"Gone"=-
+"Commented"=dword:00000001 ; one
+"CommentedHex"=hex:01,\ ; continued
+  02 ; done
+
+[-HKEY_LOCAL_MACHINE\SOFTWARE\Old]
+"#;
+        let file = RegFile::parse(text).unwrap();
+        assert_eq!(file.version(), Version::V5);
+        assert_eq!(
+            file.operations(),
+            &[
+                Operation::CreateKey { path: KEY.into() },
+                set("", Data::String("Default".try_into().unwrap())),
+                set(
+                    "Quoted \"name\"",
+                    Data::String(r"C:\Program Files\Contoso".try_into().unwrap())
+                ),
+                set("Dword", Data::U32(42)),
+                set("Binary", Data::Binary(vec![1, 2, 255])),
+                set("Empty", Data::Binary(vec![])),
+                set("Expand", Data::ExpandString("%PATH%".try_into().unwrap())),
+                set(
+                    "Multi",
+                    Data::MultiString(vec!["a".try_into().unwrap(), "bc".try_into().unwrap()])
+                ),
+                set("Qword", Data::U64(1)),
+                set("Long", Data::Binary((0..26).collect())),
+                Operation::DeleteValue {
+                    path: KEY.into(),
+                    name: "Gone".into()
+                },
+                set("Commented", Data::U32(1)),
+                set("CommentedHex", Data::Binary(vec![1, 2])),
+                Operation::DeleteKey {
+                    path: r"HKEY_LOCAL_MACHINE\SOFTWARE\Old".into()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_regedit4_strings_as_ansi() {
+        let text = "REGEDIT4\r\n\r\n[HKEY_LOCAL_MACHINE\\SOFTWARE\\Contoso]\r\n\
+                    \"Expand\"=hex(2):25,50,41,54,48,25,80,00\r\n";
+        let file = RegFile::parse(text).unwrap();
+        assert_eq!(file.version(), Version::Regedit4);
+        assert_eq!(
+            file.operations()[1],
+            set(
+                "Expand",
+                Data::ExpandString("%PATH%\u{20ac}".try_into().unwrap())
+            )
+        );
+    }
+
+    #[test]
+    fn keeps_data_of_dataless_types() {
+        let text = "REGEDIT4\n[HKEY_LOCAL_MACHINE\\SOFTWARE\\Contoso]\n\"None\"=hex(0):01,02\n\
+                    \"Resource\"=hex(8):03\n\"Empty\"=hex(0):\n";
+        let file = RegFile::parse(text).unwrap();
+        assert_eq!(
+            file.operations()[1..],
+            [
+                set("None", Data::Raw(0, vec![1, 2])),
+                set("Resource", Data::Raw(8, vec![3])),
+                set("Empty", Data::None),
+            ]
+        );
+        let read = RegFile::parse(&file.to_string()).unwrap();
+        assert_eq!(read.operations(), file.operations());
+    }
+
+    #[test]
+    fn reports_line_numbers() {
+        let error = |text: &str| RegFile::parse(text).unwrap_err();
+
+        assert!(matches!(error("REGEDIT5\n"), Error::InvalidHeader));
+        assert!(matches!(error(""), Error::InvalidHeader));
+        assert!(matches!(
+            error("REGEDIT4\n\n\"x\"=dword:1\n"),
+            Error::Syntax(3, "value outside of a key")
+        ));
+        assert!(matches!(
+            error("REGEDIT4\n[A]\n\"x\"=dword:123456789\n"),
+            Error::Syntax(3, "invalid dword")
+        ));
+        assert!(matches!(
+            error("REGEDIT4\n[A]\n\"x\"=hex:01,\\\n  zz\n\"y\"=-\n"),
+            Error::Syntax(4, "invalid hex byte")
+        ));
+        assert!(matches!(
+            error("REGEDIT4\n[A]\n\"x\"=hex(4):01\n"),
+            Error::Data(3, _)
+        ));
+        assert!(matches!(
+            error("REGEDIT4\n[A]\n\"x\"=hex(100):01\n"),
+            Error::UnsupportedType(3, 0x100)
+        ));
+        assert!(matches!(
+            error("REGEDIT4\n[A]\n\"x=\"a\"\n"),
+            Error::Syntax(3, _)
+        ));
+        assert!(matches!(
+            error("REGEDIT4\n[A\n"),
+            Error::Syntax(2, "unterminated key path")
+        ));
+    }
+}
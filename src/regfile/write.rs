@@ -0,0 +1,266 @@
+use std::fmt::Write;
+
+use super::{encode_ansi, RegFile, Version};
+use crate::operation::Operation;
+use crate::Data;
+
+/// The column at which `regedit` wraps hex data onto a new line.
+const MAX_HEX_CHARS: usize = 77;
+
+pub(super) fn write(file: &RegFile) -> String {
+    let mut out = String::new();
+    out.push_str(file.version.header());
+    out.push_str("\r\n\r\n");
+
+    // The key that is currently open for values, if any section has been started.
+    let mut current: Option<&str> = None;
+    let mut is_open = false;
+
+    for operation in &file.operations {
+        match operation {
+            Operation::CreateKey { path } => {
+                start_section(&mut out, &mut is_open);
+                let _ = write!(out, "[{}]\r\n", path);
+                current = Some(path);
+            }
+            Operation::DeleteKey { path } => {
+                start_section(&mut out, &mut is_open);
+                let _ = write!(out, "[-{}]\r\n", path);
+                current = None;
+            }
+            Operation::SetValue { path, name, .. } | Operation::DeleteValue { path, name } => {
+                if current != Some(path.as_str()) {
+                    start_section(&mut out, &mut is_open);
+                    let _ = write!(out, "[{}]\r\n", path);
+                    current = Some(path);
+                }
+
+                let name = quote_name(name);
+                out.push_str(&name);
+                match operation {
+                    Operation::SetValue { data, .. } => {
+                        write_data(&mut out, name.encode_utf16().count(), data, file.version)
+                    }
+                    _ => out.push('-'),
+                }
+                out.push_str("\r\n");
+            }
+        }
+    }
+
+    if is_open {
+        out.push_str("\r\n");
+    }
+    out
+}
+
+/// Ends the previous section, if there is one, with a blank line.
+fn start_section(out: &mut String, is_open: &mut bool) {
+    if *is_open {
+        out.push_str("\r\n");
+    }
+    *is_open = true;
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// The value name as it starts a line, including the `=`.
+fn quote_name(name: &str) -> String {
+    match name {
+        "" => "@=".into(),
+        name => format!("\"{}\"=", escape(name)),
+    }
+}
+
+fn write_data(out: &mut String, column: usize, data: &Data, version: Version) {
+    match data {
+        // Strings spanning lines would not read back, so are written as hex instead.
+        Data::String(s) if !s.as_slice().iter().any(|c| *c == 0x0a || *c == 0x0d) => {
+            let _ = write!(out, "\"{}\"", escape(&s.to_string_lossy()));
+        }
+        Data::U32(x) => {
+            let _ = write!(out, "dword:{:08x}", x);
+        }
+        data => {
+            let ty = data.ty();
+            let bytes = match (version, data) {
+                (Version::Regedit4, Data::String(s))
+                | (Version::Regedit4, Data::ExpandString(s)) => {
+                    let mut bytes = encode_ansi(&s.to_string_lossy());
+                    bytes.push(0);
+                    bytes
+                }
+                (Version::Regedit4, Data::MultiString(x)) => {
+                    let mut bytes = vec![];
+                    for s in x {
+                        bytes.extend(encode_ansi(&s.to_string_lossy()));
+                        bytes.push(0);
+                    }
+                    bytes.push(0);
+                    bytes
+                }
+                _ => data.to_bytes(),
+            };
+
+            let prefix = match ty {
+                3 => "hex:".to_string(),
+                ty => format!("hex({:x}):", ty),
+            };
+            out.push_str(&prefix);
+            write_hex(out, column + prefix.len(), &bytes, "\r\n");
+        }
+    }
+}
+
+/// Writes bytes as comma separated hex, wrapping lines the same way `regedit` does, given
+/// the column the data starts at.
+pub(crate) fn write_hex(out: &mut String, mut column: usize, bytes: &[u8], newline: &str) {
+    for (i, byte) in bytes.iter().enumerate() {
+        let _ = write!(out, "{:02x}", byte);
+        if i + 1 == bytes.len() {
+            break;
+        }
+
+        out.push(',');
+        column += 3;
+        if column >= MAX_HEX_CHARS {
+            out.push('\\');
+            out.push_str(newline);
+            out.push_str("  ");
+            column = 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+    use crate::offline::fixture::{self, FixtureKey, FixtureValue};
+    use crate::offline::Hive;
+
+    const KEY: &str = r"HKEY_CURRENT_USER\Software\Contoso";
+
+    fn set(name: &str, data: Data) -> Operation {
+        Operation::SetValue {
+            path: KEY.into(),
+            name: name.into(),
+            data,
+        }
+    }
+
+    #[test]
+    fn writes_like_regedit() {
+        let file = RegFile::new(
+            Version::V5,
+            vec![
+                Operation::CreateKey { path: KEY.into() },
+                set("", Data::String(r#"C:\a "b""#.try_into().unwrap())),
+                set("Dword", Data::U32(42)),
+                set("Long", Data::Binary((0..50).collect())),
+                set("Multi", Data::MultiString(vec!["a".try_into().unwrap()])),
+                set("Lines", Data::String("a\r\nb".try_into().unwrap())),
+                Operation::DeleteValue {
+                    path: KEY.into(),
+                    name: "Gone".into(),
+                },
+                Operation::DeleteKey {
+                    path: r"HKEY_CURRENT_USER\Software\Old".into(),
+                },
+                Operation::SetValue {
+                    path: r"HKEY_CURRENT_USER\Software\Other".into(),
+                    name: "None".into(),
+                    data: Data::None,
+                },
+            ],
+        );
+
+        let expected = "Windows Registry Editor Version 5.00\r\n\
+\r\n\
+[HKEY_CURRENT_USER\\Software\\Contoso]\r\n\
+@=\"C:\\\\a \\\"b\\\"\"\r\n\
+\"Dword\"=dword:0000002a\r\n\
+\"Long\"=hex:00,01,02,03,04,05,06,07,08,09,0a,0b,0c,0d,0e,0f,10,11,12,13,14,15,\\\r\n  \
+16,17,18,19,1a,1b,1c,1d,1e,1f,20,21,22,23,24,25,26,27,28,29,2a,2b,2c,2d,2e,\\\r\n  \
+2f,30,31\r\n\
+\"Multi\"=hex(7):61,00,00,00,00,00\r\n\
+\"Lines\"=hex(1):61,00,0d,00,0a,00,62,00,00,00\r\n\
+\"Gone\"=-\r\n\
+\r\n\
+[-HKEY_CURRENT_USER\\Software\\Old]\r\n\
+\r\n\
+[HKEY_CURRENT_USER\\Software\\Other]\r\n\
+\"None\"=hex(0):\r\n\
+\r\n";
+        assert_eq!(file.to_string(), expected);
+
+        let mut bytes = vec![0xff, 0xfe];
+        bytes.extend(
+            expected
+                .encode_utf16()
+                .flat_map(|x| x.to_le_bytes().to_vec()),
+        );
+        assert_eq!(file.to_bytes(), bytes);
+
+        // Everything reads back, with values outside a created key gaining a key header.
+        let read = RegFile::from_bytes(&file.to_bytes()).unwrap();
+        assert_eq!(read.operations()[..8], file.operations()[..8]);
+        assert_eq!(read.operations()[9], file.operations()[8]);
+    }
+
+    #[test]
+    fn writes_regedit4_as_ansi() {
+        let file = RegFile::new(
+            Version::Regedit4,
+            vec![
+                Operation::CreateKey { path: KEY.into() },
+                set(
+                    "Caf\u{e9}",
+                    Data::String("\u{20ac}5 \u{3b1}".try_into().unwrap()),
+                ),
+                set(
+                    "Expand",
+                    Data::ExpandString("%A%\u{20ac}".try_into().unwrap()),
+                ),
+            ],
+        );
+
+        let bytes = file.to_bytes();
+        assert_eq!(
+            bytes,
+            &b"REGEDIT4\r\n\r\n[HKEY_CURRENT_USER\\Software\\Contoso]\r\n\
+\"Caf\xe9\"=\"\x805 ?\"\r\n\"Expand\"=hex(2):25,41,25,80,00\r\n\r\n"[..]
+        );
+        assert_eq!(
+            RegFile::from_bytes(&bytes).unwrap().operations()[2],
+            set(
+                "Expand",
+                Data::ExpandString("%A%\u{20ac}".try_into().unwrap())
+            )
+        );
+    }
+
+    #[test]
+    fn exports_offline_hive() {
+        let root = FixtureKey::new("ROOT")
+            .value(FixtureValue::new("Version", Data::U32(2)))
+            .key(
+                FixtureKey::new("Sub")
+                    .value(FixtureValue::new("", Data::String("x".try_into().unwrap()))),
+            )
+            .key(FixtureKey::new("Empty"));
+        let hive = Hive::from_bytes(fixture::build(&root, 8)).unwrap();
+
+        let file = RegFile::export(&hive.root().unwrap(), KEY, Version::V5).unwrap();
+        assert_eq!(
+            file.to_string(),
+            "Windows Registry Editor Version 5.00\r\n\r\n\
+[HKEY_CURRENT_USER\\Software\\Contoso]\r\n\"Version\"=dword:00000002\r\n\r\n\
+[HKEY_CURRENT_USER\\Software\\Contoso\\Empty]\r\n\r\n\
+[HKEY_CURRENT_USER\\Software\\Contoso\\Sub]\r\n@=\"x\"\r\n\r\n"
+        );
+    }
+}
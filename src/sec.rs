@@ -1,5 +1,6 @@
 #![allow(non_upper_case_globals)]
 
+#[cfg(windows)]
 use windows::Win32::System::Registry::REG_SAM_FLAGS;
 
 bitflags::bitflags! {
@@ -26,6 +27,7 @@ impl Default for Security {
     }
 }
 
+#[cfg(windows)]
 impl From<Security> for REG_SAM_FLAGS {
     fn from(sec: Security) -> Self {
         REG_SAM_FLAGS(sec.bits())
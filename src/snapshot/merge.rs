@@ -0,0 +1,343 @@
+use super::Snapshot;
+use crate::operation::join;
+use crate::tree::{fold, Tree};
+use crate::Data;
+
+/// A value changed differently on both sides of a [`merge`](fn.merge.html). Each side is
+/// `None` where the value, or its key, does not exist.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    path: String,
+    relative: String,
+    name: String,
+    base: Option<Data>,
+    ours: Option<Data>,
+    theirs: Option<Data>,
+}
+
+impl Conflict {
+    /// The full path of the value's key, under our snapshot's path.
+    #[inline]
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    pub fn base(&self) -> Option<&Data> {
+        self.base.as_ref()
+    }
+
+    #[inline]
+    pub fn ours(&self) -> Option<&Data> {
+        self.ours.as_ref()
+    }
+
+    #[inline]
+    pub fn theirs(&self) -> Option<&Data> {
+        self.theirs.as_ref()
+    }
+}
+
+/// How a conflict is resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    Ours,
+    Theirs,
+    /// Replaces the value with the given data, or deletes it for `None`.
+    Data(Option<Data>),
+}
+
+/// The result of a three-way merge: the merged tree, with conflicting values still to be
+/// resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Merge {
+    path: String,
+    tree: Tree,
+    conflicts: Vec<Conflict>,
+}
+
+impl Merge {
+    #[inline]
+    pub fn conflicts(&self) -> &[Conflict] {
+        &self.conflicts
+    }
+
+    #[inline]
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+
+    /// Resolves each conflict with `resolve`, in the order they were found.
+    pub fn resolve<F>(self, mut resolve: F) -> Snapshot
+    where
+        F: FnMut(&Conflict) -> Resolution,
+    {
+        let mut tree = self.tree;
+        for conflict in &self.conflicts {
+            let data = match resolve(conflict) {
+                Resolution::Ours => conflict.ours.clone(),
+                Resolution::Theirs => conflict.theirs.clone(),
+                Resolution::Data(data) => data,
+            };
+
+            // Our data is already in place, keeping its position among the key's values.
+            match data {
+                Some(data) => {
+                    tree.create_key(&conflict.relative)
+                        .set_value(&conflict.name, data);
+                }
+                None => {
+                    if let Some(key) = tree.key_mut(&conflict.relative) {
+                        key.remove_value(&conflict.name);
+                    }
+                }
+            }
+        }
+        Snapshot::new(&self.path, tree)
+    }
+
+    /// Resolves every conflict with our side.
+    pub fn prefer_ours(self) -> Snapshot {
+        self.resolve(|_| Resolution::Ours)
+    }
+
+    /// Resolves every conflict with their side.
+    pub fn prefer_theirs(self) -> Snapshot {
+        self.resolve(|_| Resolution::Theirs)
+    }
+}
+
+/// Merges the changes made from `base` to `ours` with those made from `base` to `theirs`,
+/// such as a user's customised settings with a vendor's new defaults.
+///
+/// Keys and values changed on only one side, or changed the same way on both, take that
+/// change. Values changed differently on both sides are conflicts. A key deleted on one side
+/// is kept if the other side changed values below it, holding only what survives the merge.
+/// The merged snapshot has our path.
+pub fn merge(base: &Snapshot, ours: &Snapshot, theirs: &Snapshot) -> Merge {
+    let mut conflicts = vec![];
+    let tree = merge_key(
+        &ours.path,
+        "",
+        Some(&base.tree),
+        Some(&ours.tree),
+        Some(&theirs.tree),
+        &mut conflicts,
+    )
+    .unwrap_or_default();
+
+    Merge {
+        path: ours.path.clone(),
+        tree,
+        conflicts,
+    }
+}
+
+/// Takes the side that changed from the base, if only one did.
+fn pick<T: PartialEq + Copy>(base: T, ours: T, theirs: T) -> Result<T, ()> {
+    if ours == theirs || theirs == base {
+        Ok(ours)
+    } else if ours == base {
+        Ok(theirs)
+    } else {
+        Err(())
+    }
+}
+
+/// The names on any side, matched ignoring case, in our order then theirs then the base's.
+fn names(sides: [Vec<&str>; 3]) -> Vec<&str> {
+    let mut seen = vec![];
+    let mut names = vec![];
+    for name in sides.iter().flatten() {
+        let folded = fold(name);
+        if !seen.contains(&folded) {
+            seen.push(folded);
+            names.push(*name);
+        }
+    }
+    names
+}
+
+fn value_names(tree: Option<&Tree>) -> Vec<&str> {
+    tree.map(|x| x.values().iter().map(|(name, _)| name.as_str()).collect())
+        .unwrap_or_default()
+}
+
+fn key_names(tree: Option<&Tree>) -> Vec<&str> {
+    tree.map(|x| x.keys().map(|(name, _)| name).collect())
+        .unwrap_or_default()
+}
+
+fn merge_key(
+    root: &str,
+    relative: &str,
+    base: Option<&Tree>,
+    ours: Option<&Tree>,
+    theirs: Option<&Tree>,
+    conflicts: &mut Vec<Conflict>,
+) -> Option<Tree> {
+    let exists = pick(base.is_some(), ours.is_some(), theirs.is_some()).unwrap_or(true);
+    let mut tree = Tree::new();
+    tree.set_last_write_time(ours.or(theirs).and_then(Tree::last_write_time));
+
+    for name in names([value_names(ours), value_names(theirs), value_names(base)]) {
+        let (b, o, t) = (
+            base.and_then(|x| x.value(name)),
+            ours.and_then(|x| x.value(name)),
+            theirs.and_then(|x| x.value(name)),
+        );
+
+        let data = match pick(b, o, t) {
+            Ok(data) => data,
+            Err(()) => {
+                conflicts.push(Conflict {
+                    path: join(root, relative),
+                    relative: relative.to_string(),
+                    name: name.to_string(),
+                    base: b.cloned(),
+                    ours: o.cloned(),
+                    theirs: t.cloned(),
+                });
+                o
+            }
+        };
+        if let Some(data) = data {
+            tree.set_value(name, data.clone());
+        }
+    }
+
+    for name in names([key_names(ours), key_names(theirs), key_names(base)]) {
+        if let Some(subtree) = merge_key(
+            root,
+            &join(relative, name),
+            base.and_then(|x| x.key(name)),
+            ours.and_then(|x| x.key(name)),
+            theirs.and_then(|x| x.key(name)),
+            conflicts,
+        ) {
+            *tree.create_key(name) = subtree;
+        }
+    }
+
+    if exists || !tree.values().is_empty() || tree.keys().next().is_some() {
+        Some(tree)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT: &str = r"HKEY_CURRENT_USER\Software\Contoso";
+
+    fn snapshot(build: impl FnOnce(&mut Tree)) -> Snapshot {
+        let mut tree = Tree::new();
+        build(&mut tree);
+        Snapshot::new(ROOT, tree)
+    }
+
+    fn base() -> Snapshot {
+        snapshot(|tree| {
+            tree.set_value("Theme", Data::U32(0));
+            tree.set_value("Timeout", Data::U32(30));
+            tree.set_value("Proxy", Data::U32(0));
+            tree.create_key("Plugins\\Old")
+                .set_value("On", Data::U32(1));
+        })
+    }
+
+    #[test]
+    fn merges_and_reports_conflicts() {
+        // The user changed the theme and timeout and removed the old plugin.
+        let ours = snapshot(|tree| {
+            tree.set_value("Theme", Data::U32(2));
+            tree.set_value("Timeout", Data::U32(60));
+            tree.set_value("Proxy", Data::U32(0));
+            tree.create_key("Plugins");
+        });
+        // The vendor changed the timeout, dropped the proxy, changed the old plugin and
+        // added a new one.
+        let theirs = snapshot(|tree| {
+            tree.set_value("Theme", Data::U32(0));
+            tree.set_value("Timeout", Data::U32(45));
+            tree.create_key("Plugins\\Old")
+                .set_value("On", Data::U32(0));
+            tree.create_key("Plugins\\New")
+                .set_value("On", Data::U32(1));
+        });
+
+        let merge = merge(&base(), &ours, &theirs);
+        assert_eq!(
+            merge.conflicts(),
+            &[
+                Conflict {
+                    path: ROOT.into(),
+                    relative: "".into(),
+                    name: "Timeout".into(),
+                    base: Some(Data::U32(30)),
+                    ours: Some(Data::U32(60)),
+                    theirs: Some(Data::U32(45)),
+                },
+                Conflict {
+                    path: format!(r"{}\Plugins\Old", ROOT),
+                    relative: r"Plugins\Old".into(),
+                    name: "On".into(),
+                    base: Some(Data::U32(1)),
+                    ours: None,
+                    theirs: Some(Data::U32(0)),
+                },
+            ]
+        );
+
+        let expected = |timeout: u32, old: Option<u32>| {
+            snapshot(|tree| {
+                tree.set_value("Theme", Data::U32(2));
+                tree.set_value("Timeout", Data::U32(timeout));
+                tree.create_key("Plugins\\New")
+                    .set_value("On", Data::U32(1));
+                if let Some(on) = old {
+                    tree.create_key("Plugins\\Old")
+                        .set_value("On", Data::U32(on));
+                }
+            })
+        };
+        assert_eq!(merge.clone().prefer_ours(), expected(60, None));
+        assert_eq!(merge.clone().prefer_theirs(), expected(45, Some(0)));
+        assert_eq!(
+            merge.resolve(|conflict| match conflict.name() {
+                "Timeout" => Resolution::Data(Some(Data::U32(90))),
+                _ => Resolution::Ours,
+            }),
+            expected(90, None)
+        );
+    }
+
+    #[test]
+    fn takes_one_sided_changes_cleanly() {
+        let ours = base();
+        let theirs = snapshot(|tree| {
+            tree.set_value("theme", Data::U32(0));
+            tree.set_value("Timeout", Data::U32(30));
+            tree.set_value("Proxy", Data::U32(1));
+        });
+
+        // Their change of case alone is not a change.
+        let merge = merge(&base(), &ours, &theirs);
+        assert!(merge.is_clean());
+        assert_eq!(
+            merge.prefer_ours(),
+            snapshot(|tree| {
+                tree.set_value("Theme", Data::U32(0));
+                tree.set_value("Timeout", Data::U32(30));
+                tree.set_value("Proxy", Data::U32(1));
+            })
+        );
+    }
+}
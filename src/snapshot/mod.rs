@@ -0,0 +1,395 @@
+//! Capturing a subtree at a point in time, finding what changed between two captures, such
+//! as before and after running an installer, and merging the changes of two captures made
+//! from a common one.
+//!
+//! ```
+//! use registry::{snapshot::{Change, Snapshot}, tree::Tree, Data};
+//!
+//! let mut tree = Tree::new();
+//! tree.create_key("Contoso").set_value("Version", Data::U32(1));
+//! let before = Snapshot::capture(&&tree, r"HKEY_LOCAL_MACHINE\SOFTWARE").unwrap();
+//!
+//! tree.create_key("Contoso").set_value("Version", Data::U32(2));
+//! let after = Snapshot::capture(&&tree, r"HKEY_LOCAL_MACHINE\SOFTWARE").unwrap();
+//!
+//! let diff = before.diff(&after);
+//! assert_eq!(
+//!     diff.changes(),
+//!     &[Change::ModifyValue {
+//!         path: r"HKEY_LOCAL_MACHINE\SOFTWARE\Contoso".into(),
+//!         name: "Version".into(),
+//!         old: Data::U32(1),
+//!         new: Data::U32(2),
+//!     }]
+//! );
+//! ```
+
+use crate::operation::{join, Operation};
+use crate::regfile::{RegFile, Version};
+use crate::tree::{KeySource, Tree};
+use crate::{Data, FileTime};
+
+mod merge;
+
+pub use merge::{merge, Conflict, Merge, Resolution};
+
+/// A subtree as it was captured, along with the full path it was captured from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    path: String,
+    tree: Tree,
+}
+
+impl Snapshot {
+    /// A snapshot of `tree` as found at `path`, such as
+    /// `HKEY_LOCAL_MACHINE\SOFTWARE\Contoso`.
+    pub fn new(path: &str, tree: Tree) -> Snapshot {
+        Snapshot {
+            path: path.to_string(),
+            tree,
+        }
+    }
+
+    /// Reads a key and everything below it from a source, recording `path` as where it was
+    /// found, as sources do not know where they are mounted.
+    pub fn capture<S: KeySource>(source: &S, path: &str) -> Result<Snapshot, S::Error> {
+        Ok(Snapshot::new(path, Tree::capture(source)?))
+    }
+
+    /// The snapshot without the last write times of its keys, so that a diff only reports
+    /// changes to content.
+    pub fn without_times(mut self) -> Snapshot {
+        fn clear(tree: &mut Tree) {
+            tree.set_last_write_time(None);
+            let names = tree
+                .keys()
+                .map(|(name, _)| name.to_string())
+                .collect::<Vec<_>>();
+            for name in names {
+                if let Some(key) = tree.key_mut(&name) {
+                    clear(key);
+                }
+            }
+        }
+
+        clear(&mut self.tree);
+        self
+    }
+
+    #[inline]
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    #[inline]
+    pub fn tree(&self) -> &Tree {
+        &self.tree
+    }
+
+    #[inline]
+    pub fn into_tree(self) -> Tree {
+        self.tree
+    }
+
+    /// The changes from this snapshot to a later one, with paths under the later snapshot's
+    /// path. Keys and values are matched ignoring case.
+    pub fn diff(&self, later: &Snapshot) -> Diff {
+        let mut changes = vec![];
+        diff_key(&later.path, &self.tree, &later.tree, &mut changes);
+        Diff { changes }
+    }
+}
+
+/// A difference between two snapshots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// A key that was added. Its values and subkeys follow as changes of their own.
+    AddKey { path: String },
+
+    /// A key that was removed, along with everything that was below it.
+    RemoveKey { path: String, old: Tree },
+
+    /// A key present in both snapshots whose last write time differs. Only reported when
+    /// both snapshots record a time.
+    ModifyKey {
+        path: String,
+        old: FileTime,
+        new: FileTime,
+    },
+
+    AddValue {
+        path: String,
+        name: String,
+        data: Data,
+    },
+
+    RemoveValue {
+        path: String,
+        name: String,
+        data: Data,
+    },
+
+    ModifyValue {
+        path: String,
+        name: String,
+        old: Data,
+        new: Data,
+    },
+}
+
+impl Change {
+    /// The path of the key this change applies to.
+    pub fn path(&self) -> &str {
+        match self {
+            Change::AddKey { path }
+            | Change::RemoveKey { path, .. }
+            | Change::ModifyKey { path, .. }
+            | Change::AddValue { path, .. }
+            | Change::RemoveValue { path, .. }
+            | Change::ModifyValue { path, .. } => path,
+        }
+    }
+}
+
+/// The changes between two snapshots, in the order of the later snapshot's keys, with each
+/// key's value changes before those of its subkeys.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Diff {
+    changes: Vec<Change>,
+}
+
+impl Diff {
+    #[inline]
+    pub fn changes(&self) -> &[Change] {
+        &self.changes
+    }
+
+    #[inline]
+    pub fn into_changes(self) -> Vec<Change> {
+        self.changes
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// The operations that turn the earlier snapshot into the later one. Changed key times
+    /// have no operation.
+    pub fn to_operations(&self) -> Vec<Operation> {
+        self.changes
+            .iter()
+            .filter_map(|change| match change {
+                Change::AddKey { path } => Some(Operation::CreateKey { path: path.clone() }),
+                Change::RemoveKey { path, .. } => Some(Operation::DeleteKey { path: path.clone() }),
+                Change::ModifyKey { .. } => None,
+                Change::AddValue { path, name, data }
+                | Change::ModifyValue {
+                    path,
+                    name,
+                    new: data,
+                    ..
+                } => Some(Operation::SetValue {
+                    path: path.clone(),
+                    name: name.clone(),
+                    data: data.clone(),
+                }),
+                Change::RemoveValue { path, name, .. } => Some(Operation::DeleteValue {
+                    path: path.clone(),
+                    name: name.clone(),
+                }),
+            })
+            .collect()
+    }
+
+    /// The operations as a `.reg` file, which applies the changes when imported.
+    pub fn to_reg_file(&self, version: Version) -> RegFile {
+        RegFile::new(version, self.to_operations())
+    }
+}
+
+fn diff_key(path: &str, old: &Tree, new: &Tree, changes: &mut Vec<Change>) {
+    if let (Some(old), Some(new)) = (old.last_write_time(), new.last_write_time()) {
+        if old != new {
+            changes.push(Change::ModifyKey {
+                path: path.to_string(),
+                old,
+                new,
+            });
+        }
+    }
+
+    for (name, data) in old.values() {
+        if new.value(name).is_none() {
+            changes.push(Change::RemoveValue {
+                path: path.to_string(),
+                name: name.clone(),
+                data: data.clone(),
+            });
+        }
+    }
+    for (name, data) in new.values() {
+        match old.value(name) {
+            None => changes.push(Change::AddValue {
+                path: path.to_string(),
+                name: name.clone(),
+                data: data.clone(),
+            }),
+            Some(existing) if existing != data => changes.push(Change::ModifyValue {
+                path: path.to_string(),
+                name: name.clone(),
+                old: existing.clone(),
+                new: data.clone(),
+            }),
+            Some(_) => {}
+        }
+    }
+
+    for (name, key) in old.keys() {
+        if new.key(name).is_none() {
+            changes.push(Change::RemoveKey {
+                path: join(path, name),
+                old: key.clone(),
+            });
+        }
+    }
+    for (name, key) in new.keys() {
+        let path = join(path, name);
+        match old.key(name) {
+            Some(existing) => diff_key(&path, existing, key, changes),
+            None => {
+                changes.push(Change::AddKey { path: path.clone() });
+                diff_key(&path, &Tree::new(), key, changes);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+
+    const ROOT: &str = r"HKEY_LOCAL_MACHINE\SOFTWARE";
+
+    fn string(s: &str) -> Data {
+        Data::String(s.try_into().unwrap())
+    }
+
+    fn before() -> Tree {
+        let mut tree = Tree::new();
+        let key = tree.create_key("Contoso");
+        key.set_value("Version", Data::U32(1));
+        key.set_value("Legacy", string("yes"));
+        key.set_value("Same", string("same"));
+        tree.create_key(r"Contoso\Cache\Old")
+            .set_value("x", Data::U32(0));
+        tree
+    }
+
+    fn after() -> Tree {
+        let mut tree = Tree::new();
+        let key = tree.create_key("contoso");
+        key.set_value("SAME", string("same"));
+        key.set_value("Version", Data::U32(2));
+        key.set_value("Installed", Data::U32(1));
+        tree.create_key(r"Contoso\Plugins\Zip")
+            .set_value("", string("zip.dll"));
+        tree
+    }
+
+    #[test]
+    fn finds_changes() {
+        let diff = Snapshot::new(ROOT, before()).diff(&Snapshot::new(ROOT, after()));
+        let path = |x: &str| join(ROOT, x);
+
+        assert_eq!(
+            diff.changes(),
+            &[
+                Change::RemoveValue {
+                    path: path("contoso"),
+                    name: "Legacy".into(),
+                    data: string("yes"),
+                },
+                Change::ModifyValue {
+                    path: path("contoso"),
+                    name: "Version".into(),
+                    old: Data::U32(1),
+                    new: Data::U32(2),
+                },
+                Change::AddValue {
+                    path: path("contoso"),
+                    name: "Installed".into(),
+                    data: Data::U32(1),
+                },
+                Change::RemoveKey {
+                    path: path(r"contoso\Cache"),
+                    old: before().key(r"Contoso\Cache").unwrap().clone(),
+                },
+                Change::AddKey {
+                    path: path(r"contoso\Plugins"),
+                },
+                Change::AddKey {
+                    path: path(r"contoso\Plugins\Zip"),
+                },
+                Change::AddValue {
+                    path: path(r"contoso\Plugins\Zip"),
+                    name: "".into(),
+                    data: string("zip.dll"),
+                },
+            ]
+        );
+
+        // Applying the changes to the earlier tree gives the later one.
+        let mut tree = before();
+        crate::operation::apply(
+            &mut tree,
+            &diff.to_operations(),
+            &crate::operation::Options::new(ROOT),
+        )
+        .unwrap();
+        assert!(Snapshot::new(ROOT, tree)
+            .diff(&Snapshot::new(ROOT, after()))
+            .is_empty());
+
+        assert_eq!(
+            diff.to_reg_file(Version::V5).to_string(),
+            "Windows Registry Editor Version 5.00\r\n\
+\r\n\
+[HKEY_LOCAL_MACHINE\\SOFTWARE\\contoso]\r\n\
+\"Legacy\"=-\r\n\
+\"Version\"=dword:00000002\r\n\
+\"Installed\"=dword:00000001\r\n\
+\r\n\
+[-HKEY_LOCAL_MACHINE\\SOFTWARE\\contoso\\Cache]\r\n\
+\r\n\
+[HKEY_LOCAL_MACHINE\\SOFTWARE\\contoso\\Plugins]\r\n\
+\r\n\
+[HKEY_LOCAL_MACHINE\\SOFTWARE\\contoso\\Plugins\\Zip]\r\n\
+@=\"zip.dll\"\r\n\
+\r\n"
+        );
+    }
+
+    #[test]
+    fn reports_times_only_when_both_are_known() {
+        let time = |ticks| Some(FileTime::new(ticks));
+        let mut old = Tree::new();
+        old.set_last_write_time(time(1));
+        let mut new = Tree::new();
+        new.set_last_write_time(time(2));
+
+        let old = Snapshot::new(ROOT, old);
+        let new = Snapshot::new(ROOT, new);
+        assert_eq!(
+            old.diff(&new).changes(),
+            &[Change::ModifyKey {
+                path: ROOT.into(),
+                old: FileTime::new(1),
+                new: FileTime::new(2),
+            }]
+        );
+        assert!(old.diff(&new.without_times()).is_empty());
+    }
+}
@@ -0,0 +1,524 @@
+//! Reading and changing registry trees independently of where they are stored.
+//!
+//! [`KeySource`](trait.KeySource.html) is implemented for the live registry's
+//! [`RegKey`](../struct.RegKey.html) on Windows, for offline hive keys and for the in-memory
+//! [`Tree`](struct.Tree.html), so code that reads a whole subtree, such as exporting it to a
+//! `.reg` file, works with any of them. [`KeyTarget`](trait.KeyTarget.html) is the
+//! equivalent for making changes, implemented for `Hive` and `RegKey` on Windows, the offline
+//! [`Editor`](../offline/struct.Editor.html) and `Tree`.
+
+use std::convert::Infallible;
+
+use crate::{offline, Data, FileTime};
+
+/// A key that can be read along with everything below it.
+pub trait KeySource: Sized {
+    type Error;
+
+    /// The names of the immediate subkeys, in the order the source stores them.
+    fn key_names(&self) -> Result<Vec<String>, Self::Error>;
+
+    /// Opens an immediate subkey, returning `None` if it does not exist.
+    fn open_key(&self, name: &str) -> Result<Option<Self>, Self::Error>;
+
+    /// The values of this key, in the order the source stores them. The default value has
+    /// an empty name.
+    fn values(&self) -> Result<Vec<(String, Data)>, Self::Error>;
+
+    /// The time the key was last written to, if the source records one.
+    fn last_write_time(&self) -> Result<Option<FileTime>, Self::Error> {
+        Ok(None)
+    }
+}
+
+/// A key whose descendants can be read and changed by path, such as when applying
+/// [`Operation`](../operation/enum.Operation.html)s.
+///
+/// Paths are backslash separated, relative to the target and compare ignoring case. The
+/// empty path is the target itself.
+pub trait KeyTarget {
+    type Error;
+
+    fn key_exists(&self, path: &str) -> Result<bool, Self::Error>;
+
+    /// Reads a value, returning `None` if it or its key does not exist.
+    fn read_value(&self, path: &str, name: &str) -> Result<Option<Data>, Self::Error>;
+
+    /// Reads a key and everything below it, returning `None` if it does not exist.
+    fn read_key(&self, path: &str) -> Result<Option<Tree>, Self::Error>;
+
+    /// Creates a key, along with any missing keys on its path.
+    fn create_key(&mut self, path: &str) -> Result<(), Self::Error>;
+
+    /// Deletes a key and everything below it, doing nothing if it does not exist. Deleting
+    /// the empty path removes everything in the target but leaves the target itself.
+    fn delete_key(&mut self, path: &str) -> Result<(), Self::Error>;
+
+    /// Sets a value on an existing key, replacing any value with the same name.
+    fn set_value(&mut self, path: &str, name: &str, data: &Data) -> Result<(), Self::Error>;
+
+    /// Deletes a value, doing nothing if it or its key does not exist.
+    fn delete_value(&mut self, path: &str, name: &str) -> Result<(), Self::Error>;
+}
+
+/// The components of a backslash separated path, skipping empty ones.
+pub(crate) fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('\\').filter(|x| !x.is_empty())
+}
+
+/// A name converted to upper case the way the registry compares names.
+pub(crate) fn fold(name: &str) -> Vec<u16> {
+    name.encode_utf16().map(offline::upcase).collect()
+}
+
+/// A key and everything below it, held in memory.
+///
+/// Subkeys are kept sorted and looked up ignoring case, as the registry does, while values
+/// keep the order they were added in.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tree {
+    keys: Vec<(String, Tree)>,
+    values: Vec<(String, Data)>,
+    last_write_time: Option<FileTime>,
+}
+
+impl Tree {
+    #[inline]
+    pub fn new() -> Tree {
+        Tree::default()
+    }
+
+    /// Reads a key and everything below it from a source.
+    pub fn capture<S: KeySource>(source: &S) -> Result<Tree, S::Error> {
+        let mut tree = Tree {
+            keys: vec![],
+            values: source.values()?,
+            last_write_time: source.last_write_time()?,
+        };
+
+        for name in source.key_names()? {
+            // A key deleted since it was listed is skipped.
+            if let Some(key) = source.open_key(&name)? {
+                let subtree = Tree::capture(&key)?;
+                *tree.create_key(&name) = subtree;
+            }
+        }
+        Ok(tree)
+    }
+
+    fn find(&self, name: &str) -> Result<usize, usize> {
+        let name = fold(name);
+        self.keys
+            .binary_search_by(|(x, _)| fold(x).as_slice().cmp(&name))
+    }
+
+    /// The immediate subkeys with their names, sorted the way the registry sorts them.
+    pub fn keys(&self) -> impl Iterator<Item = (&str, &Tree)> {
+        self.keys.iter().map(|(name, tree)| (name.as_str(), tree))
+    }
+
+    /// Finds a descendant by its backslash separated path, ignoring case.
+    pub fn key(&self, path: &str) -> Option<&Tree> {
+        let mut tree = self;
+        for name in components(path) {
+            tree = &tree.keys[tree.find(name).ok()?].1;
+        }
+        Some(tree)
+    }
+
+    pub fn key_mut(&mut self, path: &str) -> Option<&mut Tree> {
+        let mut tree = self;
+        for name in components(path) {
+            let index = tree.find(name).ok()?;
+            tree = &mut tree.keys[index].1;
+        }
+        Some(tree)
+    }
+
+    /// Finds a descendant, creating it and any missing keys along its path.
+    pub fn create_key(&mut self, path: &str) -> &mut Tree {
+        let mut tree = self;
+        for name in components(path) {
+            let index = match tree.find(name) {
+                Ok(index) => index,
+                Err(index) => {
+                    tree.keys.insert(index, (name.to_string(), Tree::new()));
+                    index
+                }
+            };
+            tree = &mut tree.keys[index].1;
+        }
+        tree
+    }
+
+    /// Removes a descendant, returning it. The empty path cannot be removed.
+    pub fn remove_key(&mut self, path: &str) -> Option<Tree> {
+        let names = components(path).collect::<Vec<_>>();
+        let (last, parents) = names.split_last()?;
+        let parent = self.key_mut(&parents.join("\\"))?;
+        let index = parent.find(last).ok()?;
+        Some(parent.keys.remove(index).1)
+    }
+
+    /// The values of this key, in the order they were added.
+    #[inline]
+    pub fn values(&self) -> &[(String, Data)] {
+        &self.values
+    }
+
+    /// Finds a value by name, ignoring case.
+    pub fn value(&self, name: &str) -> Option<&Data> {
+        let name = fold(name);
+        self.values
+            .iter()
+            .find(|(x, _)| fold(x) == name)
+            .map(|(_, data)| data)
+    }
+
+    /// Sets a value, returning the data it replaced. A replaced value keeps its position.
+    pub fn set_value(&mut self, name: &str, data: Data) -> Option<Data> {
+        let folded = fold(name);
+        match self.values.iter_mut().find(|(x, _)| fold(x) == folded) {
+            Some((_, existing)) => Some(std::mem::replace(existing, data)),
+            None => {
+                self.values.push((name.to_string(), data));
+                None
+            }
+        }
+    }
+
+    pub fn remove_value(&mut self, name: &str) -> Option<Data> {
+        let name = fold(name);
+        let index = self.values.iter().position(|(x, _)| fold(x) == name)?;
+        Some(self.values.remove(index).1)
+    }
+
+    #[inline]
+    pub fn last_write_time(&self) -> Option<FileTime> {
+        self.last_write_time
+    }
+
+    #[inline]
+    pub fn set_last_write_time(&mut self, time: Option<FileTime>) {
+        self.last_write_time = time;
+    }
+}
+
+impl<'a> KeySource for &'a Tree {
+    type Error = Infallible;
+
+    fn key_names(&self) -> Result<Vec<String>, Infallible> {
+        Ok(self.keys.iter().map(|(name, _)| name.clone()).collect())
+    }
+
+    fn open_key(&self, name: &str) -> Result<Option<Self>, Infallible> {
+        let tree: &'a Tree = self;
+        Ok(tree.find(name).ok().map(|index| &tree.keys[index].1))
+    }
+
+    fn values(&self) -> Result<Vec<(String, Data)>, Infallible> {
+        Ok(self.values.clone())
+    }
+
+    fn last_write_time(&self) -> Result<Option<FileTime>, Infallible> {
+        Ok(self.last_write_time)
+    }
+}
+
+/// Missing keys are created when setting values, as a tree cannot report an error.
+impl KeyTarget for Tree {
+    type Error = Infallible;
+
+    fn key_exists(&self, path: &str) -> Result<bool, Infallible> {
+        Ok(self.key(path).is_some())
+    }
+
+    fn read_value(&self, path: &str, name: &str) -> Result<Option<Data>, Infallible> {
+        Ok(self.key(path).and_then(|x| x.value(name)).cloned())
+    }
+
+    fn read_key(&self, path: &str) -> Result<Option<Tree>, Infallible> {
+        Ok(self.key(path).cloned())
+    }
+
+    fn create_key(&mut self, path: &str) -> Result<(), Infallible> {
+        Tree::create_key(self, path);
+        Ok(())
+    }
+
+    fn delete_key(&mut self, path: &str) -> Result<(), Infallible> {
+        if components(path).next().is_none() {
+            self.keys.clear();
+            self.values.clear();
+        } else {
+            self.remove_key(path);
+        }
+        Ok(())
+    }
+
+    fn set_value(&mut self, path: &str, name: &str, data: &Data) -> Result<(), Infallible> {
+        Tree::create_key(self, path).set_value(name, data.clone());
+        Ok(())
+    }
+
+    fn delete_value(&mut self, path: &str, name: &str) -> Result<(), Infallible> {
+        if let Some(key) = self.key_mut(path) {
+            key.remove_value(name);
+        }
+        Ok(())
+    }
+}
+
+impl<'a> KeySource for offline::Key<'a> {
+    type Error = offline::Error;
+
+    fn key_names(&self) -> Result<Vec<String>, offline::Error> {
+        self.keys()?
+            .map(|key| Ok(key?.name()?.to_string_lossy()))
+            .collect()
+    }
+
+    fn open_key(&self, name: &str) -> Result<Option<Self>, offline::Error> {
+        self.subkey(name)
+    }
+
+    fn values(&self) -> Result<Vec<(String, Data)>, offline::Error> {
+        offline::Key::values(self)?
+            .map(|value| {
+                let value = value?;
+                Ok((value.name()?.to_string_lossy(), value.data()?))
+            })
+            .collect()
+    }
+
+    fn last_write_time(&self) -> Result<Option<FileTime>, offline::Error> {
+        offline::Key::last_write_time(self).map(Some)
+    }
+}
+
+impl KeySource for crate::RegKey {
+    type Error = crate::Error;
+
+    fn key_names(&self) -> Result<Vec<String>, crate::Error> {
+        self.keys().map(|key| Ok(key?.to_string())).collect()
+    }
+
+    fn open_key(&self, name: &str) -> Result<Option<Self>, crate::Error> {
+        match self.open(name, crate::Security::Read) {
+            Ok(key) => Ok(Some(key)),
+            Err(crate::key::Error::NotFound(..)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn values(&self) -> Result<Vec<(String, Data)>, crate::Error> {
+        crate::RegKey::values(self)
+            .map(|value| {
+                let (name, data) = value?.into_inner();
+                Ok((name.to_string_lossy(), data))
+            })
+            .collect()
+    }
+
+    fn last_write_time(&self) -> Result<Option<FileTime>, crate::Error> {
+        Ok(Some(self.info()?.last_write_time()))
+    }
+}
+
+fn not_found<T>(result: Result<T, offline::Error>) -> Result<Option<T>, offline::Error> {
+    match result {
+        Ok(x) => Ok(Some(x)),
+        Err(offline::Error::NotFound(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+impl KeyTarget for offline::Editor {
+    type Error = offline::Error;
+
+    fn key_exists(&self, path: &str) -> Result<bool, offline::Error> {
+        Ok(not_found(self.hive().open(path))?.is_some())
+    }
+
+    fn read_value(&self, path: &str, name: &str) -> Result<Option<Data>, offline::Error> {
+        let hive = self.hive();
+        match not_found(hive.open(path))? {
+            Some(key) => not_found(key.value(name).and_then(|x| x.data())),
+            None => Ok(None),
+        }
+    }
+
+    fn read_key(&self, path: &str) -> Result<Option<Tree>, offline::Error> {
+        let hive = self.hive();
+        match not_found(hive.open(path))? {
+            Some(key) => Tree::capture(&key).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn create_key(&mut self, path: &str) -> Result<(), offline::Error> {
+        self.create(path).map(drop)
+    }
+
+    fn delete_key(&mut self, path: &str) -> Result<(), offline::Error> {
+        if components(path).next().is_some() {
+            return not_found(self.delete(path, true)).map(drop);
+        }
+
+        // The root of a hive cannot be deleted, so it is emptied instead.
+        let (keys, values) = {
+            let hive = self.hive();
+            let root = hive.root()?;
+            (root.key_names()?, KeySource::values(&root)?)
+        };
+        let mut root = self.root();
+        for name in keys {
+            root.delete(&*name, true)?;
+        }
+        for (name, _) in values {
+            root.delete_value(&*name)?;
+        }
+        Ok(())
+    }
+
+    fn set_value(&mut self, path: &str, name: &str, data: &Data) -> Result<(), offline::Error> {
+        self.open(path)?.set_value(name, data)
+    }
+
+    fn delete_value(&mut self, path: &str, name: &str) -> Result<(), offline::Error> {
+        match not_found(self.open(path))? {
+            Some(mut key) => not_found(key.delete_value(name)).map(drop),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Implements `KeyTarget` for the live registry, where `Hive` and `RegKey` share the same
+/// methods for opening, creating and deleting keys.
+macro_rules! impl_key_target {
+    ($ty:ty) => {
+        impl KeyTarget for $ty {
+            type Error = crate::Error;
+
+            fn key_exists(&self, path: &str) -> Result<bool, crate::Error> {
+                match self.open(path, crate::Security::Read) {
+                    Ok(_) => Ok(true),
+                    Err(crate::key::Error::NotFound(..)) => Ok(false),
+                    Err(e) => Err(e.into()),
+                }
+            }
+
+            fn read_value(&self, path: &str, name: &str) -> Result<Option<Data>, crate::Error> {
+                let key = match self.open(path, crate::Security::Read) {
+                    Ok(key) => key,
+                    Err(crate::key::Error::NotFound(..)) => return Ok(None),
+                    Err(e) => return Err(e.into()),
+                };
+                match key.value(name) {
+                    Ok(data) => Ok(Some(data)),
+                    Err(crate::value::Error::NotFound(..)) => Ok(None),
+                    Err(e) => Err(e.into()),
+                }
+            }
+
+            fn read_key(&self, path: &str) -> Result<Option<Tree>, crate::Error> {
+                match self.open(path, crate::Security::Read) {
+                    Ok(key) => Tree::capture(&key).map(Some),
+                    Err(crate::key::Error::NotFound(..)) => Ok(None),
+                    Err(e) => Err(e.into()),
+                }
+            }
+
+            fn create_key(&mut self, path: &str) -> Result<(), crate::Error> {
+                self.create(path, crate::Security::Read)?;
+                Ok(())
+            }
+
+            fn delete_key(&mut self, path: &str) -> Result<(), crate::Error> {
+                match self.delete(path, true) {
+                    Ok(()) | Err(crate::key::Error::NotFound(..)) => Ok(()),
+                    Err(e) => Err(e.into()),
+                }
+            }
+
+            fn set_value(
+                &mut self,
+                path: &str,
+                name: &str,
+                data: &Data,
+            ) -> Result<(), crate::Error> {
+                self.open(path, crate::Security::SetValue)?
+                    .set_value(name, data)?;
+                Ok(())
+            }
+
+            fn delete_value(&mut self, path: &str, name: &str) -> Result<(), crate::Error> {
+                let key = match self.open(path, crate::Security::SetValue) {
+                    Ok(key) => key,
+                    Err(crate::key::Error::NotFound(..)) => return Ok(()),
+                    Err(e) => return Err(e.into()),
+                };
+                match key.delete_value(name) {
+                    Ok(()) | Err(crate::value::Error::NotFound(..)) => Ok(()),
+                    Err(e) => Err(e.into()),
+                }
+            }
+        }
+    };
+}
+
+impl_key_target!(crate::Hive);
+impl_key_target!(crate::RegKey);
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+    use crate::offline::fixture::{self, FixtureKey, FixtureValue};
+    use crate::offline::Hive;
+
+    #[test]
+    fn keys_sort_and_match_ignoring_case() {
+        let mut tree = Tree::new();
+        tree.create_key("beta");
+        tree.create_key(r"Alpha\Inner");
+        tree.create_key(r"ALPHA\inner\Deep");
+        tree.create_key("_under");
+
+        let names = tree.keys().map(|(name, _)| name).collect::<Vec<_>>();
+        assert_eq!(names, ["Alpha", "beta", "_under"]);
+        assert!(tree.key(r"alpha\INNER\deep").is_some());
+
+        let key = tree.key_mut("Alpha").unwrap();
+        assert_eq!(key.set_value("Name", Data::U32(1)), None);
+        assert_eq!(key.set_value("NAME", Data::U32(2)), Some(Data::U32(1)));
+        assert_eq!(key.values(), &[("Name".to_string(), Data::U32(2))]);
+
+        assert!(tree.remove_key(r"alpha\inner").is_some());
+        assert!(tree.key(r"Alpha\Inner").is_none());
+        assert!(tree.remove_key("").is_none());
+    }
+
+    #[test]
+    fn captures_offline_hive() {
+        let root = FixtureKey::new("ROOT")
+            .value(FixtureValue::new("Version", Data::U32(2)))
+            .key(
+                FixtureKey::new("Sub")
+                    .value(FixtureValue::new("", Data::String("x".try_into().unwrap()))),
+            );
+        let hive = Hive::from_bytes(fixture::build(&root, 8)).unwrap();
+        let root = hive.root().unwrap();
+
+        let tree = Tree::capture(&root).unwrap();
+        assert_eq!(tree.value("version"), Some(&Data::U32(2)));
+        assert_eq!(
+            tree.key("sub").unwrap().last_write_time(),
+            Some(root.open("Sub").unwrap().last_write_time().unwrap())
+        );
+
+        // A captured tree is itself a source, and captures to an identical copy.
+        assert_eq!(Tree::capture(&&tree).unwrap(), tree);
+    }
+}
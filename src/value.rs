@@ -1,11 +1,18 @@
 use std::{
-    convert::{Infallible, TryFrom, TryInto},
+    convert::{Infallible, TryFrom},
     fmt::{Debug, Display},
     io,
 };
 
 use utfx::U16CString;
-use windows::{core::PCWSTR, Win32::System::Registry::{RegDeleteValueW, RegQueryValueExW, RegSetValueExW, HKEY, REG_BINARY, REG_DWORD, REG_DWORD_BIG_ENDIAN, REG_EXPAND_SZ, REG_FULL_RESOURCE_DESCRIPTOR, REG_LINK, REG_MULTI_SZ, REG_NONE, REG_QWORD, REG_RESOURCE_LIST, REG_RESOURCE_REQUIREMENTS_LIST, REG_SZ, REG_VALUE_TYPE}};
+
+use crate::backend::ErrorCode;
+#[cfg(windows)]
+use windows::Win32::System::Registry::{
+    REG_BINARY, REG_DWORD, REG_DWORD_BIG_ENDIAN, REG_EXPAND_SZ, REG_FULL_RESOURCE_DESCRIPTOR,
+    REG_LINK, REG_MULTI_SZ, REG_NONE, REG_QWORD, REG_RESOURCE_LIST, REG_RESOURCE_REQUIREMENTS_LIST,
+    REG_SZ, REG_VALUE_TYPE,
+};
 
 #[derive(Debug, thiserror::Error)]
 #[non_exhaustive]
@@ -19,6 +26,9 @@ pub enum Error {
     #[error("Unhandled type: 0x{0:x}")]
     UnhandledType(u32),
 
+    #[error("Invalid data length {1} for type 0x{0:x}")]
+    InvalidLength(u32, usize),
+
     #[error("Invalid null found in string")]
     InvalidNul(#[from] utfx::NulError<u16>),
 
@@ -44,22 +54,19 @@ pub enum Error {
 }
 
 impl Error {
-    #[cfg(test)]
+    #[cfg(all(test, windows))]
     pub(crate) fn is_not_found(&self) -> bool {
-        match self {
-            Error::NotFound(_, _) => true,
-            _ => false,
-        }
+        matches!(self, Error::NotFound(_, _))
     }
 
-    fn from_code(code: i32, value_name: String) -> Self {
-        let err = std::io::Error::from_raw_os_error(code);
+    pub(crate) fn from_code(code: ErrorCode, value_name: String) -> Self {
+        let err = io::Error::from(code);
 
-        return match err.kind() {
+        match code.kind() {
             io::ErrorKind::NotFound => Error::NotFound(value_name, err),
             io::ErrorKind::PermissionDenied => Error::PermissionDenied(value_name, err),
             _ => Error::Unknown(value_name, err),
-        };
+        }
     }
 }
 
@@ -90,6 +97,7 @@ impl Type {
     const MAX: u32 = 11;
 }
 
+#[cfg(windows)]
 impl From<Type> for REG_VALUE_TYPE {
     fn from(ty: Type) -> Self {
         match ty {
@@ -109,6 +117,7 @@ impl From<Type> for REG_VALUE_TYPE {
     }
 }
 
+#[cfg(windows)]
 impl TryFrom<REG_VALUE_TYPE> for Type {
     type Error = Error;
 
@@ -132,7 +141,7 @@ impl TryFrom<REG_VALUE_TYPE> for Type {
 }
 
 /// A type-safe wrapper around Windows Registry value data.
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
 pub enum Data {
     None,
     String(U16CString),
@@ -146,6 +155,9 @@ pub enum Data {
     FullResourceDescriptor,
     ResourceRequirementsList,
     U64(u64),
+    /// Data of `REG_NONE`, `REG_LINK` or one of the resource types, kept as the bytes stored
+    /// with its type code, since the variants for those types hold none.
+    Raw(u32, Vec<u8>),
 }
 
 impl Debug for Data {
@@ -163,12 +175,13 @@ impl Debug for Data {
             Data::U32BE(x) => write!(f, "U32BE({})", x),
             Data::Link => f.write_str("Link"),
             x @ Data::MultiString(_) => {
-                write!(f, "MultiString({})", x.to_string())
+                write!(f, "MultiString({})", x)
             }
             Data::ResourceList => f.write_str("ResourceList"),
             Data::FullResourceDescriptor => f.write_str("FullResourceDescriptor"),
             Data::ResourceRequirementsList => f.write_str("ResourceRequirementsList"),
             Data::U64(x) => write!(f, "U64({})", x),
+            Data::Raw(ty, x) => write!(f, "Raw(0x{:x}, {:?})", ty, x),
         }
     }
 }
@@ -198,13 +211,88 @@ impl Display for Data {
             Data::FullResourceDescriptor => f.write_str("<Full Resource Descriptor>"),
             Data::ResourceRequirementsList => f.write_str("<Resource Requirements List>"),
             Data::U64(x) => write!(f, "0x{:032x}", x),
+            Data::Raw(_, x) => write!(
+                f,
+                "<{}>",
+                x.iter()
+                    .map(|x| format!("{:02x}", x))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
         }
     }
 }
 
 impl Data {
-    fn as_type(&self) -> Type {
-        match self {
+    /// Parses value data from the raw bytes and type code as stored in the registry.
+    ///
+    /// Strings are read up to their first null or the end of the data, whichever comes first,
+    /// as data written by other tools is not always correctly terminated. Data of a type
+    /// whose variant holds none is returned as `Data::Raw` unless it is empty.
+    pub fn from_bytes(ty: u32, bytes: &[u8]) -> Result<Data, Error> {
+        let ty = Type::try_from(ty).map_err(|e| Error::UnhandledType(e.0))?;
+        let check_len = |len: usize| {
+            if bytes.len() < len {
+                Err(Error::InvalidLength(ty as u32, bytes.len()))
+            } else {
+                Ok(())
+            }
+        };
+
+        let raw = |data: Data| match bytes {
+            [] => data,
+            _ => Data::Raw(ty as u32, bytes.to_vec()),
+        };
+
+        Ok(match ty {
+            Type::None => raw(Data::None),
+            Type::String => Data::String(parse_utf16_bytes(bytes)),
+            Type::ExpandString => Data::ExpandString(parse_utf16_bytes(bytes)),
+            Type::Binary => Data::Binary(bytes.to_vec()),
+            Type::U32 => {
+                check_len(4)?;
+                Data::U32(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            }
+            Type::U32BE => {
+                check_len(4)?;
+                Data::U32BE(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            }
+            Type::Link => raw(Data::Link),
+            Type::MultiString => Data::MultiString(parse_utf16_multi_bytes(bytes)),
+            Type::ResourceList => raw(Data::ResourceList),
+            Type::FullResourceDescriptor => raw(Data::FullResourceDescriptor),
+            Type::ResourceRequirementsList => raw(Data::ResourceRequirementsList),
+            Type::U64 => {
+                check_len(8)?;
+                let mut buf = [0u8; 8];
+                buf.copy_from_slice(&bytes[..8]);
+                Data::U64(u64::from_le_bytes(buf))
+            }
+        })
+    }
+
+    /// Parses value data as `from_bytes` does, but fails if a string is missing its null
+    /// terminator or a multi-string its final pair of nulls.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub(crate) fn from_bytes_strict(ty: u32, bytes: &[u8]) -> Result<Data, Error> {
+        match Type::try_from(ty) {
+            Ok(Type::String) | Ok(Type::ExpandString) => {
+                U16CString::from_vec_with_nul(bytes_to_utf16(bytes))?;
+            }
+            Ok(Type::MultiString) => {
+                let vec = bytes_to_utf16(bytes);
+                if !vec.ends_with(&[0, 0]) {
+                    return Err(Error::MissingMultiNul);
+                }
+            }
+            _ => {}
+        }
+        Data::from_bytes(ty, bytes)
+    }
+
+    /// The type code the data is stored with.
+    pub(crate) fn ty(&self) -> u32 {
+        let ty = match self {
             Data::None => Type::None,
             Data::String(_) => Type::String,
             Data::ExpandString(_) => Type::ExpandString,
@@ -217,10 +305,12 @@ impl Data {
             Data::FullResourceDescriptor => Type::FullResourceDescriptor,
             Data::ResourceRequirementsList => Type::ResourceRequirementsList,
             Data::U64(_) => Type::U64,
-        }
+            Data::Raw(ty, _) => return *ty,
+        };
+        ty as u32
     }
 
-    fn to_bytes(&self) -> Vec<u8> {
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
         match self {
             Data::None => vec![],
             Data::String(s) => string_to_utf16_byte_vec(s),
@@ -234,15 +324,47 @@ impl Data {
             Data::FullResourceDescriptor => vec![],
             Data::ResourceRequirementsList => vec![],
             Data::U64(x) => x.to_le_bytes().to_vec(),
+            Data::Raw(_, x) => x.to_vec(),
         }
     }
 }
 
+fn bytes_to_utf16(bytes: &[u8]) -> Vec<u16> {
+    bytes
+        .chunks_exact(2)
+        .map(|x| u16::from_le_bytes([x[0], x[1]]))
+        .collect()
+}
+
+fn parse_utf16_bytes(bytes: &[u8]) -> U16CString {
+    let mut vec = bytes_to_utf16(bytes);
+    if let Some(len) = vec.iter().position(|x| *x == 0) {
+        vec.truncate(len);
+    }
+    // SAFETY: truncated at the first null, if there was one.
+    unsafe { U16CString::from_vec_unchecked(vec) }
+}
+
+fn parse_utf16_multi_bytes(bytes: &[u8]) -> Vec<U16CString> {
+    let vec = bytes_to_utf16(bytes);
+    let mut strings = vec
+        .split(|x| *x == 0)
+        // SAFETY: split on nulls, so no part contains one.
+        .map(|x| unsafe { U16CString::from_vec_unchecked(x) })
+        .collect::<Vec<_>>();
+
+    // Drop the empty strings produced by the terminators.
+    while strings.last().map(|x| x.is_empty()).unwrap_or(false) {
+        strings.pop();
+    }
+    strings
+}
+
 #[inline(always)]
 fn multi_string_bytes(s: &[U16CString]) -> Vec<u8> {
     let mut vec = s
         .iter()
-        .flat_map(|x| string_to_utf16_byte_vec(&*x))
+        .flat_map(string_to_utf16_byte_vec)
         .collect::<Vec<u8>>();
     vec.push(0);
     vec.push(0);
@@ -258,113 +380,6 @@ fn string_to_utf16_byte_vec(s: &U16CString) -> Vec<u8> {
         .collect()
 }
 
-fn parse_wide_string_nul(vec: Vec<u16>) -> Result<U16CString, Error> {
-    Ok(U16CString::from_vec_with_nul(vec)?)
-}
-
-fn parse_wide_multi_string(vec: Vec<u16>) -> Result<Vec<U16CString>, Error> {
-    let len = vec.len();
-    if len < 2 || vec[len - 1] != 0 || vec[len - 2] != 0 {
-        return Err(Error::MissingMultiNul);
-    }
-
-    (&vec[0..vec.len() - 2])
-        .split(|x| *x == 0)
-        .map(U16CString::new)
-        .collect::<Result<Vec<_>, _>>()
-        .map_err(Error::InvalidNul)
-}
-
-#[inline]
-pub(crate) fn set_value<S>(base: HKEY, value_name: S, data: &Data) -> Result<(), Error>
-where
-    S: TryInto<U16CString>,
-    S::Error: Into<Error>,
-{
-    let value_name = value_name.try_into().map_err(Into::into)?;
-    let raw_ty = data.as_type();
-    let vec = data.to_bytes();
-    let result = unsafe {
-        RegSetValueExW(
-            base,
-            PCWSTR(value_name.as_ptr()),
-            0,
-            raw_ty.into(),
-            Some(&vec),
-        )
-    };
-
-    if result.is_err() {
-        return Err(Error::from_code(result.0 as i32, value_name.to_string_lossy()));
-    }
-
-    Ok(())
-}
-
-#[inline]
-pub(crate) fn delete_value<S>(base: HKEY, value_name: S) -> Result<(), Error>
-where
-    S: TryInto<U16CString>,
-    S::Error: Into<Error>,
-{
-    let value_name = value_name.try_into().map_err(Into::into)?;
-    let result = unsafe { RegDeleteValueW(base, PCWSTR(value_name.as_ptr())) };
-
-    if result.is_err() {
-        return Err(Error::from_code(result.0 as i32, value_name.to_string_lossy()));
-    }
-
-    Ok(())
-}
-
-#[inline]
-pub(crate) fn query_value<S>(base: HKEY, value_name: S) -> Result<Data, Error>
-where
-    S: TryInto<U16CString>,
-    S::Error: Into<Error>,
-{
-    let value_name = value_name.try_into().map_err(Into::into)?;
-    let mut sz: u32 = 0;
-
-    // Get the required buffer size first
-    let result = unsafe {
-        RegQueryValueExW(
-            base,
-            PCWSTR(value_name.as_ptr()),
-            None,
-            None,
-            None,
-            Some(&mut sz),
-        )
-    };
-
-    if result.is_err() {
-        return Err(Error::from_code(result.0 as i32, value_name.to_string_lossy()));
-    }
-
-    // sz is size in bytes, we'll make a u16 vec.
-    let mut buf: Vec<u16> = vec![0u16; (sz / 2 + sz % 2) as usize];
-    let mut ty = REG_VALUE_TYPE::default();
-
-    // Get the actual value
-    let result = unsafe {
-        RegQueryValueExW(
-            base,
-            PCWSTR(value_name.as_ptr()),
-            None,
-            Some(&mut ty),
-            Some(buf.as_mut_ptr() as *mut u8),
-            Some(&mut sz),
-        )
-    };
-
-    if result.is_err() {
-        return Err(Error::from_code(result.0 as i32, value_name.to_string_lossy()));
-    }
-
-    parse_value_type_data(ty, buf)
-}
-
 pub fn u16_to_u8_vec(mut vec: Vec<u16>) -> Vec<u8> {
     unsafe {
         let capacity = vec.capacity();
@@ -375,39 +390,6 @@ pub fn u16_to_u8_vec(mut vec: Vec<u16>) -> Vec<u8> {
     }
 }
 
-#[inline(always)]
-pub(crate) fn parse_value_type_data(ty: REG_VALUE_TYPE, buf: Vec<u16>) -> Result<Data, Error> {
-    let ty = Type::try_from(ty)?;
-
-    match ty {
-        Type::None => return Ok(Data::None),
-        Type::String => return parse_wide_string_nul(buf).map(Data::String),
-        Type::ExpandString => return parse_wide_string_nul(buf).map(Data::ExpandString),
-        Type::Link => return Ok(Data::Link),
-        Type::MultiString => return parse_wide_multi_string(buf).map(Data::MultiString),
-        Type::ResourceList => return Ok(Data::ResourceList),
-        Type::FullResourceDescriptor => return Ok(Data::FullResourceDescriptor),
-        Type::ResourceRequirementsList => return Ok(Data::ResourceRequirementsList),
-        _ => {}
-    }
-
-    let buf = u16_to_u8_vec(buf);
-
-    match ty {
-        Type::Binary => Ok(Data::Binary(buf)),
-        Type::U32 => Ok(Data::U32(u32::from_le_bytes([
-            buf[0], buf[1], buf[2], buf[3],
-        ]))),
-        Type::U32BE => Ok(Data::U32BE(u32::from_be_bytes([
-            buf[0], buf[1], buf[2], buf[3],
-        ]))),
-        Type::U64 => Ok(Data::U64(u64::from_le_bytes([
-            buf[0], buf[1], buf[2], buf[3], buf[4], buf[5], buf[6], buf[7],
-        ]))),
-        _ => unreachable!(),
-    }
-}
-
 #[derive(Debug, thiserror::Error)]
 #[error("Invalid or unknown type value: {0:#x}")]
 pub struct TryIntoTypeError(u32);
@@ -424,3 +406,82 @@ impl TryFrom<u32> for Type {
         Ok(unsafe { std::mem::transmute::<u32, Type>(ty) })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    #[test]
+    fn data_from_bytes() {
+        assert_eq!(
+            Data::from_bytes(4, &[0x2a, 0, 0, 0]).unwrap(),
+            Data::U32(42)
+        );
+        assert_eq!(
+            Data::from_bytes(5, &[0, 0, 0, 0x2a]).unwrap(),
+            Data::U32BE(42)
+        );
+        assert_eq!(
+            Data::from_bytes(3, &[1, 2, 3]).unwrap(),
+            Data::Binary(vec![1, 2, 3])
+        );
+        assert_eq!(
+            Data::from_bytes(1, &[0x41, 0, 0x42, 0, 0, 0]).unwrap(),
+            Data::String("AB".try_into().unwrap())
+        );
+        // Unterminated strings are accepted.
+        assert_eq!(
+            Data::from_bytes(2, &[0x41, 0]).unwrap(),
+            Data::ExpandString("A".try_into().unwrap())
+        );
+        // Types without a variant holding data keep their bytes.
+        assert_eq!(Data::from_bytes(0, &[]).unwrap(), Data::None);
+        assert_eq!(Data::from_bytes(6, &[]).unwrap(), Data::Link);
+        let data = Data::from_bytes(8, &[1, 2]).unwrap();
+        assert_eq!(data, Data::Raw(8, vec![1, 2]));
+        assert_eq!((data.ty(), data.to_bytes()), (8, vec![1, 2]));
+        assert!(matches!(
+            Data::from_bytes(11, &[0; 4]),
+            Err(Error::InvalidLength(11, 4))
+        ));
+        assert!(matches!(
+            Data::from_bytes(0x20, &[]),
+            Err(Error::UnhandledType(0x20))
+        ));
+    }
+
+    #[test]
+    fn multi_string_round_trip() {
+        let data = Data::MultiString(vec![
+            "one".try_into().unwrap(),
+            "".try_into().unwrap(),
+            "three".try_into().unwrap(),
+        ]);
+        assert_eq!(Data::from_bytes(7, &data.to_bytes()).unwrap(), data);
+        assert_eq!(
+            Data::from_bytes(7, &[0, 0]).unwrap(),
+            Data::MultiString(vec![])
+        );
+    }
+
+    #[test]
+    fn strict_data_needs_terminators() {
+        assert_eq!(
+            Data::from_bytes_strict(1, &[0x41, 0, 0, 0]).unwrap(),
+            Data::String("A".try_into().unwrap())
+        );
+        assert!(matches!(
+            Data::from_bytes_strict(2, &[0x41, 0]),
+            Err(Error::MissingNul(_))
+        ));
+        assert!(matches!(
+            Data::from_bytes_strict(7, &[0x41, 0, 0, 0]),
+            Err(Error::MissingMultiNul)
+        ));
+        assert_eq!(
+            Data::from_bytes_strict(4, &[0x2a, 0, 0, 0]).unwrap(),
+            Data::U32(42)
+        );
+    }
+}
@@ -0,0 +1,146 @@
+//! Reading and writing Wine's text registry files, such as `system.reg` and `user.reg` in a
+//! Wine prefix, as [`Tree`](../tree/struct.Tree.html)s.
+//!
+//! Each file holds the keys below one root, such as `Machine` for `system.reg` or
+//! `User\S-1-5-21-0-0-0-1000` for `user.reg`, with key paths relative to it. Files are
+//! written the way `wineserver` saves them, so a seeded prefix looks as if Wine wrote it.
+//!
+//! Value data is read the same way as in `.reg` files: data of `REG_NONE`, `REG_LINK` and the
+//! resource types is kept as `Data::Raw`, and types the crate does not know are reported as
+//! [`Error::UnsupportedType`].
+//!
+//! ```
+//! use registry::{wine::RegistryFile, Data};
+//!
+//! let mut file = RegistryFile::parse(r#"WINE REGISTRY Version 2
+//! ;; All keys relative to \\Machine
+//!
+//! [Software\\Contoso] 1700000000
+//! "Version"=dword:00000002
+//! "#)?;
+//!
+//! assert_eq!(file.hive_path().as_deref(), Some("HKEY_LOCAL_MACHINE"));
+//! let contoso = file.tree_mut().create_key(r"Software\Contoso");
+//! assert_eq!(contoso.value("Version"), Some(&Data::U32(2)));
+//! contoso.set_value("Installed", Data::U32(1));
+//! # Ok::<(), registry::wine::Error>(())
+//! ```
+
+use std::fmt::Display;
+
+use crate::tree::{components, fold, Tree};
+
+mod parse;
+mod write;
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("Missing or unsupported header")]
+    InvalidHeader,
+
+    #[error("Syntax error on line {0}: {1}")]
+    Syntax(usize, &'static str),
+
+    #[error("Unsupported value type {1:#x} on line {0}")]
+    UnsupportedType(usize, u32),
+
+    #[error("Invalid value data on line {0}")]
+    Data(usize, #[source] crate::value::Error),
+}
+
+/// The header line that starts a registry file.
+const HEADER: &str = "WINE REGISTRY Version 2";
+
+/// The contents of a Wine registry file: the root its keys are relative to, and the keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegistryFile {
+    root: String,
+    arch: Option<String>,
+    tree: Tree,
+}
+
+impl RegistryFile {
+    /// A file holding `tree` below `root`, a backslash separated path in Wine's registry such
+    /// as `Machine`.
+    pub fn new(root: &str, tree: Tree) -> RegistryFile {
+        RegistryFile {
+            root: components(root).collect::<Vec<_>>().join("\\"),
+            arch: None,
+            tree,
+        }
+    }
+
+    /// Parses the text of a registry file.
+    pub fn parse(text: &str) -> Result<RegistryFile, Error> {
+        parse::parse(text)
+    }
+
+    /// Parses a registry file from its raw bytes. Wine writes characters outside of ASCII as
+    /// escapes, so the file is read as UTF-8.
+    pub fn from_bytes(bytes: &[u8]) -> Result<RegistryFile, Error> {
+        RegistryFile::parse(&String::from_utf8_lossy(bytes))
+    }
+
+    /// The file as it would be written to disk.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+
+    /// The path in Wine's registry that the keys are relative to, such as `Machine`.
+    #[inline]
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+
+    /// The root as a path under a predefined hive, such as `HKEY_LOCAL_MACHINE` for
+    /// `Machine` or `HKEY_USERS\S-1-5-21-0-0-0-1000` for a user's root. Returns `None` for
+    /// roots outside of both.
+    pub fn hive_path(&self) -> Option<String> {
+        let mut names = components(&self.root);
+        let hive = match fold(names.next()?).as_slice() {
+            x if x == fold("Machine").as_slice() => "HKEY_LOCAL_MACHINE",
+            x if x == fold("User").as_slice() => "HKEY_USERS",
+            _ => return None,
+        };
+        Some(
+            std::iter::once(hive)
+                .chain(names)
+                .collect::<Vec<_>>()
+                .join("\\"),
+        )
+    }
+
+    /// The architecture of the prefix, such as `win64`, if the file records one.
+    #[inline]
+    pub fn arch(&self) -> Option<&str> {
+        self.arch.as_deref()
+    }
+
+    #[inline]
+    pub fn set_arch(&mut self, arch: Option<&str>) {
+        self.arch = arch.map(str::to_string);
+    }
+
+    #[inline]
+    pub fn tree(&self) -> &Tree {
+        &self.tree
+    }
+
+    #[inline]
+    pub fn tree_mut(&mut self) -> &mut Tree {
+        &mut self.tree
+    }
+
+    #[inline]
+    pub fn into_tree(self) -> Tree {
+        self.tree
+    }
+}
+
+/// Writes the text of the file, as `wineserver` does.
+impl Display for RegistryFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&write::write(self))
+    }
+}
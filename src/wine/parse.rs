@@ -0,0 +1,255 @@
+use std::iter::Peekable;
+use std::str::CharIndices;
+use std::time::{Duration, UNIX_EPOCH};
+
+use super::{Error, RegistryFile, HEADER};
+use crate::tree::Tree;
+use crate::{Data, FileTime};
+
+pub(super) fn parse(text: &str) -> Result<RegistryFile, Error> {
+    let mut lines = text.lines().enumerate().map(|(i, line)| (i + 1, line));
+    match lines.next() {
+        Some((_, line)) if line.trim_end() == HEADER => {}
+        _ => return Err(Error::InvalidHeader),
+    }
+
+    let mut root = String::new();
+    let mut arch = None;
+    let mut tree = Tree::new();
+    // The key that options and values currently apply to, if any.
+    let mut current: Option<String> = None;
+
+    while let Some((number, line)) = lines.next() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(path) = line.strip_prefix(";; All keys relative to ") {
+            let (path, _) = unescape(path, None, number)?;
+            root = super::components(&String::from_utf16_lossy(&path))
+                .collect::<Vec<_>>()
+                .join("\\");
+            continue;
+        }
+        if line.starts_with(';') {
+            continue;
+        }
+
+        if let Some(rest) = line.strip_prefix('[') {
+            let (path, rest) = unescape(rest, Some(']'), number)?;
+            let path = String::from_utf16_lossy(&path);
+            let key = tree.create_key(&path);
+
+            // The seconds since the Unix epoch, superseded by any `#time` option that follows.
+            // Zero stands for an unknown time.
+            if let Ok(seconds @ 1..) = rest.trim().parse::<u64>() {
+                let time = UNIX_EPOCH + Duration::from_secs(seconds);
+                key.set_last_write_time(Some(time.into()));
+            }
+            current = Some(path);
+            continue;
+        }
+
+        if let Some(option) = line.strip_prefix('#') {
+            match &current {
+                Some(path) => {
+                    if let Some(time) = option.strip_prefix("time=") {
+                        let time = u64::from_str_radix(time, 16)
+                            .map_err(|_| Error::Syntax(number, "invalid time"))?;
+                        tree.create_key(path)
+                            .set_last_write_time(Some(FileTime::new(time)));
+                    }
+                }
+                None => {
+                    if let Some(value) = option.strip_prefix("arch=") {
+                        arch = Some(value.to_string());
+                    }
+                }
+            }
+            // Other options, such as classes and symbolic links, are not kept.
+            continue;
+        }
+
+        if !line.starts_with('"') && !line.starts_with('@') {
+            return Err(Error::Syntax(number, "expected a key or value"));
+        }
+
+        let path = current
+            .as_ref()
+            .ok_or(Error::Syntax(number, "value outside of a key"))?;
+        let (name, rest) = match line.strip_prefix('@') {
+            Some(rest) => (String::new(), rest),
+            None => {
+                let (name, rest) = unescape(&line[1..], Some('"'), number)?;
+                (String::from_utf16_lossy(&name), rest)
+            }
+        };
+        let rest = rest
+            .strip_prefix('=')
+            .ok_or(Error::Syntax(number, "expected '=' after value name"))?;
+
+        // Hex data may continue onto following lines, each ending with a backslash.
+        let mut data = rest.to_string();
+        if data.starts_with("hex") {
+            while data.ends_with('\\') {
+                data.pop();
+                match lines.next() {
+                    Some((_, next)) => data.push_str(next.trim()),
+                    None => break,
+                }
+            }
+        }
+
+        let data = parse_data(&data, number)?;
+        tree.create_key(path).set_value(&name, data);
+    }
+
+    Ok(RegistryFile { root, arch, tree })
+}
+
+/// Reads an escaped string up to an unescaped `end`, or the end of the text if there is none.
+/// Returns the UTF-16 string and the text after `end`.
+///
+/// Escapes are those written by Wine: C style control characters, octal and `\x` hex code
+/// units, and a backslash before any other character standing for that character.
+fn unescape(s: &str, end: Option<char>, number: usize) -> Result<(Vec<u16>, &str), Error> {
+    let mut out = vec![];
+    let mut chars = s.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        if Some(c) == end {
+            return Ok((out, &s[i + 1..]));
+        }
+        if c != '\\' {
+            let mut buf = [0u16; 2];
+            out.extend_from_slice(c.encode_utf16(&mut buf));
+            continue;
+        }
+
+        let (_, c) = chars
+            .next()
+            .ok_or(Error::Syntax(number, "unterminated escape"))?;
+        let unit = match c {
+            'a' => 0x07,
+            'b' => 0x08,
+            'e' => 0x1b,
+            'f' => 0x0c,
+            'n' => 0x0a,
+            'r' => 0x0d,
+            't' => 0x09,
+            'v' => 0x0b,
+            'x' => match digits(&mut chars, 16, 4, 0) {
+                (_, 0) => return Err(Error::Syntax(number, "invalid hex escape")),
+                (unit, _) => unit,
+            },
+            '0'..='7' => digits(&mut chars, 8, 2, c as u32 - u32::from(b'0')).0,
+            c => {
+                let mut buf = [0u16; 2];
+                out.extend_from_slice(c.encode_utf16(&mut buf));
+                continue;
+            }
+        };
+        out.push(unit);
+    }
+
+    match end {
+        Some(_) => Err(Error::Syntax(number, "unterminated string")),
+        None => Ok((out, "")),
+    }
+}
+
+/// Reads up to `max` digits in the given radix onto `value`, returning the result and the
+/// number of digits read.
+fn digits(
+    chars: &mut Peekable<CharIndices<'_>>,
+    radix: u32,
+    max: usize,
+    mut value: u32,
+) -> (u16, usize) {
+    let mut count = 0;
+    while count < max {
+        match chars.peek().and_then(|(_, c)| c.to_digit(radix)) {
+            Some(digit) => {
+                value = value * radix + digit;
+                chars.next();
+                count += 1;
+            }
+            None => break,
+        }
+    }
+    (value as u16, count)
+}
+
+fn parse_data(s: &str, number: usize) -> Result<Data, Error> {
+    let from_bytes = |ty: u32, bytes: &[u8]| match Data::from_bytes(ty, bytes) {
+        Ok(data) => Ok(data),
+        Err(crate::value::Error::UnhandledType(ty)) => Err(Error::UnsupportedType(number, ty)),
+        Err(e) => Err(Error::Data(number, e)),
+    };
+
+    // Strings are written without their final terminator, which is added back here.
+    let string = |ty: u32, s: &str| {
+        let rest = s
+            .strip_prefix('"')
+            .ok_or(Error::Syntax(number, "expected a string"))?;
+        let (mut units, rest) = unescape(rest, Some('"'), number)?;
+        if !rest.trim().is_empty() {
+            return Err(Error::Syntax(number, "unexpected text after string"));
+        }
+        units.push(0);
+        let bytes = units
+            .iter()
+            .flat_map(|x| x.to_le_bytes().to_vec())
+            .collect::<Vec<_>>();
+        from_bytes(ty, &bytes)
+    };
+
+    if s.starts_with('"') {
+        return string(1, s);
+    }
+
+    if let Some(rest) = s.strip_prefix("str(") {
+        let close = rest
+            .find("):")
+            .ok_or(Error::Syntax(number, "invalid string type"))?;
+        let ty = u32::from_str_radix(&rest[..close], 16)
+            .map_err(|_| Error::Syntax(number, "invalid string type"))?;
+        return string(ty, &rest[close + 2..]);
+    }
+
+    if let Some(digits) = s.strip_prefix("dword:") {
+        if digits.is_empty() || digits.len() > 8 {
+            return Err(Error::Syntax(number, "invalid dword"));
+        }
+        let value =
+            u32::from_str_radix(digits, 16).map_err(|_| Error::Syntax(number, "invalid dword"))?;
+        return Ok(Data::U32(value));
+    }
+
+    let (ty, list) = if let Some(list) = s.strip_prefix("hex:") {
+        (3, list)
+    } else if let Some(rest) = s.strip_prefix("hex(") {
+        let close = rest
+            .find("):")
+            .ok_or(Error::Syntax(number, "invalid hex type"))?;
+        let ty = u32::from_str_radix(&rest[..close], 16)
+            .map_err(|_| Error::Syntax(number, "invalid hex type"))?;
+        (ty, &rest[close + 2..])
+    } else {
+        return Err(Error::Syntax(number, "unrecognised value data"));
+    };
+
+    let bytes = list
+        .split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(|x| match x.len() {
+            1 | 2 => {
+                u8::from_str_radix(x, 16).map_err(|_| Error::Syntax(number, "invalid hex byte"))
+            }
+            _ => Err(Error::Syntax(number, "invalid hex byte")),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    from_bytes(ty, &bytes)
+}
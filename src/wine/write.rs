@@ -0,0 +1,289 @@
+use std::fmt::Write;
+use std::time::UNIX_EPOCH;
+
+use super::{RegistryFile, HEADER};
+use crate::regfile::write_hex;
+use crate::tree::Tree;
+use crate::Data;
+
+/// The escapes for control characters, with `.` for those written in octal.
+const ESCAPES: &[u8; 32] = b".......abtnvfr.............e....";
+
+pub(super) fn write(file: &RegistryFile) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "{}", HEADER);
+    out.push_str(";; All keys relative to ");
+    for name in super::components(&file.root) {
+        out.push_str("\\\\");
+        escape(&mut out, name, b"[]");
+    }
+    out.push('\n');
+
+    if let Some(arch) = &file.arch {
+        let _ = write!(out, "\n#arch={}\n", arch);
+    }
+
+    write_key(&mut out, &file.tree, &mut vec![]);
+    out
+}
+
+/// Writes a key and its subkeys. Keys with subkeys but no values are left implied by their
+/// subkeys, as Wine does, unless they have a last write time to keep.
+fn write_key<'a>(out: &mut String, tree: &'a Tree, path: &mut Vec<&'a str>) {
+    if !tree.values().is_empty() || tree.keys().next().is_none() || tree.last_write_time().is_some()
+    {
+        out.push_str("\n[");
+        for (i, name) in path.iter().enumerate() {
+            if i > 0 {
+                out.push_str("\\\\");
+            }
+            escape(out, name, b"[]");
+        }
+
+        let seconds = tree
+            .last_write_time()
+            .and_then(|x| x.to_system_time().duration_since(UNIX_EPOCH).ok())
+            .map(|x| x.as_secs() as u32)
+            .unwrap_or(0);
+        let _ = writeln!(out, "] {}", seconds);
+        if let Some(time) = tree.last_write_time() {
+            let ticks = time.ticks();
+            let _ = writeln!(out, "#time={:x}{:08x}", ticks >> 32, ticks as u32);
+        }
+
+        for (name, data) in tree.values() {
+            write_value(out, name, data);
+        }
+    }
+
+    for (name, key) in tree.keys() {
+        path.push(name);
+        write_key(out, key, path);
+        path.pop();
+    }
+}
+
+fn write_value(out: &mut String, name: &str, data: &Data) {
+    let start = out.len();
+    match name {
+        "" => out.push_str("@="),
+        name => {
+            out.push('"');
+            escape(out, name, b"\"");
+            out.push_str("\"=");
+        }
+    }
+    let column = out.len() - start;
+
+    match data {
+        Data::String(_) | Data::ExpandString(_) | Data::MultiString(_) => {
+            if let Data::ExpandString(_) | Data::MultiString(_) = data {
+                let _ = write!(out, "str({:x}):", data.ty());
+            }
+
+            // The final terminator is left implied.
+            let mut units = data
+                .to_bytes()
+                .chunks_exact(2)
+                .map(|x| u16::from_le_bytes([x[0], x[1]]))
+                .collect::<Vec<_>>();
+            units.pop();
+
+            out.push('"');
+            escape_units(out, &units, b"\"");
+            out.push('"');
+        }
+        Data::U32(x) => {
+            let _ = write!(out, "dword:{:08x}", x);
+        }
+        data => {
+            let prefix = match data.ty() {
+                3 => "hex:".to_string(),
+                ty => format!("hex({:x}):", ty),
+            };
+            out.push_str(&prefix);
+            write_hex(out, column + prefix.len(), &data.to_bytes(), "\n");
+        }
+    }
+    out.push('\n');
+}
+
+fn escape(out: &mut String, s: &str, delimiters: &[u8]) {
+    escape_units(out, &s.encode_utf16().collect::<Vec<_>>(), delimiters);
+}
+
+/// Escapes a UTF-16 string the way `wineserver` does, so that the file is plain ASCII.
+fn escape_units(out: &mut String, units: &[u16], delimiters: &[u8]) {
+    for (i, unit) in units.iter().copied().enumerate() {
+        let next = units.get(i + 1).copied();
+
+        if unit > 127 {
+            // A shorter escape would run into a following hex digit.
+            match next {
+                Some(next) if next < 128 && (next as u8).is_ascii_hexdigit() => {
+                    let _ = write!(out, "\\x{:04x}", unit);
+                }
+                _ => {
+                    let _ = write!(out, "\\x{:x}", unit);
+                }
+            }
+        } else if unit < 32 {
+            match ESCAPES[unit as usize] {
+                b'.' => match next {
+                    Some(next) if (u16::from(b'0')..=u16::from(b'7')).contains(&next) => {
+                        let _ = write!(out, "\\{:03o}", unit);
+                    }
+                    _ => {
+                        let _ = write!(out, "\\{:o}", unit);
+                    }
+                },
+                c => {
+                    out.push('\\');
+                    out.push(c as char);
+                }
+            }
+        } else {
+            let c = unit as u8;
+            if c == b'\\' || delimiters.contains(&c) {
+                out.push('\\');
+            }
+            out.push(c as char);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::super::Error;
+    use super::*;
+    use crate::FileTime;
+
+    const SYSTEM: &str = r#"WINE REGISTRY Version 2
+;; All keys relative to \\Machine
+
+#arch=win64
+
+[Software\\Contoso] 1700000000
+#time=1da1747c66d0000
+@="Default"
+"Caf\xe9"="C:\\Program Files\\Contoso"
+"Path"=str(2):"%SystemRoot%\\system32"
+"List"=str(7):"a\0b\0"
+"Lines"="one\ntwo\x20ac2\0012\1"
+"Count"=dword:0000002a
+"Blob"=hex:00,01,02,03,04,05,06,07,08,09,0a,0b,0c,0d,0e,0f,10,11,12,13,14,15,\
+  16,17,18,19
+"Quad"=hex(b):01,00,00,00,00,00,00,00
+
+[Software\\Contoso\\Empty\[1\]] 1700000000
+#time=1da1747c66d0000
+"#;
+
+    #[test]
+    fn reads_and_writes_like_wine() {
+        let file = RegistryFile::parse(SYSTEM).unwrap();
+        assert_eq!(file.root(), "Machine");
+        assert_eq!(file.arch(), Some("win64"));
+        assert_eq!(file.hive_path().as_deref(), Some("HKEY_LOCAL_MACHINE"));
+
+        let tree = file.tree();
+        let contoso = tree.key(r"Software\Contoso").unwrap();
+        let time = FileTime::new(0x1da1747c66d0000);
+        assert_eq!(contoso.last_write_time(), Some(time));
+        assert_eq!(
+            contoso.value("Caf\u{e9}"),
+            Some(&Data::String(
+                r"C:\Program Files\Contoso".try_into().unwrap()
+            ))
+        );
+        assert_eq!(
+            contoso.value("Path"),
+            Some(&Data::ExpandString(
+                r"%SystemRoot%\system32".try_into().unwrap()
+            ))
+        );
+        assert_eq!(
+            contoso.value("List"),
+            Some(&Data::MultiString(vec![
+                "a".try_into().unwrap(),
+                "b".try_into().unwrap()
+            ]))
+        );
+        assert_eq!(
+            contoso.value("Lines"),
+            Some(&Data::String(
+                "one\ntwo\u{20ac}2\u{1}2\u{1}".try_into().unwrap()
+            ))
+        );
+        assert_eq!(
+            contoso.value("Blob"),
+            Some(&Data::Binary((0..26).collect()))
+        );
+        assert_eq!(contoso.value("Quad"), Some(&Data::U64(1)));
+        assert!(tree.key(r"Software\Contoso\Empty[1]").is_some());
+
+        // Keys with only subkeys are implied, and have no time of their own.
+        assert_eq!(tree.key("Software").unwrap().last_write_time(), None);
+
+        assert_eq!(file.to_string(), SYSTEM);
+    }
+
+    #[test]
+    fn writes_relative_user_root() {
+        let mut tree = Tree::new();
+        tree.create_key(r"Software\Wine")
+            .set_value("Version", Data::String("win10".try_into().unwrap()));
+        let file = RegistryFile::new(r"User\S-1-5-21-0-0-0-1000", tree);
+        assert_eq!(
+            file.hive_path().as_deref(),
+            Some(r"HKEY_USERS\S-1-5-21-0-0-0-1000")
+        );
+
+        let text = file.to_string();
+        assert_eq!(
+            text,
+            "WINE REGISTRY Version 2\n\
+;; All keys relative to \\\\User\\\\S-1-5-21-0-0-0-1000\n\
+\n\
+[Software\\\\Wine] 0\n\
+\"Version\"=\"win10\"\n"
+        );
+        assert_eq!(RegistryFile::parse(&text).unwrap(), file);
+    }
+
+    #[test]
+    fn keeps_times_and_data_of_keys() {
+        let mut tree = Tree::new();
+        let time = FileTime::new(0x1da1747c66d0000);
+        tree.create_key("Parent").set_last_write_time(Some(time));
+        tree.create_key(r"Parent\Child")
+            .set_value("Link", Data::Raw(6, vec![0x41, 0]));
+        let file = RegistryFile::new("Machine", tree);
+
+        let text = file.to_string();
+        assert!(text.contains("[Parent] 1700000000\n#time=1da1747c66d0000\n"));
+        assert!(text.contains("\"Link\"=hex(6):41,00\n"));
+        assert_eq!(RegistryFile::parse(&text).unwrap(), file);
+    }
+
+    #[test]
+    fn reports_errors() {
+        let error = |text: &str| RegistryFile::parse(text).unwrap_err();
+
+        assert!(matches!(error("REGEDIT4\n"), Error::InvalidHeader));
+        assert!(matches!(
+            error("WINE REGISTRY Version 2\n\"x\"=dword:1\n"),
+            Error::Syntax(2, "value outside of a key")
+        ));
+        assert!(matches!(
+            error("WINE REGISTRY Version 2\n[A] 0\n\"x\"=str(2):\"abc\n"),
+            Error::Syntax(3, "unterminated string")
+        ));
+        assert!(matches!(
+            error("WINE REGISTRY Version 2\n[A] 0\n\"x\"=hex(100):01\n"),
+            Error::UnsupportedType(3, 0x100)
+        ));
+    }
+}